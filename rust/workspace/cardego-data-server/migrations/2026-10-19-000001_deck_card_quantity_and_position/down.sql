@@ -0,0 +1,15 @@
+DROP INDEX decks_cards_relation_deck_id_card_id;
+
+CREATE TABLE decks_cards_relation_old (
+    id INTEGER NOT NULL PRIMARY KEY,
+    deck_id INTEGER NOT NULL,
+    card_id INTEGER NOT NULL
+);
+
+INSERT INTO decks_cards_relation_old (id, deck_id, card_id)
+SELECT id, deck_id, card_id
+FROM decks_cards_relation
+ORDER BY deck_id, position;
+
+DROP TABLE decks_cards_relation;
+ALTER TABLE decks_cards_relation_old RENAME TO decks_cards_relation;
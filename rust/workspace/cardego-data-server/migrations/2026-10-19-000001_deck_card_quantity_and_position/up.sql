@@ -0,0 +1,31 @@
+-- Decks can now hold several copies of a card in a user-defined order.
+ALTER TABLE decks_cards_relation ADD COLUMN quantity INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE decks_cards_relation ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+
+-- Fold duplicate (deck, card) rows into a single row with a quantity.
+UPDATE decks_cards_relation
+SET quantity = (
+    SELECT COUNT(*)
+    FROM decks_cards_relation AS duplicate
+    WHERE duplicate.deck_id = decks_cards_relation.deck_id
+        AND duplicate.card_id = decks_cards_relation.card_id
+);
+
+DELETE FROM decks_cards_relation
+WHERE id NOT IN (
+    SELECT MIN(id)
+    FROM decks_cards_relation
+    GROUP BY deck_id, card_id
+);
+
+-- Keep the existing insertion order as the initial deck order.
+UPDATE decks_cards_relation
+SET position = (
+    SELECT COUNT(*)
+    FROM decks_cards_relation AS previous
+    WHERE previous.deck_id = decks_cards_relation.deck_id
+        AND previous.id < decks_cards_relation.id
+);
+
+CREATE UNIQUE INDEX decks_cards_relation_deck_id_card_id
+    ON decks_cards_relation (deck_id, card_id);
@@ -0,0 +1,2 @@
+DROP TABLE deck_rules_cardclass_limits;
+DROP TABLE deck_rules;
@@ -0,0 +1,15 @@
+CREATE TABLE deck_rules (
+    id INTEGER NOT NULL PRIMARY KEY,
+    decktype TEXT NOT NULL UNIQUE,
+    max_copies_per_card INTEGER,
+    min_deck_size INTEGER,
+    max_deck_size INTEGER
+);
+
+CREATE TABLE deck_rules_cardclass_limits (
+    id INTEGER NOT NULL PRIMARY KEY,
+    deck_rules_id INTEGER NOT NULL REFERENCES deck_rules (id) ON DELETE CASCADE,
+    cardclass TEXT NOT NULL,
+    max_cards INTEGER NOT NULL,
+    UNIQUE (deck_rules_id, cardclass)
+);
@@ -0,0 +1,22 @@
+CREATE TABLE card_classes (
+    id INTEGER NOT NULL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    color TEXT NOT NULL,
+    sort_order INTEGER NOT NULL DEFAULT 0
+);
+
+-- Seed the registry with the classes and colors that used to be hard-coded
+-- in `models.rs` and `card.css`.
+INSERT INTO card_classes (code, name, color, sort_order) VALUES
+    ('Kn', 'Knowledge', '#ffffff', 0),
+    ('Tr', 'Trait', '#e6ffcc', 1),
+    ('It', 'Item', '#f2f2f2', 2),
+    ('Eq', 'Equipment', '#e5ecff', 3),
+    ('Ar', 'Armor', '#cce6ff', 4),
+    ('Co', 'Consumable', '#ffe5e5', 5),
+    ('Te', 'Technique', '#ffffe5', 6),
+    ('Sp', 'Spell', '#ffe5ff', 7),
+    ('Po', 'Power', '#e6ffe6', 8),
+    ('1H', '1-Handed Arms', '#e5ffff', 9),
+    ('2H', '2-Handed Arms', '#ccffff', 10);
@@ -0,0 +1,13 @@
+-- Rewrite speed and action values that only differ by case or surrounding
+-- whitespace into their canonical spelling. Anything else is left alone and
+-- is reported by `admin audit`.
+
+UPDATE cards SET speed = 'Fast' WHERE lower(trim(speed)) = 'fast';
+UPDATE cards SET speed = 'Normal' WHERE lower(trim(speed)) = 'normal';
+UPDATE cards SET speed = 'Slow' WHERE lower(trim(speed)) = 'slow';
+
+UPDATE cards SET action = 'Attack' WHERE lower(trim(action)) = 'attack';
+UPDATE cards SET action = 'Defend' WHERE lower(trim(action)) = 'defend';
+UPDATE cards SET action = 'Move' WHERE lower(trim(action)) = 'move';
+UPDATE cards SET action = 'Utility' WHERE lower(trim(action)) = 'utility';
+UPDATE cards SET action = 'Passive' WHERE lower(trim(action)) = 'passive';
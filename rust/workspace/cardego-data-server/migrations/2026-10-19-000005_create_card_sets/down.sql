@@ -0,0 +1,3 @@
+DROP INDEX card_sets_cards_relation_card_id;
+DROP TABLE card_sets_cards_relation;
+DROP TABLE card_sets;
@@ -0,0 +1,18 @@
+CREATE TABLE card_sets (
+    id INTEGER NOT NULL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    -- An ISO 8601 date such as 2026-10-19, or NULL while unreleased.
+    release_date TEXT
+);
+
+CREATE TABLE card_sets_cards_relation (
+    id INTEGER NOT NULL PRIMARY KEY,
+    card_set_id INTEGER NOT NULL REFERENCES card_sets(id),
+    card_id INTEGER NOT NULL REFERENCES cards(id),
+    collector_number INTEGER NOT NULL,
+    UNIQUE (card_set_id, card_id),
+    UNIQUE (card_set_id, collector_number)
+);
+
+CREATE INDEX card_sets_cards_relation_card_id ON card_sets_cards_relation (card_id);
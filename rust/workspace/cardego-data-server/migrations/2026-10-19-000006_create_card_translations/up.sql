@@ -0,0 +1,10 @@
+-- Translations of a card's text. The text on `cards` itself is in the
+-- default locale, which is used whenever a translation is missing.
+CREATE TABLE card_translations (
+    id INTEGER NOT NULL PRIMARY KEY,
+    card_id INTEGER NOT NULL REFERENCES cards(id),
+    locale TEXT NOT NULL,
+    name TEXT NOT NULL,
+    desc TEXT NOT NULL,
+    UNIQUE (card_id, locale)
+);
@@ -0,0 +1,2 @@
+DROP INDEX card_relations_related_card_id;
+DROP TABLE card_relations;
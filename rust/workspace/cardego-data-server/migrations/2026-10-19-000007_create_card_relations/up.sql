@@ -0,0 +1,12 @@
+-- Typed links from one card to another: `card_id` upgrades to, creates, or
+-- is related to `related_card_id`.
+CREATE TABLE card_relations (
+    id INTEGER NOT NULL PRIMARY KEY,
+    card_id INTEGER NOT NULL REFERENCES cards(id),
+    related_card_id INTEGER NOT NULL REFERENCES cards(id),
+    kind TEXT NOT NULL CHECK (kind IN ('upgrades_to', 'creates', 'related')),
+    UNIQUE (card_id, related_card_id, kind),
+    CHECK (card_id != related_card_id)
+);
+
+CREATE INDEX card_relations_related_card_id ON card_relations (related_card_id);
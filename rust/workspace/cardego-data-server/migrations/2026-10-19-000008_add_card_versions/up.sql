@@ -0,0 +1,3 @@
+-- Every change to a card bumps its version, so that editors can tell when
+-- the card they are editing was changed by someone else.
+ALTER TABLE cards ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+ALTER TABLE decks DROP COLUMN cloned_from_id;
+ALTER TABLE cards DROP COLUMN cloned_from_id;
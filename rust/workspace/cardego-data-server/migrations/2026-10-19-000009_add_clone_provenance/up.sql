@@ -0,0 +1,3 @@
+-- Cards and decks made by cloning another one remember where they came from.
+ALTER TABLE cards ADD COLUMN cloned_from_id INTEGER REFERENCES cards(id);
+ALTER TABLE decks ADD COLUMN cloned_from_id INTEGER REFERENCES decks(id);
@@ -0,0 +1,10 @@
+-- Card templates that can be changed without rebuilding the server. A theme
+-- with a `cardclass` is used for the cards of that class, unless another
+-- theme is asked for.
+CREATE TABLE card_themes (
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    cardclass TEXT UNIQUE,
+    template TEXT NOT NULL,
+    css TEXT NOT NULL DEFAULT ''
+);
@@ -0,0 +1,14 @@
+DROP TRIGGER cards_clear_value_flags;
+DROP TRIGGER cards_known_values_on_update;
+DROP TRIGGER cards_known_values_on_insert;
+
+UPDATE cards SET speed = (
+    SELECT value FROM card_value_flags
+    WHERE card_id = cards.id AND field = 'speed'
+) WHERE id IN (SELECT card_id FROM card_value_flags WHERE field = 'speed');
+UPDATE cards SET action = (
+    SELECT value FROM card_value_flags
+    WHERE card_id = cards.id AND field = 'action'
+) WHERE id IN (SELECT card_id FROM card_value_flags WHERE field = 'action');
+
+DROP TABLE card_value_flags;
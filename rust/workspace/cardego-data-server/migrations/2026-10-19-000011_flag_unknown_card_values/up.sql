@@ -0,0 +1,50 @@
+-- Cards whose speed or action is still not a known value would fail to load
+-- at all. Their values are kept here, the cards are given a placeholder, and
+-- `admin audit` reports them until they are set to a real value.
+CREATE TABLE card_value_flags (
+    id INTEGER NOT NULL PRIMARY KEY,
+    card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+    field TEXT NOT NULL,
+    value TEXT NOT NULL,
+    UNIQUE (card_id, field)
+);
+
+INSERT INTO card_value_flags (card_id, field, value)
+    SELECT id, 'speed', speed FROM cards
+    WHERE speed NOT IN ('Fast', 'Normal', 'Slow');
+INSERT INTO card_value_flags (card_id, field, value)
+    SELECT id, 'action', action FROM cards
+    WHERE action NOT IN ('Attack', 'Defend', 'Move', 'Utility', 'Passive');
+
+UPDATE cards SET speed = 'Normal'
+    WHERE speed NOT IN ('Fast', 'Normal', 'Slow');
+UPDATE cards SET action = 'Utility'
+    WHERE action NOT IN ('Attack', 'Defend', 'Move', 'Utility', 'Passive');
+
+-- From now on unknown values are refused when they are written, including
+-- by hand, instead of breaking every read of the card.
+CREATE TRIGGER cards_known_values_on_insert
+    BEFORE INSERT ON cards
+    WHEN NEW.speed NOT IN ('Fast', 'Normal', 'Slow')
+        OR NEW.action NOT IN ('Attack', 'Defend', 'Move', 'Utility', 'Passive')
+BEGIN
+    SELECT RAISE(ABORT, 'Unknown card speed or action');
+END;
+
+CREATE TRIGGER cards_known_values_on_update
+    BEFORE UPDATE OF speed, action ON cards
+    WHEN NEW.speed NOT IN ('Fast', 'Normal', 'Slow')
+        OR NEW.action NOT IN ('Attack', 'Defend', 'Move', 'Utility', 'Passive')
+BEGIN
+    SELECT RAISE(ABORT, 'Unknown card speed or action');
+END;
+
+-- Changing a placeholder clears its flag.
+CREATE TRIGGER cards_clear_value_flags
+    AFTER UPDATE OF speed, action ON cards
+BEGIN
+    DELETE FROM card_value_flags
+    WHERE card_id = NEW.id
+        AND ((field = 'speed' AND NEW.speed IS NOT OLD.speed)
+            OR (field = 'action' AND NEW.action IS NOT OLD.action));
+END;
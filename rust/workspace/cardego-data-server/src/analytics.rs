@@ -0,0 +1,257 @@
+//! Aggregate statistics over the card pool, for balancing the game.
+//!
+//! Statistics are taken over every card, or over the cards matched by a
+//! search query, so that e.g. one class or one set can be compared against
+//! the rest. They can be read as JSON, or as CSV rows of
+//! `section,group,key,value` that pivot easily in a spreadsheet.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::bulk::BulkFormat;
+use crate::database::DatabaseContext;
+use crate::models::{Card, CardAttribute};
+
+/// The description length percentiles that are reported.
+const DESC_LENGTH_PERCENTILES: &[u32] = &[10, 25, 50, 75, 90];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitiativeStats {
+    pub cardclass: String,
+    pub count: usize,
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+    /// How many cards of the class have each initiative.
+    pub distribution: BTreeMap<i32, usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttributeFrequency {
+    pub id: i32,
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DescLengthStats {
+    pub min: usize,
+    pub max: usize,
+    /// Lengths in characters by percentile, nearest rank.
+    pub percentiles: BTreeMap<u32, usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardPoolAnalytics {
+    pub card_count: usize,
+    pub initiative_by_class: Vec<InitiativeStats>,
+    pub actions: BTreeMap<String, usize>,
+    pub speeds: BTreeMap<String, usize>,
+    /// Attributes by how many cards have them, most frequent first.
+    pub attributes: Vec<AttributeFrequency>,
+    pub desc_length: DescLengthStats,
+}
+
+#[derive(Debug, Serialize)]
+struct CsvAnalyticsRow<'a> {
+    section: &'a str,
+    group: &'a str,
+    key: String,
+    value: String,
+}
+
+/// The value at the `percentile` of `sorted` values, by nearest rank.
+pub fn percentile(sorted: &[usize], percentile: u32) -> usize {
+    if sorted.is_empty() {
+        return 0;
+    }
+
+    let rank = (percentile as f64 / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+/// Takes the statistics of `cards`, whose attributes are looked up by card
+/// id in `attributes`.
+pub fn summarize_cards(
+    cards: &[Card],
+    attributes: &HashMap<i32, Vec<CardAttribute>>,
+) -> CardPoolAnalytics {
+    let mut initiatives: BTreeMap<&str, Vec<i32>> = BTreeMap::new();
+    let mut actions: BTreeMap<String, usize> = BTreeMap::new();
+    let mut speeds: BTreeMap<String, usize> = BTreeMap::new();
+    let mut attribute_counts: HashMap<i32, AttributeFrequency> = HashMap::new();
+    let mut desc_lengths = Vec::with_capacity(cards.len());
+
+    for card in cards {
+        initiatives
+            .entry(&card.cardclass)
+            .or_default()
+            .push(card.initiative);
+        *actions.entry(card.action.to_string()).or_default() += 1;
+        *speeds.entry(card.speed.to_string()).or_default() += 1;
+        desc_lengths.push(card.desc.chars().count());
+
+        for attribute in attributes.get(&card.id).into_iter().flatten() {
+            attribute_counts
+                .entry(attribute.id)
+                .or_insert_with(|| AttributeFrequency {
+                    id: attribute.id,
+                    name: attribute.name.clone(),
+                    count: 0,
+                })
+                .count += 1;
+        }
+    }
+
+    let initiative_by_class = initiatives
+        .into_iter()
+        .map(|(cardclass, values)| {
+            let mut distribution = BTreeMap::new();
+            for value in &values {
+                *distribution.entry(*value).or_default() += 1;
+            }
+
+            InitiativeStats {
+                cardclass: cardclass.to_owned(),
+                count: values.len(),
+                min: *values.iter().min().unwrap(),
+                max: *values.iter().max().unwrap(),
+                mean: values.iter().sum::<i32>() as f64 / values.len() as f64,
+                distribution,
+            }
+        })
+        .collect();
+
+    let mut attributes = attribute_counts
+        .values()
+        .cloned()
+        .collect::<Vec<AttributeFrequency>>();
+    attributes.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+
+    desc_lengths.sort();
+    let desc_length = DescLengthStats {
+        min: desc_lengths.first().copied().unwrap_or(0),
+        max: desc_lengths.last().copied().unwrap_or(0),
+        percentiles: DESC_LENGTH_PERCENTILES
+            .iter()
+            .map(|p| (*p, percentile(&desc_lengths, *p)))
+            .collect(),
+    };
+
+    CardPoolAnalytics {
+        card_count: cards.len(),
+        initiative_by_class,
+        actions,
+        speeds,
+        attributes,
+        desc_length,
+    }
+}
+
+/// Writes out `analytics`. CSV has one `section,group,key,value` row per
+/// number, e.g. `initiative,Warrior,3,12` for twelve warrior cards of
+/// initiative 3.
+pub fn serialize_card_pool_analytics(
+    format: BulkFormat,
+    analytics: &CardPoolAnalytics,
+) -> Result<String> {
+    match format {
+        BulkFormat::Json => Ok(serde_json::to_string_pretty(analytics)?),
+        BulkFormat::Yaml => Ok(serde_yaml::to_string(analytics)?),
+        BulkFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            let mut row = |section: &str, group: &str, key: String, value: String| {
+                writer.serialize(CsvAnalyticsRow {
+                    section,
+                    group,
+                    key,
+                    value,
+                })
+            };
+
+            row(
+                "cards",
+                "",
+                "count".to_owned(),
+                analytics.card_count.to_string(),
+            )?;
+            for stats in &analytics.initiative_by_class {
+                for (initiative, count) in &stats.distribution {
+                    row(
+                        "initiative",
+                        &stats.cardclass,
+                        initiative.to_string(),
+                        count.to_string(),
+                    )?;
+                }
+                row(
+                    "initiative_mean",
+                    &stats.cardclass,
+                    "mean".to_owned(),
+                    format!("{:.2}", stats.mean),
+                )?;
+            }
+            for (action, count) in &analytics.actions {
+                row("action", "", action.clone(), count.to_string())?;
+            }
+            for (speed, count) in &analytics.speeds {
+                row("speed", "", speed.clone(), count.to_string())?;
+            }
+            for attribute in &analytics.attributes {
+                row(
+                    "attribute",
+                    "",
+                    attribute.name.clone(),
+                    attribute.count.to_string(),
+                )?;
+            }
+            for (p, length) in &analytics.desc_length.percentiles {
+                row("desc_length", "", format!("p{}", p), length.to_string())?;
+            }
+
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+impl DatabaseContext {
+    /// Takes the statistics of every card, or of the cards matching `query`.
+    pub fn card_pool_analytics(&self, query: Option<&str>) -> Result<CardPoolAnalytics> {
+        use crate::schema::cards::dsl::*;
+
+        let found_cards = match query {
+            Some(query_string) => {
+                let card_ids = self.query_card_ids(query_string)?;
+                cards
+                    .filter(id.eq_any(card_ids))
+                    .load::<Card>(self.connection.as_ref())?
+            }
+            None => cards.load::<Card>(self.connection.as_ref())?,
+        };
+
+        let attributes =
+            self.get_card_attributes_by_card_ids(found_cards.iter().map(|card| card.id).collect())?;
+
+        Ok(summarize_cards(&found_cards, &attributes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analytics::percentile;
+
+    #[test]
+    fn given_sorted_lengths_when_percentile_then_nearest_rank_used() {
+        let lengths = vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
+
+        assert_eq!(percentile(&lengths, 10), 10);
+        assert_eq!(percentile(&lengths, 50), 50);
+        assert_eq!(percentile(&lengths, 75), 80);
+        assert_eq!(percentile(&lengths, 100), 100);
+        assert_eq!(percentile(&[], 50), 0);
+    }
+}
@@ -0,0 +1,409 @@
+//! Online snapshots of the SQLite database.
+//!
+//! Snapshots are taken with SQLite's online backup API, so they are
+//! consistent even while the server is serving requests. Diesel does not
+//! expose the raw handle behind its connection, so the backup runs on a
+//! second handle to the same database file.
+//!
+//! The art the cards point at is kept with each snapshot, in a directory
+//! named after it, and copied back into the art of the database when the
+//! snapshot is restored.
+
+extern crate chrono;
+extern crate libsqlite3_sys;
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+
+use anyhow::{anyhow, Result};
+use libsqlite3_sys as ffi;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::image::art::copy_art;
+
+pub const SNAPSHOT_EXTENSION: &str = "db";
+/// The extension of the directory holding the art of a snapshot.
+pub const SNAPSHOT_ART_EXTENSION: &str = "art";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub file_name: String,
+    pub size_bytes: u64,
+    /// The latest migration applied to the snapshot, if any.
+    pub schema_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotReport {
+    pub snapshot: SnapshotInfo,
+    pub pruned: Vec<String>,
+}
+
+#[derive(QueryableByName)]
+struct SchemaVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+/// A raw SQLite handle, closed when dropped.
+struct RawConnection(*mut ffi::sqlite3);
+
+impl RawConnection {
+    fn open(path: &str, flags: c_int) -> Result<RawConnection> {
+        let c_path = CString::new(path)?;
+        let mut handle = std::ptr::null_mut();
+
+        let result =
+            unsafe { ffi::sqlite3_open_v2(c_path.as_ptr(), &mut handle, flags, std::ptr::null()) };
+        let connection = RawConnection(handle);
+
+        if result != ffi::SQLITE_OK {
+            return Err(anyhow!(
+                "Could not open '{}': {}",
+                path,
+                connection.error_message()
+            ));
+        }
+
+        Ok(connection)
+    }
+
+    fn error_message(&self) -> String {
+        if self.0.is_null() {
+            return "out of memory".to_owned();
+        }
+
+        unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// Copies every page of `source` into `destination` with the online backup
+/// API, waiting out any locks held by other connections.
+fn copy_database(source: &str, destination: &str) -> Result<()> {
+    let source = RawConnection::open(source, ffi::SQLITE_OPEN_READONLY)?;
+    let destination = RawConnection::open(
+        destination,
+        ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+    )?;
+
+    let main = CString::new("main")?;
+
+    unsafe {
+        let backup =
+            ffi::sqlite3_backup_init(destination.0, main.as_ptr(), source.0, main.as_ptr());
+        if backup.is_null() {
+            return Err(anyhow!(
+                "Could not start backup: {}",
+                destination.error_message()
+            ));
+        }
+
+        let mut result = ffi::SQLITE_OK;
+        while result == ffi::SQLITE_OK || result == ffi::SQLITE_BUSY || result == ffi::SQLITE_LOCKED
+        {
+            result = ffi::sqlite3_backup_step(backup, 100);
+            if result == ffi::SQLITE_BUSY || result == ffi::SQLITE_LOCKED {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+        }
+
+        ffi::sqlite3_backup_finish(backup);
+
+        if result != ffi::SQLITE_DONE {
+            return Err(anyhow!("Backup failed: {}", destination.error_message()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the latest migration applied to the database at `path`.
+pub fn get_schema_version(path: &str) -> Result<Option<String>> {
+    let connection = SqliteConnection::establish(path)?;
+
+    let versions =
+        sql_query("SELECT version FROM __diesel_schema_migrations ORDER BY version DESC LIMIT 1")
+            .load::<SchemaVersion>(&connection)
+            .or_else(|err| match err {
+                // Databases that have never been migrated have no migrations table.
+                diesel::result::Error::DatabaseError(_, ref info)
+                    if info.message().contains("no such table") =>
+                {
+                    Ok(vec![])
+                }
+                err => Err(err),
+            })?;
+
+    Ok(versions.into_iter().next().map(|row| row.version))
+}
+
+fn snapshot_info(path: &Path) -> Result<SnapshotInfo> {
+    let path_string = path.to_string_lossy().into_owned();
+
+    Ok(SnapshotInfo {
+        file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size_bytes: std::fs::metadata(path)?.len(),
+        schema_version: get_schema_version(&path_string)?,
+    })
+}
+
+/// Lists the snapshots in `directory`, newest first. Snapshot names start
+/// with a UTC timestamp, so their names sort by age.
+pub fn list_snapshots(directory: &str) -> Result<Vec<SnapshotInfo>> {
+    let directory = Path::new(directory);
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut paths = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some(SNAPSHOT_EXTENSION))
+        .collect::<Vec<PathBuf>>();
+
+    paths.sort();
+    paths.reverse();
+
+    paths.iter().map(|path| snapshot_info(path)).collect()
+}
+
+/// Deletes all but the newest `keep` snapshots and returns the names of the
+/// deleted ones.
+pub fn prune_snapshots(directory: &str, keep: usize) -> Result<Vec<String>> {
+    let mut pruned = Vec::new();
+
+    for snapshot in list_snapshots(directory)?.into_iter().skip(keep) {
+        debug!("Pruning snapshot {}", snapshot.file_name);
+        let path = Path::new(directory).join(&snapshot.file_name);
+        std::fs::remove_file(&path)?;
+
+        let art_directory = path.with_extension(SNAPSHOT_ART_EXTENSION);
+        if art_directory.is_dir() {
+            std::fs::remove_dir_all(art_directory)?;
+        }
+        pruned.push(snapshot.file_name);
+    }
+
+    Ok(pruned)
+}
+
+/// Resolves a snapshot name to its path, refusing names that could point
+/// outside of the snapshot directory.
+pub fn get_snapshot_path(directory: &str, file_name: &str) -> Result<PathBuf> {
+    let is_plain_name = Path::new(file_name)
+        .file_name()
+        .map(|name| name == file_name)
+        .unwrap_or(false);
+
+    if !is_plain_name {
+        Err(ClientError::InvalidInput(format!(
+            "'{}' is not a snapshot name",
+            file_name
+        )))?
+    }
+
+    let path = Path::new(directory).join(file_name);
+    if !path.is_file() {
+        Err(ClientError::ResourceNotFound)?
+    }
+
+    Ok(path)
+}
+
+impl DatabaseContext {
+    /// Writes a timestamped snapshot of the database into `directory`, then
+    /// prunes the snapshots beyond the newest `keep`. The new snapshot is
+    /// always kept, even when `keep` is 0.
+    pub fn create_snapshot(&self, directory: &str, keep: usize) -> Result<SnapshotReport> {
+        std::fs::create_dir_all(directory)?;
+
+        let stem = Path::new(&self.database_url)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "database".to_owned());
+        let file_name = format!(
+            "{}-{}.{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%3fZ"),
+            stem,
+            SNAPSHOT_EXTENSION
+        );
+        let path = Path::new(directory).join(&file_name);
+
+        if path.exists() {
+            return Err(anyhow!("Snapshot '{}' already exists", file_name));
+        }
+
+        info!("Writing snapshot of {} to {:?}", self.database_url, path);
+        copy_database(&self.database_url, &path.to_string_lossy())?;
+        copy_art(
+            self.get_art_store()?.directory(),
+            &path.with_extension(SNAPSHOT_ART_EXTENSION),
+        )?;
+
+        let snapshot = snapshot_info(&path)?;
+        let pruned = prune_snapshots(directory, keep.max(1))?;
+
+        Ok(SnapshotReport { snapshot, pruned })
+    }
+
+    /// Replaces the contents of the database with a snapshot. The snapshot
+    /// must be at the same schema version as the database, and the current
+    /// contents are snapshotted first so that a restore can be undone.
+    pub fn restore_snapshot(
+        &mut self,
+        directory: &str,
+        file_name: &str,
+        keep: usize,
+    ) -> Result<SnapshotReport> {
+        let path = get_snapshot_path(directory, file_name)?;
+        let art_directory = path.with_extension(SNAPSHOT_ART_EXTENSION);
+        let path = path.to_string_lossy().into_owned();
+
+        let snapshot_version = get_schema_version(&path)?;
+        let current_version = get_schema_version(&self.database_url)?;
+
+        if snapshot_version != current_version {
+            Err(ClientError::InvalidInput(format!(
+                "Snapshot '{}' is at schema version {}, but the database is at {}",
+                file_name,
+                snapshot_version.as_deref().unwrap_or("none"),
+                current_version.as_deref().unwrap_or("none")
+            )))?
+        }
+
+        // Keep one more snapshot than usual so the one being restored is not
+        // pruned by the safety snapshot.
+        let safety_snapshot = self.create_snapshot(directory, keep + 1)?;
+
+        info!("Restoring {} from {}", self.database_url, path);
+
+        // The snapshot is copied next to the database first, then moved over
+        // it in one step while an exclusive lock keeps other connections from
+        // reading or writing the database halfway through.
+        let restoring_path = format!("{}.restoring", self.database_url);
+        if Path::new(&restoring_path).exists() {
+            std::fs::remove_file(&restoring_path)?;
+        }
+        copy_database(&path, &restoring_path)?;
+
+        sql_query("BEGIN EXCLUSIVE").execute(self.connection.as_ref())?;
+
+        if let Err(err) = std::fs::rename(&restoring_path, &self.database_url) {
+            sql_query("ROLLBACK").execute(self.connection.as_ref())?;
+            std::fs::remove_file(&restoring_path)?;
+            return Err(anyhow!("Could not replace {}: {}", self.database_url, err));
+        }
+
+        // The open connection still points at the file that was replaced, so
+        // it is closed, releasing the lock, and the database is opened again.
+        *self.connection = SqliteConnection::establish(":memory:")?;
+        *self.connection = SqliteConnection::establish(&self.database_url)?;
+
+        // Art is only ever added, so art stored since the snapshot is kept.
+        let restored_art = copy_art(&art_directory, self.get_art_store()?.directory())?;
+        debug!("Restored {} art file(s) from {}", restored_art, file_name);
+
+        Ok(safety_snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::backup::get_snapshot_path;
+    use crate::database::DatabaseContext;
+    use crate::image::art::ArtStore;
+
+    use ::image::{Rgba, RgbaImage};
+
+    #[test]
+    fn given_path_outside_directory_when_get_snapshot_path_then_rejected() {
+        assert!(get_snapshot_path("runtime/data/backups", "../databases/cards.db").is_err());
+        assert!(get_snapshot_path("runtime/data/backups", "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn given_keep_of_zero_when_create_snapshot_then_new_snapshot_kept() {
+        let directory = std::env::temp_dir().join(format!("backup-test-{}", std::process::id()));
+        let database = directory.join("cards.db");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let db = DatabaseContext::new(&database.to_string_lossy())
+            .unwrap()
+            .with_art_store(ArtStore::new(&directory.to_string_lossy()));
+        sql_query("CREATE TABLE cards (id INTEGER PRIMARY KEY)")
+            .execute(db.connection.as_ref())
+            .unwrap();
+
+        let backups = directory.join("backups");
+        let report = db.create_snapshot(&backups.to_string_lossy(), 0).unwrap();
+
+        assert!(report.pruned.is_empty());
+        assert!(backups.join(&report.snapshot.file_name).is_file());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_snapshot_when_restored_then_contents_and_art_restored() {
+        let directory = std::env::temp_dir().join(format!("restore-test-{}", std::process::id()));
+        let database = directory.join("cards.db");
+        let backups = directory.join("backups");
+        let backups = backups.to_string_lossy();
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let art_store = ArtStore::new(&directory.to_string_lossy());
+        let mut db = DatabaseContext::new(&database.to_string_lossy())
+            .unwrap()
+            .with_art_store(art_store.clone());
+        sql_query("CREATE TABLE cards (id INTEGER PRIMARY KEY)")
+            .execute(db.connection.as_ref())
+            .unwrap();
+        sql_query("INSERT INTO cards (id) VALUES (1)")
+            .execute(db.connection.as_ref())
+            .unwrap();
+        let art = art_store
+            .store(&RgbaImage::from_pixel(16, 16, Rgba([0xFF, 0, 0, 0xFF])))
+            .unwrap();
+
+        let snapshot = db.create_snapshot(&backups, 10).unwrap().snapshot;
+        std::fs::remove_file(art_store.path(&art.hash)).unwrap();
+        sql_query("INSERT INTO cards (id) VALUES (2)")
+            .execute(db.connection.as_ref())
+            .unwrap();
+
+        db.restore_snapshot(&backups, &snapshot.file_name, 10)
+            .unwrap();
+
+        let count = sql_query("DELETE FROM cards")
+            .execute(db.connection.as_ref())
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(!directory.join("cards.db.restoring").exists());
+        assert!(art_store.path(&art.hash).is_file());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
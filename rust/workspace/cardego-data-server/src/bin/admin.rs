@@ -0,0 +1,249 @@
+extern crate anyhow;
+extern crate cardego_server;
+
+use cardego_server::backup;
+use cardego_server::bulk::{self, BulkFormat, ImportAction};
+use cardego_server::database::DatabaseContext;
+use cardego_server::databases::DatabaseConfig;
+use cardego_server::image::art::ArtStore;
+use cardego_server::image::cache::RenderCache;
+use cardego_server::ApplicationConfig;
+
+const USAGE: &str = "\
+Usage: admin [--db <name>] <command>
+
+Commands run against the default database unless another database from
+config/databases.yml is named with --db.
+
+Commands:
+    audit                                     Report cards whose speed or action is not a known value
+    export <cards|decks> <json|csv|yaml>      Write every card or deck to stdout
+    import <cards|decks> <file> [--dry-run]   Create or update cards or decks from a file
+    backup                                    Snapshot the database and prune old snapshots
+    backups                                   List the database snapshots, newest first
+    restore <snapshot>                        Replace the database with a snapshot
+    check [--repair]                          Report inconsistent rows, deleting orphaned ones with --repair";
+
+fn run_audit(db: &DatabaseContext) -> anyhow::Result<bool> {
+    let entries = db.audit_card_values()?;
+
+    for entry in &entries {
+        let suggestion = entry
+            .suggestion
+            .as_ref()
+            .map(|s| format!(" (did you mean '{}'?)", s))
+            .unwrap_or_default();
+
+        let replaced_with = entry
+            .replaced_with
+            .as_ref()
+            .map(|s| format!(", replaced with '{}'", s))
+            .unwrap_or_default();
+
+        println!(
+            "card #{} '{}': unknown {} '{}'{}{}",
+            entry.card_id, entry.card_name, entry.field, entry.value, replaced_with, suggestion
+        );
+    }
+
+    println!("{} non-conforming value(s) found", entries.len());
+
+    Ok(entries.is_empty())
+}
+
+fn run_export(db: &DatabaseContext, kind: &str, format: &str) -> anyhow::Result<bool> {
+    let format: BulkFormat = format.parse()?;
+
+    let output = match kind {
+        "cards" => bulk::serialize_card_records(format, &db.export_cards(None)?)?,
+        "decks" => bulk::serialize_deck_records(format, &db.export_decks()?)?,
+        _ => return Err(anyhow::anyhow!("Unknown export '{}'", kind)),
+    };
+
+    print!("{}", output);
+
+    Ok(true)
+}
+
+fn run_import(
+    db: &mut DatabaseContext,
+    kind: &str,
+    path: &str,
+    dry_run: bool,
+) -> anyhow::Result<bool> {
+    let format = BulkFormat::from_path(path).ok_or_else(|| {
+        anyhow::anyhow!("Cannot tell the format of '{}' from its extension", path)
+    })?;
+    let input = std::fs::read_to_string(path)?;
+
+    let report = match kind {
+        "cards" => db.import_cards(bulk::parse_card_records(format, &input)?, dry_run)?,
+        "decks" => db.import_decks(bulk::parse_deck_records(format, &input)?, dry_run)?,
+        _ => return Err(anyhow::anyhow!("Unknown import '{}'", kind)),
+    };
+
+    for row in &report.rows {
+        let name = row.name.clone().unwrap_or_default();
+
+        match row.action {
+            ImportAction::Error => {
+                for error in &row.errors {
+                    println!("row {} '{}': {}", row.row, name, error);
+                }
+            }
+            ImportAction::Unchanged => {}
+            action => {
+                println!("row {} '{}': {:?}", row.row, name, action);
+                for change in &row.changes {
+                    println!("    {}: '{}' -> '{}'", change.field, change.old, change.new);
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} created, {} updated, {} unchanged, {} failed{}",
+        report.created,
+        report.updated,
+        report.unchanged,
+        report.failed,
+        if report.applied {
+            ""
+        } else {
+            "; nothing was written"
+        }
+    );
+
+    Ok(!report.has_errors())
+}
+
+fn run_backup(
+    db: &DatabaseContext,
+    config: &ApplicationConfig,
+    database: &DatabaseConfig,
+) -> anyhow::Result<bool> {
+    let report = db.create_snapshot(&database.backup_directory, config.backup_retention)?;
+
+    println!("wrote snapshot {}", report.snapshot.file_name);
+    for file_name in &report.pruned {
+        println!("pruned snapshot {}", file_name);
+    }
+
+    Ok(true)
+}
+
+fn run_list_backups(database: &DatabaseConfig) -> anyhow::Result<bool> {
+    for snapshot in backup::list_snapshots(&database.backup_directory)? {
+        println!(
+            "{}\t{} bytes\tschema {}",
+            snapshot.file_name,
+            snapshot.size_bytes,
+            snapshot.schema_version.as_deref().unwrap_or("none")
+        );
+    }
+
+    Ok(true)
+}
+
+fn run_restore(
+    db: &mut DatabaseContext,
+    config: &ApplicationConfig,
+    database: &DatabaseConfig,
+    file_name: &str,
+) -> anyhow::Result<bool> {
+    let report = db.restore_snapshot(
+        &database.backup_directory,
+        file_name,
+        config.backup_retention,
+    )?;
+
+    println!(
+        "restored {}; the previous contents were saved to {}",
+        file_name, report.snapshot.file_name
+    );
+
+    Ok(true)
+}
+
+fn run_check(db: &mut DatabaseContext, repair: bool) -> anyhow::Result<bool> {
+    let report = db.check_integrity(repair)?;
+
+    for issue in &report.issues {
+        println!(
+            "{}{}: {} ({} {:?})",
+            if issue.repaired { "repaired " } else { "" },
+            issue.kind.as_str(),
+            issue.message,
+            issue.table,
+            issue.ids
+        );
+    }
+
+    let repairable = report
+        .issues
+        .iter()
+        .filter(|issue| issue.repairable && !issue.repaired)
+        .count();
+
+    println!(
+        "{} issue(s) found{}",
+        report.issues.len(),
+        if repairable > 0 {
+            format!("; {} can be repaired with --repair", repairable)
+        } else {
+            "".to_owned()
+        }
+    );
+
+    Ok(!report.has_issues())
+}
+
+fn main() -> anyhow::Result<()> {
+    // Collect command line arguments
+    let args: Vec<String> = std::env::args().collect();
+
+    let config = ApplicationConfig::new()?;
+
+    let mut args = args.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+
+    let database = match args.as_slice() {
+        [_, "--db", name, ..] => {
+            let name = name.to_string();
+            args.drain(1..3);
+            config.get_database(&name).cloned().unwrap_or_else(|| {
+                eprintln!("unknown database '{}'", name);
+                std::process::exit(2);
+            })
+        }
+        _ => config.default_database().clone(),
+    };
+
+    let mut db = DatabaseContext::new(&database.database_endpoint)?
+        .with_render_cache(RenderCache::new(
+            &database.render_directory,
+            config.render_cache_max_bytes,
+        ))
+        .with_art_store(ArtStore::new(&database.render_directory));
+
+    let success = match args.as_slice() {
+        [_, "audit"] => run_audit(&db)?,
+        [_, "export", kind, format] => run_export(&db, kind, format)?,
+        [_, "import", kind, path] => run_import(&mut db, kind, path, false)?,
+        [_, "import", kind, path, "--dry-run"] => run_import(&mut db, kind, path, true)?,
+        [_, "backup"] => run_backup(&db, &config, &database)?,
+        [_, "backups"] => run_list_backups(&database)?,
+        [_, "restore", file_name] => run_restore(&mut db, &config, &database, file_name)?,
+        [_, "check"] => run_check(&mut db, false)?,
+        [_, "check", "--repair"] => run_check(&mut db, true)?,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if !success {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
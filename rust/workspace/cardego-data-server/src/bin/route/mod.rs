@@ -5,27 +5,55 @@ extern crate cardego_server;
 extern crate derive_more;
 extern crate thiserror;
 
+use cardego_server::analytics;
+use cardego_server::backup;
+use cardego_server::bulk::{self, BulkFormat, ImportReport};
+use cardego_server::card_versions::{self, CardPrecondition};
 use cardego_server::database::DatabaseContext;
+use cardego_server::deck_rules::DeckRules;
 use cardego_server::errors::{AppError, ClientError, Result, ServerError};
+use cardego_server::image::art::{self, ArtStore, StoredArt};
+use cardego_server::image::cache::{self, RenderCache, RenderScope};
+use cardego_server::image::output::{ImageOutputOptions, OutputFormat};
+use cardego_server::image::renderer::{cardsheet_rows, CARDSHEET_COLUMNS, CARD_HEIGHT, CARD_WIDTH};
+use cardego_server::image::tts;
+use cardego_server::print::{self, PrintOptions};
+use cardego_server::render_jobs::{RenderJob, RenderJobStatus, RenderTarget};
+use cardego_server::translations::Localizable;
 use cardego_server::ServerState;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
 use log::{debug, info};
 
-use cardego_server::models::{FullCardData, NewFullCardData};
+use cardego_server::models::{
+    Card, CardCloneOverrides, CardRelationEntry, CardTranslationText, DeckCardEntry,
+    DeckCardQuantity, DeckCloneOverrides, DeckRename, FullCardData, FullCardDataPayload,
+    NewCardClass, NewCardRelationEntry, NewCardSet, NewCardSetEntry, NewCardTheme,
+    NewDeckCardEntry, NewFullCardData,
+};
 
 use juniper::http::playground::playground_source;
 use juniper::http::GraphQLRequest;
 use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 
+/// Numbers the print-and-play PDFs being rendered, so that each renders its
+/// cards into files of its own.
+static NEXT_PRINT_ID: AtomicU64 = AtomicU64::new(0);
+
 pub async fn index() -> impl Responder {
     HttpResponse::Ok().body("Hello, world!")
 }
 
 pub fn get_connection(state: &ServerState) -> Result<DatabaseContext> {
-    DatabaseContext::new(state.config.database_endpoint.as_str())
-        .or(Err(AppError::Server(ServerError::DatabaseConnectionError)))
+    let db = DatabaseContext::new(state.database.database_endpoint.as_str())
+        .or(Err(AppError::Server(ServerError::DatabaseConnectionError)))?;
+
+    Ok(db
+        .with_render_cache(get_render_cache(state))
+        .with_art_store(ArtStore::new(&state.database.render_directory)))
 }
 
 pub fn lock_server_state(
@@ -39,21 +67,79 @@ pub fn lock_server_state(
     Ok(result)
 }
 
+/// Translates the cards into the locale given by the `lang` query parameter,
+/// if there is one.
+fn localize_cards<T: Localizable>(
+    db: &DatabaseContext,
+    query: &std::collections::HashMap<String, String>,
+    cards: &mut [T],
+) -> Result<()> {
+    if let Some(lang) = query.get("lang") {
+        db.localize_cards(cards, lang)?;
+    }
+
+    Ok(())
+}
+
+/// The relations to list on a rendered card. They are only listed when asked
+/// for with `related=true`.
+fn get_relations_to_render(
+    db: &DatabaseContext,
+    query: &std::collections::HashMap<String, String>,
+    card_id: i32,
+) -> Result<Vec<CardRelationEntry>> {
+    let show_relations = query
+        .get("related")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+
+    if show_relations {
+        Ok(db.get_card_relations(card_id)?)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// The theme asked for with the `theme` query parameter, if any.
+fn get_theme(query: &std::collections::HashMap<String, String>) -> Option<&str> {
+    query.get("theme").map(|theme| theme.as_str())
+}
+
 pub async fn route_get_card(
     state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
     path: web::Path<(i32,)>,
+    query: web::Query<std::collections::HashMap<String, String>>,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
 
-    let card = db.get_card(path.0).or(Err(ClientError::ResourceNotFound))?;
+    let mut card = db.get_card(path.0).or(Err(ClientError::ResourceNotFound))?;
+    let etag = card_versions::card_etag(
+        card.id,
+        card.version,
+        query.get("lang").map(|lang| lang.as_str()),
+    );
+
+    let not_modified = req
+        .headers()
+        .get("If-None-Match")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| card_versions::if_none_match_matches(value, &etag))
+        .unwrap_or(false);
+
+    if not_modified {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+
+    localize_cards(&db, &query, std::slice::from_mut(&mut card))?;
 
     let attributes = db
         .get_card_attributes_by_card_id(path.0)
         .map(|v| Some(v))
         .unwrap_or(None);
 
-    Ok(HttpResponse::Ok().json(FullCardData {
+    Ok(HttpResponse::Ok().header("ETag", etag).json(FullCardData {
         id: card.id,
         cardclass: card.cardclass,
         action: card.action,
@@ -62,13 +148,157 @@ pub async fn route_get_card(
         name: card.name,
         desc: card.desc,
         image_url: card.image_url,
+        version: card.version,
+        cloned_from_id: card.cloned_from_id,
         attributes,
+        relations: Some(db.get_card_relations(path.0)?),
     }))
 }
 
+fn get_render_cache(state: &ServerState) -> RenderCache {
+    RenderCache::new(
+        &state.database.render_directory,
+        state.config.render_cache_max_bytes,
+    )
+}
+
+fn is_not_modified(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get("If-None-Match")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| card_versions::if_none_match_matches(value, etag))
+        .unwrap_or(false)
+}
+
+/// Serves a rendered image, along with the ETag of its render.
+fn image_response(image_path: &std::path::Path, etag: String) -> Result<HttpResponse> {
+    encoded_image_response(image_path, OutputFormat::Png, etag)
+}
+
+/// Serves an image in `format`, along with the ETag of its variant.
+fn encoded_image_response(
+    image_path: &std::path::Path,
+    format: OutputFormat,
+    etag: String,
+) -> Result<HttpResponse> {
+    // Read the formatted data back in to be transmitted over the wire.
+    let new_file = File::open(image_path)?;
+    let length = new_file.metadata()?.len();
+    let buffer = std::fs::read(image_path)?;
+
+    info!("Serving local image {:?}", image_path);
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .content_length(length)
+        .header("ETag", etag)
+        .body(buffer))
+}
+
+/// Serves the variant of the image under `key` asked for in `options`,
+/// from the cache. `render` renders the image at the scale it is given and
+/// returns the path of the PNG, which is encoded into other formats.
+fn cached_image_response<F: FnOnce(f32) -> anyhow::Result<String>>(
+    render_cache: &RenderCache,
+    scope: &RenderScope,
+    key: &str,
+    options: &ImageOutputOptions,
+    scale: f32,
+    render: F,
+) -> Result<HttpResponse> {
+    let variant_key = options.variant_key(key, scale);
+
+    let image_path = match options.format {
+        OutputFormat::Png => render_cache.get_or_render(scope, &variant_key, || render(scale))?,
+        format => render_cache.get_or_encode(scope, &variant_key, format.extension(), || {
+            let rendered_path = render(scale)?;
+            let image = ::image::open(&rendered_path)?.to_rgba8();
+            std::fs::remove_file(&rendered_path)?;
+
+            options.encode(&image)
+        })?,
+    };
+
+    encoded_image_response(
+        &image_path,
+        options.format,
+        cache::render_etag(&variant_key),
+    )
+}
+
+/// Queues a render job. Takes `theme` in the query, like card images.
+pub async fn route_create_render_job(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    target: web::Json<RenderTarget>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (render_jobs, database) = {
+        let state = lock_server_state(&state)?;
+
+        // Check the theme now, rather than failing the job later.
+        get_connection(&state)?.select_card_themes(get_theme(&query))?;
+
+        (state.render_jobs.clone(), state.database.clone())
+    };
+
+    let job = render_jobs.submit(
+        &database,
+        target.into_inner(),
+        get_theme(&query).map(str::to_owned),
+    )?;
+
+    Ok(HttpResponse::Accepted()
+        .header("Location", format!("{}/{}", req.path(), job.id))
+        .json(job))
+}
+
+fn get_render_job(state: &web::Data<Arc<Mutex<ServerState>>>, job_id: u64) -> Result<RenderJob> {
+    let (render_jobs, database_name) = {
+        let state = lock_server_state(state)?;
+        (state.render_jobs.clone(), state.database.name.clone())
+    };
+
+    Ok(render_jobs
+        .get(&database_name, job_id)
+        .ok_or(ClientError::ResourceNotFound)?)
+}
+
+pub async fn route_get_render_job(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<u64>,
+) -> Result<HttpResponse> {
+    let job = get_render_job(&state, *path)?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+pub async fn route_get_render_job_image(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<u64>,
+) -> Result<HttpResponse> {
+    let job = get_render_job(&state, *path)?;
+
+    let result_path = match (job.status, job.result_path) {
+        (RenderJobStatus::Done, Some(result_path)) => result_path,
+        (status, _) => Err(ClientError::InvalidInput(format!(
+            "Render job {} is {:?}, not done",
+            job.id, status
+        )))?,
+    };
+
+    let buffer = std::fs::read(&result_path)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .content_length(buffer.len() as u64)
+        .body(buffer))
+}
+
 pub async fn route_get_card_image_as_html(
     state: web::Data<Arc<Mutex<ServerState>>>,
     path: web::Path<(i32,)>,
+    query: web::Query<std::collections::HashMap<String, String>>,
 ) -> Result<HttpResponse> {
     use cardego_server::image;
 
@@ -77,14 +307,25 @@ pub async fn route_get_card_image_as_html(
     // Get the card data from the database.
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
-    let card_info = db
+    let mut card_info = db
         .get_card(card_id)
         .or(Err(ClientError::ResourceNotFound))?;
+    localize_cards(&db, &query, std::slice::from_mut(&mut card_info))?;
 
     debug!("got card info: {:?}", &card_info);
 
+    let assets = db.get_card_assets()?;
+
     // Generate the image from the template and write it into file.
-    let out_html_string = image::generate_card_image_html_string(&card_info)?;
+    let relations = get_relations_to_render(&db, &query, card_id)?;
+
+    let themes = db.select_card_themes(get_theme(&query))?;
+    let out_html_string = match themes.theme_of(&card_info) {
+        Some(theme) => {
+            image::generate_themed_card_image_html_string(theme, &card_info, &assets, &relations)?
+        }
+        None => image::generate_card_image_html_string(&card_info, &assets, &relations)?,
+    };
 
     info!("Generated HTML for {:?}", &card_info.id);
 
@@ -94,45 +335,237 @@ pub async fn route_get_card_image_as_html(
         .body(out_html_string))
 }
 
-pub async fn route_get_card_image_css() -> Result<HttpResponse> {
-    let file = std::fs::read("static/templates/card.css")?;
+pub async fn route_get_card_image_css(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    // Card class colors live in the database, so append them to the static
+    // stylesheet.
+    let mut file = std::fs::read_to_string("static/templates/card.css")?;
+    file.push('\n');
+    file.push_str(&db.get_card_class_registry()?.to_css());
+
     Ok(HttpResponse::Ok()
         .content_type("text/css; charset=UTF-8")
         .body(file))
 }
 
+/// Points a card at stored art.
+fn card_art_response(state: &ServerState, card_id: i32, art: &StoredArt) -> Result<HttpResponse> {
+    let db = get_connection(state)?;
+
+    let card = db.set_card_art(card_id, art)?;
+
+    Ok(HttpResponse::Created()
+        .header(
+            "ETag",
+            card_versions::card_etag(card.id, card.version, None),
+        )
+        .json(art))
+}
+
+/// The art of the database of the card `card_id`, and the card's
+/// `image_url`. The card is looked up before any art is stored for it.
+fn get_card_art_store(
+    state: &web::Data<Arc<Mutex<ServerState>>>,
+    card_id: i32,
+) -> Result<(ArtStore, Option<String>)> {
+    let state = lock_server_state(state)?;
+    let db = get_connection(&state)?;
+
+    let card = db
+        .get_card(card_id)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok((db.get_art_store()?.clone(), card.image_url))
+}
+
+/// Processes and stores art away from the server's workers, as decoding and
+/// resizing large images takes a while.
+async fn process_and_store_art(art_store: ArtStore, bytes: Vec<u8>) -> Result<StoredArt> {
+    Ok(web::block(move || -> Result<StoredArt> {
+        Ok(art_store.store(&art::process_art(&bytes)?)?)
+    })
+    .await?)
+}
+
+/// Uploads the art of a card, as the bytes of an image file.
+pub async fn route_upload_card_art(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<i32>,
+    mut body: web::Payload,
+) -> Result<HttpResponse> {
+    let (art_store, _) = get_card_art_store(&state, *path)?;
+
+    // The upload is read as it arrives, so that art that is too large is
+    // refused before all of it is read.
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| ClientError::InvalidInput(err.to_string()))?;
+        if bytes.len() + chunk.len() > art::MAX_ART_BYTES {
+            Err(ClientError::InvalidInput(format!(
+                "Art may be at most {} bytes",
+                art::MAX_ART_BYTES
+            )))?
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let stored = process_and_store_art(art_store, bytes).await?;
+
+    let state = lock_server_state(&state)?;
+    card_art_response(&state, *path, &stored)
+}
+
+/// Downloads the art of a card from the `url` in the query, or else from
+/// its `image_url`, and stores it so that it is never fetched again.
+pub async fn route_ingest_card_art(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<i32>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (art_store, image_url) = get_card_art_store(&state, *path)?;
+
+    let url = match query.get("url") {
+        Some(url) => url.clone(),
+        None => image_url
+            .ok_or_else(|| ClientError::InvalidInput(format!("Card {} has no image_url", *path)))?,
+    };
+
+    let bytes = art::download_art(&url).await?;
+    let stored = process_and_store_art(art_store, bytes).await?;
+
+    let state = lock_server_state(&state)?;
+    card_art_response(&state, *path, &stored)
+}
+
+/// Serves stored art, which takes the same `format` and size parameters as
+/// card images. Art is stored as an image rather than drawn, so other sizes
+/// are resampled from it.
+pub async fn route_get_art(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let hash = path.into_inner();
+    let (art_path, render_cache) = {
+        let state = lock_server_state(&state)?;
+        (
+            ArtStore::new(&state.database.render_directory).path(&hash),
+            get_render_cache(&state),
+        )
+    };
+
+    if !art::is_art_hash(&hash) || !art_path.exists() {
+        Err(ClientError::ResourceNotFound)?
+    }
+
+    let output_options = ImageOutputOptions::from_query(&query)?;
+    // Art is always stored at the same size.
+    let (width, height) = (
+        art::ART_WIDTH * art::ART_SCALE,
+        art::ART_HEIGHT * art::ART_SCALE,
+    );
+    let scale = output_options.render_scale(width, height)?;
+    let variant_key = output_options.variant_key(&hash, scale);
+    let etag = cache::render_etag(&variant_key);
+
+    if is_not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+
+    if variant_key == hash {
+        return image_response(&art_path, etag);
+    }
+
+    let image_path = render_cache.get_or_encode(
+        &RenderScope::Art(hash.clone()),
+        &variant_key,
+        output_options.format.extension(),
+        || {
+            let image = ::image::open(&art_path)?.to_rgba8();
+            let (output_width, output_height) = output_options.output_dimensions(width, height)?;
+            if (output_width, output_height) == image.dimensions() {
+                return output_options.encode(&image);
+            }
+
+            output_options.encode(&::image::imageops::resize(
+                &image,
+                output_width,
+                output_height,
+                ::image::imageops::FilterType::Lanczos3,
+            ))
+        },
+    )?;
+
+    encoded_image_response(&image_path, output_options.format, etag)
+}
+
 pub async fn route_get_card_image_by_html(
     state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
     path: web::Path<(i32,)>,
+    query: web::Query<std::collections::HashMap<String, String>>,
 ) -> Result<HttpResponse> {
     use cardego_server::image;
 
     let card_id = path.0;
 
+    let output_options = ImageOutputOptions::from_query(&query)?;
+
     // Get the card data from the database.
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
-    let card_info = db
+    let mut card_info = db
         .get_card(card_id)
         .or(Err(ClientError::ResourceNotFound))?;
+    localize_cards(&db, &query, std::slice::from_mut(&mut card_info))?;
 
     debug!("got card info: {:?}", &card_info);
 
+    let assets = db.get_card_assets()?;
+
     // Generate the image from the template and write it into file.
-    let out_file_name = image::generate_card_image(&card_info)?;
+    let relations = get_relations_to_render(&db, &query, card_id)?;
 
-    // Read the formatted data back in to be transmitted over the wire.
-    let new_file = File::open(&out_file_name)?;
-    let length = new_file.metadata()?.len();
-    let buffer = std::fs::read(&out_file_name)?;
+    let themes = db.select_card_themes(get_theme(&query))?;
+    let theme = themes.theme_of(&card_info);
 
-    info!("Generated local image {:?}", out_file_name);
+    let key = cache::card_render_key(
+        state.card_renderer.name(),
+        theme,
+        &card_info,
+        &assets.card_classes,
+        &relations,
+    )?;
+    let scale = output_options.render_scale(CARD_WIDTH, CARD_HEIGHT)?;
+    let etag = cache::render_etag(&output_options.variant_key(&key, scale));
 
-    // We currently use PNG as our format.
-    Ok(HttpResponse::Ok()
-        .content_type("image/png")
-        .content_length(length)
-        .body(buffer))
+    if is_not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+
+    cached_image_response(
+        &get_render_cache(&state),
+        &RenderScope::Card(card_id),
+        &key,
+        &output_options,
+        scale,
+        |scale| {
+            image::generate_card_image(
+                state.card_renderer.as_ref(),
+                image::card_image_path(&state.database.render_directory, &card_id.to_string()),
+                &card_info,
+                &assets,
+                &relations,
+                theme,
+                scale,
+            )
+        },
+    )
 }
 
 pub async fn route_create_card(
@@ -141,7 +574,7 @@ pub async fn route_create_card(
     card: web::Json<NewFullCardData>,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
-    let mut db = get_connection(&state)?;
+    let db = get_connection(&state)?;
 
     let full_card_data: FullCardData = db.create_card(&card)?;
 
@@ -152,160 +585,1203 @@ pub async fn route_create_card(
 
 pub async fn route_update_card(
     state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
     path: web::Path<i32>,
-    card: web::Json<FullCardData>,
+    card: web::Json<FullCardDataPayload>,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
-    let mut db = get_connection(&state)?;
+    let db = get_connection(&state)?;
 
-    let mut card: FullCardData = card.into_inner();
+    let mut card: FullCardDataPayload = card.into_inner();
     card.id = *path;
 
-    let _full_card_data: FullCardData = db.update_card(card)?;
+    // Without `If-Match` the update is unconditional, as it always was.
+    let precondition = match req.headers().get("If-Match") {
+        Some(value) => card_versions::parse_if_match(
+            value.to_str().map_err(|_| {
+                ClientError::PreconditionFailed("If-Match is not readable".to_owned())
+            })?,
+            card.id,
+        )?,
+        None => CardPrecondition::Unconditional,
+    };
 
-    Ok(HttpResponse::Ok().finish())
+    let full_card_data: FullCardData = db.update_card_from_payload(card, precondition)?;
+
+    Ok(HttpResponse::Ok()
+        .header(
+            "ETag",
+            card_versions::card_etag(full_card_data.id, full_card_data.version, None),
+        )
+        .finish())
 }
 
-pub async fn route_get_deck(
-    state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<String>,
-) -> Result<HttpResponse> {
-    let state = lock_server_state(&state)?;
-    let db = get_connection(&state)?;
+/// Reads the overrides of a clone request. An empty body clones as is.
+fn parse_clone_overrides<T: serde::de::DeserializeOwned + Default>(body: &str) -> Result<T> {
+    if body.trim().is_empty() {
+        return Ok(T::default());
+    }
 
-    let cards = db
-        .get_cards_by_deck_name(path.to_string())
-        .or(Err(ClientError::ResourceNotFound))?;
+    serde_json::from_str(body).map_err(|e| {
+        AppError::Client(ClientError::InvalidInput(format!(
+            "Invalid clone overrides: {}",
+            e
+        )))
+    })
+}
 
-    Ok(HttpResponse::Ok().json(cards))
+/// The path of the collection holding the resource at `path`, given the
+/// number of segments below it, e.g. `/cards` for `/cards/12/clone`.
+fn collection_path(path: &str, depth: usize) -> &str {
+    let mut collection = path.trim_end_matches('/');
+    for _ in 0..depth {
+        collection = collection.rsplit_once('/').map_or("", |(parent, _)| parent);
+    }
+    collection
 }
 
-pub async fn route_create_deck(
+pub async fn route_clone_card(
     state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<String>,
+    req: HttpRequest,
+    path: web::Path<i32>,
     body: String,
 ) -> Result<HttpResponse> {
-    // Validate that the body is a list of i32
-    let strings: Vec<&str> = body.split_whitespace().collect();
-    let card_ids: Vec<i32> = strings.iter().flat_map(|s| s.parse()).collect();
-
-    if strings.len() != card_ids.len() {
-        return Err(AppError::Client(ClientError::InvalidInput(
-            "One of the strings provided was not a valid card id".to_owned(),
-        )));
-    }
+    let overrides: CardCloneOverrides = parse_clone_overrides(&body)?;
 
-    // Init the database state
     let state = lock_server_state(&state)?;
     let mut db = get_connection(&state)?;
 
-    let new_deck = db.create_deck(path.to_string(), card_ids)?;
+    let full_card_data = db.clone_card(*path, &overrides)?;
 
-    Ok(HttpResponse::Ok().json(new_deck))
+    Ok(HttpResponse::Created()
+        .header(
+            "Location",
+            format!("{}/{}", collection_path(req.path(), 2), full_card_data.id),
+        )
+        .header(
+            "ETag",
+            card_versions::card_etag(full_card_data.id, full_card_data.version, None),
+        )
+        .json(full_card_data))
 }
 
-pub async fn route_get_deck_cardsheet(
+pub async fn route_get_card_translations(
     state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<String>,
+    path: web::Path<i32>,
 ) -> Result<HttpResponse> {
-    use cardego_server::image;
-
-    // Get a connection to the database
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
 
-    // Get the cards.
-    let cards = db
-        .get_cards_by_deck_name(path.to_string())
-        .or(Err(ClientError::ResourceNotFound))?;
+    db.get_card(*path).or(Err(ClientError::ResourceNotFound))?;
+    let translations = db.get_card_translations(*path)?;
 
-    // Generate the image from the template and write it into file.
-    let out_file_name = image::generate_deck_cardsheet_image(&path, cards)?;
+    Ok(HttpResponse::Ok().json(translations))
+}
 
-    // Read the formatted data back in to be transmitted over the wire.
-    let new_file = File::open(&out_file_name)?;
-    let length = new_file.metadata()?.len();
-    let buffer = std::fs::read(&out_file_name)?;
+pub async fn route_put_card_translation(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(i32, String)>,
+    text: web::Json<CardTranslationText>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
 
-    info!("Generated local image {:?}", out_file_name);
+    let translation = db.put_card_translation(path.0, &path.1, &text)?;
 
-    // We currently use PNG as our format.
-    Ok(HttpResponse::Ok()
-        .content_type("image/png")
-        .content_length(length)
-        .body(buffer))
+    Ok(HttpResponse::Ok().json(translation))
 }
 
-pub async fn route_query_decks(
+pub async fn route_delete_card_translation(
     state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<String>,
+    path: web::Path<(i32, String)>,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
-    let db = get_connection(&state)?;
+    let mut db = get_connection(&state)?;
 
-    let decks = db
-        .query_decks_by_name(path.to_string())
-        .or(Err(ClientError::ResourceNotFound))?;
+    db.delete_card_translation(path.0, &path.1)?;
 
-    Ok(HttpResponse::Ok().json(decks))
+    Ok(HttpResponse::NoContent().finish())
 }
 
-pub async fn route_query_cards(
+pub async fn route_get_missing_translations(
     state: web::Data<Arc<Mutex<ServerState>>>,
     query: web::Query<std::collections::HashMap<String, String>>,
-    req: HttpRequest,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
 
-    info!("query_string: {:?}", req.query_string());
-
-    let query_string = query.get("q").ok_or_else(|| {
-        ClientError::OtherError(anyhow!("Invalid query `{}` provided", req.query_string()))
-    })?;
+    // Locales nobody has started translating yet can be asked for by name.
+    let locales = query
+        .get("locales")
+        .map(|locales| {
+            locales
+                .split(',')
+                .map(|locale| locale.trim().to_owned())
+                .filter(|locale| !locale.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
 
-    let cards = db
-        .query_cards(query_string)
-        .map_err(|err| ClientError::OtherError(anyhow!("Query cards error `{}`", err)))?;
+    let missing = db.get_missing_translations(&locales)?;
 
-    Ok(HttpResponse::Ok().json(cards))
+    Ok(HttpResponse::Ok().json(missing))
 }
 
-pub async fn route_query_cards_by_name(
+pub async fn route_get_card_relations(
     state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<String>,
+    path: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let state = lock_server_state(&state)?;
     let db = get_connection(&state)?;
 
-    let cards = db
-        .query_cards_by_name(path.to_string())
-        .or(Err(ClientError::ResourceNotFound))?;
+    db.get_card(*path).or(Err(ClientError::ResourceNotFound))?;
+    let relations = db.get_card_relations(*path)?;
 
-    Ok(HttpResponse::Ok().json(cards))
+    Ok(HttpResponse::Ok().json(relations))
 }
 
-pub async fn graphql(
+pub async fn route_create_card_relation(
     state: web::Data<Arc<Mutex<ServerState>>>,
-    // The incoming HTTP request
-    data: web::Json<GraphQLRequest>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    entry: web::Json<NewCardRelationEntry>,
 ) -> Result<HttpResponse> {
-    // Handle the incoming request and return a string result or error
-    let res = web::block(move || {
-        let state = lock_server_state(&state)?;
-        let db = get_connection(&state)?;
-        let res = data.execute(&state.schema, &db);
-        Ok::<_, anyhow::Error>(serde_json::to_string(&res)?)
-    })
-    .await?;
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .body(res))
+    let relation = db.create_card_relation(*path, &entry)?;
+
+    Ok(HttpResponse::Created()
+        .header("Location", format!("{}/{}", req.path(), relation.id))
+        .json(relation))
 }
 
-pub async fn graphql_playground() -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(playground_source("/graphql")))
+pub async fn route_update_card_relation(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(i32, i32)>,
+    entry: web::Json<NewCardRelationEntry>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let relation = db.update_card_relation(path.0, path.1, &entry)?;
+
+    Ok(HttpResponse::Ok().json(relation))
+}
+
+pub async fn route_delete_card_relation(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    db.delete_card_relation(path.0, path.1)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn route_get_card_classes(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let card_classes = db.get_card_classes()?;
+
+    Ok(HttpResponse::Ok().json(card_classes))
+}
+
+pub async fn route_get_card_class(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let card_class = db
+        .get_card_class(&path)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(card_class))
+}
+
+pub async fn route_create_card_class(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    card_class: web::Json<NewCardClass>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let card_class = db.create_card_class(&card_class)?;
+
+    Ok(HttpResponse::Created()
+        .header("Location", format!("{}/{}", req.path(), card_class.code))
+        .json(card_class))
+}
+
+pub async fn route_update_card_class(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    card_class: web::Json<NewCardClass>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let card_class = db.update_card_class(&path, &card_class)?;
+
+    Ok(HttpResponse::Ok().json(card_class))
+}
+
+pub async fn route_delete_card_class(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    db.delete_card_class(&path)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn route_get_card_themes(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let themes = db.get_card_theme_registry()?;
+
+    Ok(HttpResponse::Ok().json(themes.themes()))
+}
+
+pub async fn route_get_card_theme(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let themes = db.get_card_theme_registry()?;
+    let theme = themes.get(&path).ok_or(ClientError::ResourceNotFound)?;
+
+    Ok(HttpResponse::Ok().json(theme))
+}
+
+pub async fn route_create_card_theme(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    theme: web::Json<NewCardTheme>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let theme = db.create_card_theme(&theme)?;
+
+    Ok(HttpResponse::Created()
+        .header("Location", format!("{}/{}", req.path(), theme.name))
+        .json(theme))
+}
+
+pub async fn route_update_card_theme(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    theme: web::Json<NewCardTheme>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let theme = db.update_card_theme(&path, &theme)?;
+
+    Ok(HttpResponse::Ok().json(theme))
+}
+
+pub async fn route_delete_card_theme(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    db.delete_card_theme(&path)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn route_get_card_sets(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let card_sets = db.get_card_sets()?;
+
+    Ok(HttpResponse::Ok().json(card_sets))
+}
+
+pub async fn route_get_card_set(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let card_set = db
+        .get_card_set(&path)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(card_set))
+}
+
+pub async fn route_create_card_set(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    card_set: web::Json<NewCardSet>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let card_set = db.create_card_set(&card_set)?;
+
+    Ok(HttpResponse::Created()
+        .header("Location", format!("{}/{}", req.path(), card_set.code))
+        .json(card_set))
+}
+
+pub async fn route_update_card_set(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    card_set: web::Json<NewCardSet>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let card_set = db.update_card_set(&path, &card_set)?;
+
+    Ok(HttpResponse::Ok().json(card_set))
+}
+
+pub async fn route_delete_card_set(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    db.delete_card_set(&path)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn route_get_card_set_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let entries = db
+        .get_card_set_entries(&path)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_add_card_set_card(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    entry: web::Json<NewCardSetEntry>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.add_card_to_set(&path, &entry)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_remove_card_set_card(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.remove_card_from_set(&path.0, path.1)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_get_deck(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let mut cards = db
+        .get_cards_by_deck_name(path.to_string())
+        .or(Err(ClientError::ResourceNotFound))?;
+    localize_cards(&db, &query, &mut cards)?;
+
+    Ok(HttpResponse::Ok().json(cards))
+}
+
+pub async fn route_create_deck(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: String,
+) -> Result<HttpResponse> {
+    // Validate that the body is a list of i32
+    let strings: Vec<&str> = body.split_whitespace().collect();
+    let card_ids: Vec<i32> = strings.iter().flat_map(|s| s.parse()).collect();
+
+    if strings.len() != card_ids.len() {
+        return Err(AppError::Client(ClientError::InvalidInput(
+            "One of the strings provided was not a valid card id".to_owned(),
+        )));
+    }
+
+    // Init the database state
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    // Only check the deck construction rules when asked to.
+    let validate = query
+        .get("validate")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+
+    let new_deck = db.create_deck(path.to_string(), card_ids, validate)?;
+
+    Ok(HttpResponse::Ok().json(new_deck))
+}
+
+pub async fn route_rename_deck(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    body: web::Json<DeckRename>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let deck = db.rename_deck(&path, &body.name)?;
+
+    Ok(HttpResponse::Ok().json(deck))
+}
+
+pub async fn route_clone_deck(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: String,
+) -> Result<HttpResponse> {
+    let overrides: DeckCloneOverrides = parse_clone_overrides(&body)?;
+
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let deck = db.clone_deck(&path, &overrides)?;
+
+    Ok(HttpResponse::Created()
+        .header(
+            "Location",
+            format!("{}/{}", collection_path(req.path(), 2), deck.name),
+        )
+        .json(deck))
+}
+
+pub async fn route_get_deck_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let entries = db
+        .get_deck_card_entries_by_deck_name(&path)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_add_deck_card(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    body: web::Json<NewDeckCardEntry>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.add_card_to_deck(&path, body.card_id, body.quantity.unwrap_or(1))?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_set_deck_card_quantity(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(String, i32)>,
+    body: web::Json<DeckCardQuantity>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.set_deck_card_quantity(&path.0, path.1, body.quantity)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_remove_deck_card(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.remove_card_from_deck(&path.0, path.1)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_reorder_deck_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    body: web::Json<Vec<i32>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let entries = db.reorder_deck_cards(&path, &body)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn route_validate_deck(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let report = db.validate_deck(&path)?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn route_get_deck_rules(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let rules = db
+        .get_deck_rules(&path)?
+        .ok_or(ClientError::ResourceNotFound)?;
+
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+pub async fn route_put_deck_rules(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    rules: web::Json<DeckRules>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let mut rules: DeckRules = rules.into_inner();
+    rules.decktype = path.to_string();
+
+    let rules = db.put_deck_rules(&rules)?;
+
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+pub async fn route_get_deck_cardsheet(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    use cardego_server::image;
+
+    let output_options = ImageOutputOptions::from_query(&query)?;
+
+    // Get a connection to the database
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    // Get the cards.
+    let mut cards = db
+        .get_cards_by_deck_name(path.to_string())
+        .or(Err(ClientError::ResourceNotFound))?;
+    localize_cards(&db, &query, &mut cards)?;
+
+    let assets = db.get_card_assets()?;
+    let themes = db.select_card_themes(get_theme(&query))?;
+
+    // Generate the image from the template and write it into file.
+    let key = cache::cardsheet_render_key(
+        state.card_renderer.name(),
+        &cards,
+        &assets.card_classes,
+        &themes,
+    )?;
+    let scale = output_options.render_scale(
+        CARD_WIDTH * CARDSHEET_COLUMNS,
+        CARD_HEIGHT * cardsheet_rows(cards.len()),
+    )?;
+    let etag = cache::render_etag(&output_options.variant_key(&key, scale));
+
+    if is_not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+
+    cached_image_response(
+        &get_render_cache(&state),
+        &RenderScope::Deck(path.to_string()),
+        &key,
+        &output_options,
+        scale,
+        |scale| {
+            image::generate_deck_cardsheet_image(
+                state.card_renderer.as_ref(),
+                &state.database.render_directory,
+                &path,
+                cards,
+                &assets,
+                &themes,
+                scale,
+            )
+        },
+    )
+}
+
+/// Lays a deck out for print-and-play, every card repeated as many times as
+/// it is in the deck. See `print::PrintOptions` for the query parameters.
+pub async fn route_get_deck_print_pdf(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    use cardego_server::image;
+
+    let options = PrintOptions::from_query(&query)?;
+
+    // Rendering a whole deck at print size takes a while, so it is done on
+    // the thread pool without holding the server state.
+    let (database, card_renderer, render_cache) = {
+        let state = lock_server_state(&state)?;
+        (
+            state.database.clone(),
+            state.card_renderer.clone(),
+            get_render_cache(&state),
+        )
+    };
+    let deck_name = path.into_inner();
+    let query = query.into_inner();
+
+    let pdf = {
+        let deck_name = deck_name.clone();
+
+        web::block(move || -> Result<Vec<u8>> {
+            let db = DatabaseContext::new(&database.database_endpoint)
+                .or(Err(AppError::Server(ServerError::DatabaseConnectionError)))?
+                .with_art_store(ArtStore::new(&database.render_directory));
+
+            let entries = db
+                .get_deck_card_entries_by_deck_name(&deck_name)
+                .or(Err(ClientError::ResourceNotFound))?;
+            let mut cards = entries
+                .iter()
+                .map(|entry| entry.card.clone())
+                .collect::<Vec<Card>>();
+            localize_cards(&db, &query, &mut cards)?;
+
+            let assets = db.get_card_assets()?;
+            let themes = db.select_card_themes(get_theme(&query))?;
+
+            // The faces are copied out of the cache, which could otherwise
+            // evict them before they are printed.
+            let print_id = NEXT_PRINT_ID.fetch_add(1, Ordering::Relaxed);
+            let print_directory = std::path::Path::new(&database.render_directory)
+                .join("decks/images")
+                .join(format!("print-{}", print_id));
+            std::fs::create_dir_all(&print_directory)?;
+
+            let render = || -> Result<Vec<u8>> {
+                let mut faces = Vec::new();
+                for card in &cards {
+                    let theme = themes.theme_of(card);
+                    let key = cache::scaled_render_key(
+                        &cache::card_render_key(
+                            card_renderer.name(),
+                            theme,
+                            card,
+                            &assets.card_classes,
+                            &[],
+                        )?,
+                        print::PRINT_SCALE,
+                    );
+
+                    let face = print_directory.join(format!("{}.png", card.id));
+                    render_cache.copy_or_render(
+                        &RenderScope::Card(card.id),
+                        &key,
+                        || {
+                            image::generate_card_image(
+                                card_renderer.as_ref(),
+                                image::card_image_path(
+                                    &database.render_directory,
+                                    &format!("print-{}-{}", print_id, card.id),
+                                ),
+                                card,
+                                &assets,
+                                &[],
+                                theme,
+                                print::PRINT_SCALE,
+                            )
+                        },
+                        &face,
+                    )?;
+                    faces.push(face);
+                }
+
+                let copies = entries
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(face, entry)| {
+                        std::iter::repeat_n(face, entry.quantity.max(0) as usize)
+                    })
+                    .collect::<Vec<usize>>();
+
+                let back = if options.card_backs {
+                    Some(std::path::Path::new(image::CARD_BACK_FILE_PATH))
+                } else {
+                    None
+                };
+
+                Ok(print::render_print_pdf(&faces, &copies, back, &options)?)
+            };
+            let pdf = render();
+            std::fs::remove_dir_all(&print_directory)?;
+
+            pdf
+        })
+        .await?
+    };
+
+    info!("Serving print sheets of deck {:?}", deck_name.as_str());
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header(
+            "Content-Disposition",
+            format!("inline; filename=\"{}.pdf\"", deck_name.replace('"', "")),
+        )
+        .body(pdf))
+}
+
+/// The cards of a deck, in deck order, for Tabletop Simulator.
+fn get_tts_deck_entries(
+    db: &DatabaseContext,
+    deck_name: &str,
+    query: &std::collections::HashMap<String, String>,
+) -> Result<Vec<DeckCardEntry>> {
+    let mut entries = db
+        .get_deck_card_entries_by_deck_name(deck_name)
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    if entries.is_empty() {
+        Err(ClientError::InvalidInput(format!(
+            "Deck `{}` has no cards",
+            deck_name
+        )))?
+    }
+
+    let mut cards = entries
+        .iter()
+        .map(|entry| entry.card.clone())
+        .collect::<Vec<Card>>();
+    localize_cards(db, query, &mut cards)?;
+
+    for (entry, card) in entries.iter_mut().zip(cards) {
+        entry.card = card;
+    }
+
+    Ok(entries)
+}
+
+/// The Tabletop Simulator saved object of a deck. Its sheets and card back
+/// are linked to this server, next to the saved object itself.
+pub async fn route_get_deck_tts_object(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let entries = get_tts_deck_entries(&db, &path, &query)?;
+
+    let connection_info = req.connection_info();
+    let base_url = format!(
+        "{}://{}{}",
+        connection_info.scheme(),
+        connection_info.host(),
+        req.path().trim_end_matches(".json")
+    );
+    // Sheets are drawn in the same language and theme as the saved object.
+    let query_string = match req.query_string() {
+        "" => String::new(),
+        query_string => format!("?{}", query_string),
+    };
+
+    let saved_object = tts::tts_saved_object(
+        &path,
+        &entries,
+        |sheet_id| format!("{}/{}.png{}", base_url, sheet_id, query_string),
+        &format!("{}/back.png", base_url),
+    );
+
+    Ok(HttpResponse::Ok().json(saved_object))
+}
+
+pub async fn route_get_deck_tts_sheet(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    req: HttpRequest,
+    path: web::Path<(String, usize)>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    use cardego_server::image;
+
+    let (deck_name, sheet_id) = path.into_inner();
+
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    // Sheets are numbered from 1, as in the saved object.
+    let entries = get_tts_deck_entries(&db, &deck_name, &query)?;
+    let cards = sheet_id
+        .checked_sub(1)
+        .and_then(|index| entries.chunks(tts::TTS_CARDS_PER_SHEET).nth(index))
+        .ok_or(ClientError::ResourceNotFound)?
+        .iter()
+        .map(|entry| entry.card.clone())
+        .collect::<Vec<Card>>();
+
+    let assets = db.get_card_assets()?;
+    let themes = db.select_card_themes(get_theme(&query))?;
+
+    let key = cache::tts_sheet_render_key(
+        state.card_renderer.name(),
+        sheet_id,
+        &cards,
+        &assets.card_classes,
+        &themes,
+    )?;
+    let etag = cache::render_etag(&key);
+
+    if is_not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified().header("ETag", etag).finish());
+    }
+
+    let out_file_name = get_render_cache(&state).get_or_render(
+        &RenderScope::Deck(deck_name.clone()),
+        &key,
+        || {
+            image::generate_tts_sheet_image(
+                state.card_renderer.as_ref(),
+                &state.database.render_directory,
+                &deck_name,
+                sheet_id,
+                cards,
+                &assets,
+                &themes,
+            )
+        },
+    )?;
+
+    image_response(&out_file_name, etag)
+}
+
+pub async fn route_get_deck_tts_back() -> Result<HttpResponse> {
+    use cardego_server::image;
+
+    let buffer = std::fs::read(image::CARD_BACK_FILE_PATH)?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(buffer))
+}
+
+pub async fn route_query_decks(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let decks = db
+        .query_decks_by_name(path.to_string())
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(decks))
+}
+
+pub async fn route_query_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    info!("query_string: {:?}", req.query_string());
+
+    let query_string = query.get("q").ok_or_else(|| {
+        ClientError::OtherError(anyhow!("Invalid query `{}` provided", req.query_string()))
+    })?;
+
+    let mut cards = db.search_cards(query_string)?;
+    localize_cards(&db, &query, &mut cards)?;
+
+    Ok(HttpResponse::Ok().json(cards))
+}
+
+pub async fn route_query_cards_by_name(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let cards = db
+        .query_cards_by_name(path.to_string())
+        .or(Err(ClientError::ResourceNotFound))?;
+
+    Ok(HttpResponse::Ok().json(cards))
+}
+
+fn get_bulk_format(
+    query: &std::collections::HashMap<String, String>,
+) -> std::result::Result<BulkFormat, ClientError> {
+    query
+        .get("format")
+        .map(|format| format.parse())
+        .unwrap_or(Ok(BulkFormat::Json))
+}
+
+fn is_dry_run(query: &std::collections::HashMap<String, String>) -> bool {
+    query
+        .get("dry_run")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+/// Imports that fail on any row are not applied, and come back as a 422 so
+/// that clients can tell them apart from dry runs.
+fn import_report_response(report: ImportReport) -> HttpResponse {
+    if report.has_errors() {
+        HttpResponse::UnprocessableEntity().json(report)
+    } else {
+        HttpResponse::Ok().json(report)
+    }
+}
+
+pub async fn route_export_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let format = get_bulk_format(&query)?;
+
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let records = db.export_cards(query.get("q").map(|q| q.as_str()))?;
+    let body = bulk::serialize_card_records(format, &records)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(body))
+}
+
+pub async fn route_get_card_analytics(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let format = get_bulk_format(&query)?;
+
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let analytics = db.card_pool_analytics(query.get("q").map(|q| q.as_str()))?;
+    let body = analytics::serialize_card_pool_analytics(format, &analytics)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(body))
+}
+
+pub async fn route_export_decks(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let format = get_bulk_format(&query)?;
+
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let records = db.export_decks()?;
+    let body = bulk::serialize_deck_records(format, &records)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(body))
+}
+
+pub async fn route_import_cards(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: String,
+) -> Result<HttpResponse> {
+    let format = get_bulk_format(&query)?;
+    let records = bulk::parse_card_records(format, &body)?;
+
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let report = db.import_cards(records, is_dry_run(&query))?;
+
+    Ok(import_report_response(report))
+}
+
+pub async fn route_import_decks(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: String,
+) -> Result<HttpResponse> {
+    let format = get_bulk_format(&query)?;
+    let records = bulk::parse_deck_records(format, &body)?;
+
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let report = db.import_decks(records, is_dry_run(&query))?;
+
+    Ok(import_report_response(report))
+}
+
+pub async fn route_get_backups(state: web::Data<Arc<Mutex<ServerState>>>) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+
+    let snapshots = backup::list_snapshots(&state.database.backup_directory)?;
+
+    Ok(HttpResponse::Ok().json(snapshots))
+}
+
+pub async fn route_create_backup(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let report = db.create_snapshot(
+        &state.database.backup_directory,
+        state.config.backup_retention,
+    )?;
+
+    Ok(HttpResponse::Created().json(report))
+}
+
+/// Reports every inconsistency in the database.
+pub async fn route_check_integrity(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let report = db.check_integrity(false)?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Lists every card whose text does not fit on it, when drawn from its
+/// theme or the one asked for with `theme`.
+pub async fn route_lint_card_text(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let db = get_connection(&state)?;
+
+    let lints = db.lint_card_text(&db.select_card_themes(get_theme(&query))?)?;
+
+    Ok(HttpResponse::Ok().json(lints))
+}
+
+/// Repairs the inconsistencies in the database that can be repaired safely,
+/// and reports every inconsistency found.
+pub async fn route_repair_integrity(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+    let mut db = get_connection(&state)?;
+
+    let report = db.check_integrity(true)?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn graphql(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+    // The incoming HTTP request
+    data: web::Json<GraphQLRequest>,
+) -> Result<HttpResponse> {
+    // Handle the incoming request and return a string result or error
+    let res = web::block(move || {
+        let state = lock_server_state(&state)?;
+        let db = get_connection(&state)?;
+        let res = data.execute(&state.schema, &db);
+        Ok::<_, anyhow::Error>(serde_json::to_string(&res)?)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(res))
+}
+
+pub async fn graphql_playground(req: HttpRequest) -> Result<HttpResponse> {
+    // The playground is served next to the GraphQL endpoint of its database.
+    let graphql_path = format!("{}/graphql", req.path().trim_end_matches("/graphiql"));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(playground_source(&graphql_path)))
+}
+
+/// Lists the names of the databases served, and where each one is served.
+pub async fn route_get_databases(
+    state: web::Data<Arc<Mutex<ServerState>>>,
+) -> Result<HttpResponse> {
+    let state = lock_server_state(&state)?;
+
+    let databases = state
+        .config
+        .databases
+        .iter()
+        .map(|database| {
+            serde_json::json!({
+                "name": database.name,
+                "path": format!("/db/{}", database.name),
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+
+    Ok(HttpResponse::Ok().json(databases))
 }
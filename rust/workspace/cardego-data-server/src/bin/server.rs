@@ -14,6 +14,10 @@ use cardego_server::errors::Result;
 use actix_web::{middleware, web, App, HttpServer};
 use log::info;
 
+use cardego_server::bulk;
+use cardego_server::databases::DatabaseConfig;
+use cardego_server::image::renderer::load_card_renderer;
+use cardego_server::render_jobs::RenderJobQueue;
 use cardego_server::search::create_schema;
 use cardego_server::{ApplicationConfig, ServerState};
 use std::sync::{Arc, Mutex};
@@ -22,21 +26,200 @@ fn init_config() -> anyhow::Result<()> {
     log4rs::init_file("config/log4rs/log4rs.yml", Default::default())?;
     info!("Finished initializing log4rs");
 
-    std::fs::copy(
-        "static/templates/card.css",
-        "runtime/data/cards/images/templates/card.css",
-    )?;
-    info!("Copied 'card.css' from into runtime/data/cards/images/templates directory.");
-    std::fs::copy(
-        "static/templates/card.css",
-        "runtime/data/decks/images/templates/card.css",
-    )?;
-    info!("Copied 'card.css' from into runtime/data/decks/images/templates directory.");
-
     info!("Finished reading server configuration");
     Ok(())
 }
 
+/// Creates the directories a database renders its images into.
+fn init_render_directory(database: &DatabaseConfig) -> anyhow::Result<()> {
+    for kind in &["cards", "decks"] {
+        let templates_directory =
+            format!("{}/{}/images/templates", database.render_directory, kind);
+
+        std::fs::create_dir_all(&templates_directory)?;
+        std::fs::copy(
+            "static/templates/card.css",
+            format!("{}/card.css", templates_directory),
+        )?;
+        info!(
+            "Copied 'card.css' into {} for database '{}'.",
+            templates_directory, database.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Registers the routes served for each database. The default database is
+/// served at the root, and every database is also served under `/db/{name}`.
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    use crate::route::*;
+
+    cfg.route("/", web::get().to(index))
+        .route("/cards", web::get().to(route_query_cards))
+        .route("/cards", web::post().to(route_create_card))
+        .service(
+            web::scope("/cards")
+                .route("", web::get().to(route_query_cards))
+                .route("", web::post().to(route_create_card))
+                .route("/{id}", web::get().to(route_get_card))
+                .route("/{id}", web::put().to(route_update_card))
+                .route("/{id}/clone", web::post().to(route_clone_card))
+                .route(
+                    "/{id}/image.png",
+                    web::get().to(route_get_card_image_by_html),
+                )
+                .route(
+                    "/{id}/image.html",
+                    web::get().to(route_get_card_image_as_html),
+                )
+                .route("/{id}/card.css", web::get().to(route_get_card_image_css))
+                .route("/{id}/art", web::post().to(route_upload_card_art))
+                .route("/{id}/art/ingest", web::post().to(route_ingest_card_art))
+                .route("/{id}/relations", web::get().to(route_get_card_relations))
+                .route(
+                    "/{id}/relations",
+                    web::post().to(route_create_card_relation),
+                )
+                .route(
+                    "/{id}/relations/{relation_id}",
+                    web::put().to(route_update_card_relation),
+                )
+                .route(
+                    "/{id}/relations/{relation_id}",
+                    web::delete().to(route_delete_card_relation),
+                )
+                .route(
+                    "/{id}/translations",
+                    web::get().to(route_get_card_translations),
+                )
+                .route(
+                    "/{id}/translations/{locale}",
+                    web::put().to(route_put_card_translation),
+                )
+                .route(
+                    "/{id}/translations/{locale}",
+                    web::delete().to(route_delete_card_translation),
+                ),
+        )
+        .route(
+            "/translations/missing",
+            web::get().to(route_get_missing_translations),
+        )
+        .service(
+            web::scope("/card-classes")
+                .route("", web::get().to(route_get_card_classes))
+                .route("", web::post().to(route_create_card_class))
+                .route("/{code}", web::get().to(route_get_card_class))
+                .route("/{code}", web::put().to(route_update_card_class))
+                .route("/{code}", web::delete().to(route_delete_card_class)),
+        )
+        .service(
+            web::scope("/card-themes")
+                .route("", web::get().to(route_get_card_themes))
+                .route("", web::post().to(route_create_card_theme))
+                .route("/{name}", web::get().to(route_get_card_theme))
+                .route("/{name}", web::put().to(route_update_card_theme))
+                .route("/{name}", web::delete().to(route_delete_card_theme)),
+        )
+        .service(
+            web::scope("/card-sets")
+                .route("", web::get().to(route_get_card_sets))
+                .route("", web::post().to(route_create_card_set))
+                .route("/{code}", web::get().to(route_get_card_set))
+                .route("/{code}", web::put().to(route_update_card_set))
+                .route("/{code}", web::delete().to(route_delete_card_set))
+                .route("/{code}/cards", web::get().to(route_get_card_set_cards))
+                .route("/{code}/cards", web::post().to(route_add_card_set_card))
+                .route(
+                    "/{code}/cards/{card_id}",
+                    web::delete().to(route_remove_card_set_card),
+                ),
+        )
+        .service(
+            web::scope("/decks")
+                .route("/{name}", web::get().to(route_get_deck))
+                .route("/{name}", web::post().to(route_create_deck))
+                .route("/{name}", web::patch().to(route_rename_deck))
+                .route("/{name}/clone", web::post().to(route_clone_deck))
+                .route("/{name}/cards", web::get().to(route_get_deck_cards))
+                .route("/{name}/cards", web::post().to(route_add_deck_card))
+                .route(
+                    "/{name}/cards/{card_id}",
+                    web::put().to(route_set_deck_card_quantity),
+                )
+                .route(
+                    "/{name}/cards/{card_id}",
+                    web::delete().to(route_remove_deck_card),
+                )
+                .route("/{name}/order", web::put().to(route_reorder_deck_cards))
+                .route("/{name}/validate", web::get().to(route_validate_deck))
+                .route("/{name}/image.png", web::get().to(route_get_deck_cardsheet))
+                .route("/{name}/print.pdf", web::get().to(route_get_deck_print_pdf))
+                .route("/{name}/tts.json", web::get().to(route_get_deck_tts_object))
+                // The back is registered first, as it also matches the sheets.
+                .route(
+                    "/{name}/tts/back.png",
+                    web::get().to(route_get_deck_tts_back),
+                )
+                .route(
+                    "/{name}/tts/{sheet}.png",
+                    web::get().to(route_get_deck_tts_sheet),
+                ),
+        )
+        .service(
+            web::scope("/deck-rules")
+                .route("/{decktype}", web::get().to(route_get_deck_rules))
+                .route("/{decktype}", web::put().to(route_put_deck_rules)),
+        )
+        .service(
+            web::scope("/admin")
+                .route("/backups", web::get().to(route_get_backups))
+                .route("/backups", web::post().to(route_create_backup))
+                .route("/integrity", web::get().to(route_check_integrity))
+                .route("/integrity/repair", web::post().to(route_repair_integrity))
+                .route("/lint/card-text", web::get().to(route_lint_card_text)),
+        )
+        .service(
+            web::scope("/render-jobs")
+                .route("", web::post().to(route_create_render_job))
+                .route("/{id}", web::get().to(route_get_render_job))
+                .route("/{id}/image.png", web::get().to(route_get_render_job_image)),
+        )
+        .service(web::scope("/art").route("/{hash}.png", web::get().to(route_get_art)))
+        .service(web::scope("/analytics").route("/cards", web::get().to(route_get_card_analytics)))
+        .service(
+            web::scope("/export")
+                .route("/cards", web::get().to(route_export_cards))
+                .route("/decks", web::get().to(route_export_decks)),
+        )
+        .service(
+            web::scope("/import")
+                .service(
+                    web::resource("/cards")
+                        .data(web::PayloadConfig::new(bulk::MAX_IMPORT_BYTES))
+                        .route(web::post().to(route_import_cards)),
+                )
+                .service(
+                    web::resource("/decks")
+                        .data(web::PayloadConfig::new(bulk::MAX_IMPORT_BYTES))
+                        .route(web::post().to(route_import_decks)),
+                ),
+        )
+        .service(
+            web::scope("/search")
+                .route("/decks/{name}", web::get().to(route_query_decks))
+                .route("/cards/{name}", web::get().to(route_query_cards_by_name)),
+        )
+        .service(
+            web::scope("/graphql")
+                .route("", web::get().to(crate::route::graphql))
+                .route("", web::post().to(crate::route::graphql)),
+        )
+        .route("/graphiql", web::get().to(crate::route::graphql_playground))
+        .route("/databases", web::get().to(route_get_databases));
+}
+
 #[actix_rt::main]
 async fn main() -> Result<()> {
     // Collect command line arguments
@@ -45,59 +228,65 @@ async fn main() -> Result<()> {
     // Initialize all server + dependency config
     init_config()?;
 
-    // Create the shared application state
-    let state = Arc::new(Mutex::new(ServerState {
-        config: ApplicationConfig::new()?,
-        schema: create_schema(),
-    }));
+    let config = ApplicationConfig::new()?;
+    for database in &config.databases {
+        init_render_directory(database)?;
+    }
+
+    let card_renderer = load_card_renderer(config.card_renderer)?;
+    info!("Rendering cards with {}", card_renderer.name());
+
+    let render_jobs = RenderJobQueue::start(
+        config.render_workers,
+        config.render_queue_limit,
+        card_renderer.clone(),
+        config.render_cache_max_bytes,
+    );
+
+    // Create the shared application state, one per database so that each
+    // database gets its own connections and GraphQL context.
+    let states = config
+        .databases
+        .iter()
+        .map(|database| {
+            info!(
+                "Serving database '{}' from {}",
+                database.name, database.database_endpoint
+            );
+
+            let state = Arc::new(Mutex::new(ServerState {
+                config: config.clone(),
+                database: database.clone(),
+                schema: create_schema(),
+                card_renderer: card_renderer.clone(),
+                render_jobs: render_jobs.clone(),
+            }));
+
+            (database.name.clone(), state)
+        })
+        .collect::<Vec<_>>();
+    let default_state = states[0].1.clone();
 
     // Create the HTTP server with routing below and initialize it.
     info!("Initializing server framework");
     let result = HttpServer::new(move || {
-        use crate::route::*;
-
-        App::new()
-            .data(state.clone())
+        let mut app = App::new()
+            .data(default_state.clone())
             .wrap(middleware::DefaultHeaders::new().header("X-API-Version", "alpha-9"))
             // ALWAYS have compression on! This is a major performance
             // boost for amount of bytes per image get!
             .wrap(middleware::Compress::default())
-            .route("/", web::get().to(index))
-            .route("/cards", web::get().to(route_query_cards))
-            .route("/cards", web::post().to(route_create_card))
-            .service(
-                web::scope("/cards")
-                    .route("", web::get().to(route_query_cards))
-                    .route("", web::post().to(route_create_card))
-                    .route("/{id}", web::get().to(route_get_card))
-                    .route("/{id}", web::put().to(route_update_card))
-                    .route(
-                        "/{id}/image.png",
-                        web::get().to(route_get_card_image_by_html),
-                    )
-                    .route(
-                        "/{id}/image.html",
-                        web::get().to(route_get_card_image_as_html),
-                    )
-                    .route("/{id}/card.css", web::get().to(route_get_card_image_css)),
-            )
-            .service(
-                web::scope("/decks")
-                    .route("/{name}", web::get().to(route_get_deck))
-                    .route("/{name}", web::post().to(route_create_deck))
-                    .route("/{name}/image.png", web::get().to(route_get_deck_cardsheet)),
-            )
-            .service(
-                web::scope("/search")
-                    .route("/decks/{name}", web::get().to(route_query_decks))
-                    .route("/cards/{name}", web::get().to(route_query_cards_by_name)),
-            )
-            .service(
-                web::scope("/graphql")
-                    .route("", web::get().to(crate::route::graphql))
-                    .route("", web::post().to(crate::route::graphql)),
-            )
-            .route("/graphiql", web::get().to(crate::route::graphql_playground))
+            .configure(configure_routes);
+
+        for (name, state) in &states {
+            app = app.service(
+                web::scope(&format!("/db/{}", name))
+                    .data(state.clone())
+                    .configure(configure_routes),
+            );
+        }
+
+        app
     })
     // Local testing? Use localhost:80 for HTTP
     .bind(&args[1])?
@@ -0,0 +1,1068 @@
+//! Bulk import and export of cards and decks as JSON, CSV or YAML.
+//!
+//! Imports are planned in full before anything is written. If any row has
+//! an error, or if the import is a dry run, nothing is applied; otherwise
+//! every row is applied inside a single transaction.
+//!
+//! Cards with stored art are exported with the art itself, so that it is
+//! stored again wherever they are imported.
+
+extern crate csv;
+extern crate serde_yaml;
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use ::image::RgbaImage;
+
+use crate::card_values::{CardAction, CardSpeed};
+use crate::database::DatabaseContext;
+use crate::deck_name_error;
+use crate::errors::ClientError;
+use crate::image::art::{exported_art_url, read_exported_art};
+use crate::models::{Card, Deck, NewCardCardAttributeRelation, NewDeck, NewFullCardData};
+use crate::models::{NewCard, NewDeckCardRelation};
+
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::Integer);
+
+/// The largest import file accepted over HTTP, in bytes.
+pub const MAX_IMPORT_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkFormat {
+    Json,
+    Csv,
+    Yaml,
+}
+
+impl BulkFormat {
+    /// Guesses the format from a file name's extension.
+    pub fn from_path(path: &str) -> Option<BulkFormat> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?;
+        extension.parse().ok()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BulkFormat::Json => "application/json",
+            BulkFormat::Csv => "text/csv; charset=utf-8",
+            BulkFormat::Yaml => "application/x-yaml",
+        }
+    }
+}
+
+impl FromStr for BulkFormat {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(BulkFormat::Json),
+            "csv" => Ok(BulkFormat::Csv),
+            "yaml" | "yml" => Ok(BulkFormat::Yaml),
+            _ => Err(ClientError::InvalidInput(format!(
+                "Unknown format '{}'; expected json, csv or yaml",
+                s
+            ))),
+        }
+    }
+}
+
+/// A card as it appears in an import or export file. Cards without an `id`
+/// are matched against existing cards by name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardRecord {
+    #[serde(default)]
+    pub id: Option<i32>,
+    pub cardclass: String,
+    pub action: CardAction,
+    pub speed: CardSpeed,
+    pub initiative: i32,
+    pub name: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub card_attributes: Vec<i32>,
+    /// The stored art `image_url` points at, as a base64 PNG.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub art: Option<String>,
+}
+
+/// CSV has no lists, so attribute ids are written space-separated.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvCardRecord {
+    id: Option<i32>,
+    cardclass: String,
+    action: CardAction,
+    speed: CardSpeed,
+    initiative: i32,
+    name: String,
+    desc: String,
+    image_url: Option<String>,
+    card_attributes: Option<String>,
+    art: Option<String>,
+}
+
+impl From<&CardRecord> for CsvCardRecord {
+    fn from(record: &CardRecord) -> Self {
+        CsvCardRecord {
+            id: record.id,
+            cardclass: record.cardclass.clone(),
+            action: record.action,
+            speed: record.speed,
+            initiative: record.initiative,
+            name: record.name.clone(),
+            desc: record.desc.clone(),
+            image_url: record.image_url.clone(),
+            card_attributes: Some(
+                record
+                    .card_attributes
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            art: record.art.clone(),
+        }
+    }
+}
+
+impl CsvCardRecord {
+    fn into_card_record(self) -> std::result::Result<CardRecord, String> {
+        let card_attributes = self
+            .card_attributes
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|s| {
+                s.parse::<i32>()
+                    .map_err(|_| format!("'{}' is not a valid card attribute id", s))
+            })
+            .collect::<std::result::Result<Vec<i32>, String>>()?;
+
+        Ok(CardRecord {
+            id: self.id,
+            cardclass: self.cardclass,
+            action: self.action,
+            speed: self.speed,
+            initiative: self.initiative,
+            name: self.name,
+            desc: self.desc,
+            image_url: self.image_url,
+            card_attributes,
+            art: self.art,
+        })
+    }
+}
+
+fn default_decktype() -> String {
+    "user".to_owned()
+}
+
+fn default_quantity() -> i32 {
+    1
+}
+
+/// A deck as it appears in an import or export file. Decks are matched
+/// against existing decks by name, and their cards are listed in deck order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeckRecord {
+    pub name: String,
+    #[serde(default = "default_decktype")]
+    pub decktype: String,
+    #[serde(default)]
+    pub cards: Vec<DeckRecordCard>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeckRecordCard {
+    pub card_id: i32,
+    #[serde(default = "default_quantity")]
+    pub quantity: i32,
+}
+
+/// In CSV, each row is one card of a deck. Consecutive rows with the same
+/// deck name make up one deck, and a deck with no cards is a single row
+/// without a `card_id`.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvDeckRow {
+    deck: String,
+    decktype: Option<String>,
+    card_id: Option<i32>,
+    quantity: Option<i32>,
+}
+
+/// Parses an import file into one result per row, so that a bad row does not
+/// hide errors in the rows after it. Only errors in the file as a whole are
+/// returned as `Err`.
+pub fn parse_card_records(
+    format: BulkFormat,
+    input: &str,
+) -> Result<Vec<std::result::Result<CardRecord, String>>> {
+    match format {
+        BulkFormat::Csv => Ok(csv::Reader::from_reader(input.as_bytes())
+            .deserialize::<CsvCardRecord>()
+            .map(|row| {
+                row.map_err(|err| err.to_string())
+                    .and_then(CsvCardRecord::into_card_record)
+            })
+            .collect()),
+        _ => parse_structured_rows(format, input),
+    }
+}
+
+pub fn parse_deck_records(
+    format: BulkFormat,
+    input: &str,
+) -> Result<Vec<std::result::Result<DeckRecord, String>>> {
+    match format {
+        BulkFormat::Csv => {
+            let mut results: Vec<std::result::Result<DeckRecord, String>> = Vec::new();
+
+            for row in csv::Reader::from_reader(input.as_bytes()).deserialize::<CsvDeckRow>() {
+                let row = match row {
+                    Ok(row) => row,
+                    Err(err) => {
+                        results.push(Err(err.to_string()));
+                        continue;
+                    }
+                };
+
+                let card = row.card_id.map(|card_id| DeckRecordCard {
+                    card_id,
+                    quantity: row.quantity.unwrap_or(1),
+                });
+
+                match results.last_mut() {
+                    Some(Ok(deck)) if deck.name == row.deck => deck.cards.extend(card),
+                    _ => results.push(Ok(DeckRecord {
+                        name: row.deck,
+                        decktype: row.decktype.unwrap_or_else(default_decktype),
+                        cards: card.into_iter().collect(),
+                    })),
+                }
+            }
+
+            Ok(results)
+        }
+        _ => parse_structured_rows(format, input),
+    }
+}
+
+fn parse_structured_rows<T: DeserializeOwned>(
+    format: BulkFormat,
+    input: &str,
+) -> Result<Vec<std::result::Result<T, String>>> {
+    let results = match format {
+        BulkFormat::Json => serde_json::from_str::<Vec<serde_json::Value>>(input)
+            .map_err(|err| ClientError::InvalidInput(format!("Invalid JSON: {}", err)))?
+            .into_iter()
+            .map(|value| serde_json::from_value::<T>(value).map_err(|err| err.to_string()))
+            .collect(),
+        BulkFormat::Yaml => serde_yaml::from_str::<Vec<serde_yaml::Value>>(input)
+            .map_err(|err| ClientError::InvalidInput(format!("Invalid YAML: {}", err)))?
+            .into_iter()
+            .map(|value| serde_yaml::from_value::<T>(value).map_err(|err| err.to_string()))
+            .collect(),
+        BulkFormat::Csv => unreachable!("CSV rows are parsed by their own record types"),
+    };
+
+    Ok(results)
+}
+
+pub fn serialize_card_records(format: BulkFormat, records: &[CardRecord]) -> Result<String> {
+    match format {
+        BulkFormat::Json => Ok(serde_json::to_string_pretty(records)?),
+        BulkFormat::Yaml => Ok(serde_yaml::to_string(records)?),
+        BulkFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for record in records {
+                writer.serialize(CsvCardRecord::from(record))?;
+            }
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+pub fn serialize_deck_records(format: BulkFormat, records: &[DeckRecord]) -> Result<String> {
+    match format {
+        BulkFormat::Json => Ok(serde_json::to_string_pretty(records)?),
+        BulkFormat::Yaml => Ok(serde_yaml::to_string(records)?),
+        BulkFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for record in records {
+                if record.cards.is_empty() {
+                    writer.serialize(CsvDeckRow {
+                        deck: record.name.clone(),
+                        decktype: Some(record.decktype.clone()),
+                        card_id: None,
+                        quantity: None,
+                    })?;
+                }
+
+                for card in &record.cards {
+                    writer.serialize(CsvDeckRow {
+                        deck: record.name.clone(),
+                        decktype: Some(record.decktype.clone()),
+                        card_id: Some(card.card_id),
+                        quantity: Some(card.quantity),
+                    })?;
+                }
+            }
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportAction {
+    Create,
+    Update,
+    Unchanged,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportRowResult {
+    /// The 1-based position of the record in the import file.
+    pub row: usize,
+    pub action: ImportAction,
+    pub id: Option<i32>,
+    pub name: Option<String>,
+    pub changes: Vec<FieldChange>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub applied: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub rows: Vec<ImportRowResult>,
+}
+
+impl ImportReport {
+    fn new(dry_run: bool, rows: Vec<ImportRowResult>) -> Self {
+        let count = |action| rows.iter().filter(|row| row.action == action).count();
+
+        ImportReport {
+            dry_run,
+            applied: false,
+            created: count(ImportAction::Create),
+            updated: count(ImportAction::Update),
+            unchanged: count(ImportAction::Unchanged),
+            failed: count(ImportAction::Error),
+            rows,
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.failed > 0
+    }
+}
+
+fn error_row(row: usize, name: Option<String>, errors: Vec<String>) -> ImportRowResult {
+    ImportRowResult {
+        row,
+        action: ImportAction::Error,
+        id: None,
+        name,
+        changes: vec![],
+        errors,
+    }
+}
+
+fn diff_field<T: ToString + PartialEq>(
+    changes: &mut Vec<FieldChange>,
+    field: &str,
+    old: T,
+    new: T,
+) {
+    if old != new {
+        changes.push(FieldChange {
+            field: field.to_owned(),
+            old: old.to_string(),
+            new: new.to_string(),
+        });
+    }
+}
+
+/// Reads the art an imported card carries, which must be the stored art
+/// its `image_url` points at.
+fn read_record_art(record: &CardRecord) -> std::result::Result<Option<RgbaImage>, String> {
+    let encoded = match &record.art {
+        Some(encoded) => encoded,
+        None => return Ok(None),
+    };
+
+    let bytes = base64::decode(encoded.trim()).map_err(|err| format!("art: {}", err))?;
+    let image = read_exported_art(&bytes).map_err(|err| format!("art: {}", err))?;
+
+    if record.image_url.as_deref() != Some(exported_art_url(&image).as_str()) {
+        return Err("art: The art is not the stored art that image_url points at".to_owned());
+    }
+
+    Ok(Some(image))
+}
+
+fn format_deck_cards(cards: &[DeckRecordCard]) -> String {
+    cards
+        .iter()
+        .map(|card| format!("{}x{}", card.quantity, card.card_id))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl DatabaseContext {
+    pub fn export_cards(&self, query: Option<&str>) -> Result<Vec<CardRecord>> {
+        use crate::schema::cards::dsl::*;
+
+        let found_cards = match query {
+            Some(query_string) => {
+                let card_ids = self.query_card_ids(query_string)?;
+                cards
+                    .filter(id.eq_any(card_ids))
+                    .order(id.asc())
+                    .load::<Card>(self.connection.as_ref())?
+            }
+            None => cards
+                .order(id.asc())
+                .load::<Card>(self.connection.as_ref())?,
+        };
+
+        let mut attributes =
+            self.get_card_attributes_by_card_ids(found_cards.iter().map(|card| card.id).collect())?;
+        let art_store = self.get_art_store()?;
+
+        let results = found_cards
+            .into_iter()
+            .map(|card| {
+                let mut card_attributes = attributes
+                    .remove(&card.id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|attr| attr.id)
+                    .collect::<Vec<i32>>();
+                card_attributes.sort();
+
+                let art = card
+                    .image_url
+                    .as_deref()
+                    .and_then(|url| art_store.read(url))
+                    .map(base64::encode);
+
+                CardRecord {
+                    id: Some(card.id),
+                    cardclass: card.cardclass,
+                    action: card.action,
+                    speed: card.speed,
+                    initiative: card.initiative,
+                    name: card.name,
+                    desc: card.desc,
+                    image_url: card.image_url,
+                    card_attributes,
+                    art,
+                }
+            })
+            .collect();
+
+        Ok(results)
+    }
+
+    pub fn export_decks(&self) -> Result<Vec<DeckRecord>> {
+        use crate::schema::decks::dsl::*;
+
+        let all_decks = decks
+            .order(name.asc())
+            .load::<Deck>(self.connection.as_ref())?;
+
+        all_decks
+            .into_iter()
+            .map(|deck| {
+                let entries = self.get_deck_card_entries_by_deck_name(&deck.name)?;
+                Ok(DeckRecord {
+                    name: deck.name,
+                    decktype: deck.decktype,
+                    cards: entries
+                        .into_iter()
+                        .map(|entry| DeckRecordCard {
+                            card_id: entry.card.id,
+                            quantity: entry.quantity,
+                        })
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Imports cards, matching each record to an existing card by id, or by
+    /// name if it has no id.
+    pub fn import_cards(
+        &mut self,
+        records: Vec<std::result::Result<CardRecord, String>>,
+        dry_run: bool,
+    ) -> Result<ImportReport> {
+        debug!(
+            "import_cards: {} row(s), dry_run: {}",
+            records.len(),
+            dry_run
+        );
+
+        let context = self.get_card_validation_context()?;
+        let mut seen_names = HashSet::new();
+        let mut rows = Vec::new();
+        let mut plan: Vec<(usize, Option<i32>, CardRecord)> = Vec::new();
+        // The art carried by the records, even those that are unchanged.
+        let mut imported_art: Vec<RgbaImage> = Vec::new();
+
+        for (index, record) in records.into_iter().enumerate() {
+            let row = index + 1;
+
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    rows.push(error_row(row, None, vec![err]));
+                    continue;
+                }
+            };
+
+            let (art, art_error) = match read_record_art(&record) {
+                Ok(art) => (art, None),
+                Err(err) => (None, Some(err)),
+            };
+
+            let mut errors = NewFullCardData::from(&record)
+                .validate(&context)
+                .err()
+                .map(|errors| {
+                    errors
+                        .errors
+                        .into_iter()
+                        // Art the record carries is stored when it is applied.
+                        .filter(|error| art.is_none() || error.code != "unknown_art")
+                        .map(|error| format!("{}: {}", error.field, error.message))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default();
+            errors.extend(art_error);
+
+            if !seen_names.insert(record.name.clone()) {
+                errors.push(format!(
+                    "name: '{}' appears more than once in this import",
+                    record.name
+                ));
+            }
+
+            let existing = match self.find_existing_card(&record) {
+                Ok(existing) => existing,
+                Err(err) => {
+                    errors.push(err.to_string());
+                    None
+                }
+            };
+
+            if !errors.is_empty() {
+                rows.push(error_row(row, Some(record.name), errors));
+                continue;
+            }
+            imported_art.extend(art);
+
+            let (action, changes) = match &existing {
+                Some(card) => {
+                    let changes = self.diff_card(card, &record)?;
+                    if changes.is_empty() {
+                        (ImportAction::Unchanged, changes)
+                    } else {
+                        (ImportAction::Update, changes)
+                    }
+                }
+                None => (ImportAction::Create, vec![]),
+            };
+
+            rows.push(ImportRowResult {
+                row,
+                action,
+                id: existing.as_ref().map(|card| card.id).or(record.id),
+                name: Some(record.name.clone()),
+                changes,
+                errors: vec![],
+            });
+
+            if action != ImportAction::Unchanged {
+                plan.push((rows.len() - 1, existing.map(|card| card.id), record));
+            }
+        }
+
+        let mut report = ImportReport::new(dry_run, rows);
+
+        if dry_run || report.has_errors() {
+            return Ok(report);
+        }
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            for (report_index, existing_id, record) in &plan {
+                let card_id = self.write_card_record(*existing_id, record)?;
+                report.rows[*report_index].id = Some(card_id);
+            }
+
+            // Art is stored once every card is written, and before they are
+            // committed, so that a failed write stores no art and a failed
+            // store writes no cards.
+            let art_store = self.get_art_store()?;
+            for art in &imported_art {
+                art_store.store(art)?;
+            }
+
+            Ok(())
+        })?;
+
+        report.applied = true;
+        debug!("import_cards succeeded");
+        Ok(report)
+    }
+
+    /// Imports decks, matching each record to an existing deck by name. The
+    /// card list of an existing deck is replaced by the imported one.
+    pub fn import_decks(
+        &mut self,
+        records: Vec<std::result::Result<DeckRecord, String>>,
+        dry_run: bool,
+    ) -> Result<ImportReport> {
+        use crate::schema::cards;
+
+        debug!(
+            "import_decks: {} row(s), dry_run: {}",
+            records.len(),
+            dry_run
+        );
+
+        let known_card_ids = cards::table
+            .select(cards::id)
+            .load::<i32>(self.connection.as_ref())?
+            .into_iter()
+            .collect::<HashSet<i32>>();
+        let mut seen_names = HashSet::new();
+        let mut rows = Vec::new();
+        let mut plan: Vec<(usize, Option<i32>, DeckRecord)> = Vec::new();
+
+        for (index, record) in records.into_iter().enumerate() {
+            let row = index + 1;
+
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    rows.push(error_row(row, None, vec![err]));
+                    continue;
+                }
+            };
+
+            let mut errors = Vec::new();
+            let mut seen_card_ids = HashSet::new();
+
+            if let Some(message) = deck_name_error(&record.name) {
+                errors.push(format!("name: {}", message));
+            }
+            if !seen_names.insert(record.name.clone()) {
+                errors.push(format!(
+                    "name: '{}' appears more than once in this import",
+                    record.name
+                ));
+            }
+            for card in &record.cards {
+                if !known_card_ids.contains(&card.card_id) {
+                    errors.push(format!("cards: unknown card id {}", card.card_id));
+                }
+                if card.quantity < 1 {
+                    errors.push(format!(
+                        "cards: card {} has quantity {}, but needs at least 1",
+                        card.card_id, card.quantity
+                    ));
+                }
+                if !seen_card_ids.insert(card.card_id) {
+                    errors.push(format!(
+                        "cards: card {} is listed more than once",
+                        card.card_id
+                    ));
+                }
+            }
+
+            if !errors.is_empty() {
+                rows.push(error_row(row, Some(record.name), errors));
+                continue;
+            }
+
+            let existing = {
+                use crate::schema::decks::dsl::*;
+
+                decks
+                    .filter(name.eq(&record.name))
+                    .first::<Deck>(self.connection.as_ref())
+                    .optional()?
+            };
+
+            let (action, changes) = match &existing {
+                Some(deck) => {
+                    let existing_cards = self
+                        .get_deck_card_entries_by_deck_name(&deck.name)?
+                        .into_iter()
+                        .map(|entry| DeckRecordCard {
+                            card_id: entry.card.id,
+                            quantity: entry.quantity,
+                        })
+                        .collect::<Vec<DeckRecordCard>>();
+
+                    let mut changes = vec![];
+                    diff_field(&mut changes, "decktype", &deck.decktype, &record.decktype);
+                    diff_field(
+                        &mut changes,
+                        "cards",
+                        format_deck_cards(&existing_cards),
+                        format_deck_cards(&record.cards),
+                    );
+
+                    if changes.is_empty() {
+                        (ImportAction::Unchanged, changes)
+                    } else {
+                        (ImportAction::Update, changes)
+                    }
+                }
+                None => (ImportAction::Create, vec![]),
+            };
+
+            rows.push(ImportRowResult {
+                row,
+                action,
+                id: existing.as_ref().map(|deck| deck.id),
+                name: Some(record.name.clone()),
+                changes,
+                errors: vec![],
+            });
+
+            if action != ImportAction::Unchanged {
+                plan.push((rows.len() - 1, existing.map(|deck| deck.id), record));
+            }
+        }
+
+        let mut report = ImportReport::new(dry_run, rows);
+
+        if dry_run || report.has_errors() {
+            return Ok(report);
+        }
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            for (report_index, existing_id, record) in &plan {
+                let deck_id = self.write_deck_record(*existing_id, record)?;
+                report.rows[*report_index].id = Some(deck_id);
+            }
+
+            Ok(())
+        })?;
+
+        report.applied = true;
+        debug!("import_decks succeeded");
+        Ok(report)
+    }
+
+    fn find_existing_card(&self, record: &CardRecord) -> Result<Option<Card>> {
+        use crate::schema::cards::dsl::*;
+
+        if let Some(card_id) = record.id {
+            return Ok(cards
+                .find(card_id)
+                .first::<Card>(self.connection.as_ref())
+                .optional()?);
+        }
+
+        let mut matches = cards
+            .filter(name.eq(&record.name))
+            .load::<Card>(self.connection.as_ref())?;
+
+        if matches.len() > 1 {
+            Err(ClientError::InvalidInput(format!(
+                "name: {} cards are named '{}'; give an id to choose one",
+                matches.len(),
+                record.name
+            )))?
+        }
+
+        Ok(matches.pop())
+    }
+
+    fn diff_card(&self, card: &Card, record: &CardRecord) -> Result<Vec<FieldChange>> {
+        let mut old_attributes = self
+            .get_card_attributes_by_card_id(card.id)?
+            .into_iter()
+            .map(|attr| attr.id)
+            .collect::<Vec<i32>>();
+        let mut new_attributes = record.card_attributes.clone();
+        old_attributes.sort();
+        new_attributes.sort();
+
+        let mut changes = vec![];
+        diff_field(
+            &mut changes,
+            "cardclass",
+            &card.cardclass,
+            &record.cardclass,
+        );
+        diff_field(&mut changes, "action", card.action, record.action);
+        diff_field(&mut changes, "speed", card.speed, record.speed);
+        diff_field(
+            &mut changes,
+            "initiative",
+            card.initiative,
+            record.initiative,
+        );
+        diff_field(&mut changes, "name", &card.name, &record.name);
+        diff_field(&mut changes, "desc", &card.desc, &record.desc);
+        diff_field(
+            &mut changes,
+            "image_url",
+            card.image_url.clone().unwrap_or_default(),
+            record.image_url.clone().unwrap_or_default(),
+        );
+        diff_field(
+            &mut changes,
+            "card_attributes",
+            format!("{:?}", old_attributes),
+            format!("{:?}", new_attributes),
+        );
+
+        Ok(changes)
+    }
+
+    /// Writes a card record, replacing its attributes, and returns its id.
+    fn write_card_record(&self, existing_id: Option<i32>, record: &CardRecord) -> Result<i32> {
+        use crate::schema::cards;
+        use crate::schema::cards_card_attributes_relation as relation;
+
+        let card_id = match (existing_id, record.id) {
+            (Some(card_id), _) => {
+                diesel::update(cards::table.find(card_id))
+                    .set((
+                        cards::cardclass.eq(&record.cardclass),
+                        cards::action.eq(record.action),
+                        cards::speed.eq(record.speed),
+                        cards::initiative.eq(record.initiative),
+                        cards::name.eq(&record.name),
+                        cards::desc.eq(&record.desc),
+                        cards::image_url.eq(&record.image_url),
+                        cards::version.eq(cards::version + 1),
+                    ))
+                    .execute(self.connection.as_ref())?;
+                card_id
+            }
+            (None, Some(new_id)) => {
+                diesel::insert_into(cards::table)
+                    .values(&Card {
+                        id: new_id,
+                        cardclass: record.cardclass.clone(),
+                        action: record.action,
+                        speed: record.speed,
+                        initiative: record.initiative,
+                        name: record.name.clone(),
+                        desc: record.desc.clone(),
+                        image_url: record.image_url.clone(),
+                        version: 1,
+                        cloned_from_id: None,
+                    })
+                    .execute(self.connection.as_ref())?;
+                new_id
+            }
+            (None, None) => {
+                diesel::insert_into(cards::table)
+                    .values(&NewCard {
+                        cardclass: &record.cardclass,
+                        action: record.action,
+                        speed: record.speed,
+                        initiative: record.initiative,
+                        name: &record.name,
+                        desc: &record.desc,
+                        image_url: record.image_url.as_deref(),
+                    })
+                    .execute(self.connection.as_ref())?;
+                diesel::select(last_insert_rowid).get_result::<i32>(self.connection.as_ref())?
+            }
+        };
+
+        diesel::delete(relation::table.filter(relation::card_id.eq(card_id)))
+            .execute(self.connection.as_ref())?;
+
+        let new_relations = record
+            .card_attributes
+            .iter()
+            .map(|attr| NewCardCardAttributeRelation {
+                card_id,
+                card_attribute_id: *attr,
+            })
+            .collect::<Vec<NewCardCardAttributeRelation>>();
+
+        diesel::insert_into(relation::table)
+            .values(&new_relations)
+            .execute(self.connection.as_ref())?;
+        self.invalidate_card_renders(card_id)?;
+
+        Ok(card_id)
+    }
+
+    /// Writes a deck record, replacing its card list, and returns its id.
+    fn write_deck_record(&self, existing_id: Option<i32>, record: &DeckRecord) -> Result<i32> {
+        use crate::schema::decks;
+        use crate::schema::decks_cards_relation as relation;
+
+        let deck_id = match existing_id {
+            Some(deck_id) => {
+                diesel::update(decks::table.find(deck_id))
+                    .set(decks::decktype.eq(&record.decktype))
+                    .execute(self.connection.as_ref())?;
+                deck_id
+            }
+            None => {
+                diesel::insert_into(decks::table)
+                    .values(&NewDeck {
+                        id: None,
+                        name: &record.name,
+                        decktype: &record.decktype,
+                    })
+                    .execute(self.connection.as_ref())?;
+                diesel::select(last_insert_rowid).get_result::<i32>(self.connection.as_ref())?
+            }
+        };
+
+        diesel::delete(relation::table.filter(relation::deck_id.eq(deck_id)))
+            .execute(self.connection.as_ref())?;
+
+        let new_relations = record
+            .cards
+            .iter()
+            .enumerate()
+            .map(|(index, card)| NewDeckCardRelation {
+                deck_id,
+                card_id: card.card_id,
+                quantity: card.quantity,
+                position: index as i32,
+            })
+            .collect::<Vec<NewDeckCardRelation>>();
+
+        diesel::insert_into(relation::table)
+            .values(&new_relations)
+            .execute(self.connection.as_ref())?;
+
+        Ok(deck_id)
+    }
+}
+
+impl From<&CardRecord> for NewFullCardData {
+    fn from(record: &CardRecord) -> Self {
+        NewFullCardData {
+            cardclass: record.cardclass.clone(),
+            action: record.action.to_string(),
+            speed: record.speed.to_string(),
+            initiative: record.initiative,
+            name: record.name.clone(),
+            desc: record.desc.clone(),
+            image_url: record.image_url.clone(),
+            card_attributes: Some(record.card_attributes.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bulk::*;
+
+    fn record() -> CardRecord {
+        CardRecord {
+            id: Some(7),
+            cardclass: "Sp".to_owned(),
+            action: CardAction::Attack,
+            speed: CardSpeed::Fast,
+            initiative: 2,
+            name: "Fireball".to_owned(),
+            desc: "Range 3, \"hot\".".to_owned(),
+            image_url: None,
+            card_attributes: vec![1, 4],
+            art: None,
+        }
+    }
+
+    #[test]
+    fn given_card_records_when_round_tripped_then_unchanged() {
+        for format in &[BulkFormat::Json, BulkFormat::Csv, BulkFormat::Yaml] {
+            let output = serialize_card_records(*format, &[record()]).unwrap();
+            let parsed = parse_card_records(*format, &output).unwrap();
+            assert_eq!(parsed, vec![Ok(record())], "format {:?}", format);
+        }
+    }
+
+    #[test]
+    fn given_bad_csv_row_when_parsed_then_other_rows_still_parsed() {
+        let input = "id,cardclass,action,speed,initiative,name,desc,image_url,card_attributes\n\
+            ,Sp,Attack,Fast,2,Fireball,Hot,,1 2\n\
+            ,Sp,Attack,Fats,2,Icebolt,Cold,,\n";
+
+        let parsed = parse_card_records(BulkFormat::Csv, input).unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].as_ref().unwrap().card_attributes, vec![1, 2]);
+        assert!(parsed[1].is_err());
+    }
+
+    #[test]
+    fn given_csv_deck_rows_when_parsed_then_grouped_by_deck() {
+        let input = "deck,decktype,card_id,quantity\n\
+            starter,user,1,2\n\
+            starter,user,5,\n\
+            boss,,3,1\n";
+
+        let parsed = parse_deck_records(BulkFormat::Csv, input).unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        let starter = parsed[0].as_ref().unwrap();
+        assert_eq!(starter.cards.len(), 2);
+        assert_eq!(starter.cards[1].quantity, 1);
+        assert_eq!(parsed[1].as_ref().unwrap().decktype, "user");
+    }
+
+    #[test]
+    fn given_empty_deck_when_round_tripped_through_csv_then_kept() {
+        let decks = vec![
+            DeckRecord {
+                name: "empty".to_owned(),
+                decktype: "user".to_owned(),
+                cards: vec![],
+            },
+            DeckRecord {
+                name: "starter".to_owned(),
+                decktype: "user".to_owned(),
+                cards: vec![DeckRecordCard {
+                    card_id: 1,
+                    quantity: 2,
+                }],
+            },
+        ];
+
+        let output = serialize_deck_records(BulkFormat::Csv, &decks).unwrap();
+        let parsed = parse_deck_records(BulkFormat::Csv, &output).unwrap();
+
+        assert_eq!(parsed, decks.into_iter().map(Ok).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,234 @@
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::debug;
+use regex::Regex;
+
+use std::collections::HashMap;
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{CardClass, NewCardClass};
+
+lazy_static! {
+    static ref CARD_CLASS_CODE_REGEX: Regex = Regex::new(r"^[A-Za-z0-9]+$").unwrap();
+    static ref CARD_CLASS_COLOR_REGEX: Regex =
+        Regex::new(r"^#([0-9A-Fa-f]{3}|[0-9A-Fa-f]{6})$").unwrap();
+}
+
+/// Every known card class, keyed by its code.
+#[derive(Debug, Clone, Default)]
+pub struct CardClassRegistry {
+    classes: HashMap<String, CardClass>,
+}
+
+impl CardClassRegistry {
+    pub fn new(classes: Vec<CardClass>) -> Self {
+        Self {
+            classes: classes
+                .into_iter()
+                .map(|class| (class.code.clone(), class))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, code: &str) -> Option<&CardClass> {
+        self.classes.get(code)
+    }
+
+    /// The display name for a class code. Unknown codes are shown as-is so
+    /// that a card with a missing class can still be rendered.
+    pub fn display_name(&self, code: &str) -> String {
+        self.get(code)
+            .map(|class| class.name.clone())
+            .unwrap_or_else(|| code.to_owned())
+    }
+
+    /// Generates the `cardclass-background-color-*` rules used by the card
+    /// templates.
+    pub fn to_css(&self) -> String {
+        let mut classes = self.classes.values().collect::<Vec<&CardClass>>();
+        classes.sort_by_key(|class| (class.sort_order, class.code.clone()));
+
+        classes
+            .iter()
+            .map(|class| {
+                format!(
+                    ".cardclass-background-color-{} {{\n    background-color: {};\n}}\n",
+                    class.code, class.color
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Checks that a card class can be safely used in the registry. Codes end
+/// up in CSS class names and colors end up in CSS rules, so both are
+/// restricted.
+pub fn validate_card_class(card_class: &NewCardClass) -> std::result::Result<(), ClientError> {
+    if !CARD_CLASS_CODE_REGEX.is_match(&card_class.code) {
+        return Err(ClientError::InvalidInput(format!(
+            "Card class code '{}' must be alphanumeric",
+            card_class.code
+        )));
+    }
+
+    if card_class.name.trim().is_empty() {
+        return Err(ClientError::InvalidInput(
+            "Card class names cannot be empty".to_owned(),
+        ));
+    }
+
+    if !CARD_CLASS_COLOR_REGEX.is_match(&card_class.color) {
+        return Err(ClientError::InvalidInput(format!(
+            "Card class color '{}' must be a hex color such as #ffe5e5",
+            card_class.color
+        )));
+    }
+
+    Ok(())
+}
+
+impl DatabaseContext {
+    pub fn get_card_classes(&self) -> Result<Vec<CardClass>> {
+        use crate::schema::card_classes::dsl::*;
+
+        let results = card_classes
+            .order((sort_order.asc(), code.asc()))
+            .load(self.connection.as_ref())?;
+
+        Ok(results)
+    }
+
+    pub fn get_card_class_registry(&self) -> Result<CardClassRegistry> {
+        Ok(CardClassRegistry::new(self.get_card_classes()?))
+    }
+
+    pub fn get_card_class(&self, class_code: &str) -> Result<CardClass> {
+        use crate::schema::card_classes::dsl::*;
+
+        let result = card_classes
+            .filter(code.eq(class_code))
+            .first(self.connection.as_ref())?;
+
+        Ok(result)
+    }
+
+    pub fn create_card_class(&mut self, card_class: &NewCardClass) -> Result<CardClass> {
+        use crate::schema::card_classes;
+
+        debug!("create_card_class: {:?}", card_class);
+
+        validate_card_class(card_class)?;
+
+        if self.get_card_class(&card_class.code).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Card class '{}' already exists",
+                card_class.code
+            )))?
+        }
+
+        diesel::insert_into(card_classes::table)
+            .values(card_class)
+            .execute(self.connection.as_mut())?;
+
+        self.get_card_class(&card_class.code)
+    }
+
+    /// Updates a card class. Changing the code also moves every card of the
+    /// old class over to the new code.
+    pub fn update_card_class(
+        &mut self,
+        class_code: &str,
+        card_class: &NewCardClass,
+    ) -> Result<CardClass> {
+        use crate::schema::card_classes;
+        use crate::schema::cards;
+
+        debug!("update_card_class: {} {:?}", class_code, card_class);
+
+        validate_card_class(card_class)?;
+
+        let existing = self
+            .get_card_class(class_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if class_code != card_class.code && self.get_card_class(&card_class.code).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Card class '{}' already exists",
+                card_class.code
+            )))?
+        }
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::update(card_classes::table.find(existing.id))
+                .set(card_class)
+                .execute(self.connection.as_ref())?;
+
+            if class_code != card_class.code {
+                diesel::update(cards::table.filter(cards::cardclass.eq(class_code)))
+                    .set((
+                        cards::cardclass.eq(&card_class.code),
+                        cards::version.eq(cards::version + 1),
+                    ))
+                    .execute(self.connection.as_ref())?;
+            }
+
+            Ok(())
+        })?;
+
+        self.get_card_class(&card_class.code)
+    }
+
+    /// Deletes a card class. Classes still used by cards cannot be deleted.
+    pub fn delete_card_class(&mut self, class_code: &str) -> Result<()> {
+        use crate::schema::card_classes;
+        use crate::schema::cards;
+
+        debug!("delete_card_class: {}", class_code);
+
+        let existing = self
+            .get_card_class(class_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let cards_using_class: i64 = cards::table
+            .filter(cards::cardclass.eq(class_code))
+            .count()
+            .get_result(self.connection.as_ref())?;
+
+        if cards_using_class > 0 {
+            Err(ClientError::InvalidInput(format!(
+                "Card class '{}' is still used by {} card(s)",
+                class_code, cards_using_class
+            )))?
+        }
+
+        diesel::delete(card_classes::table.find(existing.id)).execute(self.connection.as_mut())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_classes::validate_card_class;
+    use crate::models::NewCardClass;
+
+    fn card_class(code: &str, color: &str) -> NewCardClass {
+        NewCardClass {
+            code: code.to_owned(),
+            name: "Spell".to_owned(),
+            color: color.to_owned(),
+            sort_order: 0,
+        }
+    }
+
+    #[test]
+    fn given_card_class_when_validate_then_code_and_color_checked() {
+        assert!(validate_card_class(&card_class("Sp", "#ffe5ff")).is_ok());
+        assert!(validate_card_class(&card_class("2H", "#abc")).is_ok());
+        assert!(validate_card_class(&card_class("S p", "#ffe5ff")).is_err());
+        assert!(validate_card_class(&card_class("Sp", "red; }")).is_err());
+    }
+}
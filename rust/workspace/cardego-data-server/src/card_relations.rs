@@ -0,0 +1,361 @@
+//! Typed relations between cards: one card upgrades to another, creates
+//! another as a token, or is simply related to it.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
+
+use anyhow::Result;
+use log::debug;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::card_values::CardRelationKind;
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{CardRelation, CardRelationEntry, NewCardRelation, NewCardRelationEntry};
+use crate::search::query::ast::{Expression, Literal, Predicate};
+
+/// Which end of a relation a search predicate names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelationDirection {
+    /// The card being searched for is the `card_id` of the relation.
+    Forward,
+    /// The card being searched for is the `related_card_id` of the relation.
+    Reverse,
+    Both,
+}
+
+/// The search predicates for relations, such as `upgrades_from:"Fireball"`,
+/// and the relation each one follows.
+const RELATION_PREDICATES: &[(&str, CardRelationKind, RelationDirection)] = &[
+    (
+        "upgrades_to",
+        CardRelationKind::UpgradesTo,
+        RelationDirection::Forward,
+    ),
+    (
+        "upgrades_from",
+        CardRelationKind::UpgradesTo,
+        RelationDirection::Reverse,
+    ),
+    (
+        "creates",
+        CardRelationKind::Creates,
+        RelationDirection::Forward,
+    ),
+    (
+        "created_by",
+        CardRelationKind::Creates,
+        RelationDirection::Reverse,
+    ),
+    (
+        "related",
+        CardRelationKind::Related,
+        RelationDirection::Both,
+    ),
+];
+
+pub fn relation_predicate_names() -> Vec<&'static str> {
+    RELATION_PREDICATES
+        .iter()
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+impl CardRelationKind {
+    /// How the relation reads on a rendered card.
+    pub fn label(&self) -> &'static str {
+        match self {
+            CardRelationKind::UpgradesTo => "Upgrades to",
+            CardRelationKind::Creates => "Creates",
+            CardRelationKind::Related => "See also",
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct CardIdRow {
+    #[sql_type = "Integer"]
+    card_id: i32,
+}
+
+/// Builds a query for the ids of cards at one end of a relation, where the
+/// card at the other end is exposed as `other_id` and `other_name`.
+fn relation_subquery(kind: CardRelationKind, direction: RelationDirection) -> String {
+    let select = |card_column: &str, other_column: &str| {
+        format!(
+            "SELECT card_relations.{} AS card_id, \
+                cards.id AS other_id, \
+                cards.name AS other_name \
+            FROM card_relations \
+                INNER JOIN cards ON cards.id = card_relations.{} \
+            WHERE card_relations.kind = '{}'",
+            card_column,
+            other_column,
+            kind.as_str()
+        )
+    };
+
+    match direction {
+        RelationDirection::Forward => select("card_id", "related_card_id"),
+        RelationDirection::Reverse => select("related_card_id", "card_id"),
+        RelationDirection::Both => format!(
+            "{} UNION ALL {}",
+            select("card_id", "related_card_id"),
+            select("related_card_id", "card_id")
+        ),
+    }
+}
+
+impl DatabaseContext {
+    /// Lists the relations from a card to other cards.
+    pub fn get_card_relations(&self, target_card_id: i32) -> Result<Vec<CardRelationEntry>> {
+        Ok(self
+            .get_card_relations_by_card_ids(vec![target_card_id])?
+            .remove(&target_card_id)
+            .unwrap_or_default())
+    }
+
+    pub fn get_card_relations_by_card_ids(
+        &self,
+        card_ids: Vec<i32>,
+    ) -> Result<HashMap<i32, Vec<CardRelationEntry>>> {
+        use crate::schema::card_relations;
+        use crate::schema::cards;
+
+        allow_tables_to_appear_in_same_query!(card_relations, cards);
+
+        let rows = card_relations::table
+            .inner_join(cards::table.on(cards::id.eq(card_relations::related_card_id)))
+            .filter(card_relations::card_id.eq_any(card_ids))
+            .order((card_relations::kind.asc(), cards::name.asc()))
+            .select((card_relations::all_columns, cards::name))
+            .load::<(CardRelation, String)>(self.connection.as_ref())?;
+
+        let mut results: HashMap<i32, Vec<CardRelationEntry>> = HashMap::new();
+        for (relation, related_card_name) in rows {
+            results
+                .entry(relation.card_id)
+                .or_default()
+                .push(CardRelationEntry {
+                    id: relation.id,
+                    kind: relation.kind,
+                    related_card_id: relation.related_card_id,
+                    related_card_name,
+                });
+        }
+
+        Ok(results)
+    }
+
+    pub fn create_card_relation(
+        &mut self,
+        target_card_id: i32,
+        entry: &NewCardRelationEntry,
+    ) -> Result<CardRelationEntry> {
+        use crate::schema::card_relations::dsl::*;
+
+        debug!("create_card_relation: {} {:?}", target_card_id, entry);
+
+        self.validate_card_relation(target_card_id, entry)?;
+
+        diesel::insert_into(card_relations)
+            .values(&NewCardRelation {
+                card_id: target_card_id,
+                related_card_id: entry.related_card_id,
+                kind: entry.kind,
+            })
+            .execute(self.connection.as_mut())?;
+        self.bump_card_version(target_card_id)?;
+
+        self.find_card_relation(target_card_id, entry)
+    }
+
+    pub fn update_card_relation(
+        &mut self,
+        target_card_id: i32,
+        relation_id: i32,
+        entry: &NewCardRelationEntry,
+    ) -> Result<CardRelationEntry> {
+        use crate::schema::card_relations::dsl::*;
+
+        debug!(
+            "update_card_relation: {} {} {:?}",
+            target_card_id, relation_id, entry
+        );
+
+        let existing = card_relations
+            .find(relation_id)
+            .filter(card_id.eq(target_card_id))
+            .first::<CardRelation>(self.connection.as_ref())
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if existing.kind != entry.kind || existing.related_card_id != entry.related_card_id {
+            self.validate_card_relation(target_card_id, entry)?;
+        }
+
+        diesel::update(card_relations.find(relation_id))
+            .set((
+                related_card_id.eq(entry.related_card_id),
+                kind.eq(entry.kind),
+            ))
+            .execute(self.connection.as_mut())?;
+        self.bump_card_version(target_card_id)?;
+
+        self.find_card_relation(target_card_id, entry)
+    }
+
+    pub fn delete_card_relation(&mut self, target_card_id: i32, relation_id: i32) -> Result<()> {
+        use crate::schema::card_relations::dsl::*;
+
+        debug!("delete_card_relation: {} {}", target_card_id, relation_id);
+
+        let deleted = diesel::delete(
+            card_relations
+                .find(relation_id)
+                .filter(card_id.eq(target_card_id)),
+        )
+        .execute(self.connection.as_mut())?;
+
+        if deleted == 0 {
+            Err(ClientError::ResourceNotFound)?
+        }
+
+        self.bump_card_version(target_card_id)
+    }
+
+    fn validate_card_relation(
+        &self,
+        target_card_id: i32,
+        entry: &NewCardRelationEntry,
+    ) -> Result<()> {
+        use crate::schema::card_relations::dsl::*;
+
+        self.get_card(target_card_id)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if target_card_id == entry.related_card_id {
+            Err(ClientError::InvalidInput(
+                "A card cannot be related to itself".to_owned(),
+            ))?
+        }
+
+        self.get_card(entry.related_card_id)
+            .or(Err(ClientError::InvalidInput(format!(
+                "Card {} does not exist",
+                entry.related_card_id
+            ))))?;
+
+        let duplicates: i64 = card_relations
+            .filter(card_id.eq(target_card_id))
+            .filter(related_card_id.eq(entry.related_card_id))
+            .filter(kind.eq(entry.kind))
+            .count()
+            .get_result(self.connection.as_ref())?;
+
+        if duplicates > 0 {
+            Err(ClientError::InvalidInput(format!(
+                "Card {} already {} card {}",
+                target_card_id,
+                entry.kind.label().to_lowercase(),
+                entry.related_card_id
+            )))?
+        }
+
+        Ok(())
+    }
+
+    fn find_card_relation(
+        &self,
+        target_card_id: i32,
+        entry: &NewCardRelationEntry,
+    ) -> Result<CardRelationEntry> {
+        self.get_card_relations(target_card_id)?
+            .into_iter()
+            .find(|relation| {
+                relation.kind == entry.kind && relation.related_card_id == entry.related_card_id
+            })
+            .ok_or_else(|| anyhow!("Card relation was not found after writing it"))
+    }
+
+    /// Finds the ids of cards matching the relation predicates of a search,
+    /// such as `upgrades_from:"Fireball"` or `creates=12`. String literals
+    /// match the other card's name and integers match its id. Returns `None`
+    /// if some part of the search has no relation predicates, so that no
+    /// filtering is done.
+    pub fn get_card_ids_by_card_relation_filter(
+        &self,
+        expr: &Expression,
+    ) -> Result<Option<HashSet<i32>>> {
+        if expr.0.is_empty() || expr.0.iter().any(|group| group.0.is_empty()) {
+            return Ok(None);
+        }
+
+        let mut results = HashSet::new();
+
+        for and_expr_group in &expr.0 {
+            let mut group_results: Option<HashSet<i32>> = None;
+
+            for predicate in &and_expr_group.0 {
+                let matches = self.get_card_ids_by_relation_predicate(predicate)?;
+                group_results = Some(match group_results {
+                    Some(ids) => ids.intersection(&matches).copied().collect(),
+                    None => matches,
+                });
+            }
+
+            results.extend(group_results.unwrap_or_default());
+        }
+
+        Ok(Some(results))
+    }
+
+    fn get_card_ids_by_relation_predicate(&self, predicate: &Predicate) -> Result<HashSet<i32>> {
+        let (_, relation_kind, direction) = RELATION_PREDICATES
+            .iter()
+            .find(|(name, _, _)| *name == predicate.name)
+            .ok_or_else(|| anyhow!("Unknown relation predicate `{}`", predicate.name))?;
+
+        let other_column = match predicate.literal {
+            Literal::Integer(_) => "other_id",
+            _ => "other_name",
+        };
+
+        let sql_query_string = format!(
+            "SELECT DISTINCT card_id FROM ({}) WHERE {}",
+            relation_subquery(*relation_kind, *direction),
+            Predicate {
+                name: other_column.to_owned(),
+                op: predicate.op,
+                literal: predicate.literal.clone(),
+            }
+            .to_sql_string()
+        );
+
+        debug!("card_relations query: {}", sql_query_string);
+
+        let results = sql_query(sql_query_string)
+            .load::<CardIdRow>(self.connection.as_ref())?
+            .into_iter()
+            .map(|row| row.card_id)
+            .collect();
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_relations::{relation_subquery, RelationDirection};
+    use crate::card_values::CardRelationKind;
+
+    #[test]
+    fn given_reverse_predicate_when_relation_subquery_then_ends_swapped() {
+        let query = relation_subquery(CardRelationKind::UpgradesTo, RelationDirection::Reverse);
+
+        assert!(query.starts_with("SELECT card_relations.related_card_id AS card_id"));
+        assert!(query.contains("ON cards.id = card_relations.card_id"));
+        assert!(query.contains("kind = 'upgrades_to'"));
+    }
+}
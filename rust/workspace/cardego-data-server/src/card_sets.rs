@@ -0,0 +1,322 @@
+extern crate chrono;
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
+
+use anyhow::Result;
+use log::debug;
+use regex::Regex;
+
+use std::collections::HashSet;
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{
+    Card, CardSet, CardSetCardRelation, CardSetEntry, NewCardSet, NewCardSetCardRelation,
+    NewCardSetEntry,
+};
+use crate::search::query::ast::Expression;
+
+lazy_static! {
+    static ref CARD_SET_CODE_REGEX: Regex = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+}
+
+#[derive(QueryableByName)]
+struct CardIdRow {
+    #[sql_type = "Integer"]
+    card_id: i32,
+}
+
+/// Checks that a card set can be stored. Codes are used in URLs and in
+/// `set=` search predicates, so they are restricted to a few characters.
+pub fn validate_card_set(card_set: &NewCardSet) -> std::result::Result<(), ClientError> {
+    if !CARD_SET_CODE_REGEX.is_match(&card_set.code) {
+        return Err(ClientError::InvalidInput(format!(
+            "Card set code '{}' may only contain letters, digits, '-' and '_'",
+            card_set.code
+        )));
+    }
+
+    if card_set.name.trim().is_empty() {
+        return Err(ClientError::InvalidInput(
+            "Card set names cannot be empty".to_owned(),
+        ));
+    }
+
+    if let Some(date) = &card_set.release_date {
+        if chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+            return Err(ClientError::InvalidInput(format!(
+                "Release date '{}' must be a date such as 2026-10-19",
+                date
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl DatabaseContext {
+    /// Lists every card set, oldest release first. Unreleased sets come last.
+    pub fn get_card_sets(&self) -> Result<Vec<CardSet>> {
+        use crate::schema::card_sets::dsl::*;
+
+        let results = card_sets
+            .order((release_date.is_null(), release_date.asc(), code.asc()))
+            .load(self.connection.as_ref())?;
+
+        Ok(results)
+    }
+
+    pub fn get_card_set(&self, set_code: &str) -> Result<CardSet> {
+        use crate::schema::card_sets::dsl::*;
+
+        let result = card_sets
+            .filter(code.eq(set_code))
+            .first(self.connection.as_ref())?;
+
+        Ok(result)
+    }
+
+    pub fn create_card_set(&mut self, card_set: &NewCardSet) -> Result<CardSet> {
+        use crate::schema::card_sets;
+
+        debug!("create_card_set: {:?}", card_set);
+
+        validate_card_set(card_set)?;
+
+        if self.get_card_set(&card_set.code).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Card set '{}' already exists",
+                card_set.code
+            )))?
+        }
+
+        diesel::insert_into(card_sets::table)
+            .values(card_set)
+            .execute(self.connection.as_mut())?;
+
+        self.get_card_set(&card_set.code)
+    }
+
+    pub fn update_card_set(&mut self, set_code: &str, card_set: &NewCardSet) -> Result<CardSet> {
+        use crate::schema::card_sets;
+
+        debug!("update_card_set: {} {:?}", set_code, card_set);
+
+        validate_card_set(card_set)?;
+
+        let existing = self
+            .get_card_set(set_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if set_code != card_set.code && self.get_card_set(&card_set.code).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Card set '{}' already exists",
+                card_set.code
+            )))?
+        }
+
+        diesel::update(card_sets::table.find(existing.id))
+            .set(card_set)
+            .execute(self.connection.as_mut())?;
+
+        self.get_card_set(&card_set.code)
+    }
+
+    /// Deletes a card set. The cards themselves are kept; they are only
+    /// removed from the set.
+    pub fn delete_card_set(&mut self, set_code: &str) -> Result<()> {
+        use crate::schema::card_sets;
+        use crate::schema::card_sets_cards_relation as relation;
+
+        debug!("delete_card_set: {}", set_code);
+
+        let existing = self
+            .get_card_set(set_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::delete(relation::table.filter(relation::card_set_id.eq(existing.id)))
+                .execute(self.connection.as_ref())?;
+            diesel::delete(card_sets::table.find(existing.id)).execute(self.connection.as_ref())?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Lists the cards in a set in collector-number order.
+    pub fn get_card_set_entries(&self, set_code: &str) -> Result<Vec<CardSetEntry>> {
+        use crate::schema::card_sets_cards_relation as relation;
+        use crate::schema::cards;
+
+        allow_tables_to_appear_in_same_query!(relation, cards);
+
+        let card_set = self.get_card_set(set_code)?;
+
+        let results = relation::table
+            .inner_join(cards::table.on(cards::id.eq(relation::card_id)))
+            .filter(relation::card_set_id.eq(card_set.id))
+            .order(relation::collector_number.asc())
+            .select((cards::all_columns, relation::collector_number))
+            .load::<(Card, i32)>(self.connection.as_ref())?
+            .into_iter()
+            .map(|(card, collector_number)| CardSetEntry {
+                card,
+                collector_number,
+            })
+            .collect();
+
+        Ok(results)
+    }
+
+    /// Adds a card to a set under a collector number that is not yet taken in
+    /// that set. Adding a card that is already in the set moves it to the
+    /// new number.
+    pub fn add_card_to_set(
+        &mut self,
+        set_code: &str,
+        entry: &NewCardSetEntry,
+    ) -> Result<Vec<CardSetEntry>> {
+        use crate::schema::card_sets_cards_relation::dsl::*;
+
+        debug!("add_card_to_set: {} {:?}", set_code, entry);
+
+        let card_set = self
+            .get_card_set(set_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        self.get_card(entry.card_id)
+            .or(Err(ClientError::InvalidInput(format!(
+                "Card {} does not exist",
+                entry.card_id
+            ))))?;
+
+        if entry.collector_number < 1 {
+            Err(ClientError::InvalidInput(
+                "Collector numbers start at 1".to_owned(),
+            ))?
+        }
+
+        let taken = card_sets_cards_relation
+            .filter(card_set_id.eq(card_set.id))
+            .filter(collector_number.eq(entry.collector_number))
+            .filter(card_id.ne(entry.card_id))
+            .first::<CardSetCardRelation>(self.connection.as_ref())
+            .optional()?;
+
+        if let Some(taken) = taken {
+            Err(ClientError::InvalidInput(format!(
+                "Collector number {} in '{}' is already used by card {}",
+                entry.collector_number, set_code, taken.card_id
+            )))?
+        }
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::delete(
+                card_sets_cards_relation
+                    .filter(card_set_id.eq(card_set.id))
+                    .filter(card_id.eq(entry.card_id)),
+            )
+            .execute(self.connection.as_ref())?;
+
+            diesel::insert_into(card_sets_cards_relation)
+                .values(&NewCardSetCardRelation {
+                    card_set_id: card_set.id,
+                    card_id: entry.card_id,
+                    collector_number: entry.collector_number,
+                })
+                .execute(self.connection.as_ref())?;
+
+            Ok(())
+        })?;
+
+        self.get_card_set_entries(set_code)
+    }
+
+    pub fn remove_card_from_set(
+        &mut self,
+        set_code: &str,
+        target_card_id: i32,
+    ) -> Result<Vec<CardSetEntry>> {
+        use crate::schema::card_sets_cards_relation::dsl::*;
+
+        debug!("remove_card_from_set: {} {}", set_code, target_card_id);
+
+        let card_set = self
+            .get_card_set(set_code)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let deleted = diesel::delete(
+            card_sets_cards_relation
+                .filter(card_set_id.eq(card_set.id))
+                .filter(card_id.eq(target_card_id)),
+        )
+        .execute(self.connection.as_mut())?;
+
+        if deleted == 0 {
+            Err(ClientError::ResourceNotFound)?
+        }
+
+        self.get_card_set_entries(set_code)
+    }
+
+    /// Finds the ids of cards matching the `set`, `set_name` and
+    /// `collector_number` predicates of a search. Returns `None` if the
+    /// search has no such predicates, so that no filtering is done.
+    pub fn get_card_ids_by_card_set_filter(
+        &self,
+        expr: &Expression,
+    ) -> Result<Option<HashSet<i32>>> {
+        if expr.is_empty() {
+            return Ok(None);
+        }
+
+        let sql_query_string = format!(
+            "SELECT card_id FROM (\
+                SELECT card_sets_cards_relation.card_id AS card_id, \
+                    card_sets.code AS [set], \
+                    card_sets.name AS set_name, \
+                    card_sets_cards_relation.collector_number AS collector_number \
+                FROM card_sets_cards_relation \
+                    INNER JOIN card_sets \
+                    ON card_sets.id = card_sets_cards_relation.card_set_id \
+            ) {}",
+            expr.to_sql_where_string()
+        );
+
+        debug!("card_sets query: {}", sql_query_string);
+
+        let results = sql_query(sql_query_string)
+            .load::<CardIdRow>(self.connection.as_ref())?
+            .into_iter()
+            .map(|row| row.card_id)
+            .collect();
+
+        Ok(Some(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_sets::validate_card_set;
+    use crate::models::NewCardSet;
+
+    fn card_set(code: &str, release_date: Option<&str>) -> NewCardSet {
+        NewCardSet {
+            code: code.to_owned(),
+            name: "Core Set 2".to_owned(),
+            release_date: release_date.map(|date| date.to_owned()),
+        }
+    }
+
+    #[test]
+    fn given_card_set_when_validate_then_code_and_release_date_checked() {
+        assert!(validate_card_set(&card_set("core2", Some("2026-10-19"))).is_ok());
+        assert!(validate_card_set(&card_set("promo-1", None)).is_ok());
+        assert!(validate_card_set(&card_set("core 2", None)).is_err());
+        assert!(validate_card_set(&card_set("core2", Some("19/10/2026"))).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! Card templates that are chosen and loaded at runtime.
+//!
+//! The built-in card template is compiled into the server. Themes replace
+//! it with a template and stylesheet read while the server runs, either
+//! from a directory under `static/templates/themes` or from the
+//! `card_themes` table, so editing a theme shows up on the next render.
+//! Themes are written for Tera, which reads much like the built-in
+//! templates. A theme can be asked for by name, or be tied to card classes
+//! and used for their cards by default. Every render of a card, on its own,
+//! in a cardsheet, in print or in a render job, is drawn from its theme.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::image::templates::SingleCardTemplate;
+use crate::models::{Card, CardTheme, NewCardTheme};
+
+pub const THEMES_DIRECTORY: &str = "static/templates/themes";
+
+/// The name that asks for the built-in template, even for cards of a class
+/// that has a theme.
+pub const DEFAULT_THEME: &str = "default";
+
+const TEMPLATE_FILE: &str = "card.html";
+const CSS_FILE: &str = "card.css";
+/// Lists the card classes a theme on disk is used for.
+const MANIFEST_FILE: &str = "theme.yml";
+
+lazy_static! {
+    static ref THEME_NAME_REGEX: Regex = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeSource {
+    Disk,
+    Database,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Theme {
+    pub name: String,
+    pub cardclasses: Vec<String>,
+    pub template: String,
+    pub css: String,
+    pub source: ThemeSource,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ThemeManifest {
+    #[serde(default)]
+    cardclasses: Vec<String>,
+}
+
+fn compile_template(template: &str) -> Result<Tera, tera::Error> {
+    let mut tera = Tera::default();
+    tera.add_raw_template(TEMPLATE_FILE, template)?;
+    Ok(tera)
+}
+
+impl Theme {
+    /// Renders a card into a page of HTML. The template sees the card as
+    /// `card`, with the same fields as the built-in template, and the
+    /// theme's stylesheet and the card class colors as `css`.
+    pub fn render(&self, card: &SingleCardTemplate) -> Result<String> {
+        let mut context = Context::new();
+        context.insert("card", card);
+        context.insert("css", &format!("{}\n{}", self.css, card.cardclass_css));
+
+        Ok(compile_template(&self.template)?.render(TEMPLATE_FILE, &context)?)
+    }
+}
+
+impl From<CardTheme> for Theme {
+    fn from(theme: CardTheme) -> Self {
+        Self {
+            name: theme.name,
+            cardclasses: theme.cardclass.into_iter().collect(),
+            template: theme.template,
+            css: theme.css,
+            source: ThemeSource::Database,
+        }
+    }
+}
+
+fn load_disk_theme(directory: &Path) -> Result<Theme> {
+    let name = directory
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{} is not a theme name", directory.display()))?;
+
+    let manifest_path = directory.join(MANIFEST_FILE);
+    let manifest = if manifest_path.exists() {
+        serde_yaml::from_str(&std::fs::read_to_string(manifest_path)?)?
+    } else {
+        ThemeManifest::default()
+    };
+
+    Ok(Theme {
+        name: name.to_string(),
+        cardclasses: manifest.cardclasses,
+        template: std::fs::read_to_string(directory.join(TEMPLATE_FILE))?,
+        css: std::fs::read_to_string(directory.join(CSS_FILE)).unwrap_or_default(),
+        source: ThemeSource::Disk,
+    })
+}
+
+/// Reads every theme in `directory`. Themes that cannot be read are left
+/// out, so that one broken theme does not break every render.
+pub fn load_disk_themes(directory: &Path) -> Vec<Theme> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| match load_disk_theme(&entry.path()) {
+            Ok(theme) => Some(theme),
+            Err(err) => {
+                warn!("Could not load the theme at {:?}: {}", entry.path(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Every known theme, keyed by its name.
+#[derive(Debug, Clone, Default)]
+pub struct ThemeRegistry {
+    themes: BTreeMap<String, Theme>,
+}
+
+impl ThemeRegistry {
+    /// Themes later in `themes` replace earlier ones of the same name.
+    pub fn new(themes: Vec<Theme>) -> Self {
+        Self {
+            themes: themes
+                .into_iter()
+                .map(|theme| (theme.name.clone(), theme))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Theme> {
+        self.themes.get(name)
+    }
+
+    pub fn themes(&self) -> Vec<&Theme> {
+        self.themes.values().collect()
+    }
+
+    /// The theme to render a card of `cardclass` with: the one `requested`,
+    /// or else the one of its class. `None` means the built-in template.
+    pub fn select(
+        &self,
+        requested: Option<&str>,
+        cardclass: &str,
+    ) -> std::result::Result<Option<&Theme>, ClientError> {
+        match requested {
+            Some(DEFAULT_THEME) => Ok(None),
+            Some(name) => self
+                .get(name)
+                .map(Some)
+                .ok_or_else(|| ClientError::InvalidInput(format!("Unknown theme `{}`", name))),
+            None => Ok(self
+                .themes
+                .values()
+                .filter(|theme| theme.cardclasses.iter().any(|class| class == cardclass))
+                // Themes in the database win over those on disk.
+                .max_by_key(|theme| theme.source == ThemeSource::Database)),
+        }
+    }
+}
+
+/// The themes the cards of one render are drawn from: the one asked for,
+/// or else the theme of each card's class.
+#[derive(Debug, Clone, Default)]
+pub struct CardThemes {
+    registry: ThemeRegistry,
+    requested: Option<String>,
+}
+
+impl CardThemes {
+    /// Fails when `requested` names a theme that does not exist.
+    pub fn new(
+        registry: ThemeRegistry,
+        requested: Option<&str>,
+    ) -> std::result::Result<Self, ClientError> {
+        if requested.is_some() {
+            registry.select(requested, "")?;
+        }
+
+        Ok(Self {
+            registry,
+            requested: requested.map(str::to_owned),
+        })
+    }
+
+    /// The theme `card` is drawn from, or `None` for the built-in template.
+    pub fn theme_of(&self, card: &Card) -> Option<&Theme> {
+        self.registry
+            .select(self.requested.as_deref(), &card.cardclass)
+            .ok()
+            .flatten()
+    }
+}
+
+/// Checks that a theme can be stored: that its name can be asked for and
+/// that its template compiles.
+pub fn validate_card_theme(theme: &NewCardTheme) -> std::result::Result<(), ClientError> {
+    if !THEME_NAME_REGEX.is_match(&theme.name) || theme.name == DEFAULT_THEME {
+        return Err(ClientError::InvalidInput(format!(
+            "Theme name '{}' must be letters, digits, '-' and '_', other than '{}'",
+            theme.name, DEFAULT_THEME
+        )));
+    }
+
+    compile_template(&theme.template).map_err(|err| {
+        ClientError::InvalidInput(format!(
+            "Theme template does not compile: {}",
+            std::error::Error::source(&err)
+                .map(|source| source.to_string())
+                .unwrap_or_else(|| err.to_string())
+        ))
+    })?;
+
+    Ok(())
+}
+
+impl DatabaseContext {
+    pub fn get_card_themes(&self) -> Result<Vec<CardTheme>> {
+        use crate::schema::card_themes::dsl::*;
+
+        Ok(card_themes
+            .order(name.asc())
+            .load(self.connection.as_ref())?)
+    }
+
+    /// The themes on disk and in the database, read afresh so that changes
+    /// to either show up straight away.
+    pub fn get_card_theme_registry(&self) -> Result<ThemeRegistry> {
+        let mut themes = load_disk_themes(Path::new(THEMES_DIRECTORY));
+        themes.extend(self.get_card_themes()?.into_iter().map(Theme::from));
+
+        Ok(ThemeRegistry::new(themes))
+    }
+
+    /// The themes to render cards with, when `requested` is asked for.
+    pub fn select_card_themes(&self, requested: Option<&str>) -> Result<CardThemes> {
+        Ok(CardThemes::new(self.get_card_theme_registry()?, requested)?)
+    }
+
+    pub fn get_card_theme(&self, theme_name: &str) -> Result<CardTheme> {
+        use crate::schema::card_themes::dsl::*;
+
+        Ok(card_themes
+            .filter(name.eq(theme_name))
+            .first(self.connection.as_ref())?)
+    }
+
+    pub fn create_card_theme(&mut self, theme: &NewCardTheme) -> Result<CardTheme> {
+        use crate::schema::card_themes;
+
+        debug!("create_card_theme: {:?}", theme.name);
+
+        validate_card_theme(theme)?;
+
+        if self.get_card_theme(&theme.name).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Theme '{}' already exists",
+                theme.name
+            )))?
+        }
+
+        diesel::insert_into(card_themes::table)
+            .values(theme)
+            .execute(self.connection.as_mut())?;
+
+        self.get_card_theme(&theme.name)
+    }
+
+    pub fn update_card_theme(
+        &mut self,
+        theme_name: &str,
+        theme: &NewCardTheme,
+    ) -> Result<CardTheme> {
+        use crate::schema::card_themes;
+
+        debug!("update_card_theme: {} {:?}", theme_name, theme.name);
+
+        validate_card_theme(theme)?;
+
+        let existing = self
+            .get_card_theme(theme_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if theme_name != theme.name && self.get_card_theme(&theme.name).is_ok() {
+            Err(ClientError::InvalidInput(format!(
+                "Theme '{}' already exists",
+                theme.name
+            )))?
+        }
+
+        diesel::update(card_themes::table.find(existing.id))
+            .set(theme)
+            .execute(self.connection.as_mut())?;
+
+        self.get_card_theme(&theme.name)
+    }
+
+    pub fn delete_card_theme(&mut self, theme_name: &str) -> Result<()> {
+        use crate::schema::card_themes;
+
+        debug!("delete_card_theme: {}", theme_name);
+
+        let existing = self
+            .get_card_theme(theme_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        diesel::delete(card_themes::table.find(existing.id)).execute(self.connection.as_mut())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_themes::{Theme, ThemeRegistry, ThemeSource};
+    use crate::image::templates::SingleCardTemplate;
+
+    fn theme(name: &str, cardclass: &str, source: ThemeSource) -> Theme {
+        Theme {
+            name: name.to_owned(),
+            cardclasses: vec![cardclass.to_owned()],
+            template: "<style>{{ css | safe }}</style><h1>{{ card.name }}</h1>".to_owned(),
+            css: format!(".{} {{}}", name),
+            source,
+        }
+    }
+
+    #[test]
+    fn given_themes_when_select_then_requested_or_class_theme_used() {
+        let registry = ThemeRegistry::new(vec![
+            theme("frost", "ATK", ThemeSource::Disk),
+            theme("ember", "ATK", ThemeSource::Database),
+            theme("stone", "DEF", ThemeSource::Disk),
+        ]);
+
+        let select = |requested: Option<&str>, cardclass: &str| {
+            registry
+                .select(requested, cardclass)
+                .unwrap()
+                .map(|theme| theme.name.as_str())
+        };
+
+        assert_eq!(select(None, "ATK"), Some("ember"));
+        assert_eq!(select(Some("frost"), "ATK"), Some("frost"));
+        assert_eq!(select(Some("default"), "DEF"), None);
+        assert_eq!(select(None, "SPL"), None);
+        assert!(registry.select(Some("missing"), "ATK").is_err());
+
+        let html = registry
+            .get("stone")
+            .unwrap()
+            .render(&SingleCardTemplate {
+                name: "<Wall>".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(html.contains(".stone {}"));
+        assert!(html.contains("&lt;Wall&gt;"));
+    }
+}
@@ -0,0 +1,329 @@
+//! Enumerated values for the `speed` and `action` fields of a card, and for
+//! the kinds of relation between cards.
+//!
+//! Both are stored as text in SQLite. Parsing is case-insensitive and
+//! ignores surrounding whitespace, but values are always written back out in
+//! their canonical spelling. Unknown values are refused by the database
+//! itself, so that reading a card never fails on a value edited in by hand.
+
+extern crate juniper;
+
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
+
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::database::DatabaseContext;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("Unknown card {field} '{value}'; expected one of {expected}")]
+pub struct UnknownCardValue {
+    pub field: &'static str,
+    pub value: String,
+    pub expected: String,
+}
+
+macro_rules! card_value_enum {
+    ($name:ident, $field:expr, { $($variant:ident => $text:expr),+ $(,)? }) => {
+        #[derive(
+            Debug,
+            Clone,
+            Copy,
+            PartialEq,
+            Eq,
+            Hash,
+            AsExpression,
+            FromSqlRow,
+            juniper::GraphQLEnum,
+        )]
+        #[sql_type = "Text"]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant),+];
+
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text),+
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = UnknownCardValue;
+
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                let trimmed = s.trim();
+                $name::ALL
+                    .iter()
+                    .find(|value| value.as_str().eq_ignore_ascii_case(trimmed))
+                    .copied()
+                    .ok_or_else(|| UnknownCardValue {
+                        field: $field,
+                        value: s.to_owned(),
+                        expected: $name::ALL
+                            .iter()
+                            .map(|value| value.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", "),
+                    })
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+
+        impl ToSql<Text, Sqlite> for $name {
+            fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+                <str as ToSql<Text, Sqlite>>::to_sql(self.as_str(), out)
+            }
+        }
+
+        impl FromSql<Text, Sqlite> for $name {
+            fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+                let s = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+                Ok(s.parse::<$name>()?)
+            }
+        }
+    };
+}
+
+card_value_enum!(CardSpeed, "speed", {
+    Fast => "Fast",
+    Normal => "Normal",
+    Slow => "Slow",
+});
+
+card_value_enum!(CardAction, "action", {
+    Attack => "Attack",
+    Defend => "Defend",
+    Move => "Move",
+    Utility => "Utility",
+    Passive => "Passive",
+});
+
+card_value_enum!(CardRelationKind, "relation kind", {
+    UpgradesTo => "upgrades_to",
+    Creates => "creates",
+    Related => "related",
+});
+
+/// A card whose stored `speed` or `action` is not one of the known values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardValueAuditEntry {
+    pub card_id: i32,
+    pub card_name: String,
+    pub field: String,
+    pub value: String,
+    pub suggestion: Option<String>,
+    /// The placeholder the card was given in place of `value`, when the
+    /// value was flagged by the migration that enforces known values.
+    pub replaced_with: Option<String>,
+}
+
+/// Suggests the closest known value for a misspelled one, if any value is
+/// within two edits of it.
+pub fn suggest_card_value(value: &str, known: &[&'static str]) -> Option<String> {
+    let value = value.trim().to_lowercase();
+
+    known
+        .iter()
+        .map(|candidate| (edit_distance(&value, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+impl DatabaseContext {
+    /// Reports every card whose speed or action is not a known value. Values
+    /// that were flagged and replaced with a placeholder when known values
+    /// began to be enforced are reported along with the placeholder; the
+    /// columns themselves are read as raw text so that any other bad rows can
+    /// still be listed.
+    pub fn audit_card_values(&self) -> Result<Vec<CardValueAuditEntry>> {
+        use crate::schema::card_value_flags::dsl as flags;
+        use crate::schema::cards::dsl::*;
+
+        let rows = cards
+            .select((id, name, speed, action))
+            .order(id.asc())
+            .load::<(i32, String, String, String)>(self.connection.as_ref())?;
+
+        let flagged = flags::card_value_flags
+            .select((flags::card_id, flags::field, flags::value))
+            .load::<(i32, String, String)>(self.connection.as_ref())?;
+
+        let speed_values = CardSpeed::ALL
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>();
+        let action_values = CardAction::ALL
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>();
+
+        let mut results = Vec::new();
+
+        for (card_id, card_name, card_speed, card_action) in rows {
+            let flagged_value = |target_field: &str| {
+                flagged
+                    .iter()
+                    .find(|(flag_card_id, flag_field, _)| {
+                        *flag_card_id == card_id && flag_field == target_field
+                    })
+                    .map(|(_, _, flag_value)| flag_value.clone())
+            };
+
+            let speed_is_known = card_speed.parse::<CardSpeed>().is_ok();
+            results.extend(audit_card_value(
+                (card_id, &card_name),
+                "speed",
+                card_speed,
+                speed_is_known,
+                flagged_value("speed"),
+                &speed_values,
+            ));
+
+            let action_is_known = card_action.parse::<CardAction>().is_ok();
+            results.extend(audit_card_value(
+                (card_id, &card_name),
+                "action",
+                card_action,
+                action_is_known,
+                flagged_value("action"),
+                &action_values,
+            ));
+        }
+
+        Ok(results)
+    }
+}
+
+/// The audit entry for one field of a card, if its value is flagged or is
+/// not a known value.
+fn audit_card_value(
+    (card_id, card_name): (i32, &str),
+    field: &str,
+    current: String,
+    is_known: bool,
+    flagged_value: Option<String>,
+    known_values: &[&'static str],
+) -> Option<CardValueAuditEntry> {
+    let (value, replaced_with) = match flagged_value {
+        Some(value) => (value, Some(current)),
+        None if !is_known => (current, None),
+        None => return None,
+    };
+
+    Some(CardValueAuditEntry {
+        card_id,
+        card_name: card_name.to_owned(),
+        field: field.to_owned(),
+        suggestion: suggest_card_value(&value, known_values),
+        value,
+        replaced_with,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_values::{audit_card_value, suggest_card_value, CardAction, CardSpeed};
+
+    #[test]
+    fn given_any_casing_when_parse_then_canonical_value() {
+        assert_eq!("Fast".parse::<CardSpeed>(), Ok(CardSpeed::Fast));
+        assert_eq!(" fast ".parse::<CardSpeed>(), Ok(CardSpeed::Fast));
+        assert_eq!("ATTACK".parse::<CardAction>(), Ok(CardAction::Attack));
+        assert_eq!(CardSpeed::Fast.to_string(), "Fast");
+    }
+
+    #[test]
+    fn given_misspelled_value_when_parse_then_error_with_suggestion() {
+        assert!("Fats".parse::<CardSpeed>().is_err());
+        assert_eq!(
+            suggest_card_value("Fats", &["Fast", "Normal", "Slow"]),
+            Some("Fast".to_owned())
+        );
+        assert_eq!(
+            suggest_card_value("Teleport", &["Fast", "Normal", "Slow"]),
+            None
+        );
+    }
+
+    #[test]
+    fn given_flagged_value_when_audited_then_reported_with_placeholder() {
+        let speeds = ["Fast", "Normal", "Slow"];
+
+        let flagged = audit_card_value(
+            (2, "Lunge"),
+            "speed",
+            "Normal".to_owned(),
+            true,
+            Some("Fats".to_owned()),
+            &speeds,
+        )
+        .unwrap();
+        assert_eq!(flagged.value, "Fats");
+        assert_eq!(flagged.replaced_with, Some("Normal".to_owned()));
+        assert_eq!(flagged.suggestion, Some("Fast".to_owned()));
+
+        let unknown = audit_card_value(
+            (3, "Parry"),
+            "speed",
+            "Fats".to_owned(),
+            false,
+            None,
+            &speeds,
+        )
+        .unwrap();
+        assert_eq!(unknown.replaced_with, None);
+
+        assert!(
+            audit_card_value((4, "Step"), "speed", "Slow".to_owned(), true, None, &speeds)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Card versions, for optimistic concurrency.
+//!
+//! Every change to a card bumps its `version`, including changes stored in
+//! other tables such as its translations and relations. Readers are handed
+//! the version as an ETag, and writers send it back in `If-Match` so that an
+//! update made on top of a stale copy of the card is refused instead of
+//! silently overwriting someone else's edit.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+
+/// The strong ETag of a card at `version`. Translated cards are a different
+/// representation, so the locale is part of the tag.
+pub fn card_etag(card_id: i32, version: i32, lang: Option<&str>) -> String {
+    match lang {
+        Some(lang) => format!("\"{}-{}-{}\"", card_id, version, lang),
+        None => format!("\"{}-{}\"", card_id, version),
+    }
+}
+
+/// What a write requires of the card it replaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardPrecondition {
+    /// The card is written whatever its version, and created if missing.
+    Unconditional,
+    /// The card must already exist, at any version.
+    Exists,
+    /// The card must still be at this version.
+    Version(i32),
+}
+
+impl From<Option<i32>> for CardPrecondition {
+    fn from(expected_version: Option<i32>) -> Self {
+        expected_version.map_or(CardPrecondition::Unconditional, CardPrecondition::Version)
+    }
+}
+
+/// Reads the card id and version out of an ETag made by `card_etag`.
+fn parse_card_etag(tag: &str) -> Option<(i32, i32)> {
+    if tag.len() < 2 || !tag.starts_with('"') || !tag.ends_with('"') {
+        return None;
+    }
+
+    let mut parts = tag[1..tag.len() - 1].splitn(3, '-');
+    let card_id = parts.next()?.parse::<i32>().ok()?;
+    let version = parts.next()?.parse::<i32>().ok()?;
+
+    Some((card_id, version))
+}
+
+/// Reads what an `If-Match` header requires of a card. `*` matches any
+/// version of the card, but only if it exists. Weak tags, tags for other
+/// cards and anything that is not a card ETag can never match, so a header
+/// made only of those fails the precondition.
+pub fn parse_if_match(
+    header: &str,
+    card_id: i32,
+) -> std::result::Result<CardPrecondition, ClientError> {
+    let tags = header.split(',').map(str::trim).collect::<Vec<&str>>();
+
+    if tags.contains(&"*") {
+        return Ok(CardPrecondition::Exists);
+    }
+
+    tags.iter()
+        .filter_map(|tag| parse_card_etag(tag))
+        .find(|(tag_card_id, _)| *tag_card_id == card_id)
+        .map(|(_, version)| CardPrecondition::Version(version))
+        .ok_or_else(|| {
+            ClientError::PreconditionFailed(format!(
+                "If-Match '{}' does not name a version of card {}",
+                header, card_id
+            ))
+        })
+}
+
+/// Whether an `If-None-Match` header names `etag`. ETags are compared
+/// weakly, as is usual for `If-None-Match`.
+pub fn if_none_match_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl DatabaseContext {
+    /// Bumps the version of a card after a change to data about it that is
+    /// stored outside of the `cards` table, and drops its rendered images.
+    pub fn bump_card_version(&self, target_card_id: i32) -> Result<()> {
+        use crate::schema::cards::dsl::*;
+
+        diesel::update(cards.find(target_card_id))
+            .set(version.eq(version + 1))
+            .execute(self.connection.as_ref())?;
+
+        self.invalidate_card_renders(target_card_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_versions::{
+        card_etag, if_none_match_matches, parse_if_match, CardPrecondition,
+    };
+
+    #[test]
+    fn given_card_etag_when_parse_if_match_then_version_read_back() {
+        assert_eq!(
+            parse_if_match(&card_etag(12, 3, None), 12).unwrap(),
+            CardPrecondition::Version(3)
+        );
+        assert_eq!(
+            parse_if_match(&card_etag(12, 3, Some("es-MX")), 12).unwrap(),
+            CardPrecondition::Version(3)
+        );
+        assert_eq!(parse_if_match("*", 12).unwrap(), CardPrecondition::Exists);
+        assert!(parse_if_match(&card_etag(13, 3, None), 12).is_err());
+        assert!(parse_if_match("W/\"12-3\"", 12).is_err());
+        assert!(parse_if_match("garbage", 12).is_err());
+    }
+
+    #[test]
+    fn given_weak_tag_when_if_none_match_then_compared_weakly() {
+        assert!(if_none_match_matches("W/\"12-3\", \"12-4\"", "\"12-3\""));
+        assert!(!if_none_match_matches("\"12-4\"", "\"12-3\""));
+    }
+}
@@ -0,0 +1,206 @@
+//! Copies of cards and decks, as starting points for variants.
+//!
+//! A card is copied along with its attributes, and a deck along with its
+//! card list. Either copy remembers the original in `cloned_from_id`.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::debug;
+
+use std::collections::HashSet;
+
+use crate::check_deck_name;
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{
+    CardCloneOverrides, Deck, DeckCardRelation, DeckCloneOverrides, FullCardData, NewDeck,
+    NewDeckCardRelation, NewFullCardData,
+};
+
+/// Names the copy of `name` so that it does not collide with any of the
+/// `taken` names, which are compared without case.
+pub fn copy_name(name: &str, taken: &HashSet<String>) -> String {
+    let is_free = |candidate: &str| !taken.contains(&candidate.to_lowercase());
+
+    let candidate = format!("{} (copy)", name);
+    if is_free(&candidate) {
+        return candidate;
+    }
+
+    (2..)
+        .map(|n| format!("{} (copy {})", name, n))
+        .find(|candidate| is_free(candidate))
+        .unwrap()
+}
+
+impl DatabaseContext {
+    /// Copies a card and its attributes into a new card, with any overrides
+    /// applied. Without a new name, the copy is named after the original.
+    pub fn clone_card(
+        &mut self,
+        source_id: i32,
+        overrides: &CardCloneOverrides,
+    ) -> Result<FullCardData> {
+        use crate::schema::cards::dsl::*;
+
+        debug!("clone_card: {} {:?}", source_id, overrides);
+
+        let source = self
+            .get_full_card_data(source_id)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let taken = cards
+            .select(name)
+            .load::<String>(self.connection.as_ref())?
+            .into_iter()
+            .map(|taken_name| taken_name.to_lowercase())
+            .collect::<HashSet<String>>();
+
+        // Cards are found again by name after they are created, so the copy
+        // needs a name of its own.
+        let new_name = match &overrides.name {
+            Some(new_name) if taken.contains(&new_name.to_lowercase()) => Err(
+                ClientError::InvalidInput(format!("A card named '{}' already exists", new_name)),
+            )?,
+            Some(new_name) => new_name.clone(),
+            None => copy_name(&source.name, &taken),
+        };
+
+        let card_data = NewFullCardData {
+            cardclass: overrides
+                .cardclass
+                .clone()
+                .unwrap_or_else(|| source.cardclass.clone()),
+            action: overrides.action.unwrap_or(source.action).to_string(),
+            speed: overrides.speed.unwrap_or(source.speed).to_string(),
+            initiative: overrides.initiative.unwrap_or(source.initiative),
+            name: new_name,
+            desc: overrides
+                .desc
+                .clone()
+                .unwrap_or_else(|| source.desc.clone()),
+            image_url: overrides
+                .image_url
+                .clone()
+                .or_else(|| source.image_url.clone()),
+            card_attributes: overrides.card_attributes.clone().or_else(|| {
+                source
+                    .attributes
+                    .as_ref()
+                    .map(|attributes| attributes.iter().map(|attr| attr.id).collect())
+            }),
+        };
+
+        let new_card_id = self.connection.transaction::<_, anyhow::Error, _>(|| {
+            let new_card = self.create_card(&card_data)?;
+
+            diesel::update(cards.find(new_card.id))
+                .set(cloned_from_id.eq(source_id))
+                .execute(self.connection.as_ref())?;
+
+            Ok(new_card.id)
+        })?;
+
+        debug!("Cloned card {} into card {}", source_id, new_card_id);
+
+        self.get_full_card_data(new_card_id)
+    }
+
+    /// Copies a deck and its card list into a new deck, with any overrides
+    /// applied. Without a new name, the copy is named after the original.
+    pub fn clone_deck(
+        &mut self,
+        source_name: &str,
+        overrides: &DeckCloneOverrides,
+    ) -> Result<Deck> {
+        use crate::schema::decks;
+        use crate::schema::decks_cards_relation;
+
+        debug!("clone_deck: {} {:?}", source_name, overrides);
+
+        let source = self
+            .get_deck_by_name(source_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let taken = decks::table
+            .select(decks::name)
+            .load::<String>(self.connection.as_ref())?
+            .into_iter()
+            .map(|taken_name| taken_name.to_lowercase())
+            .collect::<HashSet<String>>();
+
+        let new_name = match &overrides.name {
+            Some(new_name) if taken.contains(&new_name.to_lowercase()) => Err(
+                ClientError::InvalidInput(format!("A deck named '{}' already exists", new_name)),
+            )?,
+            Some(new_name) => new_name.clone(),
+            None => copy_name(&source.name, &taken),
+        };
+        // Copies of decks named before names were checked are checked too.
+        check_deck_name(&new_name)?;
+
+        let new_decktype = overrides
+            .decktype
+            .clone()
+            .unwrap_or_else(|| source.decktype.clone());
+
+        let entries = decks_cards_relation::table
+            .filter(decks_cards_relation::deck_id.eq(source.id))
+            .order(decks_cards_relation::position.asc())
+            .load::<DeckCardRelation>(self.connection.as_ref())?;
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::insert_into(decks::table)
+                .values(&NewDeck {
+                    id: None,
+                    name: &new_name,
+                    decktype: &new_decktype,
+                })
+                .execute(self.connection.as_ref())?;
+
+            let new_deck = self.get_deck_by_name(&new_name)?;
+
+            diesel::update(decks::table.find(new_deck.id))
+                .set(decks::cloned_from_id.eq(source.id))
+                .execute(self.connection.as_ref())?;
+
+            let new_entries = entries
+                .iter()
+                .map(|entry| NewDeckCardRelation {
+                    deck_id: new_deck.id,
+                    card_id: entry.card_id,
+                    quantity: entry.quantity,
+                    position: entry.position,
+                })
+                .collect::<Vec<NewDeckCardRelation>>();
+
+            diesel::insert_into(decks_cards_relation::table)
+                .values(&new_entries)
+                .execute(self.connection.as_ref())?;
+
+            Ok(())
+        })?;
+
+        debug!("Cloned deck '{}' into '{}'", source_name, new_name);
+
+        self.get_deck_by_name(&new_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloning::copy_name;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn given_taken_copy_names_when_copy_name_then_next_free_number_used() {
+        let mut taken = HashSet::new();
+        assert_eq!(copy_name("Fireball", &taken), "Fireball (copy)");
+
+        taken.insert("fireball (copy)".to_owned());
+        taken.insert("fireball (copy 2)".to_owned());
+        assert_eq!(copy_name("Fireball", &taken), "Fireball (copy 3)");
+    }
+}
@@ -3,11 +3,20 @@ extern crate diesel;
 use crate::diesel::Connection;
 use diesel::prelude::SqliteConnection;
 
+use crate::image::art::ArtStore;
+use crate::image::cache::RenderCache;
+
 // NOTE: do not use r2d2 with SQLite + Diesel because SQLite's lack of
 // support for batched inserts is currently causing compilation errors. Just
 // don't use connection pooling until we swap to MySQL or PostgreSQL.
 pub struct DatabaseContext {
     pub connection: Box<SqliteConnection>,
+    pub database_url: String,
+    /// The cache of the images rendered from this database, whose images are
+    /// dropped when the cards they show are written.
+    pub render_cache: Option<RenderCache>,
+    /// The art the cards of this database point at.
+    pub art_store: Option<ArtStore>,
 }
 
 impl DatabaseContext {
@@ -16,6 +25,19 @@ impl DatabaseContext {
 
         Ok(Self {
             connection: Box::new(connection),
+            database_url: url_endpoint.to_owned(),
+            render_cache: None,
+            art_store: None,
         })
     }
+
+    pub fn with_render_cache(mut self, render_cache: RenderCache) -> Self {
+        self.render_cache = Some(render_cache);
+        self
+    }
+
+    pub fn with_art_store(mut self, art_store: ArtStore) -> Self {
+        self.art_store = Some(art_store);
+        self
+    }
 }
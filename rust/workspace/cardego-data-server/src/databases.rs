@@ -0,0 +1,149 @@
+//! The game databases served by one server.
+//!
+//! There is always a `default` database, served at the root of the API. Any
+//! other databases, such as one per homebrew campaign, are listed in
+//! `config/databases.yml` and served under `/db/{name}`:
+//!
+//! ```yaml
+//! frostlands:
+//!   database_endpoint: runtime/data/databases/frostlands.db
+//! ```
+//!
+//! Each database renders its images into, and snapshots itself into, its own
+//! directories, so campaigns never overwrite each other's files. An entry
+//! named `default` changes the default database instead of adding one.
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const DEFAULT_DATABASE_NAME: &str = "default";
+pub const DATABASES_CONFIG_PATH: &str = "config/databases.yml";
+
+lazy_static! {
+    static ref DATABASE_NAME_REGEX: Regex = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    pub name: String,
+    pub database_endpoint: String,
+    /// Where card and deck images rendered from this database are written.
+    pub render_directory: String,
+    pub backup_directory: String,
+}
+
+/// An entry of `config/databases.yml`. Directories left out are derived
+/// from the database name.
+#[derive(Debug, Clone, Deserialize)]
+struct DatabaseConfigEntry {
+    database_endpoint: String,
+    render_directory: Option<String>,
+    backup_directory: Option<String>,
+}
+
+impl DatabaseConfig {
+    pub fn default_database() -> Self {
+        Self {
+            name: DEFAULT_DATABASE_NAME.to_owned(),
+            database_endpoint: String::from("runtime/data/databases/cards.db"),
+            render_directory: String::from("runtime/data"),
+            backup_directory: String::from("runtime/data/backups"),
+        }
+    }
+
+    fn from_entry(name: &str, entry: DatabaseConfigEntry) -> Self {
+        let (render_directory, backup_directory) = if name == DEFAULT_DATABASE_NAME {
+            let default = Self::default_database();
+            (default.render_directory, default.backup_directory)
+        } else {
+            (
+                format!("runtime/data/campaigns/{}", name),
+                format!("runtime/data/backups/{}", name),
+            )
+        };
+
+        Self {
+            name: name.to_owned(),
+            database_endpoint: entry.database_endpoint,
+            render_directory: entry.render_directory.unwrap_or(render_directory),
+            backup_directory: entry.backup_directory.unwrap_or(backup_directory),
+        }
+    }
+}
+
+/// Reads the databases listed in `config/databases.yml` contents, the
+/// default database first and the rest by name.
+pub fn parse_database_configs(contents: &str) -> Result<Vec<DatabaseConfig>> {
+    let entries: BTreeMap<String, DatabaseConfigEntry> = if contents.trim().is_empty() {
+        BTreeMap::new()
+    } else {
+        serde_yaml::from_str(contents)?
+    };
+
+    let mut databases = vec![DatabaseConfig::default_database()];
+
+    for (name, entry) in entries {
+        if !DATABASE_NAME_REGEX.is_match(&name) {
+            return Err(anyhow!(
+                "Database name '{}' may only contain letters, digits, '-' and '_'",
+                name
+            ));
+        }
+
+        let database = DatabaseConfig::from_entry(&name, entry);
+        if name == DEFAULT_DATABASE_NAME {
+            databases[0] = database;
+        } else {
+            databases.push(database);
+        }
+    }
+
+    Ok(databases)
+}
+
+/// Reads the databases listed in the file at `path`. Without the file, only
+/// the default database is served.
+pub fn load_database_configs(path: &str) -> Result<Vec<DatabaseConfig>> {
+    if !Path::new(path).exists() {
+        return Ok(vec![DatabaseConfig::default_database()]);
+    }
+
+    parse_database_configs(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::parse_database_configs;
+
+    #[test]
+    fn given_campaign_databases_when_parse_then_directories_namespaced() {
+        let databases = parse_database_configs(
+            "frostlands:\n  database_endpoint: runtime/data/databases/frostlands.db\n\
+             default:\n  database_endpoint: runtime/data/databases/core.db\n",
+        )
+        .unwrap();
+
+        assert_eq!(databases.len(), 2);
+        assert_eq!(databases[0].name, "default");
+        assert_eq!(
+            databases[0].database_endpoint,
+            "runtime/data/databases/core.db"
+        );
+        assert_eq!(databases[0].render_directory, "runtime/data");
+        assert_eq!(databases[1].name, "frostlands");
+        assert_eq!(
+            databases[1].render_directory,
+            "runtime/data/campaigns/frostlands"
+        );
+        assert_eq!(
+            databases[1].backup_directory,
+            "runtime/data/backups/frostlands"
+        );
+
+        assert!(parse_database_configs("../etc:\n  database_endpoint: x.db\n").is_err());
+    }
+}
@@ -0,0 +1,561 @@
+extern crate juniper;
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{
+    Card, DeckCardEntry, DeckRulesCardClassLimit, DeckRulesRecord, NewDeckRulesCardClassLimit,
+    NewDeckRulesRecord,
+};
+use crate::validation::ValidationErrors;
+
+/// The construction rules that every deck of a given `decktype` must follow.
+/// Any limit left as `None` is not enforced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DeckRules {
+    pub decktype: String,
+    pub max_copies_per_card: Option<i32>,
+    pub min_deck_size: Option<i32>,
+    pub max_deck_size: Option<i32>,
+    #[serde(default)]
+    pub cardclass_limits: Vec<CardClassLimit>,
+}
+
+/// The maximum number of cards of one card class that a deck may hold.
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct CardClassLimit {
+    pub cardclass: String,
+    pub max_cards: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, juniper::GraphQLEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DeckRuleViolationKind {
+    TooManyCopies,
+    DeckTooSmall,
+    DeckTooLarge,
+    TooManyOfCardClass,
+    UnknownCard,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DeckRuleViolation {
+    pub kind: DeckRuleViolationKind,
+    pub message: String,
+    pub card_ids: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DeckValidationReport {
+    pub deck: String,
+    pub decktype: String,
+    pub valid: bool,
+    pub violations: Vec<DeckRuleViolation>,
+}
+
+impl DeckRules {
+    /// Checks the deck's cards against these rules and returns every
+    /// violation found, rather than stopping at the first one.
+    pub fn validate(&self, entries: &[DeckCardEntry]) -> Vec<DeckRuleViolation> {
+        let mut violations = Vec::new();
+
+        if let Some(max_copies) = self.max_copies_per_card {
+            for entry in entries.iter().filter(|entry| entry.quantity > max_copies) {
+                violations.push(DeckRuleViolation {
+                    kind: DeckRuleViolationKind::TooManyCopies,
+                    message: format!(
+                        "'{}' has {} copies, but at most {} are allowed",
+                        entry.card.name, entry.quantity, max_copies
+                    ),
+                    card_ids: vec![entry.card.id],
+                });
+            }
+        }
+
+        let deck_size: i32 = entries.iter().map(|entry| entry.quantity).sum();
+        let all_card_ids = || entries.iter().map(|entry| entry.card.id).collect();
+
+        if let Some(min_size) = self.min_deck_size {
+            if deck_size < min_size {
+                violations.push(DeckRuleViolation {
+                    kind: DeckRuleViolationKind::DeckTooSmall,
+                    message: format!(
+                        "Deck has {} cards, but needs at least {}",
+                        deck_size, min_size
+                    ),
+                    card_ids: all_card_ids(),
+                });
+            }
+        }
+
+        if let Some(max_size) = self.max_deck_size {
+            if deck_size > max_size {
+                violations.push(DeckRuleViolation {
+                    kind: DeckRuleViolationKind::DeckTooLarge,
+                    message: format!(
+                        "Deck has {} cards, but can hold at most {}",
+                        deck_size, max_size
+                    ),
+                    card_ids: all_card_ids(),
+                });
+            }
+        }
+
+        for limit in &self.cardclass_limits {
+            let matching = entries
+                .iter()
+                .filter(|entry| entry.card.cardclass == limit.cardclass)
+                .collect::<Vec<&DeckCardEntry>>();
+            let count: i32 = matching.iter().map(|entry| entry.quantity).sum();
+
+            if count > limit.max_cards {
+                violations.push(DeckRuleViolation {
+                    kind: DeckRuleViolationKind::TooManyOfCardClass,
+                    message: format!(
+                        "Deck has {} '{}' cards, but can hold at most {}",
+                        count, limit.cardclass, limit.max_cards
+                    ),
+                    card_ids: matching.iter().map(|entry| entry.card.id).collect(),
+                });
+            }
+        }
+
+        violations
+    }
+
+    /// Checks that these rules can be kept at all, with every limit that
+    /// cannot be reported together.
+    pub fn validate_limits(&self) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let limits = [
+            ("max_copies_per_card", self.max_copies_per_card),
+            ("min_deck_size", self.min_deck_size),
+            ("max_deck_size", self.max_deck_size),
+        ];
+        for (field, limit) in limits.iter() {
+            if let Some(limit) = limit.filter(|limit| *limit < 0) {
+                errors.add(
+                    field,
+                    "negative",
+                    format!("Limits cannot be negative, but this one is {}", limit),
+                );
+            }
+        }
+
+        if let (Some(min_size), Some(max_size)) = (self.min_deck_size, self.max_deck_size) {
+            if min_size > max_size {
+                errors.add(
+                    "min_deck_size",
+                    "above_max_deck_size",
+                    format!(
+                        "Decks cannot need {} cards but hold at most {}",
+                        min_size, max_size
+                    ),
+                );
+            }
+        }
+
+        for (index, limit) in self.cardclass_limits.iter().enumerate() {
+            let field = format!("cardclass_limits[{}].max_cards", index);
+
+            if limit.max_cards < 0 {
+                errors.add(
+                    &field,
+                    "negative",
+                    format!(
+                        "Limits cannot be negative, but '{}' has {}",
+                        limit.cardclass, limit.max_cards
+                    ),
+                );
+            } else if let Some(max_size) = self
+                .max_deck_size
+                .filter(|max_size| limit.max_cards > *max_size)
+            {
+                errors.add(
+                    &field,
+                    "above_max_deck_size",
+                    format!(
+                        "'{}' allows {} cards, but decks hold at most {}",
+                        limit.cardclass, limit.max_cards, max_size
+                    ),
+                );
+            }
+        }
+
+        errors.into_result()
+    }
+}
+
+impl DatabaseContext {
+    pub fn get_deck_rules(&self, target_decktype: &str) -> Result<Option<DeckRules>> {
+        use crate::schema::deck_rules::dsl::*;
+        use crate::schema::deck_rules_cardclass_limits::dsl as limits;
+
+        let record = deck_rules
+            .filter(decktype.eq(target_decktype))
+            .first::<DeckRulesRecord>(self.connection.as_ref())
+            .optional()?;
+
+        let record = match record {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+
+        let cardclass_limits = limits::deck_rules_cardclass_limits
+            .filter(limits::deck_rules_id.eq(record.id))
+            .load::<DeckRulesCardClassLimit>(self.connection.as_ref())?
+            .into_iter()
+            .map(|limit| CardClassLimit {
+                cardclass: limit.cardclass,
+                max_cards: limit.max_cards,
+            })
+            .collect();
+
+        Ok(Some(DeckRules {
+            decktype: record.decktype,
+            max_copies_per_card: record.max_copies_per_card,
+            min_deck_size: record.min_deck_size,
+            max_deck_size: record.max_deck_size,
+            cardclass_limits,
+        }))
+    }
+
+    /// Replaces the rules for the decktype named in `rules`.
+    pub fn put_deck_rules(&mut self, rules: &DeckRules) -> Result<DeckRules> {
+        use crate::schema::deck_rules;
+        use crate::schema::deck_rules_cardclass_limits;
+
+        debug!("put_deck_rules: {:?}", rules);
+
+        rules
+            .validate_limits()
+            .map_err(ClientError::ValidationFailed)?;
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            let existing = deck_rules::table
+                .filter(deck_rules::decktype.eq(&rules.decktype))
+                .first::<DeckRulesRecord>(self.connection.as_ref())
+                .optional()?;
+
+            if let Some(existing) = existing {
+                diesel::delete(
+                    deck_rules_cardclass_limits::table
+                        .filter(deck_rules_cardclass_limits::deck_rules_id.eq(existing.id)),
+                )
+                .execute(self.connection.as_ref())?;
+                diesel::delete(deck_rules::table.find(existing.id))
+                    .execute(self.connection.as_ref())?;
+            }
+
+            diesel::insert_into(deck_rules::table)
+                .values(&NewDeckRulesRecord {
+                    decktype: &rules.decktype,
+                    max_copies_per_card: rules.max_copies_per_card,
+                    min_deck_size: rules.min_deck_size,
+                    max_deck_size: rules.max_deck_size,
+                })
+                .execute(self.connection.as_ref())?;
+
+            // Get the id of the new rules back out by their decktype.
+            let record = deck_rules::table
+                .filter(deck_rules::decktype.eq(&rules.decktype))
+                .first::<DeckRulesRecord>(self.connection.as_ref())?;
+
+            let new_limits = rules
+                .cardclass_limits
+                .iter()
+                .map(|limit| NewDeckRulesCardClassLimit {
+                    deck_rules_id: record.id,
+                    cardclass: &limit.cardclass,
+                    max_cards: limit.max_cards,
+                })
+                .collect::<Vec<NewDeckRulesCardClassLimit>>();
+
+            diesel::insert_into(deck_rules_cardclass_limits::table)
+                .values(&new_limits)
+                .execute(self.connection.as_ref())?;
+
+            Ok(())
+        })?;
+
+        debug!("put_deck_rules succeeded");
+        Ok(rules.clone())
+    }
+
+    /// Validates an existing deck against the rules for its decktype. Decks
+    /// whose decktype has no rules are always valid.
+    pub fn validate_deck(&self, deck_name: &str) -> Result<DeckValidationReport> {
+        let deck = match self.get_deck_by_name(deck_name) {
+            Err(err)
+                if matches!(
+                    err.downcast_ref::<diesel::result::Error>(),
+                    Some(diesel::result::Error::NotFound)
+                ) =>
+            {
+                Err(ClientError::ResourceNotFound)?
+            }
+            deck => deck?,
+        };
+        let entries = self.get_deck_card_entries_by_deck_name(deck_name)?;
+
+        self.validate_deck_card_entries(&deck.name, &deck.decktype, &entries)
+    }
+
+    /// Validates a not-yet-created deck, given as a list of card ids where
+    /// repeated ids are extra copies.
+    pub fn validate_card_ids_for_decktype(
+        &self,
+        deck_name: &str,
+        target_decktype: &str,
+        ids: &[i32],
+    ) -> Result<DeckValidationReport> {
+        use crate::schema::cards::dsl::*;
+
+        let grouped_ids = crate::group_card_ids_by_quantity(ids);
+        let unique_ids = grouped_ids
+            .iter()
+            .map(|(card_id, _)| *card_id)
+            .collect::<Vec<i32>>();
+
+        let found_cards = cards
+            .filter(id.eq_any(unique_ids))
+            .load::<Card>(self.connection.as_ref())?;
+
+        let mut entries = Vec::new();
+        let mut unknown_ids = Vec::new();
+        for (index, (card_id, quantity)) in grouped_ids.into_iter().enumerate() {
+            match found_cards.iter().find(|card| card.id == card_id) {
+                Some(card) => entries.push(DeckCardEntry {
+                    card: card.clone(),
+                    quantity,
+                    position: index as i32,
+                }),
+                None => unknown_ids.push(card_id),
+            }
+        }
+
+        let mut report = self.validate_deck_card_entries(deck_name, target_decktype, &entries)?;
+        if let Some(violation) = unknown_card_violation(unknown_ids) {
+            report.violations.insert(0, violation);
+            report.valid = false;
+        }
+
+        Ok(report)
+    }
+
+    /// The ids among `ids` that name no card.
+    pub fn find_unknown_card_ids(&self, ids: &[i32]) -> Result<Vec<i32>> {
+        use crate::schema::cards::dsl::*;
+
+        let found_ids = cards
+            .select(id)
+            .filter(id.eq_any(ids))
+            .load::<i32>(self.connection.as_ref())?;
+
+        let mut unknown_ids = ids
+            .iter()
+            .copied()
+            .filter(|card_id| !found_ids.contains(card_id))
+            .collect::<Vec<i32>>();
+        unknown_ids.sort_unstable();
+        unknown_ids.dedup();
+
+        Ok(unknown_ids)
+    }
+
+    fn validate_deck_card_entries(
+        &self,
+        deck_name: &str,
+        target_decktype: &str,
+        entries: &[DeckCardEntry],
+    ) -> Result<DeckValidationReport> {
+        let violations = match self.get_deck_rules(target_decktype)? {
+            Some(rules) => rules.validate(entries),
+            None => {
+                debug!("No deck rules for decktype '{}'", target_decktype);
+                vec![]
+            }
+        };
+
+        Ok(DeckValidationReport {
+            deck: deck_name.to_owned(),
+            decktype: target_decktype.to_owned(),
+            valid: violations.is_empty(),
+            violations,
+        })
+    }
+}
+
+/// The violation for cards a deck names that do not exist, if there are any.
+pub fn unknown_card_violation(unknown_ids: Vec<i32>) -> Option<DeckRuleViolation> {
+    if unknown_ids.is_empty() {
+        return None;
+    }
+
+    Some(DeckRuleViolation {
+        kind: DeckRuleViolationKind::UnknownCard,
+        message: format!(
+            "No cards have the ids {}",
+            unknown_ids
+                .iter()
+                .map(|card_id| card_id.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        card_ids: unknown_ids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_values::{CardAction, CardSpeed};
+    use crate::database::DatabaseContext;
+    use crate::deck_rules::{
+        unknown_card_violation, CardClassLimit, DeckRuleViolationKind, DeckRules,
+    };
+    use crate::errors::ClientError;
+    use crate::models::{Card, DeckCardEntry};
+
+    fn entry(id: i32, cardclass: &str, quantity: i32) -> DeckCardEntry {
+        DeckCardEntry {
+            card: Card {
+                id,
+                cardclass: cardclass.to_owned(),
+                action: CardAction::Attack,
+                speed: CardSpeed::Normal,
+                initiative: 3,
+                name: format!("Card {}", id),
+                desc: "".to_owned(),
+                image_url: None,
+                version: 1,
+                cloned_from_id: None,
+            },
+            quantity,
+            position: id,
+        }
+    }
+
+    #[test]
+    fn given_deck_within_rules_when_validate_then_no_violations() {
+        let rules = DeckRules {
+            decktype: "user".to_owned(),
+            max_copies_per_card: Some(3),
+            min_deck_size: Some(2),
+            max_deck_size: Some(10),
+            cardclass_limits: vec![CardClassLimit {
+                cardclass: "2H".to_owned(),
+                max_cards: 2,
+            }],
+        };
+        let entries = vec![entry(1, "2H", 2), entry(2, "Sp", 3)];
+
+        assert!(rules.validate(&entries).is_empty());
+    }
+
+    #[test]
+    fn given_deck_breaking_rules_when_validate_then_every_violation_reported() {
+        let rules = DeckRules {
+            decktype: "user".to_owned(),
+            max_copies_per_card: Some(2),
+            min_deck_size: None,
+            max_deck_size: Some(5),
+            cardclass_limits: vec![
+                CardClassLimit {
+                    cardclass: "2H".to_owned(),
+                    max_cards: 1,
+                },
+                CardClassLimit {
+                    cardclass: "Ar".to_owned(),
+                    max_cards: 1,
+                },
+            ],
+        };
+        let entries = vec![entry(1, "2H", 1), entry(2, "2H", 1), entry(3, "Sp", 4)];
+
+        let violations = rules.validate(&entries);
+        let kinds = violations.iter().map(|v| v.kind).collect::<Vec<_>>();
+
+        assert_eq!(
+            kinds,
+            vec![
+                DeckRuleViolationKind::TooManyCopies,
+                DeckRuleViolationKind::DeckTooLarge,
+                DeckRuleViolationKind::TooManyOfCardClass,
+            ]
+        );
+        assert_eq!(violations[0].card_ids, vec![3]);
+        assert_eq!(violations[2].card_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn given_unknown_card_ids_when_reported_then_one_violation_names_them() {
+        assert!(unknown_card_violation(vec![]).is_none());
+
+        let violation = unknown_card_violation(vec![7, 9]).unwrap();
+        assert_eq!(violation.kind, DeckRuleViolationKind::UnknownCard);
+        assert_eq!(violation.card_ids, vec![7, 9]);
+        assert_eq!(violation.message, "No cards have the ids 7, 9");
+    }
+
+    #[test]
+    fn given_rules_that_cannot_be_kept_when_put_then_every_limit_refused() {
+        let directory =
+            std::env::temp_dir().join(format!("deck-rules-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+        let rules = DeckRules {
+            decktype: "user".to_owned(),
+            max_copies_per_card: Some(-1),
+            min_deck_size: Some(40),
+            max_deck_size: Some(30),
+            cardclass_limits: vec![
+                CardClassLimit {
+                    cardclass: "ATK".to_owned(),
+                    max_cards: 31,
+                },
+                CardClassLimit {
+                    cardclass: "DEF".to_owned(),
+                    max_cards: -2,
+                },
+                CardClassLimit {
+                    cardclass: "MOV".to_owned(),
+                    max_cards: 30,
+                },
+            ],
+        };
+
+        let errors = match db
+            .put_deck_rules(&rules)
+            .unwrap_err()
+            .downcast::<ClientError>()
+        {
+            Ok(ClientError::ValidationFailed(errors)) => errors,
+            other => panic!("expected validation errors, got {:?}", other),
+        };
+        let fields = errors
+            .errors
+            .iter()
+            .map(|error| (error.field.as_str(), error.code.as_str()))
+            .collect::<Vec<(&str, &str)>>();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("max_copies_per_card", "negative"),
+                ("min_deck_size", "above_max_deck_size"),
+                ("cardclass_limits[0].max_cards", "above_max_deck_size"),
+                ("cardclass_limits[1].max_cards", "negative"),
+            ]
+        );
+        assert!(DeckRules::default().validate_limits().is_ok());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
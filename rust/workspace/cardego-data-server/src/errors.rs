@@ -2,7 +2,10 @@ extern crate anyhow;
 extern crate derive_more;
 extern crate thiserror;
 
+use crate::deck_rules::DeckValidationReport;
+use crate::validation::ValidationErrors;
 use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
 use anyhow::anyhow;
 use std::convert::From;
 
@@ -28,6 +31,14 @@ pub enum ClientError {
     ResourceNotFound,
     #[error("Invalid input for operation found: {0}")]
     InvalidInput(String),
+    #[error("Deck breaks {} construction rule(s)", .0.violations.len())]
+    DeckRuleViolations(DeckValidationReport),
+    #[error("Validation failed: {0}")]
+    ValidationFailed(ValidationErrors),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
     #[error(transparent)]
     OtherError(#[from] anyhow::Error),
 }
@@ -48,7 +59,12 @@ impl From<std::io::Error> for AppError {
 
 impl From<anyhow::Error> for AppError {
     fn from(err: anyhow::Error) -> Self {
-        AppError::Server(ServerError::OtherError(err))
+        // Client errors raised inside the database layer still need to be
+        // reported as client errors.
+        match err.downcast::<ClientError>() {
+            Ok(client_err) => AppError::Client(client_err),
+            Err(err) => AppError::Server(ServerError::OtherError(err)),
+        }
     }
 }
 
@@ -80,9 +96,13 @@ impl From<ClientError> for std::io::Error {
     fn from(err: ClientError) -> Self {
         match err {
             ClientError::ResourceNotFound => std::io::Error::new(std::io::ErrorKind::NotFound, err),
-            ClientError::InvalidInput(_) => {
+            ClientError::InvalidInput(_)
+            | ClientError::DeckRuleViolations(_)
+            | ClientError::ValidationFailed(_)
+            | ClientError::PreconditionFailed(_) => {
                 std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
             }
+            ClientError::TooManyRequests(_) => std::io::Error::from(AppError::from(err)),
             ClientError::OtherError(err) => std::io::Error::from(AppError::from(err)),
         }
     }
@@ -94,9 +114,27 @@ impl actix_web::ResponseError for AppError {
 
         match self {
             Server(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Client(ClientError::DeckRuleViolations(_))
+            | Client(ClientError::ValidationFailed(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+            Client(ClientError::PreconditionFailed(_)) => StatusCode::PRECONDITION_FAILED,
+            Client(ClientError::TooManyRequests(_)) => StatusCode::TOO_MANY_REQUESTS,
             Client(_) => StatusCode::BAD_REQUEST,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AppError::Client(ClientError::DeckRuleViolations(report)) => {
+                HttpResponse::build(self.status_code()).json(report)
+            }
+            AppError::Client(ClientError::ValidationFailed(errors)) => {
+                HttpResponse::build(self.status_code()).json(errors)
+            }
+            _ => HttpResponse::build(self.status_code())
+                .content_type("text/plain; charset=utf-8")
+                .body(self.to_string()),
+        }
+    }
 }
 
 impl<E> From<actix_web::error::BlockingError<E>> for AppError
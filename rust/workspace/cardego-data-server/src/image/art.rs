@@ -0,0 +1,725 @@
+//! Card art stored on the server.
+//!
+//! Art is uploaded, or downloaded once from its `image_url`, checked to be
+//! an image of a sensible size, then cropped and resized to fill the art box
+//! of a card. It is stored under `art` in the render directory of its
+//! database, by the hash of its pixels, so the same art is stored once
+//! however many cards use it, and cards point at it with an `art:<hash>`
+//! URL. Renders read stored art from disk and never fetch art from other
+//! servers. Art is only downloaded from hosts on the public internet, so
+//! that an `image_url` cannot reach the server itself or the network behind
+//! it.
+
+use ::image::imageops::{self, FilterType};
+use ::image::io::{Limits, Reader};
+use ::image::{ImageFormat, RgbaImage};
+use anyhow::Result;
+use diesel::prelude::*;
+use hyper::body::HttpBody;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use log::{debug, info};
+use serde::Serialize;
+use tower_service::Service;
+
+use std::fmt;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::image::cache::render_key;
+use crate::models::FullCardData;
+
+/// Where art is stored, under the render directory of its database.
+pub const ART_DIRECTORY: &str = "art";
+
+/// The size of the art box of a card, in pixels.
+pub const ART_WIDTH: u32 = 282;
+pub const ART_HEIGHT: u32 = 142;
+
+/// Art is stored at twice the size of its box, to stay sharp in print.
+pub const ART_SCALE: u32 = 2;
+
+/// The largest art file accepted, in bytes.
+pub const MAX_ART_BYTES: usize = 10 * 1024 * 1024;
+
+/// How long a download of art may take, and how many redirects it may
+/// follow.
+const ART_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_ART_REDIRECTS: usize = 5;
+
+/// The bounds on either side of art as it is uploaded, in pixels.
+pub const MIN_ART_DIMENSION: u32 = 16;
+pub const MAX_ART_DIMENSION: u32 = 8192;
+
+const ART_URL_SCHEME: &str = "art:";
+
+/// The formats art may be uploaded in.
+const ART_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+];
+
+/// Art once it is stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredArt {
+    pub hash: String,
+    /// What the `image_url` of a card with this art is.
+    pub image_url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub fn art_url(hash: &str) -> String {
+    format!("{}{}", ART_URL_SCHEME, hash)
+}
+
+/// Whether `hash` could name stored art, which also keeps it from naming
+/// any other file.
+pub fn is_art_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The hash of the stored art an `image_url` points at, if it does.
+pub fn parse_art_url(image_url: &str) -> Option<&str> {
+    image_url
+        .strip_prefix(ART_URL_SCHEME)
+        .filter(|hash| is_art_hash(hash))
+}
+
+/// The hash stored art is named by.
+fn art_hash(image: &RgbaImage) -> String {
+    render_key(&[
+        &image.width().to_le_bytes(),
+        &image.height().to_le_bytes(),
+        image.as_raw(),
+    ])
+}
+
+/// Copies the art in `source` that `destination` does not have yet, and
+/// returns how much it copied. Art never changes once stored, so it is
+/// linked rather than copied where the file system allows it.
+pub fn copy_art(source: &Path, destination: &Path) -> Result<usize> {
+    if !source.is_dir() {
+        return Ok(0);
+    }
+
+    std::fs::create_dir_all(destination)?;
+
+    let mut copied = 0;
+    for entry in std::fs::read_dir(source)? {
+        let path = entry?.path();
+        let is_art = path.extension().and_then(|ext| ext.to_str()) == Some("png")
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(is_art_hash);
+        let copy_path = destination.join(path.file_name().unwrap_or_default());
+
+        if !is_art || copy_path.exists() {
+            continue;
+        }
+
+        if std::fs::hard_link(&path, &copy_path).is_err() {
+            std::fs::copy(&path, &copy_path)?;
+        }
+        copied += 1;
+    }
+
+    Ok(copied)
+}
+
+/// The art stored for one database.
+#[derive(Debug, Clone)]
+pub struct ArtStore {
+    directory: PathBuf,
+}
+
+impl ArtStore {
+    /// The art of the database rendered into `render_directory`.
+    pub fn new(render_directory: &str) -> Self {
+        Self {
+            directory: Path::new(render_directory).join(ART_DIRECTORY),
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn path(&self, hash: &str) -> PathBuf {
+        self.directory.join(format!("{}.png", hash))
+    }
+
+    /// The stored art an `image_url` points at, as PNG, if it is stored.
+    pub fn read(&self, image_url: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.path(parse_art_url(image_url)?)).ok()
+    }
+
+    /// The `src` of the art of a card in the HTML templates. Stored art is
+    /// embedded, and any other art is left out until it is ingested, so a
+    /// render never reads files or fetches URLs a card points at.
+    pub fn src(&self, image_url: Option<&str>) -> String {
+        match image_url.and_then(|image_url| self.read(image_url)) {
+            Some(png) => format!("data:image/png;base64,{}", base64::encode(png)),
+            None => String::new(),
+        }
+    }
+
+    /// Stores processed art, unless the same art is already stored.
+    pub fn store(&self, image: &RgbaImage) -> Result<StoredArt> {
+        let hash = art_hash(image);
+        let path = self.path(&hash);
+
+        if path.exists() {
+            debug!("Art {} is already stored", hash);
+        } else {
+            std::fs::create_dir_all(&self.directory)?;
+
+            // Written aside and moved into place, so that a render never
+            // reads half of the art.
+            let partial_path = path.with_extension("png.partial");
+            image.save_with_format(&partial_path, ImageFormat::Png)?;
+            std::fs::rename(&partial_path, &path)?;
+
+            info!("Stored art {}", hash);
+        }
+
+        Ok(StoredArt {
+            image_url: art_url(&hash),
+            hash,
+            width: image.width(),
+            height: image.height(),
+        })
+    }
+}
+
+/// Checks that `bytes` are an image that may be used as art, and crops and
+/// resizes it to fill the art box.
+pub fn process_art(bytes: &[u8]) -> std::result::Result<RgbaImage, ClientError> {
+    if bytes.len() > MAX_ART_BYTES {
+        return Err(ClientError::InvalidInput(format!(
+            "Art may be at most {} bytes",
+            MAX_ART_BYTES
+        )));
+    }
+
+    let mut reader = Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|err| ClientError::InvalidInput(err.to_string()))?;
+
+    match reader.format() {
+        Some(format) if ART_FORMATS.contains(&format) => {}
+        _ => {
+            return Err(ClientError::InvalidInput(
+                "Art must be a PNG, JPEG, GIF, WebP or BMP image".to_string(),
+            ))
+        }
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_ART_DIMENSION);
+    limits.max_image_height = Some(MAX_ART_DIMENSION);
+    reader.limits(limits);
+
+    let size_error = || {
+        ClientError::InvalidInput(format!(
+            "Art must be between {} and {} pixels on each side",
+            MIN_ART_DIMENSION, MAX_ART_DIMENSION
+        ))
+    };
+
+    let image = reader
+        .decode()
+        .map_err(|err| match err {
+            ::image::ImageError::Limits(_) => size_error(),
+            err => ClientError::InvalidInput(format!("Art could not be read: {}", err)),
+        })?
+        .to_rgba8();
+
+    if image.width() < MIN_ART_DIMENSION || image.height() < MIN_ART_DIMENSION {
+        return Err(size_error());
+    }
+
+    Ok(fill_art_box(&image))
+}
+
+/// Crops the middle of `image` to the shape of the art box, then resizes it
+/// to fill the box.
+fn fill_art_box(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = (ART_WIDTH * ART_SCALE, ART_HEIGHT * ART_SCALE);
+
+    let scale = f32::max(
+        width as f32 / image.width() as f32,
+        height as f32 / image.height() as f32,
+    );
+    let crop_width = ((width as f32 / scale).round() as u32).clamp(1, image.width());
+    let crop_height = ((height as f32 / scale).round() as u32).clamp(1, image.height());
+
+    let cropped = imageops::crop_imm(
+        image,
+        (image.width() - crop_width) / 2,
+        (image.height() - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+    .to_image();
+
+    imageops::resize(&cropped, width, height, FilterType::Lanczos3)
+}
+
+/// Reads art as it was exported: a PNG of art already fitted to the art
+/// box, which is stored as it is so that it keeps its hash.
+pub fn read_exported_art(bytes: &[u8]) -> std::result::Result<RgbaImage, ClientError> {
+    let invalid = || {
+        ClientError::InvalidInput(format!(
+            "Exported art must be a {} by {} PNG of at most {} bytes",
+            ART_WIDTH * ART_SCALE,
+            ART_HEIGHT * ART_SCALE,
+            MAX_ART_BYTES
+        ))
+    };
+
+    if bytes.len() > MAX_ART_BYTES {
+        return Err(invalid());
+    }
+
+    let image = ::image::load_from_memory_with_format(bytes, ImageFormat::Png)
+        .map_err(|_| invalid())?
+        .to_rgba8();
+    if image.dimensions() != (ART_WIDTH * ART_SCALE, ART_HEIGHT * ART_SCALE) {
+        return Err(invalid());
+    }
+
+    Ok(image)
+}
+
+/// The `image_url` that exported art is stored under.
+pub fn exported_art_url(image: &RgbaImage) -> String {
+    art_url(&art_hash(image))
+}
+
+/// Whether art may be downloaded from `address`: it is not the server
+/// itself or an address on a private, link-local or otherwise internal
+/// network, such as the metadata service of a cloud host.
+pub fn is_public_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let octets = address.octets();
+
+            !(address.is_private()
+                || address.is_loopback()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_multicast()
+                || address.is_documentation()
+                || octets[0] == 0
+                // Shared address space, used inside carrier networks.
+                || (octets[0] == 100 && octets[1] & 0xC0 == 64))
+        }
+        IpAddr::V6(address) => {
+            let first_segment = address.segments()[0];
+
+            if address.is_loopback()
+                || address.is_unspecified()
+                || address.is_multicast()
+                // Unique local and link-local addresses.
+                || first_segment & 0xFE00 == 0xFC00
+                || first_segment & 0xFFC0 == 0xFE80
+            {
+                return false;
+            }
+
+            // IPv4 addresses written as IPv6 ones are checked as IPv4.
+            address
+                .to_ipv4()
+                .is_none_or(|address| is_public_address(IpAddr::V4(address)))
+        }
+    }
+}
+
+/// Why art was not downloaded from a host, as the cause of the connection
+/// that `PublicResolver` refused.
+#[derive(Debug)]
+struct NonPublicHost(String);
+
+impl fmt::Display for NonPublicHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Art cannot be downloaded from {}, which is not on the public internet",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NonPublicHost {}
+
+/// Resolves the hosts art is downloaded from, and refuses those with any
+/// address that is not public. Downloads connect only to the addresses
+/// checked here, so a host cannot be checked at one address and then be
+/// connected to at another.
+#[derive(Clone)]
+struct PublicResolver(GaiResolver);
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future =
+        Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.0.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_owned();
+        let lookup = self.0.call(name);
+
+        Box::pin(async move {
+            let addresses = lookup.await?.collect::<Vec<IpAddr>>();
+
+            if addresses.is_empty() || !addresses.iter().all(|address| is_public_address(*address))
+            {
+                return Err(NonPublicHost(host).into());
+            }
+
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// Checks that art may be downloaded from `url`: that it is http or https,
+/// and that its host is public if it is an address. Hosts that are names are
+/// checked by `PublicResolver` as they are connected to.
+fn check_art_url(url: &reqwest::Url) -> std::result::Result<(), ClientError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ClientError::InvalidInput(format!(
+            "Only http and https art can be ingested, not '{}'; upload it instead",
+            url
+        )));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| ClientError::InvalidInput(format!("'{}' has no host", url)))?;
+
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(address) if !is_public_address(address) => Err(ClientError::InvalidInput(
+            NonPublicHost(host.to_owned()).to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reports a failed download, with the host that was refused if that is why
+/// it failed.
+fn download_error(url: &str, err: &hyper::Error) -> ClientError {
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if let Some(host) = cause.downcast_ref::<NonPublicHost>() {
+            return ClientError::InvalidInput(host.to_string());
+        }
+        source = cause.source();
+    }
+
+    ClientError::InvalidInput(format!("Could not download art from {}: {}", url, err))
+}
+
+/// Downloads the art at `url`, checking that it is served as an image and is
+/// no larger than `MAX_ART_BYTES`. Redirects are followed by hand, so that
+/// every host the download goes through is checked. Downloads never go
+/// through a proxy, so the host checked is the host connected to.
+pub async fn download_art(url: &str) -> std::result::Result<Vec<u8>, ClientError> {
+    let mut http = HttpConnector::new_with_resolver(PublicResolver(GaiResolver::new()));
+    // https URLs are passed through to the TLS connector.
+    http.enforce_http(false);
+    let client =
+        hyper::Client::builder().build::<_, hyper::Body>(HttpsConnector::new_with_connector(http));
+
+    actix_rt::time::timeout(ART_DOWNLOAD_TIMEOUT, fetch_art(&client, url))
+        .await
+        .map_err(|_| {
+            ClientError::InvalidInput(format!(
+                "Could not download art from {} within {} seconds",
+                url,
+                ART_DOWNLOAD_TIMEOUT.as_secs()
+            ))
+        })?
+}
+
+async fn fetch_art(
+    client: &hyper::Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+    url: &str,
+) -> std::result::Result<Vec<u8>, ClientError> {
+    let mut current_url = reqwest::Url::parse(url)
+        .map_err(|_| ClientError::InvalidInput(format!("'{}' is not a URL", url)))?;
+
+    let mut redirects = 0;
+    let response = loop {
+        check_art_url(&current_url)?;
+
+        debug!("Downloading art from {}", current_url);
+
+        let uri = current_url
+            .as_str()
+            .parse::<hyper::Uri>()
+            .map_err(|_| ClientError::InvalidInput(format!("'{}' is not a URL", current_url)))?;
+        let response = client
+            .get(uri)
+            .await
+            .map_err(|err| download_error(url, &err))?;
+
+        if !response.status().is_redirection() {
+            if !response.status().is_success() {
+                return Err(ClientError::InvalidInput(format!(
+                    "Could not download art from {}: {}",
+                    url,
+                    response.status()
+                )));
+            }
+            break response;
+        }
+
+        if redirects == MAX_ART_REDIRECTS {
+            return Err(ClientError::InvalidInput(format!(
+                "{} redirects more than {} times",
+                url, MAX_ART_REDIRECTS
+            )));
+        }
+        redirects += 1;
+
+        let location = response
+            .headers()
+            .get(hyper::header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                ClientError::InvalidInput(format!("{} redirects nowhere", current_url))
+            })?;
+        current_url = current_url.join(location).map_err(|_| {
+            ClientError::InvalidInput(format!("{} redirects to '{}'", current_url, location))
+        })?;
+    };
+
+    let content_type = response
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.starts_with("image/") {
+        return Err(ClientError::InvalidInput(format!(
+            "{} is served as '{}', not as an image",
+            url, content_type
+        )));
+    }
+
+    let too_large =
+        || ClientError::InvalidInput(format!("{} is larger than {} bytes", url, MAX_ART_BYTES));
+
+    let content_length = response
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    if content_length > MAX_ART_BYTES as u64 {
+        return Err(too_large());
+    }
+
+    // The length is read as it arrives, as servers may leave it out.
+    let mut body = response.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| download_error(url, &err))?;
+        if bytes.len() + chunk.len() > MAX_ART_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+impl DatabaseContext {
+    /// The art of this database, which is set along with its render
+    /// directory.
+    pub fn get_art_store(&self) -> Result<&ArtStore> {
+        self.art_store
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} has no art store", self.database_url))
+    }
+
+    /// Points a card at stored art.
+    pub fn set_card_art(&self, card_id: i32, art: &StoredArt) -> Result<FullCardData> {
+        debug!("set_card_art: {} {}", card_id, art.hash);
+
+        // Only the art is written, so that a card with other fields that no
+        // longer validate can still be given art.
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            use crate::schema::cards::dsl::*;
+
+            let updated = diesel::update(cards.find(card_id))
+                .set(image_url.eq(&art.image_url))
+                .execute(self.connection.as_ref())?;
+            if updated == 0 {
+                Err(ClientError::ResourceNotFound)?
+            }
+
+            self.bump_card_version(card_id)
+        })?;
+
+        self.get_full_card_data(card_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::database::DatabaseContext;
+    use crate::errors::ClientError;
+    use crate::image::art::{
+        download_art, is_public_address, parse_art_url, process_art, ArtStore, ART_HEIGHT,
+        ART_SCALE, ART_WIDTH,
+    };
+
+    use ::image::codecs::png::PngEncoder;
+    use ::image::{ColorType, ImageEncoder, Rgba, RgbaImage};
+
+    #[test]
+    fn given_uploaded_art_when_processed_then_checked_and_cropped_to_art_box() {
+        // Tall art, red at the top, blue in the middle and green at the bottom.
+        let art = RgbaImage::from_fn(100, 300, |_, y| match y {
+            0..=99 => Rgba([0xFF, 0, 0, 0xFF]),
+            100..=199 => Rgba([0, 0, 0xFF, 0xFF]),
+            _ => Rgba([0, 0xFF, 0, 0xFF]),
+        });
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(art.as_raw(), 100, 300, ColorType::Rgba8)
+            .unwrap();
+
+        let processed = process_art(&png).unwrap();
+        assert_eq!(
+            processed.dimensions(),
+            (ART_WIDTH * ART_SCALE, ART_HEIGHT * ART_SCALE)
+        );
+        // Only the middle of the art is kept.
+        assert_eq!(processed.get_pixel(0, 0), &Rgba([0, 0, 0xFF, 0xFF]));
+
+        assert!(process_art(b"<html>not art</html>").is_err());
+        assert!(process_art(&png[..png.len() / 2]).is_err());
+
+        let hash = "ab".repeat(32);
+        assert_eq!(parse_art_url(&format!("art:{}", hash)), Some(hash.as_str()));
+        assert_eq!(parse_art_url("art:../../etc/passwd"), None);
+        let art = ArtStore::new("runtime/data/databases/missing");
+        assert_eq!(art.src(Some("https://example.com/art.png")), "");
+        assert_eq!(art.src(Some(&format!("art:{}", hash))), "");
+        assert_eq!(art.src(Some("file:///etc/passwd")), "");
+        assert_eq!(art.src(Some("/etc/passwd")), "");
+    }
+
+    #[test]
+    fn given_card_with_unknown_class_when_art_set_then_art_and_version_written() {
+        let directory = std::env::temp_dir().join(format!("art-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let art_store = ArtStore::new(&directory.to_string_lossy());
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy())
+            .unwrap()
+            .with_art_store(art_store.clone());
+        for query in &[
+            "CREATE TABLE cards (id INTEGER PRIMARY KEY, cardclass TEXT NOT NULL, \
+            action TEXT NOT NULL, speed TEXT NOT NULL, initiative INTEGER NOT NULL, \
+            name TEXT NOT NULL, desc TEXT NOT NULL, image_url TEXT, \
+            version INTEGER NOT NULL, cloned_from_id INTEGER)",
+            "CREATE TABLE card_attributes (id INTEGER PRIMARY KEY, name TEXT NOT NULL, \
+            [order] INTEGER NOT NULL)",
+            "CREATE TABLE cards_card_attributes_relation (id INTEGER PRIMARY KEY, \
+            card_id INTEGER NOT NULL, card_attribute_id INTEGER NOT NULL)",
+            "CREATE TABLE card_relations (id INTEGER PRIMARY KEY, card_id INTEGER NOT NULL, \
+            related_card_id INTEGER NOT NULL, kind TEXT NOT NULL)",
+            "INSERT INTO cards VALUES (1, 'Zz', 'Attack', 'Fast', 99, 'Lunge', '', NULL, 4, NULL)",
+        ] {
+            sql_query(*query).execute(db.connection.as_ref()).unwrap();
+        }
+
+        let art = art_store
+            .store(&RgbaImage::from_pixel(16, 16, Rgba([0xFF, 0, 0, 0xFF])))
+            .unwrap();
+        let card = db.set_card_art(1, &art).unwrap();
+
+        assert_eq!(card.image_url, Some(art.image_url.clone()));
+        assert_eq!(card.version, 5);
+        assert_eq!(card.cardclass, "Zz");
+        assert!(db.set_card_art(2, &art).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_internal_addresses_when_checked_then_art_not_downloaded_from_them() {
+        for address in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public_address(address.parse().unwrap()), "{}", address);
+        }
+
+        assert!(is_public_address("93.184.216.34".parse().unwrap()));
+        assert!(is_public_address("2606:2800:220:1::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn given_host_resolving_to_loopback_when_downloaded_then_refused_before_connecting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for url in [
+            format!("http://localhost:{}/art.png", port),
+            format!("http://127.0.0.1:{}/art.png", port),
+        ] {
+            let (url, result) = actix_rt::System::new("art-test").block_on(async move {
+                let result = download_art(&url).await;
+                (url, result)
+            });
+
+            match result {
+                Err(ClientError::InvalidInput(message)) => {
+                    assert!(
+                        message.contains("not on the public internet"),
+                        "{}",
+                        message
+                    )
+                }
+                other => panic!("{} was not refused: {:?}", url, other),
+            }
+        }
+
+        assert!(listener.accept().is_err());
+    }
+}
@@ -0,0 +1,538 @@
+//! A cache of rendered card and deck images.
+//!
+//! Images are stored under a key that hashes everything they are drawn
+//! from: the cards, the card classes, the templates and stylesheet, the
+//! themes of the cards, and the renderer. Any change to those, such as
+//! `update_card` bumping a card's version, leads to a new key, so a stale
+//! image is never served. Whenever a card is written, however it is
+//! written, the images that show it are also dropped: its own, those of
+//! the cards that list it among their relations, and the cardsheets of the
+//! decks it is in. The oldest images are evicted once the cache grows past
+//! its size limit.
+//!
+//! Keys double as strong ETags, since equal keys mean equal images.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::{debug, info};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::card_classes::CardClassRegistry;
+use crate::card_themes::{CardThemes, Theme};
+use crate::database::DatabaseContext;
+use crate::image::markup::ICONS;
+use crate::models::{Card, CardRelationEntry};
+
+/// The files in `static/templates` that cards are rendered from.
+const TEMPLATE_FILES: &[&str] = &[
+    "common.html",
+    "macro-card.html",
+    "single-card.html",
+    "cardsheet.html",
+    "card.css",
+];
+const TEMPLATES_DIRECTORY: &str = "static/templates";
+
+/// Held while images are looked up, added, copied out of the cache or
+/// evicted, as request handlers and render workers use the cache at once.
+static CACHE_LOCK: Mutex<()> = Mutex::new(());
+
+/// What a cached image is of. Each has its own directory in the cache, so
+/// that all of its images can be dropped at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderScope {
+    Card(i32),
+    Deck(String),
+    /// The cards matched by a search query.
+    Query(String),
+    /// Stored art, by its hash.
+    Art(String),
+}
+
+impl RenderScope {
+    fn directory(&self) -> PathBuf {
+        match self {
+            RenderScope::Card(card_id) => Path::new("cards").join(card_id.to_string()),
+            // Deck names and queries can hold anything, so they are hashed
+            // into names that are safe on every filesystem.
+            RenderScope::Deck(deck_name) => {
+                Path::new("decks").join(render_key(&[deck_name.as_bytes()]))
+            }
+            RenderScope::Query(query) => Path::new("queries").join(render_key(&[query.as_bytes()])),
+            RenderScope::Art(hash) => Path::new("art").join(hash),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CardRenderInput<'a> {
+    card: &'a Card,
+    theme: Option<&'a Theme>,
+    cardclass_long: String,
+    cardclass_css: String,
+    relations: &'a [CardRelationEntry],
+}
+
+/// Hashes the parts of a render into a key. Parts are length-prefixed so
+/// that moving bytes from one part to the next changes the key.
+pub fn render_key(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn templates_digest() -> Vec<u8> {
+    TEMPLATE_FILES
+        .iter()
+        .flat_map(|file| {
+            std::fs::read(Path::new(TEMPLATES_DIRECTORY).join(file)).unwrap_or_default()
+        })
+        .chain(ICONS.digest())
+        .collect()
+}
+
+/// The key of the image of `card` as drawn from `theme` by the renderer
+/// called `renderer_name`.
+pub fn card_render_key(
+    renderer_name: &str,
+    theme: Option<&Theme>,
+    card: &Card,
+    card_classes: &CardClassRegistry,
+    relations: &[CardRelationEntry],
+) -> Result<String> {
+    let input = serde_json::to_vec(&CardRenderInput {
+        card,
+        theme,
+        cardclass_long: card_classes.display_name(&card.cardclass),
+        cardclass_css: card_classes.to_css(),
+        relations,
+    })?;
+
+    Ok(render_key(&[
+        renderer_name.as_bytes(),
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        &templates_digest(),
+        &input,
+    ]))
+}
+
+/// The key of an image under `key` drawn `scale` times its usual size.
+/// Images at their usual size keep their key.
+pub fn scaled_render_key(key: &str, scale: f32) -> String {
+    if scale == 1.0 {
+        key.to_owned()
+    } else {
+        render_key(&[key.as_bytes(), &scale.to_le_bytes()])
+    }
+}
+
+/// The key of the cardsheet of `cards` as drawn from `themes` by the
+/// renderer called `renderer_name`.
+pub fn cardsheet_render_key(
+    renderer_name: &str,
+    cards: &[Card],
+    card_classes: &CardClassRegistry,
+    themes: &CardThemes,
+) -> Result<String> {
+    let inputs = cards
+        .iter()
+        .map(|card| CardRenderInput {
+            card,
+            theme: themes.theme_of(card),
+            cardclass_long: card_classes.display_name(&card.cardclass),
+            cardclass_css: String::new(),
+            relations: &[],
+        })
+        .collect::<Vec<CardRenderInput>>();
+
+    Ok(render_key(&[
+        renderer_name.as_bytes(),
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        &templates_digest(),
+        card_classes.to_css().as_bytes(),
+        &serde_json::to_vec(&inputs)?,
+    ]))
+}
+
+/// The key of the `sheet_id`th Tabletop Simulator sheet of `cards`, which
+/// also shows the hidden card image.
+pub fn tts_sheet_render_key(
+    renderer_name: &str,
+    sheet_id: usize,
+    cards: &[Card],
+    card_classes: &CardClassRegistry,
+    themes: &CardThemes,
+) -> Result<String> {
+    let hidden_image = std::fs::read(crate::image::HIDDEN_CARD_FILE_PATH)
+        .or_else(|_| std::fs::read(crate::image::CARD_BACK_FILE_PATH))
+        .unwrap_or_default();
+
+    Ok(render_key(&[
+        "tts".as_bytes(),
+        sheet_id.to_string().as_bytes(),
+        cardsheet_render_key(renderer_name, cards, card_classes, themes)?.as_bytes(),
+        &hidden_image,
+    ]))
+}
+
+/// The strong ETag of the image under `key`.
+pub fn render_etag(key: &str) -> String {
+    format!("\"{}\"", key)
+}
+
+/// A cached image, with the size and last use that eviction goes by.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+/// Picks the least recently used entries to delete so that the rest fit in
+/// `max_bytes`.
+pub fn select_evictions(mut entries: Vec<CacheEntry>, max_bytes: u64) -> Vec<CacheEntry> {
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+    entries.sort_by_key(|entry| entry.last_used);
+
+    entries
+        .into_iter()
+        .take_while(|entry| {
+            let evict = total > max_bytes;
+            total = total.saturating_sub(entry.size);
+            evict
+        })
+        .collect()
+}
+
+pub struct RenderCache {
+    directory: PathBuf,
+    max_bytes: u64,
+}
+
+impl RenderCache {
+    /// The cache of the images rendered into `render_directory`.
+    pub fn new(render_directory: &str, max_bytes: u64) -> Self {
+        Self {
+            directory: Path::new(render_directory).join("cache"),
+            max_bytes,
+        }
+    }
+
+    fn entry_path(&self, scope: &RenderScope, key: &str, extension: &str) -> PathBuf {
+        self.directory
+            .join(scope.directory())
+            .join(format!("{}.{}", key, extension))
+    }
+
+    /// Returns the cached image under `key`, rendering it with `render` if
+    /// it is not cached yet. `render` returns the path it rendered to.
+    pub fn get_or_render<F: FnOnce() -> Result<String>>(
+        &self,
+        scope: &RenderScope,
+        key: &str,
+        render: F,
+    ) -> Result<PathBuf> {
+        let path = self.entry_path(scope, key, "png");
+
+        {
+            let _lock = CACHE_LOCK.lock().unwrap();
+            if self.touch(scope, &path)? {
+                return Ok(path);
+            }
+        }
+
+        let rendered_path = render()?;
+        let _lock = CACHE_LOCK.lock().unwrap();
+        self.insert(&path, &rendered_path)?;
+
+        Ok(path)
+    }
+
+    /// Returns the cached image under `key`, with the given file
+    /// `extension`, encoding it with `encode` if it is not cached yet.
+    /// `encode` returns the bytes of the image.
+    pub fn get_or_encode<F: FnOnce() -> Result<Vec<u8>>>(
+        &self,
+        scope: &RenderScope,
+        key: &str,
+        extension: &str,
+        encode: F,
+    ) -> Result<PathBuf> {
+        let path = self.entry_path(scope, key, extension);
+
+        {
+            let _lock = CACHE_LOCK.lock().unwrap();
+            if self.touch(scope, &path)? {
+                return Ok(path);
+            }
+        }
+
+        let encoded = encode()?;
+        let _lock = CACHE_LOCK.lock().unwrap();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, encoded)?;
+        self.evict(&path)?;
+
+        Ok(path)
+    }
+
+    /// Copies the cached image under `key` to `destination`, rendering it
+    /// with `render` if it is not cached yet. Unlike with `get_or_render`,
+    /// the image cannot be evicted before it is copied.
+    pub fn copy_or_render<F: FnOnce() -> Result<String>>(
+        &self,
+        scope: &RenderScope,
+        key: &str,
+        render: F,
+        destination: &Path,
+    ) -> Result<()> {
+        let path = self.entry_path(scope, key, "png");
+
+        {
+            let _lock = CACHE_LOCK.lock().unwrap();
+            if self.touch(scope, &path)? {
+                std::fs::copy(&path, destination)?;
+                return Ok(());
+            }
+        }
+
+        let rendered_path = render()?;
+        let _lock = CACHE_LOCK.lock().unwrap();
+        self.insert(&path, &rendered_path)?;
+        std::fs::copy(&path, destination)?;
+
+        Ok(())
+    }
+
+    /// Marks the image at `path` as used, if it is cached. Must be called
+    /// with `CACHE_LOCK` held.
+    fn touch(&self, scope: &RenderScope, path: &Path) -> Result<bool> {
+        if !path.exists() {
+            debug!("Render cache miss for {:?}", scope);
+            return Ok(false);
+        }
+
+        debug!("Render cache hit for {:?} at {:?}", scope, path);
+
+        // Eviction goes by modification time, so mark the entry as used.
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now())?;
+
+        Ok(true)
+    }
+
+    /// Moves the image rendered to `rendered_path` to `path`, evicting
+    /// others to make room. Must be called with `CACHE_LOCK` held.
+    fn insert(&self, path: &Path, rendered_path: &str) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        if std::fs::rename(rendered_path, path).is_err() {
+            std::fs::copy(rendered_path, path)?;
+            std::fs::remove_file(rendered_path)?;
+        }
+
+        self.evict(path)
+    }
+
+    /// Drops every cached image of `scope`.
+    pub fn invalidate(&self, scope: &RenderScope) -> Result<()> {
+        let _lock = CACHE_LOCK.lock().unwrap();
+        let directory = self.directory.join(scope.directory());
+
+        if directory.exists() {
+            info!("Invalidating the cached images of {:?}", scope);
+            std::fs::remove_dir_all(directory)?;
+        }
+
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+
+        for kind in &["cards", "decks", "queries", "art"] {
+            let kind_directory = self.directory.join(kind);
+            if !kind_directory.exists() {
+                continue;
+            }
+
+            for scope_directory in std::fs::read_dir(kind_directory)? {
+                for file in std::fs::read_dir(scope_directory?.path())? {
+                    let file = file?;
+                    let metadata = file.metadata()?;
+
+                    entries.push(CacheEntry {
+                        path: file.path(),
+                        size: metadata.len(),
+                        last_used: metadata.modified()?,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Evicts images until the cache fits in its size limit, except for the
+    /// image at `keep`, which is about to be served.
+    fn evict(&self, keep: &Path) -> Result<()> {
+        let (kept, entries): (Vec<CacheEntry>, Vec<CacheEntry>) = self
+            .entries()?
+            .into_iter()
+            .partition(|entry| entry.path == keep);
+        let kept_size = kept.iter().map(|entry| entry.size).sum::<u64>();
+
+        for entry in select_evictions(entries, self.max_bytes.saturating_sub(kept_size)) {
+            debug!("Evicting {:?} from the render cache", entry.path);
+            std::fs::remove_file(&entry.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DatabaseContext {
+    /// Drops the cached images that show `card_id`, after it was written.
+    /// Connections without a render cache have nothing to drop.
+    pub fn invalidate_card_renders(&self, card_id: i32) -> Result<()> {
+        use crate::schema::{card_relations, decks, decks_cards_relation};
+
+        let render_cache = match &self.render_cache {
+            Some(render_cache) => render_cache,
+            None => return Ok(()),
+        };
+
+        // Relations are listed on cards by the name of the related card.
+        let relating_card_ids = card_relations::table
+            .filter(card_relations::related_card_id.eq(card_id))
+            .select(card_relations::card_id)
+            .load::<i32>(self.connection.as_ref())?;
+
+        for scope_card_id in std::iter::once(card_id).chain(relating_card_ids) {
+            render_cache.invalidate(&RenderScope::Card(scope_card_id))?;
+        }
+
+        let deck_ids = decks_cards_relation::table
+            .filter(decks_cards_relation::card_id.eq(card_id))
+            .select(decks_cards_relation::deck_id)
+            .load::<i32>(self.connection.as_ref())?;
+        let deck_names = decks::table
+            .filter(decks::id.eq_any(deck_ids))
+            .select(decks::name)
+            .load::<String>(self.connection.as_ref())?;
+
+        for deck_name in deck_names {
+            render_cache.invalidate(&RenderScope::Deck(deck_name))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::database::DatabaseContext;
+    use crate::image::cache::{select_evictions, CacheEntry, RenderCache, RenderScope};
+
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn given_cache_over_limit_when_select_evictions_then_least_recently_used_evicted() {
+        let entry = |name: &str, size: u64, age: u64| CacheEntry {
+            path: PathBuf::from(name),
+            size,
+            last_used: SystemTime::UNIX_EPOCH + Duration::from_secs(1000 - age),
+        };
+
+        let evicted = select_evictions(
+            vec![
+                entry("new", 40, 1),
+                entry("old", 40, 30),
+                entry("mid", 40, 10),
+            ],
+            90,
+        );
+
+        assert_eq!(
+            evicted
+                .iter()
+                .map(|entry| entry.path.to_str().unwrap())
+                .collect::<Vec<&str>>(),
+            vec!["old"]
+        );
+        assert!(select_evictions(vec![entry("new", 40, 1)], 90).is_empty());
+    }
+
+    #[test]
+    fn given_cached_renders_when_card_written_then_renders_showing_it_dropped() {
+        let directory =
+            std::env::temp_dir().join(format!("render-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let render_cache = RenderCache::new(&directory.to_string_lossy(), u64::MAX);
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy())
+            .unwrap()
+            .with_render_cache(RenderCache::new(&directory.to_string_lossy(), u64::MAX));
+        for statement in &[
+            "CREATE TABLE card_relations (id INTEGER PRIMARY KEY, card_id INTEGER, \
+             related_card_id INTEGER, kind TEXT)",
+            "CREATE TABLE decks (id INTEGER PRIMARY KEY, decktype TEXT, name TEXT, \
+             cloned_from_id INTEGER)",
+            "CREATE TABLE decks_cards_relation (id INTEGER PRIMARY KEY, deck_id INTEGER, \
+             card_id INTEGER, quantity INTEGER, position INTEGER)",
+            "INSERT INTO card_relations VALUES (1, 2, 1, 'counters')",
+            "INSERT INTO decks VALUES (1, 'user', 'Starter', NULL), (2, 'user', 'Other', NULL)",
+            "INSERT INTO decks_cards_relation VALUES (1, 1, 1, 1, 0), (2, 2, 3, 1, 0)",
+        ] {
+            sql_query(*statement)
+                .execute(db.connection.as_ref())
+                .unwrap();
+        }
+
+        let scopes = [
+            RenderScope::Card(1),
+            RenderScope::Card(2),
+            RenderScope::Card(3),
+            RenderScope::Deck("Starter".to_owned()),
+            RenderScope::Deck("Other".to_owned()),
+        ];
+        let cached = scopes
+            .iter()
+            .map(|scope| {
+                let source = directory.join("render.png");
+                std::fs::write(&source, b"png").unwrap();
+                render_cache
+                    .get_or_render(scope, "key", || Ok(source.to_string_lossy().into_owned()))
+                    .unwrap()
+            })
+            .collect::<Vec<PathBuf>>();
+
+        db.invalidate_card_renders(1).unwrap();
+
+        assert_eq!(
+            cached
+                .iter()
+                .map(|path| path.exists())
+                .collect::<Vec<bool>>(),
+            vec![false, false, true, false, true]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Fitting card text into its boxes.
+//!
+//! The description and the stats are drawn at the font sizes of
+//! `card.css` when they fit. Text that does not is stepped down in size,
+//! no further than a set minimum, and text that still does not fit is
+//! reported so that it can be shortened.
+//!
+//! Text is measured with the fonts built into the server, in the layout of
+//! the native renderer and the style of the card's theme, whichever
+//! renderer the server draws cards with.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::card_themes::CardThemes;
+use crate::database::DatabaseContext;
+use crate::image::native::NativeCardRenderer;
+use crate::image::style::CardStyle;
+use crate::models::Card;
+
+/// How much smaller each step down makes the text, in pixels.
+pub const FIT_STEP: f32 = 0.4;
+
+/// The sizes a piece of text may be drawn at, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FontSizeBounds {
+    pub max: f32,
+    pub min: f32,
+}
+
+/// The size a piece of text is drawn at.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FittedText {
+    pub size: f32,
+    /// Whether the text runs out of its box even at the smallest size.
+    pub overflows: bool,
+}
+
+/// Steps the font size down from `bounds.max` until `fits` says the text
+/// fits at that size, stopping at `bounds.min`.
+pub fn fit_font_size<F: Fn(f32) -> bool>(bounds: FontSizeBounds, fits: F) -> FittedText {
+    let mut size = bounds.max;
+
+    while !fits(size) {
+        if size <= bounds.min {
+            return FittedText {
+                size: bounds.min,
+                overflows: true,
+            };
+        }
+        size = (size - FIT_STEP).max(bounds.min);
+    }
+
+    FittedText {
+        size,
+        overflows: false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CardTextFit {
+    pub description: FittedText,
+    pub stats: FittedText,
+}
+
+impl CardTextFit {
+    pub fn overflows(&self) -> bool {
+        self.description.overflows || self.stats.overflows
+    }
+
+    /// Describes the text that does not fit.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.description.overflows {
+            warnings.push(format!(
+                "The description runs off the card even at {:.1}px",
+                self.description.size
+            ));
+        }
+        if self.stats.overflows {
+            warnings.push(format!(
+                "The speed and action do not fit side by side even at {:.1}px",
+                self.stats.size
+            ));
+        }
+
+        warnings
+    }
+}
+
+/// A card whose text does not fit.
+#[derive(Debug, Clone, Serialize)]
+pub struct CardTextLint {
+    pub card_id: i32,
+    pub name: String,
+    pub fit: CardTextFit,
+    pub warnings: Vec<String>,
+}
+
+impl CardTextLint {
+    /// The lint of `card`, if any of its text does not fit.
+    pub fn new(card: &Card, fit: CardTextFit) -> Option<Self> {
+        if !fit.overflows() {
+            return None;
+        }
+
+        Some(Self {
+            card_id: card.id,
+            name: card.name.clone(),
+            warnings: fit.warnings(),
+            fit,
+        })
+    }
+}
+
+impl DatabaseContext {
+    /// Lists every card whose text does not fit when drawn from its theme
+    /// in `themes`.
+    pub fn lint_card_text(&self, themes: &CardThemes) -> Result<Vec<CardTextLint>> {
+        use crate::schema::cards::dsl::*;
+
+        let measure = NativeCardRenderer::new()?;
+        let all_cards = cards
+            .order(id.asc())
+            .load::<Card>(self.connection.as_ref())?;
+
+        let mut lints = Vec::new();
+        for card in &all_cards {
+            let fit = measure.fit_text(card, &CardStyle::of_theme(themes.theme_of(card)));
+            lints.extend(CardTextLint::new(card, fit));
+        }
+
+        Ok(lints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::card_themes::{CardThemes, Theme, ThemeRegistry, ThemeSource};
+    use crate::database::DatabaseContext;
+    use crate::image::fit::{fit_font_size, FittedText, FontSizeBounds};
+
+    #[test]
+    fn given_text_when_fitted_then_stepped_down_within_bounds() {
+        let bounds = FontSizeBounds {
+            max: 12.0,
+            min: 10.0,
+        };
+        // Text 100 times as wide as its font is big, in a box `width` wide.
+        let fits_width = |width: f32| move |size: f32| size * 100.0 <= width;
+
+        assert_eq!(
+            fit_font_size(bounds, fits_width(1200.0)),
+            FittedText {
+                size: 12.0,
+                overflows: false
+            }
+        );
+
+        let shrunk = fit_font_size(bounds, fits_width(1100.0));
+        assert!(!shrunk.overflows);
+        assert!(shrunk.size <= 11.0 && shrunk.size > 10.5);
+
+        assert_eq!(
+            fit_font_size(bounds, fits_width(500.0)),
+            FittedText {
+                size: 10.0,
+                overflows: true
+            }
+        );
+    }
+
+    #[test]
+    fn given_cards_when_linted_then_text_measured_in_theme() {
+        let directory =
+            std::env::temp_dir().join(format!("card-text-lint-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+        let filler = "Deal 3 damage to every foe in range 2, then draw a card. ";
+        for statement in &[
+            "CREATE TABLE cards (id INTEGER PRIMARY KEY, cardclass TEXT, action TEXT, \
+             speed TEXT, initiative INTEGER, name TEXT, desc TEXT, image_url TEXT, \
+             version INTEGER, cloned_from_id INTEGER)"
+                .to_owned(),
+            format!(
+                "INSERT INTO cards VALUES \
+                 (1, 'ranger', 'Attack', 'Fast', 1, 'Jab', 'Hit.', NULL, 1, NULL), \
+                 (2, 'ranger', 'Attack', 'Normal', 2, 'Volley', '{}', NULL, 1, NULL), \
+                 (3, 'ranger', 'Move', 'Slow', 3, 'Saga', '{}', NULL, 1, NULL)",
+                filler.repeat(2),
+                filler.repeat(20)
+            ),
+        ] {
+            sql_query(statement.as_str())
+                .execute(db.connection.as_ref())
+                .unwrap();
+        }
+
+        let linted_ids = |themes: &CardThemes| {
+            db.lint_card_text(themes)
+                .unwrap()
+                .iter()
+                .map(|lint| lint.card_id)
+                .collect::<Vec<i32>>()
+        };
+
+        assert_eq!(linted_ids(&CardThemes::default()), vec![3]);
+
+        let large_print = ThemeRegistry::new(vec![Theme {
+            name: "large-print".to_owned(),
+            cardclasses: vec!["ranger".to_owned()],
+            template: String::new(),
+            css: ".description { font-size: 40px; }".to_owned(),
+            source: ThemeSource::Disk,
+        }]);
+        assert_eq!(
+            linted_ids(&CardThemes::new(large_print.clone(), None).unwrap()),
+            vec![2, 3]
+        );
+        assert_eq!(
+            linted_ids(&CardThemes::new(large_print, Some("default")).unwrap()),
+            vec![3]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+//! The markup of card descriptions.
+//!
+//! Descriptions may use:
+//!
+//! - `**bold**` and `*italic*` text,
+//! - symbol tokens such as `{ATK}` and `{RANGE}`, drawn as the icons of the
+//!   same name in `static/templates/icons`,
+//! - line breaks, which are kept as written,
+//! - `\` before a `*`, `{`, `}` or `\` to write it as it is.
+//!
+//! A marker without a partner on the same line, or a token without an
+//! icon, is written as it is. Descriptions are parsed into spans, which are
+//! turned into escaped HTML for the templates and into drawing commands for
+//! the native renderer.
+
+use ::image::RgbaImage;
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const ICONS_DIRECTORY: &str = "static/templates/icons";
+
+lazy_static! {
+    /// The icons in `ICONS_DIRECTORY`, read once when first used.
+    pub static ref ICONS: IconRegistry = IconRegistry::from_directory(Path::new(ICONS_DIRECTORY));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextStyle {
+    pub bold: bool,
+    pub italic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Span {
+    Text(String, TextStyle),
+    /// A symbol token, by its upper-case name.
+    Icon(String),
+    LineBreak,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Bold,
+    Italic,
+    Icon(String),
+}
+
+fn is_symbol_token(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn tokenize_line(line: &str) -> Vec<Token> {
+    let chars = line.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+
+    let flush = |text: &mut String, tokens: &mut Vec<Token>| {
+        if !text.is_empty() {
+            tokens.push(Token::Text(std::mem::take(text)));
+        }
+    };
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if matches!(chars.get(i + 1), Some('*' | '{' | '}' | '\\')) => {
+                text.push(chars[i + 1]);
+                i += 2;
+            }
+            '*' => {
+                flush(&mut text, &mut tokens);
+                if chars.get(i + 1) == Some(&'*') {
+                    tokens.push(Token::Bold);
+                    i += 2;
+                } else {
+                    tokens.push(Token::Italic);
+                    i += 1;
+                }
+            }
+            '{' => {
+                let token = chars[i + 1..]
+                    .iter()
+                    .position(|c| *c == '}')
+                    .map(|end| chars[i + 1..i + 1 + end].iter().collect::<String>())
+                    .filter(|token| is_symbol_token(token));
+
+                match token {
+                    Some(token) => {
+                        flush(&mut text, &mut tokens);
+                        i += token.chars().count() + 2;
+                        tokens.push(Token::Icon(token.to_uppercase()));
+                    }
+                    None => {
+                        text.push('{');
+                        i += 1;
+                    }
+                }
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    flush(&mut text, &mut tokens);
+    tokens
+}
+
+/// Turns the last of an odd number of `marker`s back into text.
+fn unpair_last(tokens: &mut [Token], marker: Token, text: &str) {
+    let positions = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| **token == marker)
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
+    if positions.len() % 2 == 1 {
+        tokens[*positions.last().unwrap()] = Token::Text(text.to_string());
+    }
+}
+
+/// Parses a description into spans.
+pub fn parse_markup(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        if line_index > 0 {
+            spans.push(Span::LineBreak);
+        }
+
+        let mut tokens = tokenize_line(line);
+        unpair_last(&mut tokens, Token::Bold, "**");
+        unpair_last(&mut tokens, Token::Italic, "*");
+
+        let mut style = TextStyle::default();
+        for token in tokens {
+            match token {
+                Token::Bold => style.bold = !style.bold,
+                Token::Italic => style.italic = !style.italic,
+                Token::Icon(token) => spans.push(Span::Icon(token)),
+                Token::Text(text) => match spans.last_mut() {
+                    Some(Span::Text(previous, previous_style)) if *previous_style == style => {
+                        previous.push_str(&text)
+                    }
+                    _ => spans.push(Span::Text(text, style)),
+                },
+            }
+        }
+    }
+
+    spans
+}
+
+pub struct Icon {
+    pub image: RgbaImage,
+    /// The icon file, for embedding into HTML.
+    png: Vec<u8>,
+}
+
+/// The icons that symbol tokens are drawn with, by token.
+#[derive(Default)]
+pub struct IconRegistry {
+    icons: BTreeMap<String, Icon>,
+}
+
+impl IconRegistry {
+    /// Reads every PNG in `directory` as the icon of the token of the same
+    /// name, so `atk.png` is drawn for `{ATK}`.
+    pub fn from_directory(directory: &Path) -> Self {
+        let mut registry = Self::default();
+
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return registry,
+        };
+
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            let token = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(token) if path.extension().and_then(|ext| ext.to_str()) == Some("png") => {
+                    token
+                }
+                _ => continue,
+            };
+
+            if let Err(err) = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|png| registry.insert(token, png))
+            {
+                warn!("Could not load the icon at {:?}: {}", path, err);
+            }
+        }
+
+        registry
+    }
+
+    pub fn insert(&mut self, token: &str, png: Vec<u8>) -> anyhow::Result<()> {
+        let image = ::image::load_from_memory(&png)?.to_rgba8();
+        self.icons.insert(token.to_uppercase(), Icon { image, png });
+        Ok(())
+    }
+
+    pub fn get(&self, token: &str) -> Option<&Icon> {
+        self.icons.get(token)
+    }
+
+    /// Every icon, for the keys of rendered cards.
+    pub fn digest(&self) -> Vec<u8> {
+        self.icons
+            .iter()
+            .flat_map(|(token, icon)| token.bytes().chain(icon.png.iter().copied()))
+            .collect()
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#x27;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Writes spans as HTML. Text is escaped, and icons are embedded so that
+/// the HTML renders the same from anywhere.
+pub fn markup_to_html(spans: &[Span], icons: &IconRegistry) -> String {
+    spans
+        .iter()
+        .map(|span| match span {
+            Span::Text(text, style) => {
+                let mut html = escape_html(text);
+                if style.italic {
+                    html = format!("<em>{}</em>", html);
+                }
+                if style.bold {
+                    html = format!("<strong>{}</strong>", html);
+                }
+                html
+            }
+            Span::Icon(token) => match icons.get(token) {
+                Some(icon) => format!(
+                    r#"<img class="card-icon" alt="{{{}}}" src="data:image/png;base64,{}" />"#,
+                    token,
+                    base64::encode(&icon.png)
+                ),
+                None => escape_html(&format!("{{{}}}", token)),
+            },
+            Span::LineBreak => "<br />\n".to_string(),
+        })
+        .collect()
+}
+
+/// One thing to draw, placed relative to the top left of its text box.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DrawCommand {
+    Text {
+        x: f32,
+        y: f32,
+        text: String,
+        style: TextStyle,
+    },
+    /// A square icon, as large as the text it sits in.
+    Icon {
+        x: f32,
+        y: f32,
+        size: f32,
+        token: String,
+    },
+}
+
+enum Piece<'a> {
+    Text(&'a str, TextStyle),
+    Icon(&'a str),
+    Space,
+    LineBreak,
+}
+
+/// Lays spans out into lines no wider than `max_width`, `line_height`
+/// apart, breaking lines between words as `wrap_text` does. `measure`
+/// gives the width of text in a style; icons are `font_size` wide.
+pub fn layout_markup<F: Fn(&str, TextStyle) -> f32>(
+    spans: &[Span],
+    icons: &IconRegistry,
+    max_width: f32,
+    font_size: f32,
+    line_height: f32,
+    measure: F,
+) -> Vec<DrawCommand> {
+    let mut pieces = Vec::new();
+    for span in spans {
+        match span {
+            Span::Text(text, style) => {
+                for (i, word) in text.split(' ').enumerate() {
+                    if i > 0 {
+                        pieces.push(Piece::Space);
+                    }
+                    if !word.is_empty() {
+                        pieces.push(Piece::Text(word, *style));
+                    }
+                }
+            }
+            Span::Icon(token) => pieces.push(Piece::Icon(token)),
+            Span::LineBreak => pieces.push(Piece::LineBreak),
+        }
+    }
+
+    let piece_width = |piece: &Piece| match piece {
+        Piece::Text(text, style) => measure(text, *style),
+        Piece::Icon(token) if icons.get(token).is_some() => font_size,
+        Piece::Icon(token) => measure(&format!("{{{}}}", token), TextStyle::default()),
+        _ => 0.0,
+    };
+    let space_width = measure(" ", TextStyle::default());
+
+    let mut commands = Vec::new();
+    let (mut x, mut y) = (0.0, 0.0);
+    let mut pending_space = false;
+    let mut start = 0;
+
+    while start < pieces.len() {
+        match pieces[start] {
+            Piece::Space => {
+                pending_space = true;
+                start += 1;
+                continue;
+            }
+            Piece::LineBreak => {
+                x = 0.0;
+                y += line_height;
+                pending_space = false;
+                start += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // A word is every piece up to the next space, such as an icon and
+        // the number after it.
+        let end = pieces[start..]
+            .iter()
+            .position(|piece| matches!(piece, Piece::Space | Piece::LineBreak))
+            .map_or(pieces.len(), |end| start + end);
+        let word_width = pieces[start..end].iter().map(&piece_width).sum::<f32>();
+
+        if x > 0.0 && x + space_width + word_width > max_width {
+            x = 0.0;
+            y += line_height;
+        } else if x > 0.0 && pending_space {
+            x += space_width;
+        }
+        pending_space = false;
+
+        for piece in &pieces[start..end] {
+            match piece {
+                Piece::Text(text, style) => commands.push(DrawCommand::Text {
+                    x,
+                    y,
+                    text: text.to_string(),
+                    style: *style,
+                }),
+                Piece::Icon(token) => commands.push(match icons.get(token) {
+                    Some(_) => DrawCommand::Icon {
+                        x,
+                        y,
+                        size: font_size,
+                        token: token.to_string(),
+                    },
+                    None => DrawCommand::Text {
+                        x,
+                        y,
+                        text: format!("{{{}}}", token),
+                        style: TextStyle::default(),
+                    },
+                }),
+                _ => {}
+            }
+            x += piece_width(piece);
+        }
+
+        start = end;
+    }
+
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::markup::{
+        layout_markup, markup_to_html, parse_markup, DrawCommand, IconRegistry, Span, TextStyle,
+    };
+
+    #[test]
+    fn given_description_markup_when_parsed_then_html_escaped_and_laid_out() {
+        let bold = TextStyle {
+            bold: true,
+            italic: false,
+        };
+
+        let spans = parse_markup("Deal **3** {atk} to <b>\n*Range* {RANGE} 2* \\*");
+        assert_eq!(
+            spans,
+            vec![
+                Span::Text("Deal ".to_owned(), TextStyle::default()),
+                Span::Text("3".to_owned(), bold),
+                Span::Text(" ".to_owned(), TextStyle::default()),
+                Span::Icon("ATK".to_owned()),
+                Span::Text(" to <b>".to_owned(), TextStyle::default()),
+                Span::LineBreak,
+                Span::Text(
+                    "Range".to_owned(),
+                    TextStyle {
+                        bold: false,
+                        italic: true
+                    }
+                ),
+                Span::Text(" ".to_owned(), TextStyle::default()),
+                Span::Icon("RANGE".to_owned()),
+                Span::Text(" 2* *".to_owned(), TextStyle::default()),
+            ]
+        );
+
+        let icons = IconRegistry::default();
+        assert_eq!(
+            markup_to_html(&spans[..5], &icons),
+            "Deal <strong>3</strong> {ATK} to &lt;b&gt;"
+        );
+
+        // Every character is 1 wide.
+        let commands = layout_markup(&spans, &icons, 12.0, 1.0, 2.0, |s, _| {
+            s.chars().count() as f32
+        });
+        assert_eq!(
+            commands[1],
+            DrawCommand::Text {
+                x: 5.0,
+                y: 0.0,
+                text: "3".to_owned(),
+                style: bold
+            }
+        );
+        assert!(commands.iter().any(|command| matches!(command,
+            DrawCommand::Text { y, text, .. } if text == "Range" && *y == 4.0)));
+    }
+}
@@ -3,111 +3,193 @@ extern crate log;
 extern crate regex;
 extern crate reqwest;
 
+pub mod art;
+pub mod cache;
+pub mod fit;
+pub mod markup;
+pub mod native;
+pub mod output;
+pub mod renderer;
+pub mod style;
 pub mod templates;
+pub mod tts;
 
-use crate::image::templates::{CardsheetTemplate, SingleCardTemplate};
-use crate::models::Card;
+use crate::card_themes::{CardThemes, Theme};
+use crate::image::renderer::{CardAssets, CardRenderer};
+use crate::image::templates::SingleCardTemplate;
+use crate::models::{Card, CardRelationEntry};
 
 use askama::Template;
 
-use anyhow::Result;
+use std::path::Path;
 
-use log::{debug, info, warn};
+use anyhow::Result;
 
-use std::fs::File;
-use std::io::Write;
+use log::{debug, info};
 
 pub const CARD_FRONT_FILE_PATH: &str = "static/templates/card_front.png";
 pub const CARD_BACK_FILE_PATH: &str = "static/templates/card_back.png";
-
-pub fn generate_card_image_html_string(card_info: &Card) -> Result<String> {
-    let substituted_template = SingleCardTemplate::new(card_info).render()?;
+/// Shown in Tabletop Simulator for cards in another player's hand. Without
+/// it, the card back is shown.
+pub const HIDDEN_CARD_FILE_PATH: &str = "static/templates/card_hidden.png";
+
+/// Renders a card into HTML. Any `relations` given are listed on the card.
+pub fn generate_card_image_html_string(
+    card_info: &Card,
+    assets: &CardAssets,
+    relations: &[CardRelationEntry],
+) -> Result<String> {
+    let substituted_template = SingleCardTemplate::new(card_info, assets)
+        .with_relations(relations)
+        .render()?;
 
     debug!("substituted into template: {:?}", substituted_template);
 
     Ok(substituted_template.to_string())
 }
 
-/// Returns the path of the image it generated.
-pub fn generate_card_image(card_info: &Card) -> Result<String> {
-    let substituted_template_string = generate_card_image_html_string(card_info)?;
+/// Renders a card into HTML with `theme` instead of the built-in template.
+pub fn generate_themed_card_image_html_string(
+    theme: &Theme,
+    card_info: &Card,
+    assets: &CardAssets,
+    relations: &[CardRelationEntry],
+) -> Result<String> {
+    let substituted_template =
+        theme.render(&SingleCardTemplate::new(card_info, assets).with_relations(relations))?;
 
-    let expected_image_path = format!("runtime/data/cards/images/{}.png", &card_info.id);
-    info!("expected image path: {:?}", expected_image_path);
+    debug!(
+        "substituted into theme {}: {:?}",
+        theme.name, substituted_template
+    );
 
-    // Write the substituted HTML into a file
-    let substituted_html_path =
-        format!("runtime/data/cards/images/templates/{}.html", &card_info.id);
-    std::fs::write(&substituted_html_path, &substituted_template_string)?;
+    Ok(substituted_template)
+}
 
-    debug!(
-        "finished writing substituted html to: {:?}",
-        substituted_html_path
+/// Where a card image named `image_name` is rendered, under the
+/// `render_directory` of the card's database. Renders of the same card made
+/// at once are given different names, so that they do not overwrite each
+/// other.
+pub fn card_image_path(render_directory: &str, image_name: &str) -> String {
+    format!("{}/cards/images/{}.png", render_directory, image_name)
+}
+
+/// Renders a card into `expected_image_path`, `scale` times the size of a
+/// card and drawn from `theme`, or else the built-in template. Returns the
+/// path of the image.
+pub fn generate_card_image(
+    renderer: &dyn CardRenderer,
+    expected_image_path: String,
+    card_info: &Card,
+    assets: &CardAssets,
+    relations: &[CardRelationEntry],
+    theme: Option<&Theme>,
+    scale: f32,
+) -> Result<String> {
+    info!(
+        "expected image path: {:?}, renderer: {}",
+        expected_image_path,
+        renderer.name()
     );
 
-    // Spawn off a sub-process for wkhtmltoimage to convert the image.
-    generate_image_using_wkhtmltoimage(420, 300, &substituted_html_path, &expected_image_path)?;
+    renderer.render_card(
+        card_info,
+        assets,
+        relations,
+        theme,
+        scale,
+        &expected_image_path,
+    )?;
 
     // Once the image is generated, return the path to it.
-    Ok(expected_image_path.to_string())
+    Ok(expected_image_path)
 }
 
-pub fn generate_deck_cardsheet_image(deck_name: &str, cards: Vec<Card>) -> Result<String> {
-    let expected_image_path = format!("runtime/data/decks/images/{}.png", deck_name);
-    let substituted_html_path = format!("runtime/data/decks/images/templates/{}.html", deck_name);
-    let number_of_cards: usize = cards.len();
+/// Returns the path of the cardsheet it generated, each card `scale` times
+/// its usual size and drawn from its theme in `themes`.
+pub fn generate_deck_cardsheet_image(
+    renderer: &dyn CardRenderer,
+    render_directory: &str,
+    deck_name: &str,
+    cards: Vec<Card>,
+    assets: &CardAssets,
+    themes: &CardThemes,
+    scale: f32,
+) -> Result<String> {
+    let expected_image_path = format!("{}/decks/images/{}.png", render_directory, deck_name);
+    info!(
+        "expected image path: {:?}, renderer: {}",
+        expected_image_path,
+        renderer.name()
+    );
 
-    let substituted_template = CardsheetTemplate {
-        cards: cards
-            .into_iter()
-            .map(|card| SingleCardTemplate::new(&card))
-            .collect(),
-    }
-    .render()?;
+    renderer.render_cardsheet(&cards, assets, themes, scale, &expected_image_path)?;
 
-    debug!("substituted into template: {:?}", substituted_template);
-    info!("expected image path: {:?}", expected_image_path);
+    // Once the image is generated, return the path to it.
+    Ok(expected_image_path)
+}
 
-    // Write the substituted HTML into a file
-    std::fs::write(&substituted_html_path, &substituted_template)?;
+/// Renders the `sheet_id`th Tabletop Simulator sheet of a deck, holding
+/// `cards` and the hidden card image. Returns the path of the sheet.
+pub fn generate_tts_sheet_image(
+    renderer: &dyn CardRenderer,
+    render_directory: &str,
+    deck_name: &str,
+    sheet_id: usize,
+    cards: Vec<Card>,
+    assets: &CardAssets,
+    themes: &CardThemes,
+) -> Result<String> {
+    let sheet_name = format!("{}-tts-{}", deck_name, sheet_id);
+    let card_count = cards.len();
+    let cardsheet_path = generate_deck_cardsheet_image(
+        renderer,
+        render_directory,
+        &sheet_name,
+        cards,
+        assets,
+        themes,
+        1.0,
+    )?;
 
-    debug!(
-        "finished writing substituted html to: {:?}",
-        substituted_html_path
+    let hidden_path = if Path::new(HIDDEN_CARD_FILE_PATH).exists() {
+        HIDDEN_CARD_FILE_PATH
+    } else {
+        CARD_BACK_FILE_PATH
+    };
+
+    let sheet = tts::compose_tts_sheet(
+        &::image::open(&cardsheet_path)?.to_rgba8(),
+        card_count,
+        &::image::open(hidden_path)?.to_rgba8(),
     );
 
-    // Spawn off a sub-process for wkhtmltoimage to convert the image.
-    generate_image_using_wkhtmltoimage(
-        420 * std::cmp::max(
-            2,
-            number_of_cards / 10 + ((number_of_cards % 10 > 0) as usize),
-        ),
-        300 * 10,
-        &substituted_html_path,
-        &expected_image_path,
-    )?;
+    sheet.save(&cardsheet_path)?;
 
-    // Once the image is generated, return the path to it.
-    Ok(expected_image_path.to_string())
+    Ok(cardsheet_path)
 }
 
 pub fn generate_image_using_wkhtmltoimage(
     height: usize,
     width: usize,
-    substituted_html_path: &str,
+    zoom: f32,
+    substituted_html_path: &Path,
     output_path: &str,
 ) -> Result<()> {
-    // Spawn off a sub-process for wkhtmltoimage to convert the image.
+    // Spawn off a sub-process for wkhtmltoimage to convert the image. The
+    // page is zoomed as a whole, so that text stays sharp at any size.
     let child = std::process::Command::new("./wkhtmltoimage")
         .args(vec![
             "--height",
-            &height.to_string(),
+            &((height as f32 * zoom).round() as usize).to_string(),
             "--width",
-            &width.to_string(),
+            &((width as f32 * zoom).round() as usize).to_string(),
+            "--zoom",
+            &zoom.to_string(),
             "--enable-local-file-access",
-            substituted_html_path,
-            output_path,
         ])
+        .arg(substituted_html_path)
+        .arg(output_path)
         .output()?;
 
     if !child.status.success() {
@@ -119,50 +201,3 @@ pub fn generate_image_using_wkhtmltoimage(
 
     Ok(())
 }
-
-pub async fn retrieve_image(url: &str, card_id: i32) -> anyhow::Result<String> {
-    let url = reqwest::Url::parse(url)?;
-
-    debug!("parsed image url {:?}", &url);
-
-    let fname = format!("runtime/data/cards/images/{:?}-art.png", card_id);
-    let mut dest = File::create(&fname)?;
-
-    if url.scheme() == "file" {
-        let filename = &url.path()[1..];
-
-        debug!("reading from local file {:?}", &filename);
-        let content = std::fs::read(filename)?;
-
-        debug!("writing to {:?}", fname);
-        dest.write_all(&content[..])?;
-    } else {
-        debug!("request from url: {}", url);
-
-        let response = match reqwest::get(url.clone()).await {
-            Ok(res) => {
-                debug!("Found successful response");
-                res
-            }
-            Err(err) => {
-                warn!("Could not get image {:?}; error: {:?}", &url, err);
-                Err(err)?
-            }
-        };
-
-        debug!("response: {:?}", &response);
-
-        let mut content = response.bytes().await?;
-
-        debug!("found content: {:?}", content);
-
-        debug!("writing to {:?}", fname);
-        dest.write_all(&mut content)?;
-    };
-
-    dest.flush()?;
-
-    debug!("flushed to {:?}", fname);
-
-    Ok(fname.clone())
-}
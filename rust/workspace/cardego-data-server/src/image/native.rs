@@ -0,0 +1,613 @@
+//! Draws cards straight to PNG, following the layout of `macro-card.html`
+//! and `card.css`: a header with the class and initiative circles on either
+//! side of the name, the card art, then the speed and action and the
+//! description, with its markup and icons. The description and stats are
+//! made smaller to fit their boxes where they need to be. Cards drawn from
+//! a theme keep this layout, in the colors and font sizes of the theme's
+//! `CardStyle`.
+//!
+//! The DejaVu Sans fonts in `fonts` are built into the server, so cards are
+//! drawn the same wherever it runs. Card art is read from the stored art
+//! its `image_url` points at; art that is not stored is left out.
+
+use ::image::imageops::{self, FilterType};
+use ::image::{Rgba, RgbaImage};
+use imageproc::drawing::{
+    draw_filled_circle_mut, draw_filled_rect_mut, draw_hollow_circle_mut, draw_text_mut,
+};
+use imageproc::rect::Rect;
+use rusttype::{point, Font, Scale};
+
+use anyhow::Result;
+use log::{debug, warn};
+
+use std::path::PathBuf;
+
+use crate::card_classes::CardClassRegistry;
+use crate::card_themes::{CardThemes, Theme};
+use crate::image::art::{parse_art_url, ArtStore, ART_HEIGHT, ART_WIDTH};
+use crate::image::fit::{fit_font_size, CardTextFit};
+use crate::image::markup::{layout_markup, parse_markup, DrawCommand, TextStyle, ICONS};
+use crate::image::renderer::{
+    compose_cardsheet, CardAssets, CardRenderer, CARD_HEIGHT, CARD_WIDTH,
+};
+use crate::image::style::CardStyle;
+use crate::image::templates::relation_line;
+use crate::models::{Card, CardRelationEntry};
+
+const REGULAR_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans.ttf");
+const BOLD_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans-Bold.ttf");
+const ITALIC_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSans-Oblique.ttf");
+
+// Font sizes of `card.css`, in pixels, for the text that `CardStyle` does
+// not size.
+const BIG_TEXT: f32 = 24.0;
+const TINY_TEXT: f32 = 8.0;
+const LINE_HEIGHT: f32 = 1.25;
+
+// The boxes of the layout, as `(x, y, width, height)`.
+const HEADER: (i32, i32, u32, u32) = (0, 0, 300, 80);
+const ART: (i32, i32, u32, u32) = (9, 89, ART_WIDTH, ART_HEIGHT);
+const BODY: (i32, i32, u32, u32) = (0, 242, 300, 155);
+const STATS: (i32, i32, u32, u32) = (7, 246, 286, 20);
+/// From below the stats to the bottom of the body.
+const DESCRIPTION: (i32, i32, u32, u32) = (15, 274, 270, 123);
+/// The width of each half of the stats, less some padding.
+const STATS_ITEM_WIDTH: f32 = 135.0;
+const CIRCLE_RADIUS: i32 = 30;
+
+/// Reads a CSS hex color, `#rgb` or `#rrggbb`, as card classes store them.
+pub fn parse_hex_color(color: &str) -> Option<Rgba<u8>> {
+    let hex = color.strip_prefix('#')?;
+    let digits = match hex.len() {
+        3 => hex.chars().flat_map(|c| vec![c, c]).collect::<String>(),
+        6 => hex.to_owned(),
+        _ => return None,
+    };
+
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 0xFF]))
+}
+
+/// Breaks `text` into lines no wider than `max_width`, as measured by
+/// `measure`. Line breaks in the text are kept, and words wider than a line
+/// get a line of their own.
+pub fn wrap_text<F: Fn(&str) -> f32>(text: &str, max_width: f32, measure: F) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for paragraph in text.lines() {
+        let mut line = String::new();
+
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() {
+                word.to_owned()
+            } else {
+                format!("{} {}", line, word)
+            };
+
+            if line.is_empty() || measure(&candidate) <= max_width {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_owned()));
+            }
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+pub struct NativeCardRenderer {
+    regular: Font<'static>,
+    bold: Font<'static>,
+    italic: Font<'static>,
+}
+
+fn load_font(name: &str, bytes: &'static [u8]) -> Result<Font<'static>> {
+    Font::try_from_bytes(bytes).ok_or_else(|| anyhow!("The {} font is not a TrueType font", name))
+}
+
+fn text_width(font: &Font, size: f32, text: &str) -> f32 {
+    font.layout(text, Scale::uniform(size), point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+/// A card being drawn at `scale` times the size of its layout. Everything
+/// is placed in the pixels of the layout, as `card.css` has them, while text
+/// and art are drawn at the full resolution of the image.
+struct Canvas {
+    image: RgbaImage,
+    scale: f32,
+    style: CardStyle,
+}
+
+impl Canvas {
+    fn new(scale: f32, style: CardStyle) -> Self {
+        Self {
+            image: RgbaImage::from_pixel(
+                scaled_size(CARD_WIDTH, scale),
+                scaled_size(CARD_HEIGHT, scale),
+                style.background_color,
+            ),
+            scale,
+            style,
+        }
+    }
+
+    fn at(&self, position: f32) -> i32 {
+        (position * self.scale).round() as i32
+    }
+
+    fn text(&mut self, font: &Font, size: f32, x: f32, y: f32, text: &str) {
+        let (x, y) = (self.at(x), self.at(y));
+
+        draw_text_mut(
+            &mut self.image,
+            self.style.text_color,
+            x,
+            y,
+            Scale::uniform(size * self.scale),
+            font,
+            text,
+        );
+    }
+
+    fn centered_text(&mut self, font: &Font, size: f32, x: f32, y: f32, text: &str) {
+        self.text(font, size, x - text_width(font, size, text) / 2.0, y, text);
+    }
+
+    fn fill(&mut self, (x, y, width, height): (i32, i32, u32, u32), color: Rgba<u8>) {
+        let rect = Rect::at(self.at(x as f32), self.at(y as f32)).of_size(
+            scaled_size(width, self.scale),
+            scaled_size(height, self.scale),
+        );
+
+        draw_filled_rect_mut(&mut self.image, rect, color);
+    }
+
+    fn circle(&mut self, (x, y): (i32, i32), radius: i32, fill: Rgba<u8>, outline: Rgba<u8>) {
+        let center = (self.at(x as f32), self.at(y as f32));
+        let radius = self.at(radius as f32);
+
+        draw_filled_circle_mut(&mut self.image, center, radius, fill);
+        draw_hollow_circle_mut(&mut self.image, center, radius, outline);
+    }
+
+    /// Draws `image` resized to fill the box at `(x, y)`, `width` by
+    /// `height`.
+    fn overlay(&mut self, image: &RgbaImage, (x, y, width, height): (f32, f32, f32, f32)) {
+        let image = imageops::resize(
+            image,
+            ((width * self.scale) as u32).max(1),
+            ((height * self.scale) as u32).max(1),
+            FilterType::Triangle,
+        );
+        let (x, y) = (self.at(x), self.at(y));
+
+        imageops::overlay(&mut self.image, &image, x as i64, y as i64);
+    }
+}
+
+/// `size` pixels of the layout, at `scale`.
+fn scaled_size(size: u32, scale: f32) -> u32 {
+    ((size as f32 * scale).round() as u32).max(1)
+}
+
+/// Finds the stored art of a card in `art`. Art a card only links to is
+/// left out until it is ingested.
+fn find_card_art(card: &Card, art: &ArtStore) -> Option<PathBuf> {
+    let image_url = card.image_url.as_ref()?;
+    let path = match parse_art_url(image_url) {
+        Some(hash) => art.path(hash),
+        None => {
+            debug!("Leaving out art {} of card {}", image_url, card.id);
+            return None;
+        }
+    };
+
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+impl NativeCardRenderer {
+    /// Loads the fonts built into the server.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            regular: load_font("regular", REGULAR_FONT)?,
+            bold: load_font("bold", BOLD_FONT)?,
+            italic: load_font("italic", ITALIC_FONT)?,
+        })
+    }
+
+    fn draw_wrapped_text(
+        &self,
+        canvas: &mut Canvas,
+        font: &Font,
+        size: f32,
+        (x, y, width, height): (i32, i32, u32, u32),
+        centered: bool,
+        text: &str,
+    ) -> f32 {
+        let line_height = size * LINE_HEIGHT;
+        let mut line_y = y as f32;
+
+        for line in wrap_text(text, width as f32, |s| text_width(font, size, s)) {
+            // Text that does not fit in its box is cut off, as the HTML
+            // layout does.
+            if line_y + line_height > (y + height as i32) as f32 {
+                break;
+            }
+
+            if centered {
+                let center = x as f32 + width as f32 / 2.0;
+                canvas.centered_text(font, size, center, line_y, &line);
+            } else {
+                canvas.text(font, size, x as f32, line_y, &line);
+            }
+            line_y += line_height;
+        }
+
+        line_y
+    }
+
+    fn font(&self, style: TextStyle) -> &Font<'static> {
+        // There is no bold italic font, so bold wins.
+        match style {
+            TextStyle { bold: true, .. } => &self.bold,
+            TextStyle { italic: true, .. } => &self.italic,
+            _ => &self.regular,
+        }
+    }
+
+    fn layout_markup(&self, size: f32, width: u32, text: &str) -> Vec<DrawCommand> {
+        layout_markup(
+            &parse_markup(text),
+            &ICONS,
+            width as f32,
+            size,
+            size * LINE_HEIGHT,
+            |text, style| text_width(self.font(style), size, text),
+        )
+    }
+
+    /// Draws a description written in markup, returning where it ends.
+    fn draw_markup(
+        &self,
+        canvas: &mut Canvas,
+        size: f32,
+        (x, y, width, height): (i32, i32, u32, u32),
+        text: &str,
+    ) -> f32 {
+        let line_height = size * LINE_HEIGHT;
+        let commands = self.layout_markup(size, width, text);
+
+        let mut bottom = y as f32;
+        for command in commands {
+            let (command_x, command_y) = match &command {
+                DrawCommand::Text { x, y, .. } | DrawCommand::Icon { x, y, .. } => (*x, *y),
+            };
+
+            // Text that does not fit in its box is cut off, as the HTML
+            // layout does.
+            if command_y + line_height > height as f32 {
+                break;
+            }
+
+            let (left, top) = (x as f32 + command_x, y as f32 + command_y);
+            match command {
+                DrawCommand::Text { text, style, .. } => {
+                    canvas.text(self.font(style), size, left, top, &text)
+                }
+                DrawCommand::Icon { size, token, .. } => {
+                    if let Some(icon) = ICONS.get(&token) {
+                        canvas.overlay(
+                            &icon.image,
+                            (left, top + (line_height - size) / 2.0, size, size),
+                        );
+                    }
+                }
+            }
+            bottom = top + line_height;
+        }
+
+        bottom
+    }
+
+    fn stats_width(&self, size: f32, label: &str, value: &str) -> f32 {
+        text_width(&self.bold, size, label) + text_width(&self.regular, size, value)
+    }
+
+    /// The sizes the description and stats of `card` are drawn at in
+    /// `style`.
+    pub fn fit_text(&self, card: &Card, style: &CardStyle) -> CardTextFit {
+        let (_, _, width, height) = DESCRIPTION;
+        let description = fit_font_size(style.description_sizes, |size| {
+            let line_height = size * LINE_HEIGHT;
+            self.layout_markup(size, width, &card.desc)
+                .iter()
+                .all(|command| match command {
+                    DrawCommand::Text { y, .. } | DrawCommand::Icon { y, .. } => {
+                        y + line_height <= height as f32
+                    }
+                })
+        });
+
+        let stats = fit_font_size(style.stats_sizes, |size| {
+            self.stats_width(size, "Speed: ", &card.speed.to_string()) <= STATS_ITEM_WIDTH
+                && self.stats_width(size, "Action: ", &card.action.to_string()) <= STATS_ITEM_WIDTH
+        });
+
+        CardTextFit { description, stats }
+    }
+
+    fn draw_header(&self, canvas: &mut Canvas, card: &Card, card_classes: &CardClassRegistry) {
+        let style = canvas.style;
+        canvas.fill(HEADER, style.header_color);
+
+        let class_color = card_classes
+            .get(&card.cardclass)
+            .and_then(|class| parse_hex_color(&class.color))
+            .unwrap_or(style.header_color);
+        let center_y = HEADER.1 + HEADER.3 as i32 / 2;
+
+        for center_x in &[6 + CIRCLE_RADIUS, CARD_WIDTH as i32 - 6 - CIRCLE_RADIUS] {
+            canvas.circle(
+                (*center_x, center_y),
+                CIRCLE_RADIUS,
+                class_color,
+                style.background_color,
+            );
+        }
+
+        self.draw_wrapped_text(
+            canvas,
+            &self.bold,
+            TINY_TEXT,
+            (10, center_y - 8, 52, 24),
+            true,
+            &card_classes.display_name(&card.cardclass),
+        );
+        canvas.centered_text(
+            &self.bold,
+            BIG_TEXT,
+            (CARD_WIDTH as i32 - 6 - CIRCLE_RADIUS) as f32,
+            center_y as f32 - BIG_TEXT / 2.0 - 2.0,
+            &card.initiative.to_string(),
+        );
+
+        let title_bottom = self.draw_wrapped_text(
+            canvas,
+            &self.bold,
+            style.title_size,
+            (70, 12, 160, 50),
+            true,
+            &card.name,
+        );
+        canvas.centered_text(
+            &self.bold,
+            TINY_TEXT,
+            (CARD_WIDTH / 2) as f32,
+            title_bottom,
+            &format!("(#{})", card.id),
+        );
+    }
+
+    fn draw_art(&self, canvas: &mut Canvas, card: &Card, art: &ArtStore) -> Result<()> {
+        let art_path = match find_card_art(card, art) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let art = ::image::open(&art_path)?.to_rgba8();
+        let (art_x, art_y, art_width, art_height) = ART;
+
+        // Scale the art down to fit its box, keeping its aspect ratio.
+        let ratio = f32::min(
+            art_width as f32 / art.width() as f32,
+            art_height as f32 / art.height() as f32,
+        )
+        .min(1.0);
+        let width = (art.width() as f32 * ratio).max(1.0);
+        let height = (art.height() as f32 * ratio).max(1.0);
+
+        canvas.overlay(
+            &art,
+            (
+                art_x as f32 + (art_width as f32 - width) / 2.0,
+                art_y as f32 + (art_height as f32 - height) / 2.0,
+                width,
+                height,
+            ),
+        );
+
+        Ok(())
+    }
+
+    fn draw_body(
+        &self,
+        canvas: &mut Canvas,
+        card: &Card,
+        relations: &[CardRelationEntry],
+        fit: &CardTextFit,
+    ) {
+        let style = canvas.style;
+        canvas.fill(BODY, style.body_color);
+        canvas.fill(STATS, style.stats_color);
+
+        let stats_size = fit.stats.size;
+        let stats_y = STATS.1 as f32 + (STATS.3 as f32 - stats_size) / 2.0 - 2.0;
+        for (label, value, center_x) in &[
+            ("Speed: ", card.speed.to_string(), 75.0),
+            ("Action: ", card.action.to_string(), 225.0),
+        ] {
+            let label_width = text_width(&self.bold, stats_size, label);
+            let x = center_x - self.stats_width(stats_size, label, value) / 2.0;
+
+            canvas.text(&self.bold, stats_size, x, stats_y, label);
+            canvas.text(&self.regular, stats_size, x + label_width, stats_y, value);
+        }
+
+        let body_bottom = BODY.1 + BODY.3 as i32;
+        let desc_bottom = self.draw_markup(canvas, fit.description.size, DESCRIPTION, &card.desc);
+
+        let related_top = desc_bottom as i32 + 4;
+        if !relations.is_empty() && related_top < body_bottom {
+            let related = relations
+                .iter()
+                .map(relation_line)
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            self.draw_wrapped_text(
+                canvas,
+                &self.italic,
+                TINY_TEXT,
+                (15, related_top, 270, (body_bottom - related_top) as u32),
+                false,
+                &related,
+            );
+        }
+    }
+
+    /// Draws one card onto a new image in the style of `theme`, `scale`
+    /// times the size of the layout.
+    pub fn draw_card(
+        &self,
+        card: &Card,
+        assets: &CardAssets,
+        relations: &[CardRelationEntry],
+        theme: Option<&Theme>,
+        scale: f32,
+    ) -> Result<RgbaImage> {
+        let style = CardStyle::of_theme(theme);
+        let mut canvas = Canvas::new(scale, style);
+
+        self.draw_header(&mut canvas, card, &assets.card_classes);
+        self.draw_art(&mut canvas, card, &assets.art)?;
+        let fit = self.fit_text(card, &style);
+        if fit.overflows() {
+            warn!(
+                "The text of card {} does not fit: {}",
+                card.id,
+                fit.warnings().join("; ")
+            );
+        }
+        self.draw_body(&mut canvas, card, relations, &fit);
+
+        Ok(canvas.image)
+    }
+}
+
+impl CardRenderer for NativeCardRenderer {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn render_card(
+        &self,
+        card: &Card,
+        assets: &CardAssets,
+        relations: &[CardRelationEntry],
+        theme: Option<&Theme>,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()> {
+        self.draw_card(card, assets, relations, theme, scale)?
+            .save(output_path)?;
+
+        Ok(())
+    }
+
+    fn render_cardsheet(
+        &self,
+        cards: &[Card],
+        assets: &CardAssets,
+        themes: &CardThemes,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()> {
+        compose_cardsheet(cards, scale, |card| {
+            self.draw_card(card, assets, &[], themes.theme_of(card), scale)
+        })?
+        .save(output_path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_classes::CardClassRegistry;
+    use crate::image::art::ArtStore;
+    use crate::image::native::{parse_hex_color, wrap_text, NativeCardRenderer};
+    use crate::image::renderer::{CardAssets, CardRenderer, CARD_HEIGHT, CARD_WIDTH};
+    use crate::image::style::BACKGROUND_COLOR;
+    use crate::models::Card;
+
+    use ::image::Rgba;
+
+    #[test]
+    fn given_long_text_when_wrap_text_then_lines_fit_width() {
+        // Every character is 1 wide.
+        let measure = |s: &str| s.chars().count() as f32;
+
+        assert_eq!(
+            wrap_text("Deal 3 damage to a target\nDraw a card", 10.0, measure),
+            vec!["Deal 3", "damage to", "a target", "Draw a", "card"]
+        );
+        assert_eq!(wrap_text("Unbreakable", 4.0, measure), vec!["Unbreakable"]);
+        assert_eq!(
+            parse_hex_color("#f80"),
+            Some(Rgba([0xFF, 0x88, 0x00, 0xFF]))
+        );
+        assert_eq!(parse_hex_color("red"), None);
+    }
+
+    #[test]
+    fn given_card_when_rendered_natively_at_scale_then_png_written_at_scale() {
+        let card = Card {
+            id: 1,
+            cardclass: "SPL".to_owned(),
+            action: "Attack".parse().unwrap(),
+            speed: "Normal".parse().unwrap(),
+            initiative: 3,
+            name: "Fireball".to_owned(),
+            desc: "Deal 3 damage to a target".to_owned(),
+            image_url: None,
+            version: 0,
+            cloned_from_id: None,
+        };
+        let output_path =
+            std::env::temp_dir().join(format!("cardego-native-render-{}.png", std::process::id()));
+
+        NativeCardRenderer::new()
+            .unwrap()
+            .render_card(
+                &card,
+                &CardAssets {
+                    card_classes: CardClassRegistry::default(),
+                    art: ArtStore::new(&std::env::temp_dir().to_string_lossy()),
+                },
+                &[],
+                None,
+                2.5,
+                output_path.to_str().unwrap(),
+            )
+            .unwrap();
+
+        let image = ::image::open(&output_path).unwrap().to_rgba8();
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(
+            image.dimensions(),
+            (CARD_WIDTH * 5 / 2, CARD_HEIGHT * 5 / 2)
+        );
+        assert!(image.pixels().any(|pixel| *pixel != BACKGROUND_COLOR));
+    }
+}
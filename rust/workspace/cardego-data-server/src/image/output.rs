@@ -0,0 +1,327 @@
+//! The formats and sizes card images are served in.
+//!
+//! Cards and cardsheets are rendered at the size asked for, rather than
+//! resized after the fact, so that their text stays sharp. Each variant is
+//! then cached in its own format, under a key of the render, its scale and
+//! its format. SVGs hold the PNG of the render, as cards are drawn by a
+//! browser engine that only gives back pixels.
+
+use ::image::codecs::jpeg::JpegEncoder;
+use ::image::codecs::png::PngEncoder;
+use ::image::codecs::webp::WebPEncoder;
+use ::image::{ColorType, DynamicImage, ImageEncoder, RgbaImage};
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::errors::ClientError;
+use crate::image::cache::{render_key, scaled_render_key};
+use crate::image::renderer::CARD_DPI;
+
+/// The bounds on either side of a served image, in pixels.
+pub const MIN_IMAGE_DIMENSION: u32 = 16;
+pub const MAX_IMAGE_DIMENSION: u32 = 8192;
+
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Svg,
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    /// The extension of cached images in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Svg => "svg",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::Webp),
+            "svg" => Ok(OutputFormat::Svg),
+            _ => Err(ClientError::InvalidInput(format!(
+                "Unknown image format `{}`, expected png, jpeg, webp or svg",
+                s
+            ))),
+        }
+    }
+}
+
+/// How large an image is asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSize {
+    Natural,
+    Scale(f32),
+    /// The resolution to print cards at their real size with.
+    Dpi(u32),
+    Width(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOutputOptions {
+    pub format: OutputFormat,
+    pub size: OutputSize,
+}
+
+impl Default for ImageOutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Png,
+            size: OutputSize::Natural,
+        }
+    }
+}
+
+fn parse_query_number<T: FromStr>(name: &str, value: &str) -> Result<T, ClientError> {
+    value.parse().map_err(|_| {
+        ClientError::InvalidInput(format!("`{}` must be a number, not `{}`", name, value))
+    })
+}
+
+impl ImageOutputOptions {
+    /// Reads the `format` query parameter, and at most one of `scale`, `dpi`
+    /// and `width`.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, ClientError> {
+        let mut options = Self::default();
+
+        if let Some(format) = query.get("format") {
+            options.format = format.parse()?;
+        }
+
+        let mut sizes = Vec::new();
+        if let Some(scale) = query.get("scale") {
+            sizes.push(OutputSize::Scale(parse_query_number("scale", scale)?));
+        }
+        if let Some(dpi) = query.get("dpi") {
+            sizes.push(OutputSize::Dpi(parse_query_number("dpi", dpi)?));
+        }
+        if let Some(width) = query.get("width") {
+            sizes.push(OutputSize::Width(parse_query_number("width", width)?));
+        }
+
+        match sizes.as_slice() {
+            [] => {}
+            [size] => options.size = *size,
+            _ => Err(ClientError::InvalidInput(
+                "Only one of `scale`, `dpi` and `width` may be given".to_string(),
+            ))?,
+        }
+
+        Ok(options)
+    }
+
+    /// The scale to render an image of `width` by `height` at, for it to
+    /// come out at the size asked for.
+    pub fn render_scale(&self, width: u32, height: u32) -> Result<f32, ClientError> {
+        let scale = match self.size {
+            OutputSize::Natural => 1.0,
+            OutputSize::Scale(scale) => scale,
+            OutputSize::Dpi(dpi) => dpi as f32 / CARD_DPI as f32,
+            OutputSize::Width(output_width) => output_width as f32 / width as f32,
+        };
+
+        let dimensions = (
+            (width as f32 * scale).round(),
+            (height as f32 * scale).round(),
+        );
+
+        let bounds = MIN_IMAGE_DIMENSION as f32..=MAX_IMAGE_DIMENSION as f32;
+        if !scale.is_finite() || !bounds.contains(&dimensions.0) || !bounds.contains(&dimensions.1)
+        {
+            Err(ClientError::InvalidInput(format!(
+                "Images must be between {} and {} pixels on each side",
+                MIN_IMAGE_DIMENSION, MAX_IMAGE_DIMENSION
+            )))?
+        }
+
+        Ok(scale)
+    }
+
+    /// The size of the served image of a render of `width` by `height`.
+    pub fn output_dimensions(&self, width: u32, height: u32) -> Result<(u32, u32), ClientError> {
+        let scale = self.render_scale(width, height)?;
+
+        Ok((
+            (width as f32 * scale).round() as u32,
+            (height as f32 * scale).round() as u32,
+        ))
+    }
+
+    /// The cache key of this variant of the image under `key`, rendered at
+    /// `scale`. Natural PNGs keep the key of the render.
+    pub fn variant_key(&self, key: &str, scale: f32) -> String {
+        let scaled_key = scaled_render_key(key, scale);
+
+        match self.format {
+            OutputFormat::Png => scaled_key,
+            format => render_key(&[scaled_key.as_bytes(), format.extension().as_bytes()]),
+        }
+    }
+
+    /// Encodes `image` into this format. The image is expected to already
+    /// be at the size asked for.
+    pub fn encode(&self, image: &RgbaImage) -> Result<Vec<u8>> {
+        encode_image(image, self.format)
+    }
+}
+
+fn encode_png(image: &RgbaImage) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    PngEncoder::new(&mut buffer).write_image(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgba8,
+    )?;
+    Ok(buffer)
+}
+
+/// Wraps `image` in an SVG of its size, as an embedded PNG.
+fn encode_svg(image: &RgbaImage) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+
+    Ok(format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+            r#"width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+            r#"<image width="{0}" height="{1}" href="data:image/png;base64,{2}"/>"#,
+            "</svg>"
+        ),
+        width,
+        height,
+        base64::encode(encode_png(image)?)
+    )
+    .into_bytes())
+}
+
+fn encode_image(image: &RgbaImage, format: OutputFormat) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+
+    match format {
+        OutputFormat::Png => buffer = encode_png(image)?,
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel.
+            let rgb = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+            JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY).write_image(
+                rgb.as_raw(),
+                rgb.width(),
+                rgb.height(),
+                ColorType::Rgb8,
+            )?;
+        }
+        OutputFormat::Webp => WebPEncoder::new_lossless(&mut buffer).write_image(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ColorType::Rgba8,
+        )?,
+        OutputFormat::Svg => buffer = encode_svg(image)?,
+    }
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::output::{ImageOutputOptions, OutputFormat, OutputSize};
+
+    use ::image::RgbaImage;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn given_size_parameters_when_parsed_then_dimensions_validated() {
+        let query = |pairs: &[(&str, &str)]| {
+            ImageOutputOptions::from_query(
+                &pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<HashMap<String, String>>(),
+            )
+        };
+
+        let thumbnail = query(&[("format", "webp"), ("width", "150")]).unwrap();
+        assert_eq!(thumbnail.format, OutputFormat::Webp);
+        assert_eq!(thumbnail.output_dimensions(300, 420).unwrap(), (150, 210));
+
+        let print = query(&[("format", "jpeg"), ("dpi", "300")]).unwrap();
+        assert_eq!(print.size, OutputSize::Dpi(300));
+        assert_eq!(print.output_dimensions(300, 420).unwrap(), (750, 1050));
+
+        assert!(query(&[("scale", "100")])
+            .unwrap()
+            .output_dimensions(300, 420)
+            .is_err());
+        assert!(query(&[("scale", "2"), ("width", "600")]).is_err());
+        assert!(query(&[("format", "gif")]).is_err());
+        assert_eq!(
+            query(&[("format", "SVG")]).unwrap().format,
+            OutputFormat::Svg
+        );
+    }
+
+    #[test]
+    fn given_sizes_of_same_scale_when_variant_key_then_render_shared() {
+        let scale = OutputSize::Scale(2.0);
+        let width = OutputSize::Width(600);
+        let options = |format, size| ImageOutputOptions { format, size };
+
+        let png = options(OutputFormat::Png, scale);
+        let png_scale = png.render_scale(300, 420).unwrap();
+        assert_eq!(png_scale, 2.0);
+        assert_eq!(
+            options(OutputFormat::Png, width)
+                .render_scale(300, 420)
+                .unwrap(),
+            png_scale
+        );
+
+        assert_eq!(ImageOutputOptions::default().variant_key("key", 1.0), "key");
+        assert_ne!(png.variant_key("key", png_scale), "key");
+        assert_ne!(
+            options(OutputFormat::Webp, width).variant_key("key", png_scale),
+            png.variant_key("key", png_scale)
+        );
+    }
+
+    #[test]
+    fn given_image_when_encoded_as_svg_then_png_embedded_at_size() {
+        let options = ImageOutputOptions {
+            format: OutputFormat::Svg,
+            size: OutputSize::Natural,
+        };
+
+        let svg = String::from_utf8(options.encode(&RgbaImage::new(30, 42)).unwrap()).unwrap();
+
+        assert!(
+            svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="30" height="42""#)
+        );
+        assert!(svg.contains(r#"href="data:image/png;base64,"#));
+        assert!(svg.ends_with("</svg>"));
+        assert_eq!(options.format.content_type(), "image/svg+xml");
+    }
+}
@@ -0,0 +1,336 @@
+//! The backends that turn cards into images.
+//!
+//! The native backend draws the `macro-card.html` layout itself, with fonts
+//! built into the server. The `wkhtmltoimage` backend renders the HTML
+//! templates with the `./wkhtmltoimage` program, and is kept for servers
+//! that already have it. Which one is used is set in `config/renderer.yml`:
+//!
+//! ```yaml
+//! card_renderer: wkhtmltoimage
+//! ```
+//!
+//! The server refuses to start when the chosen renderer cannot be loaded,
+//! rather than drawing cards differently than asked. Either renderer draws
+//! cards from themes: `wkhtmltoimage` renders the theme's HTML, and the
+//! native renderer draws its own layout in the theme's style.
+
+use ::image::imageops;
+use ::image::RgbaImage;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::card_classes::CardClassRegistry;
+use crate::card_themes::{CardThemes, Theme};
+use crate::database::DatabaseContext;
+use crate::image::art::ArtStore;
+use crate::image::native::NativeCardRenderer;
+use crate::image::style::BACKGROUND_COLOR;
+use crate::image::templates::{CardsheetTemplate, SingleCardTemplate};
+use crate::image::{
+    generate_card_image_html_string, generate_image_using_wkhtmltoimage,
+    generate_themed_card_image_html_string,
+};
+use crate::models::{Card, CardRelationEntry};
+
+use askama::Template;
+
+/// The size of one card, in pixels.
+pub const CARD_WIDTH: u32 = 300;
+pub const CARD_HEIGHT: u32 = 420;
+
+/// The resolution of a card, which is 2.5 by 3.5 inches in print.
+pub const CARD_DPI: u32 = 120;
+
+/// How many cards are placed side by side on a cardsheet.
+pub const CARDSHEET_COLUMNS: u32 = 10;
+
+pub const RENDERER_CONFIG_PATH: &str = "config/renderer.yml";
+
+/// What the cards of a database are drawn with besides the cards
+/// themselves: the classes they name and the art they point at.
+#[derive(Debug, Clone)]
+pub struct CardAssets {
+    pub card_classes: CardClassRegistry,
+    pub art: ArtStore,
+}
+
+impl DatabaseContext {
+    pub fn get_card_assets(&self) -> Result<CardAssets> {
+        Ok(CardAssets {
+            card_classes: self.get_card_class_registry()?,
+            art: self.get_art_store()?.clone(),
+        })
+    }
+}
+
+pub trait CardRenderer: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Renders one card into a PNG at `output_path`, `scale` times the size
+    /// of `CARD_WIDTH` by `CARD_HEIGHT`, from `theme` or else the built-in
+    /// template. Any `relations` given are listed on the card.
+    fn render_card(
+        &self,
+        card: &Card,
+        assets: &CardAssets,
+        relations: &[CardRelationEntry],
+        theme: Option<&Theme>,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()>;
+
+    /// Renders `cards` side by side, `CARDSHEET_COLUMNS` to a row, into a
+    /// PNG at `output_path`, each card `scale` times its usual size and
+    /// drawn from its theme in `themes`.
+    fn render_cardsheet(
+        &self,
+        cards: &[Card],
+        assets: &CardAssets,
+        themes: &CardThemes,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardRendererKind {
+    Native,
+    Wkhtmltoimage,
+}
+
+/// The contents of `config/renderer.yml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RendererConfig {
+    card_renderer: Option<CardRendererKind>,
+}
+
+/// Reads which renderer `config/renderer.yml` contents ask for. The native
+/// renderer is used unless another is named.
+pub fn parse_card_renderer_kind(contents: &str) -> Result<CardRendererKind> {
+    let config: RendererConfig = if contents.trim().is_empty() {
+        RendererConfig::default()
+    } else {
+        serde_yaml::from_str(contents)?
+    };
+
+    Ok(config.card_renderer.unwrap_or(CardRendererKind::Native))
+}
+
+/// Reads which renderer the file at `path` asks for. Without the file, the
+/// native renderer is used.
+pub fn load_card_renderer_kind(path: &str) -> Result<CardRendererKind> {
+    if !Path::new(path).exists() {
+        return Ok(CardRendererKind::Native);
+    }
+
+    parse_card_renderer_kind(&std::fs::read_to_string(path)?)
+}
+
+/// The number of rows of a cardsheet of `card_count` cards. Sheets are at
+/// least two rows high, as they always were.
+pub fn cardsheet_rows(card_count: usize) -> u32 {
+    std::cmp::max(2, (card_count as u32).div_ceil(CARDSHEET_COLUMNS))
+}
+
+/// The size of a card drawn `scale` times its usual size.
+pub fn scaled_card_size(scale: f32) -> (u32, u32) {
+    (
+        ((CARD_WIDTH as f32 * scale).round() as u32).max(1),
+        ((CARD_HEIGHT as f32 * scale).round() as u32).max(1),
+    )
+}
+
+/// Lays `cards` out side by side, `CARDSHEET_COLUMNS` to a row, each drawn
+/// by `draw` at `scale`.
+pub fn compose_cardsheet<F: FnMut(&Card) -> Result<RgbaImage>>(
+    cards: &[Card],
+    scale: f32,
+    mut draw: F,
+) -> Result<RgbaImage> {
+    let (card_width, card_height) = scaled_card_size(scale);
+    let mut sheet = RgbaImage::from_pixel(
+        card_width * CARDSHEET_COLUMNS,
+        card_height * cardsheet_rows(cards.len()),
+        BACKGROUND_COLOR,
+    );
+
+    for (i, card) in cards.iter().enumerate() {
+        let card_image = draw(card)?;
+        let (column, row) = (i as u32 % CARDSHEET_COLUMNS, i as u32 / CARDSHEET_COLUMNS);
+
+        imageops::overlay(
+            &mut sheet,
+            &card_image,
+            (column * card_width) as i64,
+            (row * card_height) as i64,
+        );
+    }
+
+    Ok(sheet)
+}
+
+/// Creates the renderer of the given kind, or fails when it cannot render:
+/// when the native fonts do not load, or `./wkhtmltoimage` does not run.
+pub fn load_card_renderer(kind: CardRendererKind) -> Result<Arc<dyn CardRenderer>> {
+    match kind {
+        CardRendererKind::Native => {
+            let renderer =
+                NativeCardRenderer::new().context("Could not load the native card renderer")?;
+            Ok(Arc::new(renderer))
+        }
+        CardRendererKind::Wkhtmltoimage => {
+            let status = Command::new("./wkhtmltoimage")
+                .arg("--version")
+                .output()
+                .context("Could not run ./wkhtmltoimage")?
+                .status;
+            if !status.success() {
+                return Err(anyhow!("./wkhtmltoimage --version failed with {}", status));
+            }
+
+            Ok(Arc::new(WkhtmltoimageRenderer))
+        }
+    }
+}
+
+/// Renders the HTML templates with `./wkhtmltoimage`. The HTML is written
+/// next to the images, under `templates`, so that `card.css` is found.
+pub struct WkhtmltoimageRenderer;
+
+/// Where the HTML of the image at `output_path` is written. It is named
+/// after the image, so that renders made at once do not share it.
+fn html_path_of(output_path: &str) -> PathBuf {
+    let output_path = Path::new(output_path);
+
+    output_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("templates")
+        .join(output_path.file_stem().unwrap_or_default())
+        .with_extension("html")
+}
+
+impl CardRenderer for WkhtmltoimageRenderer {
+    fn name(&self) -> &'static str {
+        "wkhtmltoimage"
+    }
+
+    fn render_card(
+        &self,
+        card: &Card,
+        assets: &CardAssets,
+        relations: &[CardRelationEntry],
+        theme: Option<&Theme>,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()> {
+        let substituted_template_string = match theme {
+            Some(theme) => generate_themed_card_image_html_string(theme, card, assets, relations)?,
+            None => generate_card_image_html_string(card, assets, relations)?,
+        };
+
+        let substituted_html_path = html_path_of(output_path);
+        std::fs::write(&substituted_html_path, &substituted_template_string)?;
+
+        debug!(
+            "finished writing substituted html to: {:?}",
+            substituted_html_path
+        );
+
+        generate_image_using_wkhtmltoimage(
+            CARD_HEIGHT as usize,
+            CARD_WIDTH as usize,
+            scale,
+            &substituted_html_path,
+            output_path,
+        )
+    }
+
+    fn render_cardsheet(
+        &self,
+        cards: &[Card],
+        assets: &CardAssets,
+        themes: &CardThemes,
+        scale: f32,
+        output_path: &str,
+    ) -> Result<()> {
+        // Themes are whole pages of their own, so cards drawn from one are
+        // rendered one by one and laid out afterwards.
+        if cards.iter().any(|card| themes.theme_of(card).is_some()) {
+            let sheet_path = Path::new(output_path);
+            let mut card_index = 0;
+            let sheet = compose_cardsheet(cards, scale, |card| {
+                card_index += 1;
+                let card_path = sheet_path
+                    .with_file_name(format!(
+                        "{}-{}.png",
+                        sheet_path.file_stem().unwrap_or_default().to_string_lossy(),
+                        card_index
+                    ))
+                    .to_string_lossy()
+                    .into_owned();
+                self.render_card(card, assets, &[], themes.theme_of(card), scale, &card_path)?;
+
+                let card_image = ::image::open(&card_path)?.to_rgba8();
+                std::fs::remove_file(&card_path)?;
+                Ok(card_image)
+            })?;
+
+            sheet.save(output_path)?;
+            return Ok(());
+        }
+
+        let substituted_html_path = html_path_of(output_path);
+
+        let substituted_template = CardsheetTemplate {
+            cardclass_css: assets.card_classes.to_css(),
+            cards: cards
+                .iter()
+                .map(|card| SingleCardTemplate::new(card, assets))
+                .collect(),
+        }
+        .render()?;
+
+        debug!("substituted into template: {:?}", substituted_template);
+
+        // Write the substituted HTML into a file
+        std::fs::write(&substituted_html_path, &substituted_template)?;
+
+        debug!(
+            "finished writing substituted html to: {:?}",
+            substituted_html_path
+        );
+
+        generate_image_using_wkhtmltoimage(
+            (CARD_HEIGHT * cardsheet_rows(cards.len())) as usize,
+            (CARD_WIDTH * CARDSHEET_COLUMNS) as usize,
+            scale,
+            &substituted_html_path,
+            output_path,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::renderer::{parse_card_renderer_kind, CardRendererKind};
+
+    #[test]
+    fn given_renderer_config_when_parse_then_kind_read() {
+        assert_eq!(
+            parse_card_renderer_kind("card_renderer: wkhtmltoimage\n").unwrap(),
+            CardRendererKind::Wkhtmltoimage
+        );
+        assert_eq!(
+            parse_card_renderer_kind("").unwrap(),
+            CardRendererKind::Native
+        );
+        assert!(parse_card_renderer_kind("card_renderer: chrome\n").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! The colors and font sizes cards are drawn and measured with.
+//!
+//! The built-in style follows `card.css`. A theme restyles it through its
+//! stylesheet, with the classes its template uses:
+//!
+//! - `.card`: `background-color` and the text `color`
+//! - `.title`: `background-color` and `font-size` of the header
+//! - `.subtitle`: `background-color` and `font-size` of the speed and action
+//! - `.description`: `background-color` and `font-size` of the body
+//!
+//! Colors are read when they are hex colors and sizes when they are in
+//! pixels; anything else keeps the built-in value. Renderers that draw HTML
+//! show the whole theme, while the native renderer keeps its own layout in
+//! the theme's style.
+
+use ::image::Rgba;
+use regex::Regex;
+
+use crate::card_themes::Theme;
+use crate::image::fit::FontSizeBounds;
+use crate::image::native::parse_hex_color;
+
+pub const BACKGROUND_COLOR: Rgba<u8> = Rgba([0xC6, 0xC6, 0xC6, 0xFF]);
+pub const PANEL_COLOR: Rgba<u8> = Rgba([0xF9, 0xF9, 0xF9, 0xFF]);
+pub const BODY_COLOR: Rgba<u8> = Rgba([0xF5, 0xF3, 0xE6, 0xFF]);
+pub const TEXT_COLOR: Rgba<u8> = Rgba([0x00, 0x00, 0x00, 0xFF]);
+
+// Font sizes of `card.css`, in pixels.
+const TITLE_TEXT: f32 = 16.0;
+const BODY_TEXT: f32 = 12.8;
+
+/// How much smaller than its usual size text may be made to fit its box.
+const MIN_TEXT_RATIO: f32 = 0.75;
+
+lazy_static! {
+    static ref CSS_RULE_REGEX: Regex = Regex::new(r"([^{}]+)\{([^{}]*)\}").unwrap();
+    static ref CSS_COMMENT_REGEX: Regex = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+}
+
+/// The sizes text usually drawn at `size` may be drawn at.
+fn shrinkable(size: f32) -> FontSizeBounds {
+    FontSizeBounds {
+        max: size,
+        min: size * MIN_TEXT_RATIO,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CardStyle {
+    pub background_color: Rgba<u8>,
+    pub header_color: Rgba<u8>,
+    pub stats_color: Rgba<u8>,
+    pub body_color: Rgba<u8>,
+    pub text_color: Rgba<u8>,
+    pub title_size: f32,
+    pub stats_sizes: FontSizeBounds,
+    pub description_sizes: FontSizeBounds,
+}
+
+impl Default for CardStyle {
+    fn default() -> Self {
+        Self {
+            background_color: BACKGROUND_COLOR,
+            header_color: PANEL_COLOR,
+            stats_color: PANEL_COLOR,
+            body_color: BODY_COLOR,
+            text_color: TEXT_COLOR,
+            title_size: TITLE_TEXT,
+            stats_sizes: shrinkable(BODY_TEXT),
+            description_sizes: shrinkable(BODY_TEXT),
+        }
+    }
+}
+
+/// The declarations of a stylesheet, as `(selector, property, value)`.
+fn css_declarations(css: &str) -> Vec<(String, String, String)> {
+    let css = CSS_COMMENT_REGEX.replace_all(css, "");
+    let mut declarations = Vec::new();
+
+    for rule in CSS_RULE_REGEX.captures_iter(&css) {
+        for selector in rule[1].split(',') {
+            for declaration in rule[2].split(';') {
+                if let Some((property, value)) = declaration.split_once(':') {
+                    declarations.push((
+                        selector.trim().to_owned(),
+                        property.trim().to_lowercase(),
+                        value.trim().to_owned(),
+                    ));
+                }
+            }
+        }
+    }
+
+    declarations
+}
+
+fn parse_pixels(value: &str) -> Option<f32> {
+    value
+        .strip_suffix("px")?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|size: &f32| *size > 0.0)
+}
+
+impl CardStyle {
+    /// The style of cards drawn from `theme`, or the built-in style.
+    pub fn of_theme(theme: Option<&Theme>) -> Self {
+        match theme {
+            Some(theme) => Self::from_css(&theme.css),
+            None => Self::default(),
+        }
+    }
+
+    /// The built-in style, with the declarations of `css` applied.
+    pub fn from_css(css: &str) -> Self {
+        let mut style = Self::default();
+
+        for (selector, property, value) in css_declarations(css) {
+            match (selector.as_str(), property.as_str()) {
+                (".card", "background-color") => {
+                    style.background_color =
+                        parse_hex_color(&value).unwrap_or(style.background_color)
+                }
+                (".card", "color") => {
+                    style.text_color = parse_hex_color(&value).unwrap_or(style.text_color)
+                }
+                (".title", "background-color") => {
+                    style.header_color = parse_hex_color(&value).unwrap_or(style.header_color)
+                }
+                (".title", "font-size") => {
+                    style.title_size = parse_pixels(&value).unwrap_or(style.title_size)
+                }
+                (".subtitle", "background-color") => {
+                    style.stats_color = parse_hex_color(&value).unwrap_or(style.stats_color)
+                }
+                (".subtitle", "font-size") => {
+                    if let Some(size) = parse_pixels(&value) {
+                        style.stats_sizes = shrinkable(size);
+                    }
+                }
+                (".description", "background-color") => {
+                    style.body_color = parse_hex_color(&value).unwrap_or(style.body_color)
+                }
+                (".description", "font-size") => {
+                    if let Some(size) = parse_pixels(&value) {
+                        style.description_sizes = shrinkable(size);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        style
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::style::{CardStyle, BACKGROUND_COLOR};
+
+    use ::image::Rgba;
+
+    #[test]
+    fn given_theme_css_when_read_then_style_overridden() {
+        let style = CardStyle::from_css(
+            "/* .card { color: #fff; } */
+            .title, .subtitle { background-color: #f4e9d0; font-size: 20px }
+            .description { font-size: 14px; color: red; }
+            .card { border: 6px solid #5b4636; color: blue; }",
+        );
+
+        assert_eq!(style.header_color, Rgba([0xF4, 0xE9, 0xD0, 0xFF]));
+        assert_eq!(style.stats_color, style.header_color);
+        assert_eq!(style.title_size, 20.0);
+        assert_eq!(style.stats_sizes.max, 20.0);
+        assert_eq!(style.description_sizes.max, 14.0);
+        assert_eq!(style.description_sizes.min, 10.5);
+        assert_eq!(style.text_color, CardStyle::default().text_color);
+        assert_eq!(style.background_color, BACKGROUND_COLOR);
+        assert_eq!(CardStyle::from_css(""), CardStyle::default());
+    }
+}
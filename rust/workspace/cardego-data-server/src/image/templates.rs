@@ -1,45 +1,67 @@
 extern crate askama;
 
-use crate::models::Card;
+use crate::image::markup::{markup_to_html, parse_markup, ICONS};
+use crate::image::renderer::CardAssets;
+use crate::models::{Card, CardRelationEntry};
 use askama::Template;
+use serde::Serialize;
 
-#[derive(Debug, Default, Clone, Template)]
+/// The line listing a relation on a card, e.g. `Upgrades to: Fireball (#12)`.
+pub fn relation_line(relation: &CardRelationEntry) -> String {
+    format!(
+        "{}: {} (#{})",
+        relation.kind.label(),
+        relation.related_card_name,
+        relation.related_card_id
+    )
+}
+
+#[derive(Debug, Default, Clone, Serialize, Template)]
 #[template(path = "single-card.html")]
 pub struct SingleCardTemplate {
     pub id: i32,
     pub cardclass: String,
     pub cardclass_long: String,
+    pub cardclass_css: String,
     pub initiative: i32,
     pub action: String,
     pub speed: String,
     pub name: String,
     pub desc: String,
+    /// The description with its markup turned into HTML.
+    pub desc_html: String,
     pub image_url: String,
+    /// Lines listing the cards this card upgrades to, creates or relates to.
+    pub related: Vec<String>,
 }
 
 impl SingleCardTemplate {
-    pub fn new(card: &Card) -> SingleCardTemplate {
-        use crate::models::TRAIT_SHORT_TO_FULLNAME;
-
+    pub fn new(card: &Card, assets: &CardAssets) -> SingleCardTemplate {
         SingleCardTemplate {
             id: card.id,
             cardclass: (&card.cardclass).to_string(),
-            cardclass_long: TRAIT_SHORT_TO_FULLNAME
-                .get(&card.cardclass)
-                .unwrap()
-                .to_string(),
+            cardclass_long: assets.card_classes.display_name(&card.cardclass),
+            cardclass_css: assets.card_classes.to_css(),
             initiative: card.initiative,
-            action: card.action.clone(),
-            speed: card.speed.clone(),
+            action: card.action.to_string(),
+            speed: card.speed.to_string(),
             name: card.name.clone(),
             desc: card.desc.clone(),
-            image_url: card.image_url.as_ref().unwrap_or(&"".to_string()).clone(),
+            desc_html: markup_to_html(&parse_markup(&card.desc), &ICONS),
+            image_url: assets.art.src(card.image_url.as_deref()),
+            related: vec![],
         }
     }
+
+    pub fn with_relations(mut self, relations: &[CardRelationEntry]) -> SingleCardTemplate {
+        self.related = relations.iter().map(relation_line).collect();
+        self
+    }
 }
 
 #[derive(Debug, Default, Template)]
-#[template(path = "cardsheet.html", escape = "none")]
+#[template(path = "cardsheet.html")]
 pub struct CardsheetTemplate {
+    pub cardclass_css: String,
     pub cards: Vec<SingleCardTemplate>,
 }
@@ -0,0 +1,247 @@
+//! Decks for Tabletop Simulator.
+//!
+//! Tabletop Simulator imports custom decks as sheets of up to 10 by 7 card
+//! faces, along with one card back. The last slot of every sheet holds the
+//! image shown for cards hidden in another player's hand, so a sheet fits
+//! at most 69 cards and a deck may span several sheets. The saved object
+//! ties them together: every card names its sheet and slot, and is given
+//! the name and description of the card it shows.
+
+use ::image::imageops::{self, FilterType};
+use ::image::RgbaImage;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+use crate::image::renderer::{CARDSHEET_COLUMNS, CARD_HEIGHT, CARD_WIDTH};
+use crate::models::DeckCardEntry;
+
+/// The most rows a sheet may have.
+pub const TTS_SHEET_ROWS: u32 = 7;
+
+/// How many cards fit on a sheet, with the last slot left for the hidden
+/// card image.
+pub const TTS_CARDS_PER_SHEET: usize = (CARDSHEET_COLUMNS * TTS_SHEET_ROWS) as usize - 1;
+
+/// The number of rows of a sheet of `card_count` cards and the hidden card
+/// image. Sheets are at least two rows high, as Tabletop Simulator asks.
+pub fn tts_sheet_rows(card_count: usize) -> u32 {
+    std::cmp::max(2, (card_count as u32 + 1).div_ceil(CARDSHEET_COLUMNS))
+}
+
+/// Lays a rendered cardsheet of `card_count` cards out as a sheet, with
+/// `hidden` in its last slot.
+pub fn compose_tts_sheet(
+    cardsheet: &RgbaImage,
+    card_count: usize,
+    hidden: &RgbaImage,
+) -> RgbaImage {
+    let rows = tts_sheet_rows(card_count);
+    let mut sheet = RgbaImage::new(CARD_WIDTH * CARDSHEET_COLUMNS, CARD_HEIGHT * rows);
+
+    let cards = imageops::crop_imm(
+        cardsheet,
+        0,
+        0,
+        sheet.width().min(cardsheet.width()),
+        sheet.height().min(cardsheet.height()),
+    );
+    imageops::overlay(&mut sheet, &cards.to_image(), 0, 0);
+
+    let hidden = imageops::resize(hidden, CARD_WIDTH, CARD_HEIGHT, FilterType::Lanczos3);
+    imageops::overlay(
+        &mut sheet,
+        &hidden,
+        ((CARDSHEET_COLUMNS - 1) * CARD_WIDTH) as i64,
+        ((rows - 1) * CARD_HEIGHT) as i64,
+    );
+
+    sheet
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsTransform {
+    pub pos_x: f32,
+    pub pos_y: f32,
+    pub pos_z: f32,
+    pub rot_x: f32,
+    pub rot_y: f32,
+    pub rot_z: f32,
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub scale_z: f32,
+}
+
+impl Default for TtsTransform {
+    /// Face down on the table, as decks are dealt from.
+    fn default() -> Self {
+        Self {
+            pos_x: 0.0,
+            pos_y: 1.0,
+            pos_z: 0.0,
+            rot_x: 0.0,
+            rot_y: 180.0,
+            rot_z: 180.0,
+            scale_x: 1.0,
+            scale_y: 1.0,
+            scale_z: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TtsCustomDeck {
+    #[serde(rename = "FaceURL")]
+    pub face_url: String,
+    #[serde(rename = "BackURL")]
+    pub back_url: String,
+    pub num_width: u32,
+    pub num_height: u32,
+    /// The hidden card image is on the sheet, not the back.
+    pub back_is_hidden: bool,
+    pub unique_back: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TtsObject {
+    pub name: String,
+    pub nickname: String,
+    pub description: String,
+    pub transform: TtsTransform,
+    #[serde(rename = "CardID", skip_serializing_if = "Option::is_none")]
+    pub card_id: Option<u32>,
+    #[serde(rename = "DeckIDs", skip_serializing_if = "Option::is_none")]
+    pub deck_ids: Option<Vec<u32>>,
+    pub custom_deck: BTreeMap<String, TtsCustomDeck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contained_objects: Option<Vec<TtsObject>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TtsSavedObject {
+    pub object_states: Vec<TtsObject>,
+}
+
+/// The saved object of the deck `deck_name` of `entries`. The faces of the
+/// `n`th sheet, counting from 1, are downloaded from `sheet_url(n)`.
+pub fn tts_saved_object<F: Fn(usize) -> String>(
+    deck_name: &str,
+    entries: &[DeckCardEntry],
+    sheet_url: F,
+    back_url: &str,
+) -> TtsSavedObject {
+    let mut custom_decks = BTreeMap::new();
+    let mut card_objects = Vec::new();
+
+    for (index, sheet) in entries.chunks(TTS_CARDS_PER_SHEET).enumerate() {
+        let sheet_id = index + 1;
+        let custom_deck = TtsCustomDeck {
+            face_url: sheet_url(sheet_id),
+            back_url: back_url.to_string(),
+            num_width: CARDSHEET_COLUMNS,
+            num_height: tts_sheet_rows(sheet.len()),
+            back_is_hidden: false,
+            unique_back: false,
+        };
+
+        for (slot, entry) in sheet.iter().enumerate() {
+            for _ in 0..entry.quantity.max(0) {
+                card_objects.push(TtsObject {
+                    name: "Card".to_string(),
+                    nickname: entry.card.name.clone(),
+                    description: entry.card.desc.clone(),
+                    transform: TtsTransform::default(),
+                    // Cards are numbered by their sheet, then their slot.
+                    card_id: Some((sheet_id * 100 + slot) as u32),
+                    deck_ids: None,
+                    custom_deck: std::iter::once((sheet_id.to_string(), custom_deck.clone()))
+                        .collect(),
+                    contained_objects: None,
+                });
+            }
+        }
+
+        custom_decks.insert(sheet_id.to_string(), custom_deck);
+    }
+
+    // A deck of one card is imported as just that card.
+    if card_objects.len() == 1 {
+        return TtsSavedObject {
+            object_states: card_objects,
+        };
+    }
+
+    TtsSavedObject {
+        object_states: vec![TtsObject {
+            name: "DeckCustom".to_string(),
+            nickname: deck_name.to_string(),
+            description: String::new(),
+            transform: TtsTransform::default(),
+            card_id: None,
+            deck_ids: Some(
+                card_objects
+                    .iter()
+                    .filter_map(|object| object.card_id)
+                    .collect(),
+            ),
+            custom_deck: custom_decks,
+            contained_objects: Some(card_objects),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_values::{CardAction, CardSpeed};
+    use crate::image::tts::{tts_saved_object, tts_sheet_rows};
+    use crate::models::{Card, DeckCardEntry};
+
+    #[test]
+    fn given_deck_past_one_sheet_when_saved_then_cards_span_sheets() {
+        let entries = (0..70)
+            .map(|id| DeckCardEntry {
+                card: Card {
+                    id,
+                    cardclass: "ATK".to_string(),
+                    action: CardAction::Attack,
+                    speed: CardSpeed::Normal,
+                    initiative: 1,
+                    name: format!("Card {}", id),
+                    desc: "Deal 1 damage.".to_string(),
+                    image_url: None,
+                    version: 1,
+                    cloned_from_id: None,
+                },
+                quantity: if id == 0 { 2 } else { 1 },
+                position: id,
+            })
+            .collect::<Vec<DeckCardEntry>>();
+
+        let saved = serde_json::to_value(tts_saved_object(
+            "Starter",
+            &entries,
+            |sheet| format!("http://localhost/tts/{}.png", sheet),
+            "http://localhost/tts/back.png",
+        ))
+        .unwrap();
+        let deck = &saved["ObjectStates"][0];
+
+        assert_eq!(tts_sheet_rows(69), 7);
+        assert_eq!(tts_sheet_rows(19), 2);
+        assert_eq!(deck["Name"], "DeckCustom");
+        assert_eq!(deck["CustomDeck"]["1"]["NumHeight"], 7);
+        assert_eq!(deck["CustomDeck"]["2"]["NumHeight"], 2);
+        assert_eq!(
+            deck["CustomDeck"]["2"]["FaceURL"],
+            "http://localhost/tts/2.png"
+        );
+        assert_eq!(deck["DeckIDs"].as_array().unwrap().len(), 71);
+        assert_eq!(deck["DeckIDs"][1], 100);
+        assert_eq!(deck["DeckIDs"][70], 200);
+        assert_eq!(deck["ContainedObjects"][70]["Nickname"], "Card 69");
+    }
+}
@@ -0,0 +1,557 @@
+//! Consistency checks for databases that have been edited by hand.
+//!
+//! SQLite does not enforce the foreign keys between tables unless asked to,
+//! so edits made outside of the server can leave rows pointing at cards that
+//! no longer exist, cards of classes that were never created, and cards
+//! whose names are no longer unique. Rows that point at nothing can be
+//! deleted without losing anything, and cards or decks cloned from ones
+//! that are gone can forget where they came from, so those can be repaired;
+//! everything else needs a person to decide what the data should have been.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Integer;
+
+use anyhow::Result;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use crate::card_values::CardValueAuditEntry;
+use crate::database::DatabaseContext;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrityIssueKind {
+    UnknownCardClass,
+    UnknownCardValue,
+    DuplicateCardName,
+    OrphanedRow,
+    DanglingReference,
+}
+
+impl IntegrityIssueKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntegrityIssueKind::UnknownCardClass => "unknown_card_class",
+            IntegrityIssueKind::UnknownCardValue => "unknown_card_value",
+            IntegrityIssueKind::DuplicateCardName => "duplicate_card_name",
+            IntegrityIssueKind::OrphanedRow => "orphaned_row",
+            IntegrityIssueKind::DanglingReference => "dangling_reference",
+        }
+    }
+
+    /// Whether issues of this kind can be repaired without a person deciding
+    /// what the data should be.
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            IntegrityIssueKind::OrphanedRow | IntegrityIssueKind::DanglingReference
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityIssue {
+    pub kind: IntegrityIssueKind,
+    pub table: String,
+    /// The ids of the offending rows of `table`.
+    pub ids: Vec<i32>,
+    /// The column that points at nothing, for dangling references.
+    #[serde(default)]
+    pub column: Option<String>,
+    pub message: String,
+    pub repairable: bool,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub issues: Vec<IntegrityIssue>,
+}
+
+impl IntegrityReport {
+    /// Whether any issue is left after repairs, if any were made.
+    pub fn has_issues(&self) -> bool {
+        self.issues.iter().any(|issue| !issue.repaired)
+    }
+}
+
+/// The references between tables that SQLite does not enforce, as
+/// `(table, column, referenced table)`.
+const REFERENCES: &[(&str, &str, &str)] = &[
+    ("cards_card_attributes_relation", "card_id", "cards"),
+    (
+        "cards_card_attributes_relation",
+        "card_attribute_id",
+        "card_attributes",
+    ),
+    ("decks_cards_relation", "card_id", "cards"),
+    ("decks_cards_relation", "deck_id", "decks"),
+    ("card_sets_cards_relation", "card_id", "cards"),
+    ("card_sets_cards_relation", "card_set_id", "card_sets"),
+    ("card_translations", "card_id", "cards"),
+    ("card_relations", "card_id", "cards"),
+    ("card_relations", "related_card_id", "cards"),
+    ("deck_rules_cardclass_limits", "deck_rules_id", "deck_rules"),
+    ("card_value_flags", "card_id", "cards"),
+];
+
+/// References that may be left empty, as `(table, column, referenced
+/// table)`. When what they point at is gone, only the reference is cleared,
+/// as the row itself is still whole.
+const OPTIONAL_REFERENCES: &[(&str, &str, &str)] = &[
+    ("cards", "cloned_from_id", "cards"),
+    ("decks", "cloned_from_id", "decks"),
+];
+
+#[derive(QueryableByName)]
+struct IdRow {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+fn issue(kind: IntegrityIssueKind, table: &str, ids: Vec<i32>, message: String) -> IntegrityIssue {
+    IntegrityIssue {
+        kind,
+        table: table.to_owned(),
+        ids,
+        column: None,
+        message,
+        repairable: kind.is_repairable(),
+        repaired: false,
+    }
+}
+
+/// Groups the ids of cards whose names collide. Names are compared without
+/// case, as SQLite's `LIKE` does when cards are looked up by name.
+pub fn group_duplicate_names(cards: &[(i32, String)]) -> Vec<(String, Vec<i32>)> {
+    let mut groups: BTreeMap<String, (String, Vec<i32>)> = BTreeMap::new();
+
+    for (card_id, card_name) in cards {
+        groups
+            .entry(card_name.to_lowercase())
+            .or_insert_with(|| (card_name.clone(), vec![]))
+            .1
+            .push(*card_id);
+    }
+
+    groups
+        .into_values()
+        .filter(|(_, ids)| ids.len() > 1)
+        .collect()
+}
+
+impl DatabaseContext {
+    /// Scans every table for inconsistencies. With `repair`, the issues that
+    /// can be repaired safely are repaired, all in one transaction.
+    pub fn check_integrity(&mut self, repair: bool) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+
+        report.issues.extend(self.check_card_classes()?);
+        report.issues.extend(self.check_card_values()?);
+        report.issues.extend(self.check_duplicate_card_names()?);
+        report.issues.extend(self.check_references()?);
+
+        debug!("check_integrity found {} issue(s)", report.issues.len());
+
+        if repair {
+            self.repair_integrity_issues(&mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    fn check_card_classes(&self) -> Result<Vec<IntegrityIssue>> {
+        use crate::schema::cards::dsl::*;
+
+        let registry = self.get_card_class_registry()?;
+
+        let mut unknown: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+        for (card_id, card_class) in cards
+            .select((id, cardclass))
+            .order(id.asc())
+            .load::<(i32, String)>(self.connection.as_ref())?
+        {
+            if registry.get(&card_class).is_none() {
+                unknown.entry(card_class).or_default().push(card_id);
+            }
+        }
+
+        Ok(unknown
+            .into_iter()
+            .map(|(card_class, ids)| {
+                issue(
+                    IntegrityIssueKind::UnknownCardClass,
+                    "cards",
+                    ids,
+                    format!("Cards use the unknown card class '{}'", card_class),
+                )
+            })
+            .collect())
+    }
+
+    fn check_card_values(&self) -> Result<Vec<IntegrityIssue>> {
+        let mut unknown: BTreeMap<(String, String), (Option<String>, Vec<i32>)> = BTreeMap::new();
+
+        for entry in self.audit_card_values()? {
+            let CardValueAuditEntry {
+                card_id,
+                field,
+                value,
+                suggestion,
+                ..
+            } = entry;
+
+            unknown
+                .entry((field, value))
+                .or_insert_with(|| (suggestion, vec![]))
+                .1
+                .push(card_id);
+        }
+
+        Ok(unknown
+            .into_iter()
+            .map(|((field, value), (suggestion, ids))| {
+                let suggestion = suggestion
+                    .map(|s| format!(" (did you mean '{}'?)", s))
+                    .unwrap_or_default();
+
+                issue(
+                    IntegrityIssueKind::UnknownCardValue,
+                    "cards",
+                    ids,
+                    format!("Cards have the unknown {} '{}'{}", field, value, suggestion),
+                )
+            })
+            .collect())
+    }
+
+    fn check_duplicate_card_names(&self) -> Result<Vec<IntegrityIssue>> {
+        use crate::schema::cards::dsl::*;
+
+        let all_cards = cards
+            .select((id, name))
+            .order(id.asc())
+            .load::<(i32, String)>(self.connection.as_ref())?;
+
+        Ok(group_duplicate_names(&all_cards)
+            .into_iter()
+            .map(|(card_name, ids)| {
+                issue(
+                    IntegrityIssueKind::DuplicateCardName,
+                    "cards",
+                    ids,
+                    format!("Cards share the name '{}'", card_name),
+                )
+            })
+            .collect())
+    }
+
+    fn check_references(&self) -> Result<Vec<IntegrityIssue>> {
+        let mut issues = Vec::new();
+
+        for (table, column, referenced_table) in REFERENCES {
+            let ids = self.find_broken_references(table, column, referenced_table)?;
+
+            if !ids.is_empty() {
+                issues.push(issue(
+                    IntegrityIssueKind::OrphanedRow,
+                    table,
+                    ids,
+                    format!(
+                        "Rows of {} point at {} that do not exist through {}",
+                        table, referenced_table, column
+                    ),
+                ));
+            }
+        }
+
+        for (table, column, referenced_table) in OPTIONAL_REFERENCES {
+            let ids = self.find_broken_references(table, column, referenced_table)?;
+
+            if !ids.is_empty() {
+                issues.push(IntegrityIssue {
+                    column: Some(column.to_string()),
+                    ..issue(
+                        IntegrityIssueKind::DanglingReference,
+                        table,
+                        ids,
+                        format!(
+                            "Rows of {} point at {} that do not exist through {}",
+                            table, referenced_table, column
+                        ),
+                    )
+                });
+            }
+        }
+
+        Ok(issues)
+    }
+
+    /// The ids of the rows of `table` whose `column` points at no row of
+    /// `referenced_table`.
+    fn find_broken_references(
+        &self,
+        table: &str,
+        column: &str,
+        referenced_table: &str,
+    ) -> Result<Vec<i32>> {
+        Ok(sql_query(format!(
+            "SELECT id FROM {table} \
+            WHERE {column} NOT IN (SELECT id FROM {referenced_table}) \
+            ORDER BY id",
+            table = table,
+            column = column,
+            referenced_table = referenced_table
+        ))
+        .load::<IdRow>(self.connection.as_ref())?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
+    fn repair_integrity_issues(&mut self, report: &mut IntegrityReport) -> Result<()> {
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            for issue in report.issues.iter().filter(|issue| issue.repairable) {
+                let ids = issue
+                    .ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                let query = match &issue.column {
+                    Some(column) => {
+                        info!(
+                            "Clearing {} of {} row(s) {:?}",
+                            column, issue.table, issue.ids
+                        );
+                        format!(
+                            "UPDATE {} SET {} = NULL WHERE id IN ({})",
+                            issue.table, column, ids
+                        )
+                    }
+                    None => {
+                        info!("Deleting {} row(s) {:?}", issue.table, issue.ids);
+                        format!("DELETE FROM {} WHERE id IN ({})", issue.table, ids)
+                    }
+                };
+
+                sql_query(query).execute(self.connection.as_ref())?;
+            }
+
+            Ok(())
+        })?;
+
+        for issue in report.issues.iter_mut() {
+            issue.repaired = issue.repairable;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::database::DatabaseContext;
+    use crate::integrity::{group_duplicate_names, IntegrityIssueKind, REFERENCES};
+
+    /// Opens a new database in its own directory with the columns the checks
+    /// read, and returns it with the directory to remove afterwards.
+    fn open_test_database(name: &str) -> (DatabaseContext, std::path::PathBuf) {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+        let mut columns: Vec<(&str, Vec<&str>)> = vec![
+            (
+                "cards",
+                vec![
+                    "name TEXT",
+                    "speed TEXT",
+                    "action TEXT",
+                    "cloned_from_id INTEGER",
+                ],
+            ),
+            ("decks", vec!["cloned_from_id INTEGER"]),
+            (
+                "card_value_flags",
+                vec!["card_id", "field TEXT", "value TEXT"],
+            ),
+        ];
+        for (table, column, referenced_table) in REFERENCES {
+            for table in &[*table, *referenced_table] {
+                if !columns.iter().any(|(existing, _)| existing == table) {
+                    columns.push((table, vec![]));
+                }
+            }
+            let entry = columns
+                .iter_mut()
+                .find(|(existing, _)| existing == table)
+                .unwrap();
+            if !entry.1.iter().any(|existing| existing.starts_with(column)) {
+                entry.1.push(column);
+            }
+        }
+        for (table, table_columns) in columns {
+            let table_columns = std::iter::once("id INTEGER PRIMARY KEY")
+                .chain(table_columns)
+                .collect::<Vec<_>>()
+                .join(", ");
+            sql_query(format!("CREATE TABLE {} ({})", table, table_columns))
+                .execute(db.connection.as_ref())
+                .unwrap();
+        }
+
+        (db, directory)
+    }
+
+    fn execute(db: &DatabaseContext, query: &str) {
+        sql_query(query).execute(db.connection.as_ref()).unwrap();
+    }
+
+    fn count(db: &DatabaseContext, table: &str) -> usize {
+        sql_query(format!("SELECT id FROM {}", table))
+            .load::<super::IdRow>(db.connection.as_ref())
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn given_names_differing_in_case_when_group_duplicate_names_then_grouped() {
+        let cards = vec![
+            (1, "Fireball".to_owned()),
+            (2, "Frostbolt".to_owned()),
+            (3, "fireball".to_owned()),
+        ];
+
+        assert_eq!(
+            group_duplicate_names(&cards),
+            vec![("Fireball".to_owned(), vec![1, 3])]
+        );
+    }
+
+    #[test]
+    fn given_unknown_card_values_when_checked_then_grouped_by_value_with_suggestion() {
+        let (db, directory) = open_test_database("integrity-values-test");
+        execute(
+            &db,
+            "INSERT INTO cards (id, name, speed, action) VALUES \
+            (1, 'Lunge', 'Fats', 'Attack'), \
+            (2, 'Parry', 'Normal', 'Attack'), \
+            (3, 'Feint', 'Fats', 'Attack')",
+        );
+        execute(
+            &db,
+            "INSERT INTO card_value_flags (id, card_id, field, value) VALUES (1, 2, 'action', 'Teleport')",
+        );
+
+        let issues = db.check_card_values().unwrap();
+
+        assert_eq!(issues.len(), 2);
+        assert!(issues
+            .iter()
+            .all(|issue| issue.kind == IntegrityIssueKind::UnknownCardValue && !issue.repairable));
+        assert_eq!(issues[0].ids, vec![2]);
+        assert_eq!(
+            issues[0].message,
+            "Cards have the unknown action 'Teleport'"
+        );
+        assert_eq!(issues[1].ids, vec![1, 3]);
+        assert_eq!(
+            issues[1].message,
+            "Cards have the unknown speed 'Fats' (did you mean 'Fast'?)"
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_broken_references_when_repaired_then_orphans_deleted_and_provenance_cleared() {
+        let (mut db, directory) = open_test_database("integrity-repair-test");
+        execute(
+            &db,
+            "INSERT INTO cards (id, name, speed, action, cloned_from_id) VALUES \
+            (1, 'Lunge', 'Fast', 'Attack', NULL), \
+            (2, 'Lunge II', 'Fast', 'Attack', 9)",
+        );
+        execute(
+            &db,
+            "INSERT INTO decks (id, cloned_from_id) VALUES (1, NULL)",
+        );
+        execute(
+            &db,
+            "INSERT INTO decks_cards_relation (id, deck_id, card_id) VALUES \
+            (1, 1, 1), (2, 1, 9), (3, 7, 1)",
+        );
+
+        let mut report = super::IntegrityReport {
+            issues: db.check_references().unwrap(),
+        };
+
+        let broken = report
+            .issues
+            .iter()
+            .map(|issue| (issue.kind, issue.table.as_str(), issue.ids.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            broken,
+            vec![
+                (
+                    IntegrityIssueKind::OrphanedRow,
+                    "decks_cards_relation",
+                    vec![2]
+                ),
+                (
+                    IntegrityIssueKind::OrphanedRow,
+                    "decks_cards_relation",
+                    vec![3]
+                ),
+                (IntegrityIssueKind::DanglingReference, "cards", vec![2]),
+            ]
+        );
+
+        db.repair_integrity_issues(&mut report).unwrap();
+
+        assert!(!report.has_issues());
+        assert_eq!(count(&db, "decks_cards_relation"), 1);
+        assert_eq!(count(&db, "cards"), 2);
+        assert!(db.check_references().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_failing_repair_when_repaired_then_nothing_changed() {
+        let (mut db, directory) = open_test_database("integrity-rollback-test");
+        execute(
+            &db,
+            "INSERT INTO decks (id, cloned_from_id) VALUES (1, NULL)",
+        );
+        execute(
+            &db,
+            "INSERT INTO decks_cards_relation (id, deck_id, card_id) VALUES (1, 1, 9)",
+        );
+
+        let mut report = super::IntegrityReport {
+            issues: db.check_references().unwrap(),
+        };
+        report.issues.push(super::issue(
+            IntegrityIssueKind::OrphanedRow,
+            "missing_table",
+            vec![1],
+            "Rows of a table that does not exist".to_owned(),
+        ));
+
+        assert!(db.repair_integrity_issues(&mut report).is_err());
+        assert!(report.issues.iter().all(|issue| !issue.repaired));
+        assert_eq!(count(&db, "decks_cards_relation"), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
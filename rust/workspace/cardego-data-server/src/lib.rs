@@ -7,35 +7,78 @@ extern crate nom;
 extern crate anyhow;
 extern crate itertools;
 
+pub mod analytics;
+pub mod backup;
+pub mod bulk;
+pub mod card_classes;
+pub mod card_relations;
+pub mod card_sets;
+pub mod card_themes;
+pub mod card_values;
+pub mod card_versions;
+pub mod cloning;
 pub mod database;
+pub mod databases;
+pub mod deck_rules;
 pub mod errors;
 pub mod image;
+pub mod integrity;
 pub mod models;
+pub mod print;
+pub mod render_jobs;
 pub mod schema;
 pub mod search;
+pub mod translations;
+pub mod validation;
 
 use diesel::prelude::*;
 
 use anyhow::{anyhow, Result};
 use log::debug;
 
+use self::card_values::{CardAction, CardSpeed};
+use self::card_versions::CardPrecondition;
 use self::database::DatabaseContext;
+use self::databases::DatabaseConfig;
+use self::deck_rules::{unknown_card_violation, DeckValidationReport};
 use self::errors::*;
+use self::image::renderer::{
+    load_card_renderer_kind, CardRenderer, CardRendererKind, RENDERER_CONFIG_PATH,
+};
 use self::models::*;
+use self::render_jobs::RenderJobQueue;
 
 use diesel::sql_query;
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
 
 use itertools::Itertools;
 
+/// The state of the server for one of the databases it serves.
 pub struct ServerState {
     pub config: ApplicationConfig,
+    pub database: DatabaseConfig,
     pub schema: crate::search::Schema,
+    /// Shared by every database, as it holds the fonts loaded at startup.
+    pub card_renderer: Arc<dyn CardRenderer>,
+    /// Shared by every database, so that the worker limits hold server-wide.
+    pub render_jobs: Arc<RenderJobQueue>,
 }
 
+#[derive(Debug, Clone)]
 pub struct ApplicationConfig {
-    pub database_endpoint: String,
+    /// The databases served, the default database first.
+    pub databases: Vec<DatabaseConfig>,
+    /// How many database snapshots to keep before pruning the oldest.
+    pub backup_retention: usize,
+    pub card_renderer: CardRendererKind,
+    /// How large the cache of rendered images may grow before the least
+    /// recently used images are evicted.
+    pub render_cache_max_bytes: u64,
+    /// How many render jobs run at once, and how many may wait to run.
+    pub render_workers: usize,
+    pub render_queue_limit: usize,
 }
 
 impl ApplicationConfig {
@@ -43,9 +86,105 @@ impl ApplicationConfig {
         debug!("Initializing ApplicationConfig");
 
         Ok(Self {
-            database_endpoint: String::from("runtime/data/databases/cards.db"),
+            databases: databases::load_database_configs(databases::DATABASES_CONFIG_PATH)?,
+            backup_retention: 10,
+            card_renderer: load_card_renderer_kind(RENDERER_CONFIG_PATH)?,
+            render_cache_max_bytes: 256 * 1024 * 1024,
+            render_workers: 2,
+            render_queue_limit: 32,
         })
     }
+
+    pub fn default_database(&self) -> &DatabaseConfig {
+        &self.databases[0]
+    }
+
+    pub fn get_database(&self, name: &str) -> Option<&DatabaseConfig> {
+        self.databases.iter().find(|database| database.name == name)
+    }
+}
+
+/// Folds a list of card ids into `(card_id, quantity)` pairs, ordered by the
+/// first appearance of each id.
+pub fn group_card_ids_by_quantity(ids: &[i32]) -> Vec<(i32, i32)> {
+    let mut results: Vec<(i32, i32)> = Vec::new();
+
+    for id in ids {
+        match results.iter_mut().find(|(card_id, _)| card_id == id) {
+            Some((_, quantity)) => *quantity += 1,
+            None => results.push((*id, 1)),
+        }
+    }
+
+    results
+}
+
+/// What setting the quantity of a card in a deck does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeckCardQuantity {
+    Remove,
+    Set(i32),
+}
+
+impl DeckCardQuantity {
+    /// A quantity of zero removes the card; negative quantities are refused.
+    pub fn new(quantity: i32) -> std::result::Result<Self, ClientError> {
+        match quantity {
+            q if q < 0 => Err(ClientError::InvalidInput(format!(
+                "Card quantities cannot be negative; found {}",
+                q
+            ))),
+            0 => Ok(DeckCardQuantity::Remove),
+            q => Ok(DeckCardQuantity::Set(q)),
+        }
+    }
+}
+
+/// Why `deck_name` cannot name a deck, if it cannot. Deck names are used in
+/// the file names of deck images, so they cannot hold path separators, `..`
+/// or control characters.
+pub fn deck_name_error(deck_name: &str) -> Option<String> {
+    if deck_name.trim().is_empty() {
+        Some("Deck names cannot be empty".to_owned())
+    } else if deck_name.contains('/') || deck_name.contains('\\') || deck_name.contains("..") {
+        Some(format!(
+            "Deck names cannot contain '/', '\\' or '..'; found '{}'",
+            deck_name
+        ))
+    } else if deck_name.chars().any(char::is_control) {
+        Some("Deck names cannot contain control characters".to_owned())
+    } else {
+        None
+    }
+}
+
+/// Checks that `deck_name` can name a deck; see `deck_name_error`.
+pub fn check_deck_name(deck_name: &str) -> std::result::Result<(), ClientError> {
+    match deck_name_error(deck_name) {
+        Some(message) => Err(ClientError::InvalidInput(message)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that a new order of a deck names every card in it exactly once.
+pub fn check_deck_order(
+    deck_name: &str,
+    current_ids: &[i32],
+    requested_ids: &[i32],
+) -> std::result::Result<(), ClientError> {
+    let mut current_ids = current_ids.to_vec();
+    let mut requested_ids = requested_ids.to_vec();
+    current_ids.sort_unstable();
+    requested_ids.sort_unstable();
+
+    if current_ids != requested_ids {
+        return Err(ClientError::InvalidInput(format!(
+            "The new order must list each card in deck '{}' exactly once",
+            deck_name
+        )));
+    }
+
+    Ok(())
 }
 
 impl DatabaseContext {
@@ -64,10 +203,7 @@ impl DatabaseContext {
     pub fn get_full_card_data(&self, card_id: i32) -> Result<FullCardData> {
         let card = self.get_card(card_id)?;
 
-        let attributes = self
-            .get_card_attributes_by_card_id(card_id)
-            .map(|v| Some(v))
-            .unwrap_or(None);
+        let attributes = self.get_card_attributes_by_card_id(card_id).ok();
 
         Ok(FullCardData {
             id: card.id,
@@ -78,150 +214,255 @@ impl DatabaseContext {
             name: card.name,
             desc: card.desc,
             image_url: card.image_url,
+            version: card.version,
+            cloned_from_id: card.cloned_from_id,
             attributes,
+            relations: Some(self.get_card_relations(card_id)?),
         })
     }
 
     // TODO: memory management on this needs to be optimized; currently just
     // clone()-ing things like a madman.
-    pub fn create_card(&mut self, card_data: &NewFullCardData) -> Result<FullCardData> {
+    pub fn create_card(&self, card_data: &NewFullCardData) -> Result<FullCardData> {
         debug!("create_card: {:?}", card_data);
 
         use schema::cards;
         use schema::cards_card_attributes_relation;
 
+        card_data
+            .validate(&self.get_card_validation_context()?)
+            .map_err(ClientError::ValidationFailed)?;
+
+        let action = card_data.action.parse::<CardAction>()?;
+        let speed = card_data.speed.parse::<CardSpeed>()?;
+
         let card = NewCard {
             cardclass: &card_data.cardclass,
-            action: &card_data.action,
-            speed: &card_data.speed,
+            action,
+            speed,
             initiative: card_data.initiative,
             name: &card_data.name,
             desc: &card_data.desc,
-            image_url: card_data.image_url.as_ref().map(|s| s.as_str()),
+            image_url: card_data.image_url.as_deref(),
         };
 
-        diesel::replace_into(cards::table)
-            .values(&card)
-            .execute(self.connection.as_mut())?;
-
-        // Get the id of the card by querying for the name.
-        //
-        // TODO: This is very bad and needed to be made deterministic. Cards
-        // with duplicate names will destroy this.
-        let card_name = &card.name;
-        let new_card_result = self.query_cards_by_name_formatted(card_name)?;
-
-        let new_card =
-            new_card_result
-                .first()
-                .into_iter()
-                .nth(0)
-                .ok_or(ServerError::OtherError(anyhow!(
-                    "Could not find expected card name '{}' after successful \
-                insert/replace into SQLite database.",
-                    &card.name
-                )))?;
-        let last_id = new_card.id;
-
-        debug!("Created card with id {}", last_id);
-
-        // Insert attributes into the attribute table
-        let new_card_attribute_relations: Option<Vec<NewCardCardAttributeRelation>> =
-            card_data.card_attributes.as_ref().map(|v| {
-                v.iter()
-                    .map(|attr| NewCardCardAttributeRelation {
-                        card_id: last_id,
-                        card_attribute_id: *attr,
-                    })
-                    .collect()
-            });
-
-        match new_card_attribute_relations {
-            Some(ref v) => {
-                diesel::insert_into(cards_card_attributes_relation::table)
-                    .values(v)
-                    .execute(self.connection.as_mut())?;
+        // The card and its attributes are written together, so that a failed
+        // write leaves no card behind.
+        let last_id = self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::replace_into(cards::table)
+                .values(&card)
+                .execute(self.connection.as_ref())?;
+
+            // Get the id of the card by querying for the name.
+            //
+            // TODO: This is very bad and needed to be made deterministic. Cards
+            // with duplicate names will destroy this.
+            let card_name = &card.name;
+            let new_card_result = self.query_cards_by_name_formatted(card_name)?;
+
+            let new_card =
+                new_card_result
+                    .first()
+                    .into_iter()
+                    .nth(0)
+                    .ok_or(ServerError::OtherError(anyhow!(
+                        "Could not find expected card name '{}' after successful \
+                    insert/replace into SQLite database.",
+                        &card.name
+                    )))?;
+            let last_id = new_card.id;
+
+            debug!("Created card with id {}", last_id);
+
+            // Insert attributes into the attribute table
+            let new_card_attribute_relations: Option<Vec<NewCardCardAttributeRelation>> =
+                card_data.card_attributes.as_ref().map(|v| {
+                    v.iter()
+                        .map(|attr| NewCardCardAttributeRelation {
+                            card_id: last_id,
+                            card_attribute_id: *attr,
+                        })
+                        .collect()
+                });
+
+            match new_card_attribute_relations {
+                Some(ref v) => {
+                    diesel::insert_into(cards_card_attributes_relation::table)
+                        .values(v)
+                        .execute(self.connection.as_ref())?;
+
+                    debug!(
+                        "Created card_attributes with ids {:?}",
+                        new_card_attribute_relations
+                    );
+                }
+                None => {
+                    debug!("No card attributes to be written; skipping");
+                }
+            };
 
-                debug!(
-                    "Created card_attributes with ids {:?}",
-                    new_card_attribute_relations
-                );
-            }
-            None => {
-                debug!("No card attributes to be written; skipping");
-            }
-        };
+            Ok(last_id)
+        })?;
 
         // Get the associated attributes out again
-        let attributes = self
-            .get_card_attributes_by_card_id(last_id)
-            .map(|v| Some(v))
-            .unwrap_or(None);
+        let attributes = self.get_card_attributes_by_card_id(last_id).ok();
+
+        // Ids of deleted cards can be handed out again.
+        self.invalidate_card_renders(last_id)?;
 
         debug!("create_card succeeded");
         Ok(FullCardData {
             id: last_id,
             attributes: attributes,
             cardclass: card_data.cardclass.clone(),
-            action: card_data.action.clone(),
-            speed: card_data.speed.clone(),
+            action,
+            speed,
             initiative: card_data.initiative,
             name: card_data.name.clone(),
             desc: card_data.desc.clone(),
             image_url: card_data.image_url.clone(),
+            version: 1,
+            cloned_from_id: None,
+            relations: Some(vec![]),
         })
     }
 
-    // TODO: memory management on this needs to be optimized; currently just
-    // clone()-ing things like a madman.
-    pub fn update_card(&mut self, card_data: FullCardData) -> Result<FullCardData> {
-        debug!("update_card: {:?}", card_data);
+    /// Writes a card, creating it if there is no card with its id yet and
+    /// the write is unconditional. Otherwise the card must exist, and when a
+    /// version is required it is only written if it is still at that
+    /// version, so that edits made on top of a stale copy of the card do not
+    /// overwrite changes made since.
+    pub fn update_card(
+        &self,
+        card_data: FullCardData,
+        precondition: CardPrecondition,
+    ) -> Result<FullCardData> {
+        debug!("update_card: {:?} {:?}", card_data, precondition);
+
+        card_data
+            .validate(&self.get_card_validation_context()?)
+            .map_err(ClientError::ValidationFailed)?;
+
+        self.write_card(card_data, precondition)
+    }
+
+    /// Writes a card as it was sent, with every invalid field reported
+    /// together, including a `speed` or `action` that is not a known value.
+    pub fn update_card_from_payload(
+        &self,
+        payload: FullCardDataPayload,
+        precondition: CardPrecondition,
+    ) -> Result<FullCardData> {
+        debug!("update_card_from_payload: {:?} {:?}", payload, precondition);
+
+        payload
+            .validate(&self.get_card_validation_context()?)
+            .map_err(ClientError::ValidationFailed)?;
 
+        self.write_card(payload.into_full_card_data()?, precondition)
+    }
+
+    /// Writes a card that has already been validated.
+    pub(crate) fn write_card(
+        &self,
+        card_data: FullCardData,
+        precondition: CardPrecondition,
+    ) -> Result<FullCardData> {
+        use schema::card_relations;
         use schema::cards;
         use schema::cards_card_attributes_relation;
 
-        let card = Card {
-            id: card_data.id,
-            cardclass: card_data.cardclass,
-            action: card_data.action,
-            speed: card_data.speed,
-            initiative: card_data.initiative,
-            name: card_data.name,
-            desc: card_data.desc,
-            image_url: card_data.image_url,
+        let card_id = card_data.id;
+        let existing = cards::table
+            .find(card_id)
+            .first::<Card>(self.connection.as_ref())
+            .optional()?;
+
+        match (&existing, precondition) {
+            (Some(_), _) => {
+                let changes = (
+                    cards::cardclass.eq(&card_data.cardclass),
+                    cards::action.eq(card_data.action),
+                    cards::speed.eq(card_data.speed),
+                    cards::initiative.eq(card_data.initiative),
+                    cards::name.eq(&card_data.name),
+                    cards::desc.eq(&card_data.desc),
+                    cards::image_url.eq(&card_data.image_url),
+                    cards::version.eq(cards::version + 1),
+                );
+
+                match precondition {
+                    // Checking the version in the same statement as the write
+                    // keeps two concurrent updates from both succeeding.
+                    CardPrecondition::Version(expected_version) => {
+                        let updated = diesel::update(
+                            cards::table
+                                .find(card_id)
+                                .filter(cards::version.eq(expected_version)),
+                        )
+                        .set(changes)
+                        .execute(self.connection.as_ref())?;
+
+                        if updated == 0 {
+                            Err(ClientError::PreconditionFailed(format!(
+                                "Card {} is no longer at version {}; it was changed by someone else",
+                                card_id, expected_version
+                            )))?
+                        }
+                    }
+                    // Without an expected version the last write wins.
+                    CardPrecondition::Unconditional | CardPrecondition::Exists => {
+                        diesel::update(cards::table.find(card_id))
+                            .set(changes)
+                            .execute(self.connection.as_ref())?;
+                    }
+                }
+            }
+            (None, CardPrecondition::Version(_)) => Err(ClientError::ResourceNotFound)?,
+            (None, CardPrecondition::Exists) => Err(ClientError::PreconditionFailed(format!(
+                "Card {} does not exist",
+                card_id
+            )))?,
+            (None, CardPrecondition::Unconditional) => {
+                diesel::insert_into(cards::table)
+                    .values(&Card {
+                        id: card_id,
+                        cardclass: card_data.cardclass.clone(),
+                        action: card_data.action,
+                        speed: card_data.speed,
+                        initiative: card_data.initiative,
+                        name: card_data.name.clone(),
+                        desc: card_data.desc.clone(),
+                        image_url: card_data.image_url.clone(),
+                        version: 1,
+                        cloned_from_id: None,
+                    })
+                    .execute(self.connection.as_ref())?;
+            }
         };
 
-        diesel::replace_into(cards::table)
-            .values(&card)
-            .execute(self.connection.as_mut())?;
+        debug!("Updated card with id {}", card_id);
 
-        // Get the id of the card by querying for the name.
-        //
-        // TODO: This is very bad and needed to be made deterministic. Cards
-        // with duplicate names will destroy this.
-        let card_name = &card.name;
-        let new_card_result = self.query_cards_by_name_formatted(card_name)?;
-
-        let new_card =
-            new_card_result
-                .first()
-                .into_iter()
-                .nth(0)
-                .ok_or(ServerError::OtherError(anyhow!(
-                    "Could not find expected card name '{}' after successful \
-                insert/replace into SQLite database.",
-                    &card.name
-                )))?;
-        let last_id = new_card.id;
+        // Cards show the names of the cards they relate to, so a rename
+        // changes them too.
+        if let Some(existing) = existing.filter(|existing| existing.name != card_data.name) {
+            let relating_card_ids = card_relations::table
+                .filter(card_relations::related_card_id.eq(existing.id))
+                .select(card_relations::card_id)
+                .distinct()
+                .load::<i32>(self.connection.as_ref())?;
 
-        debug!("Updated card with id {}", last_id);
+            for relating_card_id in relating_card_ids {
+                self.bump_card_version(relating_card_id)?;
+            }
+        }
 
         // Insert attributes into the attribute table
         let new_card_attribute_relations: Option<Vec<NewCardCardAttributeRelation>> =
             card_data.attributes.as_ref().map(|v| {
                 v.iter()
                     .map(|attr| NewCardCardAttributeRelation {
-                        card_id: last_id,
+                        card_id,
                         card_attribute_id: attr.id,
                     })
                     .collect()
@@ -231,7 +472,7 @@ impl DatabaseContext {
             Some(ref v) => {
                 diesel::replace_into(cards_card_attributes_relation::table)
                     .values(v)
-                    .execute(self.connection.as_mut())?;
+                    .execute(self.connection.as_ref())?;
 
                 debug!(
                     "Updated card_attributes with ids {:?}",
@@ -243,10 +484,13 @@ impl DatabaseContext {
             }
         };
 
+        let card = self.get_card(card_id)?;
+        self.invalidate_card_renders(card_id)?;
+
         debug!("update_card succeeded");
         Ok(FullCardData {
-            id: last_id,
-            attributes: card_data.attributes.clone(),
+            id: card.id,
+            attributes: card_data.attributes,
             cardclass: card.cardclass,
             action: card.action,
             speed: card.speed,
@@ -254,76 +498,351 @@ impl DatabaseContext {
             name: card.name,
             desc: card.desc,
             image_url: card.image_url,
+            version: card.version,
+            cloned_from_id: card.cloned_from_id,
+            relations: Some(self.get_card_relations(card_id)?),
         })
     }
 
-    pub fn create_deck(&mut self, name: String, ids: Vec<i32>) -> Result<Deck> {
+    /// Creates a new user deck from a list of card ids. When `validate` is
+    /// set, the deck is checked against the decktype's construction rules
+    /// first and is not created if it breaks any of them.
+    pub fn create_deck(&mut self, name: String, ids: Vec<i32>, validate: bool) -> Result<Deck> {
         debug!("put_deck: {} {:?}", name, ids);
 
         use schema::decks;
         use schema::decks_cards_relation;
 
-        // First, insert the deck entry
-        let new_deck = NewDeck {
-            id: None,
-            name: &name,
-            decktype: "user",
-        };
+        check_deck_name(&name)?;
 
-        diesel::insert_into(decks::table)
-            .values(&new_deck)
-            //.on_conflict(decks::name)
-            //.do_update()
-            //.set(&new_deck)
-            .execute(self.connection.as_mut())?;
+        let decktype = "user";
 
-        // Get the id of the deck
-        let new_deck = self.get_deck_by_name(&name)?;
-        let last_id = new_deck.id;
+        // Cards that do not exist are refused even when the rules are not
+        // checked, as the deck could not be loaded again.
+        let report = if validate {
+            self.validate_card_ids_for_decktype(&name, decktype, &ids)?
+        } else {
+            let violations = unknown_card_violation(self.find_unknown_card_ids(&ids)?)
+                .into_iter()
+                .collect::<Vec<_>>();
+            DeckValidationReport {
+                deck: name.clone(),
+                decktype: decktype.to_owned(),
+                valid: violations.is_empty(),
+                violations,
+            }
+        };
+        if !report.valid {
+            Err(ClientError::DeckRuleViolations(report))?
+        }
+
+        // The deck and its cards are written together, so that a failed
+        // write leaves no deck behind.
+        let new_deck = self.connection.transaction::<_, anyhow::Error, _>(|| {
+            // First, insert the deck entry
+            let new_deck = NewDeck {
+                id: None,
+                name: &name,
+                decktype,
+            };
+
+            diesel::insert_into(decks::table)
+                .values(&new_deck)
+                //.on_conflict(decks::name)
+                //.do_update()
+                //.set(&new_deck)
+                .execute(self.connection.as_ref())?;
+
+            // Get the id of the deck
+            let new_deck = self.get_deck_by_name(&name)?;
+            let last_id = new_deck.id;
+
+            debug!("Created new deck with id {}", last_id);
+
+            // Then, insert the deck's cards into the deck itself. Repeated ids
+            // are folded into a single entry with a quantity, ordered by where
+            // each card first appeared.
+            let new_deck_card_relations: Vec<NewDeckCardRelation> =
+                group_card_ids_by_quantity(&ids)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (card_id, quantity))| NewDeckCardRelation {
+                        deck_id: last_id,
+                        card_id,
+                        quantity,
+                        position: index as i32,
+                    })
+                    .collect();
 
-        debug!("Created new deck with id {}", last_id);
+            diesel::insert_into(decks_cards_relation::table)
+                .values(&new_deck_card_relations)
+                .execute(self.connection.as_ref())?;
 
-        // Then, insert the deck's cards into the deck itself
-        let new_deck_card_relations: Vec<NewDeckCardRelation> = ids
-            .iter()
-            .map(|card_id| NewDeckCardRelation {
-                deck_id: last_id,
-                card_id: *card_id,
-            })
-            .collect();
-
-        diesel::insert_into(decks_cards_relation::table)
-            .values(&new_deck_card_relations)
-            .execute(self.connection.as_mut())?;
+            Ok(new_deck)
+        })?;
 
         debug!("put_deck succeeded");
         Ok(new_deck)
     }
 
+    /// Returns the deck's cards in deck order, with each card repeated
+    /// according to its quantity in the deck.
     pub fn get_cards_by_deck_name(&self, set_name: String) -> Result<Vec<Card>> {
+        let entries = self.get_deck_card_entries_by_deck_name(&set_name)?;
+
+        let results = entries
+            .into_iter()
+            .flat_map(|entry| std::iter::repeat_n(entry.card, entry.quantity.max(0) as usize))
+            .collect();
+
+        Ok(results)
+    }
+
+    pub fn get_deck_card_entries_by_deck_name(
+        &self,
+        deck_name: &str,
+    ) -> Result<Vec<DeckCardEntry>> {
         use self::schema::*;
 
-        allow_tables_to_appear_in_same_query!(decks_cards_relation, cards, decks);
+        allow_tables_to_appear_in_same_query!(decks_cards_relation, cards);
 
-        // Get the list of cards from the card set
-        let query = decks::dsl::decks
-            .inner_join(
-                decks_cards_relation::dsl::decks_cards_relation.on(decks::dsl::name.like(set_name)),
-            )
+        let deck = self.get_deck_by_name(deck_name)?;
+
+        // Get the list of cards from the deck, in deck order
+        let query = decks_cards_relation::dsl::decks_cards_relation
             .inner_join(cards::dsl::cards.on(decks_cards_relation::dsl::card_id.eq(cards::dsl::id)))
-            .filter(decks::dsl::id.eq(decks_cards_relation::dsl::deck_id))
-            .select(cards::all_columns);
+            .filter(decks_cards_relation::dsl::deck_id.eq(deck.id))
+            .order((
+                decks_cards_relation::dsl::position.asc(),
+                decks_cards_relation::dsl::id.asc(),
+            ))
+            .select((
+                decks_cards_relation::dsl::quantity,
+                decks_cards_relation::dsl::position,
+                cards::all_columns,
+            ));
 
         debug!(
             "{}",
             diesel::debug_query::<diesel::sqlite::Sqlite, _>(&query).to_string()
         );
 
-        let results = query.load(self.connection.as_ref())?;
+        let results = query
+            .load::<(i32, i32, Card)>(self.connection.as_ref())?
+            .into_iter()
+            .map(|(quantity, position, card)| DeckCardEntry {
+                card,
+                quantity,
+                position,
+            })
+            .collect();
 
         Ok(results)
     }
 
+    pub fn rename_deck(&mut self, deck_name: &str, new_name: &str) -> Result<Deck> {
+        use self::schema::decks::dsl::*;
+
+        debug!("rename_deck: {} -> {}", deck_name, new_name);
+
+        check_deck_name(new_name)?;
+
+        let deck = self
+            .get_deck_by_name(deck_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        if let Ok(existing) = self.get_deck_by_name(new_name) {
+            if existing.id != deck.id {
+                Err(ClientError::InvalidInput(format!(
+                    "A deck named '{}' already exists",
+                    new_name
+                )))?
+            }
+        }
+
+        diesel::update(decks.find(deck.id))
+            .set(name.eq(new_name))
+            .execute(self.connection.as_mut())?;
+
+        Ok(Deck {
+            name: new_name.to_owned(),
+            ..deck
+        })
+    }
+
+    /// Adds copies of a card to the end of the deck. If the card is already
+    /// in the deck, its quantity is increased instead and its position is
+    /// kept.
+    pub fn add_card_to_deck(
+        &mut self,
+        deck_name: &str,
+        new_card_id: i32,
+        copies: i32,
+    ) -> Result<Vec<DeckCardEntry>> {
+        use self::schema::decks_cards_relation::dsl::*;
+
+        debug!(
+            "add_card_to_deck: {} {} x{}",
+            deck_name, new_card_id, copies
+        );
+
+        if copies < 1 {
+            Err(ClientError::InvalidInput(format!(
+                "Cannot add {} copies of a card to a deck",
+                copies
+            )))?
+        }
+
+        let deck = self
+            .get_deck_by_name(deck_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+        self.get_card(new_card_id)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            let existing = decks_cards_relation
+                .filter(deck_id.eq(deck.id))
+                .filter(card_id.eq(new_card_id))
+                .first::<DeckCardRelation>(self.connection.as_ref())
+                .optional()?;
+
+            match existing {
+                Some(relation) => {
+                    diesel::update(decks_cards_relation.find(relation.id))
+                        .set(quantity.eq(relation.quantity + copies))
+                        .execute(self.connection.as_ref())?;
+                }
+                None => {
+                    let last_position = decks_cards_relation
+                        .filter(deck_id.eq(deck.id))
+                        .select(diesel::dsl::max(position))
+                        .first::<Option<i32>>(self.connection.as_ref())?;
+
+                    diesel::insert_into(decks_cards_relation)
+                        .values(&NewDeckCardRelation {
+                            deck_id: deck.id,
+                            card_id: new_card_id,
+                            quantity: copies,
+                            position: last_position.map(|p| p + 1).unwrap_or(0),
+                        })
+                        .execute(self.connection.as_ref())?;
+                }
+            };
+
+            Ok(())
+        })?;
+
+        self.get_deck_card_entries_by_deck_name(deck_name)
+    }
+
+    /// Sets the number of copies of a card in the deck. Setting the quantity
+    /// to zero removes the card from the deck.
+    pub fn set_deck_card_quantity(
+        &mut self,
+        deck_name: &str,
+        target_card_id: i32,
+        new_quantity: i32,
+    ) -> Result<Vec<DeckCardEntry>> {
+        use self::schema::decks_cards_relation::dsl::*;
+
+        debug!(
+            "set_deck_card_quantity: {} {} x{}",
+            deck_name, target_card_id, new_quantity
+        );
+
+        let new_quantity = match DeckCardQuantity::new(new_quantity)? {
+            DeckCardQuantity::Remove => {
+                return self.remove_card_from_deck(deck_name, target_card_id)
+            }
+            DeckCardQuantity::Set(new_quantity) => new_quantity,
+        };
+
+        let deck = self
+            .get_deck_by_name(deck_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let updated = diesel::update(
+            decks_cards_relation
+                .filter(deck_id.eq(deck.id))
+                .filter(card_id.eq(target_card_id)),
+        )
+        .set(quantity.eq(new_quantity))
+        .execute(self.connection.as_mut())?;
+
+        if updated == 0 {
+            Err(ClientError::ResourceNotFound)?
+        }
+
+        self.get_deck_card_entries_by_deck_name(deck_name)
+    }
+
+    pub fn remove_card_from_deck(
+        &mut self,
+        deck_name: &str,
+        target_card_id: i32,
+    ) -> Result<Vec<DeckCardEntry>> {
+        use self::schema::decks_cards_relation::dsl::*;
+
+        debug!("remove_card_from_deck: {} {}", deck_name, target_card_id);
+
+        let deck = self
+            .get_deck_by_name(deck_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        let deleted = diesel::delete(
+            decks_cards_relation
+                .filter(deck_id.eq(deck.id))
+                .filter(card_id.eq(target_card_id)),
+        )
+        .execute(self.connection.as_mut())?;
+
+        if deleted == 0 {
+            Err(ClientError::ResourceNotFound)?
+        }
+
+        self.get_deck_card_entries_by_deck_name(deck_name)
+    }
+
+    /// Reorders the deck so that its cards follow `card_ids`. The list must
+    /// name every card in the deck exactly once.
+    pub fn reorder_deck_cards(
+        &mut self,
+        deck_name: &str,
+        card_ids: &[i32],
+    ) -> Result<Vec<DeckCardEntry>> {
+        use self::schema::decks_cards_relation::dsl::*;
+
+        debug!("reorder_deck_cards: {} {:?}", deck_name, card_ids);
+
+        let deck = self
+            .get_deck_by_name(deck_name)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        // The deck's cards are read in the same transaction as the new order
+        // is written, so that no card can be added or removed in between.
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            let current_ids = decks_cards_relation
+                .filter(deck_id.eq(deck.id))
+                .select(card_id)
+                .load::<i32>(self.connection.as_ref())?;
+            check_deck_order(&deck.name, &current_ids, card_ids)?;
+
+            for (index, target_card_id) in card_ids.iter().enumerate() {
+                diesel::update(
+                    decks_cards_relation
+                        .filter(deck_id.eq(deck.id))
+                        .filter(card_id.eq(*target_card_id)),
+                )
+                .set(position.eq(index as i32))
+                .execute(self.connection.as_ref())?;
+            }
+
+            Ok(())
+        })?;
+
+        self.get_deck_card_entries_by_deck_name(deck_name)
+    }
+
     pub fn get_deck_by_name(&self, s: &str) -> Result<Deck> {
         use self::schema::decks::dsl::*;
 
@@ -401,6 +920,28 @@ impl DatabaseContext {
         Ok(results)
     }
 
+    /// The cards that match a search query. A query that cannot be parsed
+    /// is invalid input; any other failure is the server's.
+    pub fn search_cards(&self, req_query_string: &str) -> Result<Vec<FullCardData>> {
+        use crate::search::query::ast::Expression;
+
+        Expression::from_query_string(req_query_string)
+            .map_err(|err| ClientError::InvalidInput(err.to_string()))?;
+
+        self.query_cards(req_query_string)
+            .map_err(|err| anyhow!("Could not query cards: {}", err))
+    }
+
+    /// The ids of the cards that match a search query, failing as
+    /// `search_cards` does.
+    pub fn query_card_ids(&self, req_query_string: &str) -> Result<Vec<i32>> {
+        Ok(self
+            .search_cards(req_query_string)?
+            .into_iter()
+            .map(|card| card.id)
+            .collect())
+    }
+
     /// Get the list of cards that match.
     /// Then, get the total list of relevant card attributes by id.
     /// Merge the CardAttribute onto the SearchCardData to become FullCardData.
@@ -437,16 +978,60 @@ impl DatabaseContext {
             .map(|card| card.id)
             .collect::<Vec<i32>>();
 
+        // Keep only the cards in the sets asked for, if any.
+        let card_ids_in_sets = self
+            .get_card_ids_by_card_set_filter(table_to_query_expression.get("card_sets").unwrap())?;
+
+        // Keep only the cards with the relations asked for, if any.
+        let card_ids_with_relations = self.get_card_ids_by_card_relation_filter(
+            table_to_query_expression.get("card_relations").unwrap(),
+        )?;
+
         // Get HashMap of (card -> card_attributes)
         let cards_to_attributes = self.get_card_attributes_by_card_id_and_filter(
-            &table_to_query_expression.get("card_attributes").unwrap(),
+            table_to_query_expression.get("card_attributes").unwrap(),
             Some(card_ids),
         )?;
 
+        let mut cards_to_relations = self
+            .get_card_relations_by_card_ids(search_results.iter().map(|card| card.id).collect())?;
+
+        // The search view predates card versions and provenance, so read
+        // them separately.
+        let cards_to_versions = {
+            use self::schema::cards::dsl::*;
+
+            cards
+                .filter(
+                    id.eq_any(
+                        search_results
+                            .iter()
+                            .map(|card| card.id)
+                            .collect::<Vec<i32>>(),
+                    ),
+                )
+                .select((id, (version, cloned_from_id)))
+                .load::<(i32, (i32, Option<i32>))>(self.connection.as_ref())?
+                .into_iter()
+                .collect::<HashMap<i32, (i32, Option<i32>)>>()
+        };
+
         // Merge search result entries with their attributes if needed
         let results: Vec<FullCardData> = search_results
             .into_iter()
             .filter(|search_card_data| cards_to_attributes.contains_key(&search_card_data.id))
+            .filter(|search_card_data| {
+                card_ids_in_sets
+                    .as_ref()
+                    .map(|ids| ids.contains(&search_card_data.id))
+                    .unwrap_or(true)
+            })
+            .filter(|search_card_data| {
+                card_ids_with_relations
+                    .as_ref()
+                    .map(|ids| ids.contains(&search_card_data.id))
+                    .unwrap_or(true)
+            })
             .map(|search_card_data| {
                 let id = search_card_data.id;
                 let attributes = cards_to_attributes.get(&id);
@@ -460,7 +1045,15 @@ impl DatabaseContext {
                     name: search_card_data.name,
                     desc: search_card_data.desc,
                     image_url: search_card_data.image_url,
+                    version: cards_to_versions
+                        .get(&id)
+                        .map(|(version, _)| *version)
+                        .unwrap_or_default(),
+                    cloned_from_id: cards_to_versions
+                        .get(&id)
+                        .and_then(|(_, cloned_from_id)| *cloned_from_id),
                     attributes: attributes.map(|v| v.clone()),
+                    relations: Some(cards_to_relations.remove(&id).unwrap_or_default()),
                 }
             })
             .collect::<Vec<FullCardData>>();
@@ -564,6 +1157,17 @@ impl DatabaseContext {
         Ok(grouped_results)
     }
 
+    pub fn get_card_attributes_by_ids(&self, ids: &[i32]) -> Result<Vec<CardAttribute>> {
+        use self::schema::card_attributes::dsl::*;
+
+        let results = card_attributes
+            .filter(id.eq_any(ids))
+            .order(order.asc())
+            .load(self.connection.as_ref())?;
+
+        Ok(results)
+    }
+
     pub fn get_card_attributes_by_card_id(&self, card_id: i32) -> Result<Vec<CardAttribute>> {
         use self::schema::*;
 
@@ -612,3 +1216,139 @@ impl DatabaseContext {
         Ok(results)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    use crate::card_versions::CardPrecondition;
+    use crate::database::DatabaseContext;
+    use crate::errors::ClientError;
+    use crate::{check_deck_name, check_deck_order, group_card_ids_by_quantity, DeckCardQuantity};
+
+    #[test]
+    fn given_duplicate_card_ids_when_grouped_then_quantities_counted_in_first_order() {
+        assert_eq!(
+            group_card_ids_by_quantity(&[3, 1, 3, 2, 3, 1]),
+            vec![(3, 3), (1, 2), (2, 1)]
+        );
+        assert_eq!(group_card_ids_by_quantity(&[]), vec![]);
+    }
+
+    #[test]
+    fn given_zero_or_negative_quantity_when_set_then_removed_or_refused() {
+        assert_eq!(DeckCardQuantity::new(0).unwrap(), DeckCardQuantity::Remove);
+        assert_eq!(DeckCardQuantity::new(4).unwrap(), DeckCardQuantity::Set(4));
+        assert!(DeckCardQuantity::new(-1).is_err());
+    }
+
+    #[test]
+    fn given_order_not_matching_deck_when_reordered_then_refused() {
+        assert!(check_deck_order("Starter", &[1, 2, 3], &[3, 1, 2]).is_ok());
+        assert!(check_deck_order("Starter", &[1, 2, 3], &[1, 2]).is_err());
+        assert!(check_deck_order("Starter", &[1, 2, 3], &[1, 2, 2, 3]).is_err());
+        assert!(check_deck_order("Starter", &[1, 2, 3], &[1, 2, 4]).is_err());
+    }
+
+    #[test]
+    fn given_name_that_could_leave_render_directory_when_checked_then_refused() {
+        assert!(check_deck_name("Starter deck (copy 2)").is_ok());
+
+        for name in &["", "  ", "../../x", "a/b", "a\\b", "..", "tab\there"] {
+            assert!(check_deck_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn given_deck_cards_fail_to_write_when_create_deck_then_no_deck_left() {
+        let directory = std::env::temp_dir().join(format!("deck-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+        // The deck's cards have nowhere to be written.
+        for query in &[
+            "CREATE TABLE cards (id INTEGER PRIMARY KEY)",
+            "CREATE TABLE decks (id INTEGER PRIMARY KEY, decktype TEXT NOT NULL, \
+            name TEXT NOT NULL, cloned_from_id INTEGER)",
+            "INSERT INTO cards (id) VALUES (1)",
+        ] {
+            sql_query(*query).execute(db.connection.as_ref()).unwrap();
+        }
+
+        assert!(db
+            .create_deck("Starter".to_owned(), vec![1], false)
+            .is_err());
+        assert!(db.get_deck_by_name("Starter").is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_unparsable_or_failing_query_when_searched_then_client_or_server_error() {
+        let directory = std::env::temp_dir().join(format!("search-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        // There is no card table for a query to run against.
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+
+        let parse_error = db.search_cards("cardclass:(").unwrap_err();
+        assert!(matches!(
+            parse_error.downcast_ref::<ClientError>(),
+            Some(ClientError::InvalidInput(_))
+        ));
+
+        let database_error = db.search_cards("cardclass:ATK").unwrap_err();
+        assert!(database_error.downcast_ref::<ClientError>().is_none());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn given_related_card_renamed_when_written_then_relating_card_version_bumped() {
+        let directory =
+            std::env::temp_dir().join(format!("write-card-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let db = DatabaseContext::new(&directory.join("cards.db").to_string_lossy()).unwrap();
+        for query in &[
+            "CREATE TABLE cards (id INTEGER PRIMARY KEY, cardclass TEXT NOT NULL, \
+            action TEXT NOT NULL, speed TEXT NOT NULL, initiative INTEGER NOT NULL, \
+            name TEXT NOT NULL, desc TEXT NOT NULL, image_url TEXT, \
+            version INTEGER NOT NULL, cloned_from_id INTEGER)",
+            "CREATE TABLE card_attributes (id INTEGER PRIMARY KEY, name TEXT NOT NULL, \
+            [order] INTEGER NOT NULL)",
+            "CREATE TABLE cards_card_attributes_relation (id INTEGER PRIMARY KEY, \
+            card_id INTEGER NOT NULL, card_attribute_id INTEGER NOT NULL)",
+            "CREATE TABLE card_relations (id INTEGER PRIMARY KEY, card_id INTEGER NOT NULL, \
+            related_card_id INTEGER NOT NULL, kind TEXT NOT NULL)",
+            "INSERT INTO cards VALUES (1, 'ATK', 'Attack', 'Fast', 1, 'Lunge', '', NULL, 4, NULL)",
+            "INSERT INTO cards VALUES (2, 'DEF', 'Defend', 'Fast', 1, 'Parry', '', NULL, 1, NULL)",
+            "INSERT INTO card_relations VALUES (1, 1, 2, 'combo')",
+        ] {
+            sql_query(*query).execute(db.connection.as_ref()).unwrap();
+        }
+
+        let mut parry = db.get_full_card_data(2).unwrap();
+        db.write_card(parry.clone(), CardPrecondition::Exists)
+            .unwrap();
+        assert_eq!(db.get_card(1).unwrap().version, 4);
+
+        parry.name = "Riposte".to_owned();
+        db.write_card(parry.clone(), CardPrecondition::Unconditional)
+            .unwrap();
+        assert_eq!(db.get_card(1).unwrap().version, 5);
+
+        // `If-Match: *` never creates a card.
+        parry.id = 3;
+        assert!(matches!(
+            db.write_card(parry, CardPrecondition::Exists)
+                .unwrap_err()
+                .downcast_ref::<ClientError>(),
+            Some(ClientError::PreconditionFailed(_))
+        ));
+        assert!(db.get_card(3).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
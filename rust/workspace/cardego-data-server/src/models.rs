@@ -3,6 +3,7 @@ extern crate diesel;
 extern crate juniper;
 
 use super::schema::*;
+use crate::card_values::{CardAction, CardRelationKind, CardSpeed};
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
@@ -26,20 +27,26 @@ use diesel::sql_types::{Integer, Text};
 pub struct Card {
     pub id: i32,
     pub cardclass: String,
-    pub action: String,
-    pub speed: String,
+    pub action: CardAction,
+    pub speed: CardSpeed,
     pub initiative: i32,
     pub name: String,
     pub desc: String,
     pub image_url: Option<String>,
+    /// Bumped on every change to the card, for optimistic concurrency.
+    #[serde(default)]
+    pub version: i32,
+    /// The card this card was cloned from, if any.
+    #[serde(default)]
+    pub cloned_from_id: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Insertable)]
 #[table_name = "cards"]
 pub struct NewCard<'a> {
     pub cardclass: &'a str,
-    pub action: &'a str,
-    pub speed: &'a str,
+    pub action: CardAction,
+    pub speed: CardSpeed,
     pub initiative: i32,
     pub name: &'a str,
     pub desc: &'a str,
@@ -62,6 +69,9 @@ pub struct Deck {
     pub id: i32,
     pub decktype: String,
     pub name: String,
+    /// The deck this deck was cloned from, if any.
+    #[serde(default)]
+    pub cloned_from_id: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Insertable)]
@@ -77,6 +87,8 @@ pub struct DeckCardRelation {
     pub id: i32,
     pub deck_id: i32,
     pub card_id: i32,
+    pub quantity: i32,
+    pub position: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Insertable)]
@@ -84,6 +96,76 @@ pub struct DeckCardRelation {
 pub struct NewDeckCardRelation {
     pub deck_id: i32,
     pub card_id: i32,
+    pub quantity: i32,
+    pub position: i32,
+}
+
+/// A card in a deck, along with how many copies of it the deck holds and
+/// where it sits in the deck's order.
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DeckCardEntry {
+    pub card: Card,
+    pub quantity: i32,
+    pub position: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeckRename {
+    pub name: String,
+}
+
+/// Fields to change on the copy when cloning a deck.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeckCloneOverrides {
+    pub name: Option<String>,
+    pub decktype: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewDeckCardEntry {
+    pub card_id: i32,
+    pub quantity: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeckCardQuantity {
+    pub quantity: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Identifiable, Queryable)]
+#[table_name = "deck_rules"]
+pub struct DeckRulesRecord {
+    pub id: i32,
+    pub decktype: String,
+    pub max_copies_per_card: Option<i32>,
+    pub min_deck_size: Option<i32>,
+    pub max_deck_size: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "deck_rules"]
+pub struct NewDeckRulesRecord<'a> {
+    pub decktype: &'a str,
+    pub max_copies_per_card: Option<i32>,
+    pub min_deck_size: Option<i32>,
+    pub max_deck_size: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Identifiable, Queryable)]
+#[table_name = "deck_rules_cardclass_limits"]
+pub struct DeckRulesCardClassLimit {
+    pub id: i32,
+    pub deck_rules_id: i32,
+    pub cardclass: String,
+    pub max_cards: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "deck_rules_cardclass_limits"]
+pub struct NewDeckRulesCardClassLimit<'a> {
+    pub deck_rules_id: i32,
+    pub cardclass: &'a str,
+    pub max_cards: i32,
 }
 
 #[derive(
@@ -137,17 +219,178 @@ pub struct CardIdWithCardAttribute {
     pub order: i32,
 }
 
+#[derive(
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    juniper::GraphQLObject,
+    Identifiable,
+    Queryable,
+    QueryableByName,
+)]
+#[table_name = "card_classes"]
+pub struct CardClass {
+    pub id: i32,
+    pub code: String,
+    pub name: String,
+    pub color: String,
+    pub sort_order: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable, AsChangeset)]
+#[table_name = "card_classes"]
+pub struct NewCardClass {
+    pub code: String,
+    pub name: String,
+    pub color: String,
+    pub sort_order: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Identifiable, Queryable)]
+#[table_name = "card_themes"]
+pub struct CardTheme {
+    pub id: i32,
+    pub name: String,
+    pub cardclass: Option<String>,
+    pub template: String,
+    pub css: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable, AsChangeset)]
+#[table_name = "card_themes"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewCardTheme {
+    pub name: String,
+    pub cardclass: Option<String>,
+    pub template: String,
+    #[serde(default)]
+    pub css: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject, Identifiable, Queryable)]
+#[table_name = "card_sets"]
+pub struct CardSet {
+    pub id: i32,
+    pub code: String,
+    pub name: String,
+    pub release_date: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Insertable, AsChangeset)]
+#[table_name = "card_sets"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewCardSet {
+    pub code: String,
+    pub name: String,
+    pub release_date: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Identifiable, Queryable)]
+#[table_name = "card_sets_cards_relation"]
+pub struct CardSetCardRelation {
+    pub id: i32,
+    pub card_set_id: i32,
+    pub card_id: i32,
+    pub collector_number: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "card_sets_cards_relation"]
+pub struct NewCardSetCardRelation {
+    pub card_set_id: i32,
+    pub card_id: i32,
+    pub collector_number: i32,
+}
+
+/// A card as listed in a set, with its collector number in that set.
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct CardSetEntry {
+    pub card: Card,
+    pub collector_number: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewCardSetEntry {
+    pub card_id: i32,
+    pub collector_number: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject, Identifiable, Queryable)]
+#[table_name = "card_translations"]
+pub struct CardTranslation {
+    pub id: i32,
+    pub card_id: i32,
+    pub locale: String,
+    pub name: String,
+    pub desc: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "card_translations"]
+pub struct NewCardTranslation<'a> {
+    pub card_id: i32,
+    pub locale: &'a str,
+    pub name: &'a str,
+    pub desc: &'a str,
+}
+
+/// The translated text of a card, as sent to the translation routes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardTranslationText {
+    pub name: String,
+    pub desc: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Identifiable, Queryable)]
+#[table_name = "card_relations"]
+pub struct CardRelation {
+    pub id: i32,
+    pub card_id: i32,
+    pub related_card_id: i32,
+    pub kind: CardRelationKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, Insertable)]
+#[table_name = "card_relations"]
+pub struct NewCardRelation {
+    pub card_id: i32,
+    pub related_card_id: i32,
+    pub kind: CardRelationKind,
+}
+
+/// A relation from a card to another card, with the other card's name.
+#[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct CardRelationEntry {
+    pub id: i32,
+    pub kind: CardRelationKind,
+    pub related_card_id: i32,
+    pub related_card_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewCardRelationEntry {
+    pub kind: CardRelationKind,
+    pub related_card_id: i32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLObject)]
 pub struct FullCardData {
     pub id: i32,
     pub cardclass: String,
-    pub action: String,
-    pub speed: String,
+    pub action: CardAction,
+    pub speed: CardSpeed,
     pub initiative: i32,
     pub name: String,
     pub desc: String,
     pub image_url: Option<String>,
+    #[serde(default)]
+    pub version: i32,
+    #[serde(default)]
+    pub cloned_from_id: Option<i32>,
     pub attributes: Option<Vec<CardAttribute>>,
+    #[serde(default)]
+    pub relations: Option<Vec<CardRelationEntry>>,
 }
 
 #[derive(
@@ -162,6 +405,23 @@ pub struct FullCardData {
 )]
 #[table_name = "search_card_data"]
 pub struct SearchCardData {
+    pub id: i32,
+    pub cardclass: String,
+    pub action: CardAction,
+    pub speed: CardSpeed,
+    pub initiative: i32,
+    pub name: String,
+    pub desc: String,
+    pub image_url: Option<String>,
+    pub attribute_ids: Option<String>,
+}
+
+/// A card as it is sent to be written. `action` and `speed` are kept as the
+/// text that was sent, so that unknown values are reported by validation
+/// along with every other invalid field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FullCardDataPayload {
+    #[serde(default)]
     pub id: i32,
     pub cardclass: String,
     pub action: String,
@@ -170,7 +430,9 @@ pub struct SearchCardData {
     pub name: String,
     pub desc: String,
     pub image_url: Option<String>,
-    pub attribute_ids: Option<String>,
+    #[serde(default)]
+    pub version: i32,
+    pub attributes: Option<Vec<CardAttribute>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, juniper::GraphQLInputObject)]
@@ -185,23 +447,21 @@ pub struct NewFullCardData {
     pub card_attributes: Option<Vec<i32>>,
 }
 
-lazy_static! {
-    pub static ref TRAIT_SHORT_TO_FULLNAME: HashMap<String, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("Kn".to_string(), "Knowledge");
-        m.insert("Tr".to_string(), "Trait");
-        m.insert("It".to_string(), "Item");
-        m.insert("Eq".to_string(), "Equipment");
-        m.insert("Ar".to_string(), "Armor");
-        m.insert("Co".to_string(), "Consumable");
-        m.insert("Te".to_string(), "Technique");
-        m.insert("Sp".to_string(), "Spell");
-        m.insert("Po".to_string(), "Power");
-        m.insert("1H".to_string(), "1-Handed Arms");
-        m.insert("2H".to_string(), "2-Handed Arms");
+/// Fields to change on the copy when cloning a card. Fields left out are
+/// copied from the original card.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CardCloneOverrides {
+    pub cardclass: Option<String>,
+    pub action: Option<CardAction>,
+    pub speed: Option<CardSpeed>,
+    pub initiative: Option<i32>,
+    pub name: Option<String>,
+    pub desc: Option<String>,
+    pub image_url: Option<String>,
+    pub card_attributes: Option<Vec<i32>>,
+}
 
-        m
-    };
+lazy_static! {
     pub static ref TABLE_TO_UNIQUE_SEARCH_TERMS: HashMap<String, Vec<&'static str>> = {
         let mut m = HashMap::new();
         m.insert(
@@ -221,6 +481,14 @@ lazy_static! {
             "card_attributes".to_string(),
             vec!["attribute_name", "attribute_id"],
         );
+        m.insert(
+            "card_sets".to_string(),
+            vec!["set", "set_name", "collector_number"],
+        );
+        m.insert(
+            "card_relations".to_string(),
+            crate::card_relations::relation_predicate_names(),
+        );
 
         m
     };
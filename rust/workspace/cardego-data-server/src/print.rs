@@ -0,0 +1,483 @@
+//! Print-and-play PDFs of decks.
+//!
+//! Cards are rendered at, and placed at, their real size, 2.5 by 3.5 inches
+//! at 300 DPI, on A4 or Letter pages, as many as fit on each. Every card
+//! can be given a bleed, its edges stretched outward so that a slightly off
+//! cut leaves no white border, and cut marks are drawn in the margins along
+//! the card edges.
+//! With card backs, every page of fronts is followed by a page of backs,
+//! mirrored so that the two line up when printed double-sided.
+
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::RgbaImage;
+use log::debug;
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use pdf_writer::{Content, Filter, Name, Pdf, Rect, Ref};
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::errors::ClientError;
+use crate::image::renderer::CARD_DPI;
+
+/// The resolution cards are printed at.
+pub const PRINT_DPI: u32 = 300;
+
+/// The size of a printed card, in pixels at `PRINT_DPI`.
+pub const PRINT_CARD_WIDTH: u32 = 750;
+pub const PRINT_CARD_HEIGHT: u32 = 1050;
+
+/// How many times larger than on screen cards are rendered for print.
+pub const PRINT_SCALE: f32 = PRINT_DPI as f32 / CARD_DPI as f32;
+
+/// The largest bleed that may be asked for, in millimetres.
+pub const MAX_BLEED_MM: f32 = 5.0;
+
+const POINTS_PER_INCH: f32 = 72.0;
+const MM_PER_INCH: f32 = 25.4;
+
+/// The space kept free around the cards for the printer and the cut marks,
+/// in points.
+const PAGE_MARGIN: f32 = 18.0;
+
+/// Cut marks start a little away from the cards, so that they are not cut
+/// into the cards themselves.
+const CUT_MARK_OFFSET: f32 = 3.0;
+const CUT_MARK_LENGTH: f32 = 12.0;
+
+fn pixels_to_points(pixels: u32) -> f32 {
+    pixels as f32 * POINTS_PER_INCH / PRINT_DPI as f32
+}
+
+fn mm_to_points(mm: f32) -> f32 {
+    mm * POINTS_PER_INCH / MM_PER_INCH
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageSize {
+    A4,
+    Letter,
+}
+
+impl PageSize {
+    /// The width and height of the page, in points.
+    pub fn dimensions(&self) -> (f32, f32) {
+        match self {
+            PageSize::A4 => (595.28, 841.89),
+            PageSize::Letter => (612.0, 792.0),
+        }
+    }
+}
+
+impl FromStr for PageSize {
+    type Err = ClientError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "a4" => Ok(PageSize::A4),
+            "letter" => Ok(PageSize::Letter),
+            _ => Err(ClientError::InvalidInput(format!(
+                "Unknown page size `{}`, expected `a4` or `letter`",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintOptions {
+    pub page_size: PageSize,
+    pub bleed_mm: f32,
+    pub cut_marks: bool,
+    pub card_backs: bool,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self {
+            page_size: PageSize::A4,
+            bleed_mm: 0.0,
+            cut_marks: true,
+            card_backs: false,
+        }
+    }
+}
+
+impl PrintOptions {
+    /// Reads the options from the `page`, `bleed` (in millimetres),
+    /// `cut_marks` and `backs` query parameters.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, ClientError> {
+        let mut options = Self::default();
+
+        if let Some(page) = query.get("page") {
+            options.page_size = page.parse()?;
+        }
+
+        if let Some(bleed) = query.get("bleed") {
+            options.bleed_mm = bleed
+                .parse::<f32>()
+                .ok()
+                .filter(|bleed| (0.0..=MAX_BLEED_MM).contains(bleed))
+                .ok_or_else(|| {
+                    ClientError::InvalidInput(format!(
+                        "Bleed `{}` must be between 0 and {} millimetres",
+                        bleed, MAX_BLEED_MM
+                    ))
+                })?;
+        }
+
+        if let Some(cut_marks) = query.get("cut_marks") {
+            options.cut_marks = cut_marks == "true" || cut_marks == "1";
+        }
+
+        if let Some(card_backs) = query.get("backs") {
+            options.card_backs = card_backs == "true" || card_backs == "1";
+        }
+
+        Ok(options)
+    }
+
+    fn bleed_points(&self) -> f32 {
+        mm_to_points(self.bleed_mm)
+    }
+
+    fn bleed_pixels(&self) -> u32 {
+        (self.bleed_mm / MM_PER_INCH * PRINT_DPI as f32).round() as u32
+    }
+}
+
+/// Where cards go on a page. Cards are laid out in a grid centred on the
+/// page, each in a cell that is the card plus its bleed on every side.
+/// Positions are in points from the bottom left of the page, as in PDF.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageLayout {
+    pub page_width: f32,
+    pub page_height: f32,
+    pub columns: usize,
+    pub rows: usize,
+    card_width: f32,
+    card_height: f32,
+    bleed: f32,
+    grid_left: f32,
+    grid_bottom: f32,
+}
+
+impl PageLayout {
+    pub fn new(page_size: PageSize, bleed: f32) -> Result<Self, ClientError> {
+        let (page_width, page_height) = page_size.dimensions();
+        let card_width = pixels_to_points(PRINT_CARD_WIDTH);
+        let card_height = pixels_to_points(PRINT_CARD_HEIGHT);
+        let cell_width = card_width + 2.0 * bleed;
+        let cell_height = card_height + 2.0 * bleed;
+
+        let columns = ((page_width - 2.0 * PAGE_MARGIN) / cell_width).floor() as usize;
+        let rows = ((page_height - 2.0 * PAGE_MARGIN) / cell_height).floor() as usize;
+
+        if columns == 0 || rows == 0 {
+            Err(ClientError::InvalidInput(
+                "Cards with this bleed do not fit on the page".to_string(),
+            ))?
+        }
+
+        Ok(Self {
+            page_width,
+            page_height,
+            columns,
+            rows,
+            card_width,
+            card_height,
+            bleed,
+            grid_left: (page_width - columns as f32 * cell_width) / 2.0,
+            grid_bottom: (page_height - rows as f32 * cell_height) / 2.0,
+        })
+    }
+
+    pub fn cards_per_page(&self) -> usize {
+        self.columns * self.rows
+    }
+
+    fn cell_width(&self) -> f32 {
+        self.card_width + 2.0 * self.bleed
+    }
+
+    fn cell_height(&self) -> f32 {
+        self.card_height + 2.0 * self.bleed
+    }
+
+    /// The bottom left corner of the cell of the `slot`th card on a page,
+    /// counting across rows from the top left. On `mirrored` pages columns
+    /// run from the right, so that backs land behind their fronts.
+    pub fn slot_origin(&self, slot: usize, mirrored: bool) -> (f32, f32) {
+        let mut column = slot % self.columns;
+        let row = slot / self.columns;
+
+        if mirrored {
+            column = self.columns - 1 - column;
+        }
+
+        (
+            self.grid_left + column as f32 * self.cell_width(),
+            self.grid_bottom + (self.rows - 1 - row) as f32 * self.cell_height(),
+        )
+    }
+
+    /// The cut marks of a page, as lines from `[x1, y1]` to `[x2, y2]`.
+    /// Each card edge gets a mark in the margins on both sides of the grid.
+    pub fn cut_marks(&self) -> Vec<[f32; 4]> {
+        let grid_right = self.grid_left + self.columns as f32 * self.cell_width();
+        let grid_top = self.grid_bottom + self.rows as f32 * self.cell_height();
+
+        let edges = |start: f32, cell: f32, card: f32, count: usize| {
+            let mut edges = (0..count)
+                .flat_map(|i| {
+                    let edge = start + i as f32 * cell + self.bleed;
+                    vec![edge, edge + card]
+                })
+                .collect::<Vec<f32>>();
+            // Without a bleed, neighbouring cards share their edges.
+            edges.dedup_by(|a, b| (*a - *b).abs() < 0.01);
+            edges
+        };
+
+        let mut marks = Vec::new();
+
+        for x in edges(
+            self.grid_left,
+            self.cell_width(),
+            self.card_width,
+            self.columns,
+        ) {
+            marks.push([
+                x,
+                grid_top + CUT_MARK_OFFSET,
+                x,
+                grid_top + CUT_MARK_OFFSET + CUT_MARK_LENGTH,
+            ]);
+            marks.push([
+                x,
+                self.grid_bottom - CUT_MARK_OFFSET,
+                x,
+                self.grid_bottom - CUT_MARK_OFFSET - CUT_MARK_LENGTH,
+            ]);
+        }
+
+        for y in edges(
+            self.grid_bottom,
+            self.cell_height(),
+            self.card_height,
+            self.rows,
+        ) {
+            marks.push([
+                self.grid_left - CUT_MARK_OFFSET,
+                y,
+                self.grid_left - CUT_MARK_OFFSET - CUT_MARK_LENGTH,
+                y,
+            ]);
+            marks.push([
+                grid_right + CUT_MARK_OFFSET,
+                y,
+                grid_right + CUT_MARK_OFFSET + CUT_MARK_LENGTH,
+                y,
+            ]);
+        }
+
+        marks
+    }
+}
+
+/// Surrounds `image` with `bleed` pixels on every side, repeating its edge
+/// pixels outward.
+pub fn add_bleed(image: &RgbaImage, bleed: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+
+    RgbaImage::from_fn(width + 2 * bleed, height + 2 * bleed, |x, y| {
+        *image.get_pixel(
+            x.saturating_sub(bleed).min(width - 1),
+            y.saturating_sub(bleed).min(height - 1),
+        )
+    })
+}
+
+/// Loads a card image at `path` and prepares it for print: given its bleed,
+/// and scaled to the printed card size if it was not rendered at that size,
+/// as card backs may not be.
+fn load_print_image(path: &Path, options: &PrintOptions) -> Result<RgbaImage> {
+    let mut image = ::image::open(path)?.to_rgba8();
+    if image.dimensions() != (PRINT_CARD_WIDTH, PRINT_CARD_HEIGHT) {
+        image = ::image::imageops::resize(
+            &image,
+            PRINT_CARD_WIDTH,
+            PRINT_CARD_HEIGHT,
+            FilterType::Lanczos3,
+        );
+    }
+
+    Ok(add_bleed(&image, options.bleed_pixels()))
+}
+
+/// Writes `image` into `pdf` as an image object, without its alpha.
+fn write_image(pdf: &mut Pdf, id: Ref, image: &RgbaImage) {
+    let rgb = image
+        .as_raw()
+        .chunks(4)
+        .flat_map(|pixel| &pixel[..3])
+        .copied()
+        .collect::<Vec<u8>>();
+    let compressed = compress_to_vec_zlib(&rgb, 6);
+
+    let mut image_object = pdf.image_xobject(id, &compressed);
+    image_object.filter(Filter::FlateDecode);
+    image_object.width(image.width() as i32);
+    image_object.height(image.height() as i32);
+    image_object.color_space().device_rgb();
+    image_object.bits_per_component(8);
+}
+
+/// Lays out the card images at `faces` into a PDF. `copies` lists the cards
+/// to print in order, as indices into `faces`, so a card is listed once for
+/// every copy of it. The image at `back`, if any, is printed behind every
+/// card.
+pub fn render_print_pdf(
+    faces: &[impl AsRef<Path>],
+    copies: &[usize],
+    back: Option<&Path>,
+    options: &PrintOptions,
+) -> Result<Vec<u8>> {
+    if copies.is_empty() {
+        Err(ClientError::InvalidInput(
+            "There are no cards to print".to_string(),
+        ))?
+    }
+
+    let layout = PageLayout::new(options.page_size, options.bleed_points())?;
+    let pages = copies.chunks(layout.cards_per_page()).collect::<Vec<_>>();
+
+    debug!(
+        "Printing {} card(s) onto {} page(s) of {} by {}",
+        copies.len(),
+        pages.len(),
+        layout.columns,
+        layout.rows
+    );
+
+    let mut next_id = 0;
+    let mut new_ref = || {
+        next_id += 1;
+        Ref::new(next_id)
+    };
+
+    let mut pdf = Pdf::new();
+    let catalog_id = new_ref();
+    let page_tree_id = new_ref();
+
+    // Every image is embedded once and drawn wherever it is printed.
+    let face_ids = faces.iter().map(|_| new_ref()).collect::<Vec<Ref>>();
+    for (face, id) in faces.iter().zip(&face_ids) {
+        write_image(&mut pdf, *id, &load_print_image(face.as_ref(), options)?);
+    }
+
+    let back_id = match back {
+        Some(back) => {
+            let id = new_ref();
+            write_image(&mut pdf, id, &load_print_image(back, options)?);
+            Some(id)
+        }
+        None => None,
+    };
+
+    let image_width = layout.cell_width();
+    let image_height = layout.cell_height();
+
+    let mut page_ids = Vec::new();
+    for page in &pages {
+        let mut sides = vec![(
+            page.iter()
+                .copied()
+                .map(|face| face_ids[face])
+                .collect::<Vec<Ref>>(),
+            false,
+        )];
+        if let Some(back_id) = back_id {
+            sides.push((vec![back_id; page.len()], true));
+        }
+
+        for (images, mirrored) in sides {
+            let page_id = new_ref();
+            let content_id = new_ref();
+            page_ids.push(page_id);
+
+            let mut content = Content::new();
+            for slot in 0..images.len() {
+                let (x, y) = layout.slot_origin(slot, mirrored);
+
+                content.save_state();
+                content.transform([image_width, 0.0, 0.0, image_height, x, y]);
+                content.x_object(Name(format!("Im{}", slot).as_bytes()));
+                content.restore_state();
+            }
+
+            if options.cut_marks {
+                content.set_line_width(0.5);
+                content.set_stroke_gray(0.0);
+                for [x1, y1, x2, y2] in layout.cut_marks() {
+                    content.move_to(x1, y1);
+                    content.line_to(x2, y2);
+                }
+                content.stroke();
+            }
+
+            pdf.stream(content_id, &content.finish());
+
+            let mut pdf_page = pdf.page(page_id);
+            pdf_page.media_box(Rect::new(0.0, 0.0, layout.page_width, layout.page_height));
+            pdf_page.parent(page_tree_id);
+            pdf_page.contents(content_id);
+
+            let mut resources = pdf_page.resources();
+            let mut x_objects = resources.x_objects();
+            for (slot, image_id) in images.iter().enumerate() {
+                x_objects.pair(Name(format!("Im{}", slot).as_bytes()), *image_id);
+            }
+        }
+    }
+
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+    pdf.catalog(catalog_id).pages(page_tree_id);
+
+    Ok(pdf.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::print::{add_bleed, PageLayout, PageSize};
+
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn given_bleed_when_laying_out_then_cards_fit_and_bleed_repeats_edges() {
+        let layout = PageLayout::new(PageSize::A4, 0.0).unwrap();
+        assert_eq!((layout.columns, layout.rows), (3, 3));
+
+        let (left, bottom) = layout.slot_origin(0, false);
+        let (mirrored_left, _) = layout.slot_origin(0, true);
+        assert!(left >= 18.0 && bottom >= 18.0);
+        assert!((mirrored_left - (layout.page_width - left - 180.0)).abs() < 0.01);
+        // Four shared vertical edges and four horizontal ones, each marked
+        // on both sides of the grid.
+        assert_eq!(layout.cut_marks().len(), 16);
+
+        let with_bleed = PageLayout::new(PageSize::Letter, 8.5).unwrap();
+        assert_eq!((with_bleed.columns, with_bleed.rows), (2, 2));
+
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        let bled = add_bleed(&image, 3);
+        assert_eq!(bled.dimensions(), (8, 8));
+        assert_eq!(*bled.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*bled.get_pixel(7, 7), Rgba([0, 0, 0, 0]));
+    }
+}
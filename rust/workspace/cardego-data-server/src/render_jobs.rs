@@ -0,0 +1,512 @@
+//! Renders in the background, for images too slow to draw during a request.
+//!
+//! Jobs are queued and picked up by a fixed pool of worker threads, which
+//! open their own database connections, so a large cardsheet no longer
+//! holds up every other request while it renders. Clients poll a job until
+//! it is done and then download its image. Only a limited number of jobs
+//! may wait in the queue, and only the most recent finished jobs are kept.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::database::DatabaseContext;
+use crate::databases::DatabaseConfig;
+use crate::errors::ClientError;
+use crate::image::art::ArtStore;
+use crate::image::cache::{self, RenderCache, RenderScope};
+use crate::image::renderer::CardRenderer;
+use crate::image::{card_image_path, generate_card_image, generate_deck_cardsheet_image};
+use crate::models::Card;
+
+/// How many finished jobs are kept for clients to collect.
+const FINISHED_JOB_RETENTION: usize = 100;
+
+/// What a job renders: one card, or a cardsheet of a deck or of the cards
+/// matching a search query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderTarget {
+    Card(i32),
+    Deck(String),
+    Query(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderJobStatus {
+    Queued,
+    Loading,
+    Rendering,
+    Done,
+    Failed,
+}
+
+impl RenderJobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(self, RenderJobStatus::Done | RenderJobStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderJob {
+    pub id: u64,
+    pub database: String,
+    pub target: RenderTarget,
+    /// The theme asked for, over those of the card classes.
+    pub theme: Option<String>,
+    pub status: RenderJobStatus,
+    /// How many cards are being rendered, once they are loaded.
+    pub card_count: Option<usize>,
+    pub error: Option<String>,
+    pub created_at: String,
+    #[serde(skip)]
+    pub result_path: Option<PathBuf>,
+    #[serde(skip)]
+    database_config: DatabaseConfig,
+}
+
+#[derive(Default)]
+struct RenderJobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, RenderJob>,
+}
+
+impl RenderJobs {
+    fn queued_count(&self) -> usize {
+        self.jobs
+            .values()
+            .filter(|job| job.status == RenderJobStatus::Queued)
+            .count()
+    }
+
+    /// Adds a job rendering `target` from `database`, unless `queue_limit`
+    /// jobs are already waiting.
+    fn add(
+        &mut self,
+        database: &DatabaseConfig,
+        target: RenderTarget,
+        theme: Option<String>,
+        queue_limit: usize,
+    ) -> std::result::Result<RenderJob, ClientError> {
+        if self.queued_count() >= queue_limit {
+            return Err(ClientError::TooManyRequests(format!(
+                "{} render jobs are already waiting",
+                queue_limit
+            )));
+        }
+
+        self.next_id += 1;
+        let job = RenderJob {
+            id: self.next_id,
+            database: database.name.clone(),
+            target,
+            theme,
+            status: RenderJobStatus::Queued,
+            card_count: None,
+            error: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            result_path: None,
+            database_config: database.clone(),
+        };
+        self.jobs.insert(job.id, job.clone());
+        self.prune(FINISHED_JOB_RETENTION);
+
+        Ok(job)
+    }
+
+    /// Marks a job as done with the image at the path rendered, or as
+    /// failed.
+    fn finish(&mut self, id: u64, result: Result<PathBuf>) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            match result {
+                Ok(path) => {
+                    job.status = RenderJobStatus::Done;
+                    job.result_path = Some(path);
+                }
+                Err(err) => {
+                    warn!("Render job {} failed: {}", job.id, err);
+                    job.status = RenderJobStatus::Failed;
+                    job.error = Some(err.to_string());
+                }
+            }
+        }
+
+        self.prune(FINISHED_JOB_RETENTION);
+    }
+
+    /// Drops the oldest finished jobs past the `retention` most recent,
+    /// along with their images.
+    fn prune(&mut self, retention: usize) {
+        let finished = self
+            .jobs
+            .values()
+            .filter(|job| job.status.is_finished())
+            .map(|job| job.id)
+            .collect::<Vec<u64>>();
+
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(retention))
+        {
+            if let Some(path) = self.jobs.remove(id).and_then(|job| job.result_path) {
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!("Could not remove render job image {:?}: {}", path, err);
+                }
+            }
+        }
+    }
+}
+
+/// Locks `mutex`, even if a worker panicked while holding it. The jobs are
+/// only ever left in a state a later worker can carry on from.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Runs `render`, turning a panic into an error so that the job it renders
+/// is still finished, as failed.
+fn render_unwinding<F: FnOnce() -> Result<PathBuf>>(render: F) -> Result<PathBuf> {
+    catch_unwind(AssertUnwindSafe(render)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_owned());
+
+        Err(anyhow!("Rendering panicked: {}", message))
+    })
+}
+
+pub struct RenderJobQueue {
+    jobs: Mutex<RenderJobs>,
+    sender: Mutex<Sender<u64>>,
+    queue_limit: usize,
+    renderer: Arc<dyn CardRenderer>,
+    render_cache_max_bytes: u64,
+}
+
+impl RenderJobQueue {
+    /// Starts `workers` threads rendering with `renderer`. At most
+    /// `queue_limit` jobs may wait for a worker.
+    pub fn start(
+        workers: usize,
+        queue_limit: usize,
+        renderer: Arc<dyn CardRenderer>,
+        render_cache_max_bytes: u64,
+    ) -> Arc<Self> {
+        let (sender, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let queue = Arc::new(Self {
+            jobs: Mutex::new(RenderJobs::default()),
+            sender: Mutex::new(sender),
+            queue_limit,
+            renderer,
+            render_cache_max_bytes,
+        });
+
+        for worker in 0..workers {
+            let queue = queue.clone();
+            let receiver = receiver.clone();
+
+            std::thread::Builder::new()
+                .name(format!("render-worker-{}", worker))
+                .spawn(move || queue.work(&receiver))
+                .expect("Could not start a render worker");
+        }
+
+        info!("Started {} render worker(s)", workers);
+
+        queue
+    }
+
+    /// Queues a render of `target` from `database`, drawn from `theme` if
+    /// one is asked for.
+    pub fn submit(
+        &self,
+        database: &DatabaseConfig,
+        target: RenderTarget,
+        theme: Option<String>,
+    ) -> Result<RenderJob> {
+        let job = lock(&self.jobs).add(database, target, theme, self.queue_limit)?;
+
+        lock(&self.sender).send(job.id)?;
+
+        debug!("Queued render job {:?}", job);
+
+        Ok(job)
+    }
+
+    /// Looks up a job of `database`. Jobs of other databases are not found,
+    /// as if they did not exist.
+    pub fn get(&self, database: &str, id: u64) -> Option<RenderJob> {
+        lock(&self.jobs)
+            .jobs
+            .get(&id)
+            .filter(|job| job.database == database)
+            .cloned()
+    }
+
+    fn update<F: FnOnce(&mut RenderJob)>(&self, id: u64, update: F) {
+        if let Some(job) = lock(&self.jobs).jobs.get_mut(&id) {
+            update(job);
+        }
+    }
+
+    fn work(&self, receiver: &Mutex<Receiver<u64>>) {
+        loop {
+            // Only hold the receiver while waiting, so that other workers
+            // can pick up jobs while this one renders.
+            let id = match lock(receiver).recv() {
+                Ok(id) => id,
+                Err(_) => return,
+            };
+
+            let job = match self.get_any(id) {
+                Some(job) => job,
+                None => continue,
+            };
+
+            info!("Rendering job {} of {:?}", id, job.target);
+
+            let result = render_unwinding(|| self.render(&job));
+            lock(&self.jobs).finish(id, result);
+        }
+    }
+
+    fn get_any(&self, id: u64) -> Option<RenderJob> {
+        lock(&self.jobs).jobs.get(&id).cloned()
+    }
+
+    fn render(&self, job: &RenderJob) -> Result<PathBuf> {
+        let database = &job.database_config;
+        let render_directory = &database.render_directory;
+
+        self.update(job.id, |job| job.status = RenderJobStatus::Loading);
+
+        let db = DatabaseContext::new(&database.database_endpoint)?
+            .with_art_store(ArtStore::new(render_directory));
+        let assets = db.get_card_assets()?;
+        let themes = db.select_card_themes(job.theme.as_deref())?;
+        let cache = RenderCache::new(render_directory, self.render_cache_max_bytes);
+
+        let cards = match &job.target {
+            RenderTarget::Card(card_id) => {
+                vec![db
+                    .get_card(*card_id)
+                    .or(Err(ClientError::ResourceNotFound))?]
+            }
+            RenderTarget::Deck(deck_name) => db
+                .get_cards_by_deck_name(deck_name.clone())
+                .or(Err(ClientError::ResourceNotFound))?,
+            RenderTarget::Query(query) => db.get_cards_by_query(query)?,
+        };
+
+        self.update(job.id, |job| {
+            job.status = RenderJobStatus::Rendering;
+            job.card_count = Some(cards.len());
+        });
+
+        // The cache may evict the image at any time, so the job keeps a copy
+        // of its own. Images are rendered under the name of the job, so that
+        // they never overwrite those rendered by requests.
+        let jobs_directory = Path::new(render_directory).join("jobs");
+        std::fs::create_dir_all(&jobs_directory)?;
+
+        let image_name = format!("job-{}", job.id);
+        let result_path = jobs_directory.join(format!("{}.png", job.id));
+
+        match &job.target {
+            RenderTarget::Card(card_id) => {
+                let relations = db.get_card_relations(*card_id)?;
+                let theme = themes.theme_of(&cards[0]);
+                let key = cache::card_render_key(
+                    self.renderer.name(),
+                    theme,
+                    &cards[0],
+                    &assets.card_classes,
+                    &relations,
+                )?;
+
+                cache.copy_or_render(
+                    &RenderScope::Card(*card_id),
+                    &key,
+                    || {
+                        generate_card_image(
+                            self.renderer.as_ref(),
+                            card_image_path(render_directory, &image_name),
+                            &cards[0],
+                            &assets,
+                            &relations,
+                            theme,
+                            1.0,
+                        )
+                    },
+                    &result_path,
+                )?;
+            }
+            RenderTarget::Deck(name) | RenderTarget::Query(name) => {
+                let key = cache::cardsheet_render_key(
+                    self.renderer.name(),
+                    &cards,
+                    &assets.card_classes,
+                    &themes,
+                )?;
+                let scope = match &job.target {
+                    RenderTarget::Deck(_) => RenderScope::Deck(name.clone()),
+                    _ => RenderScope::Query(name.clone()),
+                };
+
+                cache.copy_or_render(
+                    &scope,
+                    &key,
+                    || {
+                        generate_deck_cardsheet_image(
+                            self.renderer.as_ref(),
+                            render_directory,
+                            &image_name,
+                            cards.clone(),
+                            &assets,
+                            &themes,
+                            1.0,
+                        )
+                    },
+                    &result_path,
+                )?;
+            }
+        };
+
+        Ok(result_path)
+    }
+}
+
+impl DatabaseContext {
+    /// The cards matching a search query, by id.
+    pub fn get_cards_by_query(&self, query: &str) -> Result<Vec<Card>> {
+        use crate::schema::cards::dsl::*;
+
+        let card_ids = self.query_card_ids(query)?;
+
+        Ok(cards
+            .filter(id.eq_any(card_ids))
+            .order(id.asc())
+            .load::<Card>(self.connection.as_ref())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::DatabaseConfig;
+    use crate::errors::ClientError;
+    use crate::render_jobs::{render_unwinding, RenderJobStatus, RenderJobs, RenderTarget};
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn given_job_requests_when_deserialized_then_targets_read() {
+        let targets = vec![
+            r#"{"card": 12}"#,
+            r#"{"deck": "Starter"}"#,
+            r#"{"query": "cardclass:ATK"}"#,
+        ]
+        .into_iter()
+        .map(|body| serde_json::from_str::<RenderTarget>(body).unwrap())
+        .collect::<Vec<RenderTarget>>();
+
+        assert!(matches!(targets[0], RenderTarget::Card(12)));
+        assert!(matches!(&targets[1], RenderTarget::Deck(name) if name == "Starter"));
+        assert!(matches!(&targets[2], RenderTarget::Query(query) if query == "cardclass:ATK"));
+        assert!(serde_json::from_str::<RenderTarget>(r#"{"set": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn given_full_queue_when_job_added_then_too_many_requests() {
+        let database = DatabaseConfig::default_database();
+        let mut jobs = RenderJobs::default();
+
+        jobs.add(&database, RenderTarget::Card(1), None, 2).unwrap();
+        jobs.add(&database, RenderTarget::Card(2), None, 2).unwrap();
+
+        assert_eq!(jobs.queued_count(), 2);
+        assert!(matches!(
+            jobs.add(&database, RenderTarget::Card(3), None, 2),
+            Err(ClientError::TooManyRequests(_))
+        ));
+
+        // Jobs that have finished no longer count against the limit.
+        jobs.finish(1, Ok(PathBuf::from("1.png")));
+        assert!(jobs.add(&database, RenderTarget::Card(3), None, 2).is_ok());
+    }
+
+    #[test]
+    fn given_finished_jobs_when_finish_and_prune_then_status_set_and_oldest_dropped() {
+        let database = DatabaseConfig::default_database();
+        let mut jobs = RenderJobs::default();
+        for card_id in 1..=4 {
+            jobs.add(&database, RenderTarget::Card(card_id), None, 10)
+                .unwrap();
+        }
+
+        let image =
+            std::env::temp_dir().join(format!("render-job-test-{}.png", std::process::id()));
+        std::fs::write(&image, b"png").unwrap();
+
+        jobs.finish(1, Ok(image.clone()));
+        jobs.finish(2, Err(anyhow!("wkhtmltoimage exited")));
+        jobs.finish(3, Ok(PathBuf::from("3.png")));
+
+        assert_eq!(jobs.jobs[&1].status, RenderJobStatus::Done);
+        assert_eq!(jobs.jobs[&1].result_path, Some(image.clone()));
+        assert_eq!(jobs.jobs[&2].status, RenderJobStatus::Failed);
+        assert_eq!(jobs.jobs[&2].error.as_deref(), Some("wkhtmltoimage exited"));
+        assert_eq!(jobs.jobs[&4].status, RenderJobStatus::Queued);
+
+        jobs.prune(2);
+
+        assert_eq!(
+            jobs.jobs.keys().copied().collect::<Vec<u64>>(),
+            vec![2, 3, 4]
+        );
+        assert!(!image.exists());
+    }
+
+    #[test]
+    fn given_many_jobs_when_finished_then_oldest_pruned() {
+        let database = DatabaseConfig::default_database();
+        let mut jobs = RenderJobs::default();
+        let retained = super::FINISHED_JOB_RETENTION as u64;
+        for card_id in 1..=retained + 2 {
+            jobs.add(&database, RenderTarget::Card(card_id as i32), None, 1000)
+                .unwrap();
+        }
+
+        for id in 1..=retained + 2 {
+            jobs.finish(id, Err(anyhow!("wkhtmltoimage exited")));
+        }
+
+        assert_eq!(jobs.jobs.len(), super::FINISHED_JOB_RETENTION);
+        assert!(!jobs.jobs.contains_key(&1));
+        assert!(!jobs.jobs.contains_key(&2));
+    }
+
+    #[test]
+    fn given_render_that_panics_when_rendered_then_error_returned() {
+        let result = render_unwinding(|| panic!("card art missing"));
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Rendering panicked: card art missing"
+        );
+    }
+}
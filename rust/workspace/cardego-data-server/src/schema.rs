@@ -8,6 +8,8 @@ table! {
         name -> Text,
         desc -> Text,
         image_url -> Nullable<Text>,
+        version -> Integer,
+        cloned_from_id -> Nullable<Integer>,
     }
 }
 
@@ -16,6 +18,7 @@ table! {
         id -> Integer,
         decktype -> Text,
         name -> Text,
+        cloned_from_id -> Nullable<Integer>,
     }
 }
 
@@ -24,6 +27,8 @@ table! {
         id -> Integer,
         deck_id -> Integer,
         card_id -> Integer,
+        quantity -> Integer,
+        position -> Integer,
     }
 }
 
@@ -56,3 +61,88 @@ table! {
         attribute_ids -> Nullable<Text>,
     }
 }
+
+table! {
+    deck_rules (id) {
+        id -> Integer,
+        decktype -> Text,
+        max_copies_per_card -> Nullable<Integer>,
+        min_deck_size -> Nullable<Integer>,
+        max_deck_size -> Nullable<Integer>,
+    }
+}
+
+table! {
+    deck_rules_cardclass_limits (id) {
+        id -> Integer,
+        deck_rules_id -> Integer,
+        cardclass -> Text,
+        max_cards -> Integer,
+    }
+}
+
+table! {
+    card_classes (id) {
+        id -> Integer,
+        code -> Text,
+        name -> Text,
+        color -> Text,
+        sort_order -> Integer,
+    }
+}
+
+table! {
+    card_sets (id) {
+        id -> Integer,
+        code -> Text,
+        name -> Text,
+        release_date -> Nullable<Text>,
+    }
+}
+
+table! {
+    card_sets_cards_relation (id) {
+        id -> Integer,
+        card_set_id -> Integer,
+        card_id -> Integer,
+        collector_number -> Integer,
+    }
+}
+
+table! {
+    card_translations (id) {
+        id -> Integer,
+        card_id -> Integer,
+        locale -> Text,
+        name -> Text,
+        desc -> Text,
+    }
+}
+
+table! {
+    card_relations (id) {
+        id -> Integer,
+        card_id -> Integer,
+        related_card_id -> Integer,
+        kind -> Text,
+    }
+}
+
+table! {
+    card_themes (id) {
+        id -> Integer,
+        name -> Text,
+        cardclass -> Nullable<Text>,
+        template -> Text,
+        css -> Text,
+    }
+}
+
+table! {
+    card_value_flags (id) {
+        id -> Integer,
+        card_id -> Integer,
+        field -> Text,
+        value -> Text,
+    }
+}
@@ -4,9 +4,11 @@ pub mod query;
 
 use juniper::FieldResult;
 
-use self::juniper::{EmptyMutation, RootNode};
+use self::juniper::RootNode;
 use crate::database::DatabaseContext;
-use crate::models::{Card, FullCardData};
+use crate::deck_rules::DeckValidationReport;
+use crate::errors::ClientError;
+use crate::models::{Card, CardClass, CardSet, CardSetEntry, FullCardData, NewFullCardData};
 
 pub struct GraphQLContext;
 
@@ -34,26 +36,99 @@ impl QueryRoot {
     fn full_card_data(context: &DatabaseContext, id: i32) -> FieldResult<FullCardData> {
         Ok(context.get_full_card_data(id)?)
     }
+
+    fn card_classes(context: &DatabaseContext) -> FieldResult<Vec<CardClass>> {
+        Ok(context.get_card_classes()?)
+    }
+
+    fn card_sets(context: &DatabaseContext) -> FieldResult<Vec<CardSet>> {
+        Ok(context.get_card_sets()?)
+    }
+
+    fn card_set_cards(context: &DatabaseContext, code: String) -> FieldResult<Vec<CardSetEntry>> {
+        Ok(context.get_card_set_entries(&code)?)
+    }
+
+    fn deck_validation(
+        context: &DatabaseContext,
+        name: String,
+    ) -> FieldResult<DeckValidationReport> {
+        Ok(context.validate_deck(&name)?)
+    }
 }
 
 pub struct MutationRoot;
 
-// TODO: Figure out how to use mutations properly. Just don't use mutations
-// right now.
 #[juniper::object(
 Context = DatabaseContext,
 )]
 impl MutationRoot {
-    //fn create_full_card_data(
-    //    context: &DatabaseContext,
-    //    full_card_data: NewFullCardData)
-    //    -> FieldResult<FullCardData> {
-    //    Ok(context.create_card(&full_card_data)?)
-    //}
+    fn create_full_card_data(
+        context: &DatabaseContext,
+        full_card_data: NewFullCardData,
+    ) -> FieldResult<FullCardData> {
+        context
+            .create_card(&full_card_data)
+            .map_err(card_write_field_error)
+    }
+
+    /// Replaces a card. When `expected_version` is given, the update is only
+    /// made if the card is still at that version; otherwise it fails with the
+    /// `PRECONDITION_FAILED` code.
+    fn update_full_card_data(
+        context: &DatabaseContext,
+        id: i32,
+        full_card_data: NewFullCardData,
+        expected_version: Option<i32>,
+    ) -> FieldResult<FullCardData> {
+        full_card_data
+            .validate(&context.get_card_validation_context()?)
+            .map_err(|errors| errors.to_field_error())?;
+
+        let attributes = match &full_card_data.card_attributes {
+            Some(ids) => Some(context.get_card_attributes_by_ids(ids)?),
+            None => None,
+        };
+
+        let card_data = FullCardData {
+            id,
+            cardclass: full_card_data.cardclass,
+            action: full_card_data.action.parse()?,
+            speed: full_card_data.speed.parse()?,
+            initiative: full_card_data.initiative,
+            name: full_card_data.name,
+            desc: full_card_data.desc,
+            image_url: full_card_data.image_url,
+            version: expected_version.unwrap_or_default(),
+            cloned_from_id: None,
+            attributes,
+            relations: None,
+        };
+
+        // Already validated above, with the attribute ids as they were sent.
+        context
+            .write_card(card_data, expected_version.into())
+            .map_err(card_write_field_error)
+    }
+}
+
+/// Reports the errors of writing a card as GraphQL errors, with each invalid
+/// field listed and stale versions marked with the `PRECONDITION_FAILED` code.
+fn card_write_field_error(err: anyhow::Error) -> juniper::FieldError {
+    match err.downcast::<ClientError>() {
+        Ok(ClientError::ValidationFailed(errors)) => errors.to_field_error(),
+        Ok(ClientError::PreconditionFailed(message)) => {
+            let mut extensions = juniper::Object::with_capacity(1);
+            extensions.add_field("code", juniper::Value::scalar("PRECONDITION_FAILED"));
+            juniper::FieldError::new(message, juniper::Value::object(extensions))
+        }
+        Ok(client_err) => client_err.into(),
+        Err(err) => err.into(),
+    }
 }
 
-pub type Schema = RootNode<'static, QueryRoot, EmptyMutation<DatabaseContext>>;
+pub type Schema = RootNode<'static, QueryRoot, MutationRoot>;
 
 pub fn create_schema() -> Schema {
-    Schema::new(QueryRoot, EmptyMutation::new())
+    Schema::new(QueryRoot, MutationRoot)
 }
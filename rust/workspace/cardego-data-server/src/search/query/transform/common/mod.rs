@@ -21,6 +21,14 @@ impl Expression {
         Ok(expr)
     }
 
+    /// Whether the expression has no predicates at all, such as after
+    /// splitting out a table that the query does not mention.
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .iter()
+            .all(|and_expr_group| and_expr_group.0.is_empty())
+    }
+
     pub fn split_query_by_name(
         &self,
         mappings: &HashMap<String, Vec<&str>>,
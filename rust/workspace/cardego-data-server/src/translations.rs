@@ -0,0 +1,334 @@
+//! Translations of card names and rules text.
+//!
+//! The `name` and `desc` stored on a card are in the default locale. Other
+//! locales are stored in `card_translations`, and cards are localized by
+//! swapping in the best matching translation, falling back from a regional
+//! locale such as `es-MX` to its language `es`, and then to the default.
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use log::debug;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::database::DatabaseContext;
+use crate::errors::ClientError;
+use crate::models::{Card, CardTranslation, CardTranslationText, FullCardData, NewCardTranslation};
+use crate::validation::{ValidationErrors, MAX_CARD_DESC_LENGTH, MAX_CARD_NAME_LENGTH};
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+lazy_static! {
+    static ref LOCALE_REGEX: Regex = Regex::new(r"^[a-z]{2,3}(-[A-Z]{2})?$").unwrap();
+}
+
+/// Anything carrying a card's translatable text.
+pub trait Localizable {
+    fn card_id(&self) -> i32;
+    fn set_text(&mut self, name: String, desc: String);
+}
+
+impl Localizable for Card {
+    fn card_id(&self) -> i32 {
+        self.id
+    }
+
+    fn set_text(&mut self, name: String, desc: String) {
+        self.name = name;
+        self.desc = desc;
+    }
+}
+
+impl Localizable for FullCardData {
+    fn card_id(&self) -> i32 {
+        self.id
+    }
+
+    fn set_text(&mut self, name: String, desc: String) {
+        self.name = name;
+        self.desc = desc;
+    }
+}
+
+/// The cards that have no translation into a locale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissingTranslations {
+    pub locale: String,
+    pub cards: Vec<MissingTranslationCard>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissingTranslationCard {
+    pub id: i32,
+    pub name: String,
+}
+
+pub fn validate_locale(locale: &str) -> std::result::Result<(), ClientError> {
+    if LOCALE_REGEX.is_match(locale) {
+        Ok(())
+    } else {
+        Err(ClientError::InvalidInput(format!(
+            "'{}' is not a locale such as 'ja' or 'es-MX'",
+            locale
+        )))
+    }
+}
+
+/// The locales to try for `lang`, most specific first. The default locale is
+/// not listed, since it is the card's own text.
+pub fn candidate_locales(lang: &str) -> Vec<String> {
+    let mut candidates = vec![lang.to_owned()];
+
+    if let Some(index) = lang.find('-') {
+        candidates.push(lang[..index].to_owned());
+    }
+
+    candidates.retain(|locale| locale != DEFAULT_LOCALE);
+    candidates
+}
+
+fn validate_translation_text(text: &CardTranslationText) -> std::result::Result<(), ClientError> {
+    let mut errors = ValidationErrors::default();
+
+    if text.name.trim().is_empty() {
+        errors.add("name", "empty", "Card names cannot be empty".to_owned());
+    } else if text.name.chars().count() > MAX_CARD_NAME_LENGTH {
+        errors.add(
+            "name",
+            "too_long",
+            format!(
+                "Card names can be at most {} characters long",
+                MAX_CARD_NAME_LENGTH
+            ),
+        );
+    }
+
+    if text.desc.chars().count() > MAX_CARD_DESC_LENGTH {
+        errors.add(
+            "desc",
+            "too_long",
+            format!(
+                "Card descriptions can be at most {} characters long",
+                MAX_CARD_DESC_LENGTH
+            ),
+        );
+    }
+
+    errors.into_result().map_err(ClientError::ValidationFailed)
+}
+
+impl DatabaseContext {
+    pub fn get_card_translations(&self, target_card_id: i32) -> Result<Vec<CardTranslation>> {
+        use crate::schema::card_translations::dsl::*;
+
+        let results = card_translations
+            .filter(card_id.eq(target_card_id))
+            .order(locale.asc())
+            .load(self.connection.as_ref())?;
+
+        Ok(results)
+    }
+
+    /// Creates or replaces the translation of a card into `target_locale`.
+    pub fn put_card_translation(
+        &mut self,
+        target_card_id: i32,
+        target_locale: &str,
+        text: &CardTranslationText,
+    ) -> Result<CardTranslation> {
+        use crate::schema::card_translations::dsl::*;
+
+        debug!(
+            "put_card_translation: {} {} {:?}",
+            target_card_id, target_locale, text
+        );
+
+        validate_locale(target_locale)?;
+        if target_locale == DEFAULT_LOCALE {
+            Err(ClientError::InvalidInput(format!(
+                "'{}' is the default locale; update the card itself instead",
+                target_locale
+            )))?
+        }
+        validate_translation_text(text)?;
+
+        self.get_card(target_card_id)
+            .or(Err(ClientError::ResourceNotFound))?;
+
+        self.connection.transaction::<_, anyhow::Error, _>(|| {
+            diesel::delete(
+                card_translations
+                    .filter(card_id.eq(target_card_id))
+                    .filter(locale.eq(target_locale)),
+            )
+            .execute(self.connection.as_ref())?;
+
+            diesel::insert_into(card_translations)
+                .values(&NewCardTranslation {
+                    card_id: target_card_id,
+                    locale: target_locale,
+                    name: &text.name,
+                    desc: &text.desc,
+                })
+                .execute(self.connection.as_ref())?;
+
+            self.bump_card_version(target_card_id)
+        })?;
+
+        let result = card_translations
+            .filter(card_id.eq(target_card_id))
+            .filter(locale.eq(target_locale))
+            .first(self.connection.as_ref())?;
+
+        Ok(result)
+    }
+
+    pub fn delete_card_translation(
+        &mut self,
+        target_card_id: i32,
+        target_locale: &str,
+    ) -> Result<()> {
+        use crate::schema::card_translations::dsl::*;
+
+        debug!(
+            "delete_card_translation: {} {}",
+            target_card_id, target_locale
+        );
+
+        let deleted = diesel::delete(
+            card_translations
+                .filter(card_id.eq(target_card_id))
+                .filter(locale.eq(target_locale)),
+        )
+        .execute(self.connection.as_mut())?;
+
+        if deleted == 0 {
+            Err(ClientError::ResourceNotFound)?
+        }
+
+        self.bump_card_version(target_card_id)
+    }
+
+    /// Swaps the text of each card for its translation into `lang`, where
+    /// there is one. Cards without a translation keep their default text.
+    pub fn localize_cards<T: Localizable>(&self, cards: &mut [T], lang: &str) -> Result<()> {
+        use crate::schema::card_translations::dsl::*;
+
+        validate_locale(lang)?;
+
+        let candidates = candidate_locales(lang);
+        if candidates.is_empty() || cards.is_empty() {
+            return Ok(());
+        }
+
+        let card_ids = cards
+            .iter()
+            .map(|card| card.card_id())
+            .collect::<Vec<i32>>();
+
+        let translations = card_translations
+            .filter(card_id.eq_any(card_ids))
+            .filter(locale.eq_any(candidates.clone()))
+            .load::<CardTranslation>(self.connection.as_ref())?
+            .into_iter()
+            .map(|translation| {
+                (
+                    (translation.card_id, translation.locale.clone()),
+                    translation,
+                )
+            })
+            .collect::<HashMap<(i32, String), CardTranslation>>();
+
+        for card in cards.iter_mut() {
+            let best = candidates
+                .iter()
+                .find_map(|candidate| translations.get(&(card.card_id(), candidate.clone())));
+
+            if let Some(translation) = best {
+                card.set_text(translation.name.clone(), translation.desc.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists, for each locale, the cards that have no translation into it.
+    /// The locales checked are those given, plus every locale that has at
+    /// least one translation.
+    pub fn get_missing_translations(
+        &self,
+        extra_locales: &[String],
+    ) -> Result<Vec<MissingTranslations>> {
+        use crate::schema::card_translations;
+        use crate::schema::cards;
+
+        for extra_locale in extra_locales {
+            validate_locale(extra_locale)?;
+        }
+
+        let translated = card_translations::table
+            .select((card_translations::locale, card_translations::card_id))
+            .load::<(String, i32)>(self.connection.as_ref())?;
+
+        let locales = translated
+            .iter()
+            .map(|(locale, _)| locale.clone())
+            .chain(extra_locales.iter().cloned())
+            .filter(|locale| locale != DEFAULT_LOCALE)
+            .collect::<BTreeSet<String>>();
+
+        let all_cards = cards::table
+            .select((cards::id, cards::name))
+            .order(cards::id.asc())
+            .load::<(i32, String)>(self.connection.as_ref())?;
+
+        let results = locales
+            .into_iter()
+            .map(|target_locale| {
+                let missing = all_cards
+                    .iter()
+                    .filter(|(id, _)| {
+                        !translated
+                            .iter()
+                            .any(|(locale, card_id)| locale == &target_locale && card_id == id)
+                    })
+                    .map(|(id, name)| MissingTranslationCard {
+                        id: *id,
+                        name: name.clone(),
+                    })
+                    .collect();
+
+                MissingTranslations {
+                    locale: target_locale,
+                    cards: missing,
+                }
+            })
+            .collect();
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::translations::{candidate_locales, validate_locale};
+
+    #[test]
+    fn given_regional_locale_when_candidate_locales_then_falls_back_to_language() {
+        assert_eq!(candidate_locales("es-MX"), vec!["es-MX", "es"]);
+        assert_eq!(candidate_locales("ja"), vec!["ja"]);
+        assert!(candidate_locales("en").is_empty());
+        assert_eq!(candidate_locales("en-GB"), vec!["en-GB"]);
+    }
+
+    #[test]
+    fn given_locale_when_validate_then_only_language_tags_accepted() {
+        assert!(validate_locale("ja").is_ok());
+        assert!(validate_locale("es-MX").is_ok());
+        assert!(validate_locale("ES").is_err());
+        assert!(validate_locale("ja'; DROP TABLE cards; --").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! Field-level validation for card payloads.
+//!
+//! The rules here are independent of the transport, so the HTTP routes,
+//! GraphQL mutations and gRPC handlers all report the same errors for the
+//! same payload.
+
+extern crate juniper;
+
+use diesel::prelude::*;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::card_classes::CardClassRegistry;
+use crate::card_values::{CardAction, CardSpeed, UnknownCardValue};
+use crate::database::DatabaseContext;
+use crate::image::art::{parse_art_url, ArtStore};
+use crate::models::{FullCardData, FullCardDataPayload, NewFullCardData};
+
+pub const MAX_CARD_NAME_LENGTH: usize = 64;
+pub const MAX_CARD_DESC_LENGTH: usize = 2000;
+pub const MIN_INITIATIVE: i32 = 0;
+pub const MAX_INITIATIVE: i32 = 99;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Every field error found in a payload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &str, code: &str, message: String) {
+        self.errors.push(FieldError {
+            field: field.to_owned(),
+            code: code.to_owned(),
+            message,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_result(self) -> std::result::Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Converts the errors into a GraphQL error, with each field error
+    /// listed under the `errors` extension.
+    pub fn to_field_error(&self) -> juniper::FieldError {
+        use juniper::{Object, Value};
+
+        let errors = self
+            .errors
+            .iter()
+            .map(|error| {
+                let mut object = Object::with_capacity(3);
+                object.add_field("field", Value::scalar(error.field.clone()));
+                object.add_field("code", Value::scalar(error.code.clone()));
+                object.add_field("message", Value::scalar(error.message.clone()));
+                Value::object(object)
+            })
+            .collect::<Vec<_>>();
+
+        let mut extensions = Object::with_capacity(1);
+        extensions.add_field("errors", Value::list(errors));
+
+        juniper::FieldError::new(self, Value::object(extensions))
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} invalid field(s): {}",
+            self.errors.len(),
+            self.errors
+                .iter()
+                .map(|error| format!("{}: {}", error.field, error.message))
+                .collect::<Vec<String>>()
+                .join("; ")
+        )
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// The database state that card payloads are validated against.
+#[derive(Debug, Clone, Default)]
+pub struct CardValidationContext {
+    pub card_classes: CardClassRegistry,
+    pub card_attribute_ids: HashSet<i32>,
+    /// The art stored for the database, which `art:` URLs must point into
+    /// when it is known.
+    pub art: Option<ArtStore>,
+}
+
+impl NewFullCardData {
+    pub fn validate(
+        &self,
+        context: &CardValidationContext,
+    ) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        validate_card_fields(
+            &mut errors,
+            context,
+            &CardFields {
+                cardclass: &self.cardclass,
+                action: &self.action,
+                speed: &self.speed,
+                initiative: self.initiative,
+                name: &self.name,
+                desc: &self.desc,
+                image_url: self.image_url.as_deref(),
+            },
+        );
+
+        if let Some(ids) = &self.card_attributes {
+            validate_card_attribute_ids(&mut errors, context, "card_attributes", ids);
+        }
+
+        errors.into_result()
+    }
+}
+
+impl FullCardData {
+    pub fn validate(
+        &self,
+        context: &CardValidationContext,
+    ) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        validate_card_fields(
+            &mut errors,
+            context,
+            &CardFields {
+                cardclass: &self.cardclass,
+                action: self.action.as_str(),
+                speed: self.speed.as_str(),
+                initiative: self.initiative,
+                name: &self.name,
+                desc: &self.desc,
+                image_url: self.image_url.as_deref(),
+            },
+        );
+
+        if let Some(attributes) = &self.attributes {
+            let ids = attributes.iter().map(|attr| attr.id).collect::<Vec<i32>>();
+            validate_card_attribute_ids(&mut errors, context, "attributes", &ids);
+        }
+
+        errors.into_result()
+    }
+}
+
+impl FullCardDataPayload {
+    pub fn validate(
+        &self,
+        context: &CardValidationContext,
+    ) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        validate_card_fields(
+            &mut errors,
+            context,
+            &CardFields {
+                cardclass: &self.cardclass,
+                action: &self.action,
+                speed: &self.speed,
+                initiative: self.initiative,
+                name: &self.name,
+                desc: &self.desc,
+                image_url: self.image_url.as_deref(),
+            },
+        );
+
+        if let Some(attributes) = &self.attributes {
+            let ids = attributes.iter().map(|attr| attr.id).collect::<Vec<i32>>();
+            validate_card_attribute_ids(&mut errors, context, "attributes", &ids);
+        }
+
+        errors.into_result()
+    }
+
+    /// The card to write, once the payload has been validated.
+    pub fn into_full_card_data(self) -> std::result::Result<FullCardData, UnknownCardValue> {
+        Ok(FullCardData {
+            id: self.id,
+            cardclass: self.cardclass,
+            action: self.action.parse()?,
+            speed: self.speed.parse()?,
+            initiative: self.initiative,
+            name: self.name,
+            desc: self.desc,
+            image_url: self.image_url,
+            version: self.version,
+            cloned_from_id: None,
+            attributes: self.attributes,
+            relations: None,
+        })
+    }
+}
+
+/// The fields every kind of card payload has, borrowed to be validated.
+struct CardFields<'a> {
+    cardclass: &'a str,
+    action: &'a str,
+    speed: &'a str,
+    initiative: i32,
+    name: &'a str,
+    desc: &'a str,
+    image_url: Option<&'a str>,
+}
+
+fn validate_card_fields(
+    errors: &mut ValidationErrors,
+    context: &CardValidationContext,
+    card: &CardFields,
+) {
+    let CardFields {
+        cardclass,
+        action,
+        speed,
+        initiative,
+        name,
+        desc,
+        image_url,
+    } = *card;
+
+    if context.card_classes.get(cardclass).is_none() {
+        errors.add(
+            "cardclass",
+            "unknown_card_class",
+            format!("Unknown card class '{}'", cardclass),
+        );
+    }
+
+    if let Err(err) = action.parse::<CardAction>() {
+        errors.add("action", "unknown_value", err.to_string());
+    }
+
+    if let Err(err) = speed.parse::<CardSpeed>() {
+        errors.add("speed", "unknown_value", err.to_string());
+    }
+
+    if !(MIN_INITIATIVE..=MAX_INITIATIVE).contains(&initiative) {
+        errors.add(
+            "initiative",
+            "out_of_range",
+            format!(
+                "Initiative must be between {} and {}; found {}",
+                MIN_INITIATIVE, MAX_INITIATIVE, initiative
+            ),
+        );
+    }
+
+    if name.trim().is_empty() {
+        errors.add("name", "empty", "Card names cannot be empty".to_owned());
+    } else if name.chars().count() > MAX_CARD_NAME_LENGTH {
+        errors.add(
+            "name",
+            "too_long",
+            format!(
+                "Card names can be at most {} characters long",
+                MAX_CARD_NAME_LENGTH
+            ),
+        );
+    }
+
+    if desc.chars().count() > MAX_CARD_DESC_LENGTH {
+        errors.add(
+            "desc",
+            "too_long",
+            format!(
+                "Card descriptions can be at most {} characters long",
+                MAX_CARD_DESC_LENGTH
+            ),
+        );
+    }
+
+    // Art can only be checked to be stored where the art of the database
+    // is known.
+    let is_stored = |hash: &str| {
+        context
+            .art
+            .as_ref()
+            .is_none_or(|art| art.path(hash).exists())
+    };
+
+    if let Some(url) = image_url {
+        match (parse_art_url(url), reqwest::Url::parse(url)) {
+            (Some(hash), _) if !is_stored(hash) => errors.add(
+                "image_url",
+                "unknown_art",
+                format!("There is no stored art '{}'", hash),
+            ),
+            (Some(_), _) => {}
+            (None, Ok(parsed)) if ["http", "https"].contains(&parsed.scheme()) => {}
+            _ => errors.add(
+                "image_url",
+                "invalid_url",
+                format!("'{}' is not a valid http, https or art URL", url),
+            ),
+        }
+    }
+}
+
+fn validate_card_attribute_ids(
+    errors: &mut ValidationErrors,
+    context: &CardValidationContext,
+    field: &str,
+    ids: &[i32],
+) {
+    let unknown_ids = ids
+        .iter()
+        .filter(|id| !context.card_attribute_ids.contains(id))
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>();
+
+    if !unknown_ids.is_empty() {
+        errors.add(
+            field,
+            "unknown_card_attribute",
+            format!("Unknown card attribute id(s): {}", unknown_ids.join(", ")),
+        );
+    }
+
+    let unique_ids = ids.iter().collect::<HashSet<&i32>>();
+    if unique_ids.len() != ids.len() {
+        errors.add(
+            field,
+            "duplicate_card_attribute",
+            "Card attributes can only be listed once".to_owned(),
+        );
+    }
+}
+
+impl DatabaseContext {
+    pub fn get_card_validation_context(&self) -> Result<CardValidationContext> {
+        use crate::schema::card_attributes::dsl::*;
+
+        let card_attribute_ids = card_attributes
+            .select(id)
+            .load::<i32>(self.connection.as_ref())?
+            .into_iter()
+            .collect();
+
+        Ok(CardValidationContext {
+            card_classes: self.get_card_class_registry()?,
+            card_attribute_ids,
+            art: self.art_store.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::card_classes::CardClassRegistry;
+    use crate::models::{CardClass, NewFullCardData};
+    use crate::validation::CardValidationContext;
+
+    fn context() -> CardValidationContext {
+        CardValidationContext {
+            card_classes: CardClassRegistry::new(vec![CardClass {
+                id: 1,
+                code: "Sp".to_owned(),
+                name: "Spell".to_owned(),
+                color: "#ffe5ff".to_owned(),
+                sort_order: 0,
+            }]),
+            card_attribute_ids: vec![1, 2].into_iter().collect(),
+            art: None,
+        }
+    }
+
+    fn card() -> NewFullCardData {
+        NewFullCardData {
+            cardclass: "Sp".to_owned(),
+            action: "Attack".to_owned(),
+            speed: "Normal".to_owned(),
+            initiative: 3,
+            name: "Fireball".to_owned(),
+            desc: "Range 3.".to_owned(),
+            image_url: None,
+            card_attributes: Some(vec![1]),
+        }
+    }
+
+    #[test]
+    fn given_valid_card_when_validate_then_ok() {
+        assert!(card().validate(&context()).is_ok());
+    }
+
+    #[test]
+    fn given_invalid_card_when_validate_then_every_field_error_collected() {
+        let new_card = NewFullCardData {
+            cardclass: "Zz".to_owned(),
+            speed: "Fats".to_owned(),
+            initiative: -1,
+            name: "  ".to_owned(),
+            image_url: Some("not a url".to_owned()),
+            card_attributes: Some(vec![1, 7]),
+            ..card()
+        };
+
+        let errors = new_card.validate(&context()).unwrap_err();
+        let fields = errors
+            .errors
+            .iter()
+            .map(|error| error.field.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(
+            fields,
+            vec![
+                "cardclass",
+                "speed",
+                "initiative",
+                "name",
+                "image_url",
+                "card_attributes"
+            ]
+        );
+    }
+
+    #[test]
+    fn given_local_file_url_when_validate_then_refused() {
+        for image_url in &["file:///etc/passwd", "/etc/passwd"] {
+            let new_card = NewFullCardData {
+                image_url: Some(image_url.to_string()),
+                ..card()
+            };
+
+            let errors = new_card.validate(&context()).unwrap_err();
+            assert_eq!(errors.errors.len(), 1);
+            assert_eq!(errors.errors[0].field, "image_url");
+            assert_eq!(errors.errors[0].code, "invalid_url");
+        }
+    }
+}
@@ -1,13 +1,92 @@
 use tonic::{transport::Server, Request, Response, Status};
 
 use cardego_grpc::generated::cardego_data::cardego_data_service_server::{CardegoDataService, CardegoDataServiceServer};
+use cardego_grpc::generated::cardego_data::{Card, GetCardRequest, UpdateCardRequest};
+
+use cardego_server::database::DatabaseContext;
+use cardego_server::errors::ClientError;
+use cardego_server::image::cache::RenderCache;
+use cardego_server::models::{FullCardData, FullCardDataPayload};
+use cardego_server::ApplicationConfig;
 
 // pub mod hello_world {
 //     tonic::include_proto!("cardego_data");
 // }
 
-#[derive(Default)]
-pub struct MyServer {}
+pub struct MyServer {
+    database_endpoint: String,
+    render_directory: String,
+    render_cache_max_bytes: u64,
+}
+
+impl MyServer {
+    pub fn new(config: &ApplicationConfig) -> Self {
+        Self {
+            database_endpoint: config.default_database().database_endpoint.clone(),
+            render_directory: config.default_database().render_directory.clone(),
+            render_cache_max_bytes: config.render_cache_max_bytes,
+        }
+    }
+
+    /// Runs a database call on the blocking thread pool, since Diesel calls
+    /// block.
+    async fn with_database<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DatabaseContext) -> anyhow::Result<T> + Send + 'static,
+    {
+        let database_endpoint = self.database_endpoint.clone();
+        // Cards written here drop the images the HTTP server rendered of them.
+        let render_cache = RenderCache::new(&self.render_directory, self.render_cache_max_bytes);
+
+        tokio::task::spawn_blocking(move || {
+            let db = DatabaseContext::new(&database_endpoint)
+                .map_err(|_| Status::unavailable("Could not connect to database"))?
+                .with_render_cache(render_cache);
+            f(&db).map_err(to_status)
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+    }
+}
+
+fn to_status(err: anyhow::Error) -> Status {
+    match err.downcast::<ClientError>() {
+        Ok(ClientError::ResourceNotFound) => Status::not_found("Requested resource not found"),
+        Ok(ClientError::PreconditionFailed(message)) => Status::failed_precondition(message),
+        Ok(client_err) => Status::invalid_argument(client_err.to_string()),
+        Err(err) => Status::internal(err.to_string()),
+    }
+}
+
+fn to_card_message(card: FullCardData) -> Card {
+    Card {
+        id: card.id,
+        cardclass: card.cardclass,
+        action: card.action.to_string(),
+        speed: card.speed.to_string(),
+        initiative: card.initiative,
+        name: card.name,
+        desc: card.desc,
+        image_url: card.image_url.unwrap_or_default(),
+        version: card.version,
+    }
+}
+
+fn to_full_card_data_payload(card: Card) -> FullCardDataPayload {
+    FullCardDataPayload {
+        id: card.id,
+        cardclass: card.cardclass,
+        action: card.action,
+        speed: card.speed,
+        initiative: card.initiative,
+        name: card.name,
+        desc: card.desc,
+        image_url: Some(card.image_url).filter(|url| !url.is_empty()),
+        version: card.version,
+        attributes: None,
+    }
+}
 
 #[tonic::async_trait]
 impl CardegoDataService for MyServer {
@@ -18,6 +97,40 @@ impl CardegoDataService for MyServer {
         println!("Got a request from {:?}", request.remote_addr());
         Ok(Response::new(()))
     }
+
+    async fn get_card(
+        &self,
+        request: Request<GetCardRequest>,
+    ) -> Result<Response<Card>, Status> {
+        let card_id = request.into_inner().id;
+
+        let card = self
+            .with_database(move |db| {
+                db.get_full_card_data(card_id)
+                    .or(Err(ClientError::ResourceNotFound.into()))
+            })
+            .await?;
+
+        Ok(Response::new(to_card_message(card)))
+    }
+
+    async fn update_card(
+        &self,
+        request: Request<UpdateCardRequest>,
+    ) -> Result<Response<Card>, Status> {
+        let request = request.into_inner();
+        let card = request
+            .card
+            .ok_or_else(|| Status::invalid_argument("card is required"))?;
+        let card = to_full_card_data_payload(card);
+        let expected_version = Some(request.expected_version).filter(|version| *version > 0);
+
+        let card = self
+            .with_database(move |db| db.update_card_from_payload(card, expected_version.into()))
+            .await?;
+
+        Ok(Response::new(to_card_message(card)))
+    }
 }
 
 pub async fn start_server() -> Result<(), Box<dyn std::error::Error>> {
@@ -25,7 +138,8 @@ pub async fn start_server() -> Result<(), Box<dyn std::error::Error>> {
 
     let addr = "127.0.0.1:8080".parse().unwrap();
 
-    let server = MyServer::default();
+    let config = ApplicationConfig::new()?;
+    let server = MyServer::new(&config);
     let server = CardegoDataServiceServer::new(server);
 
     let reflection_server = tonic_reflection::server::Builder::configure()
@@ -43,4 +157,4 @@ pub async fn start_server() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,3 +1,42 @@
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Card {
+    #[prost(int32, tag = "1")]
+    pub id: i32,
+    #[prost(string, tag = "2")]
+    pub cardclass: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub action: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub speed: ::prost::alloc::string::String,
+    #[prost(int32, tag = "5")]
+    pub initiative: i32,
+    #[prost(string, tag = "6")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "7")]
+    pub desc: ::prost::alloc::string::String,
+    /// Empty if the card has no image.
+    #[prost(string, tag = "8")]
+    pub image_url: ::prost::alloc::string::String,
+    #[prost(int32, tag = "9")]
+    pub version: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetCardRequest {
+    #[prost(int32, tag = "1")]
+    pub id: i32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpdateCardRequest {
+    #[prost(message, optional, tag = "1")]
+    pub card: ::core::option::Option<Card>,
+    /// The version the card was read at. Versions start at 1, so 0 updates
+    /// the card whatever its version.
+    #[prost(int32, tag = "2")]
+    pub expected_version: i32,
+}
 /// Generated client implementations.
 pub mod cardego_data_service_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -107,6 +146,52 @@ pub mod cardego_data_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
+        pub async fn get_card(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetCardRequest>,
+        ) -> std::result::Result<tonic::Response<super::Card>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/cardego_data.CardegoDataService/GetCard",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("cardego_data.CardegoDataService", "GetCard"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Fails with FAILED_PRECONDITION if `expected_version` is set and the
+        /// card has been changed since that version.
+        pub async fn update_card(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UpdateCardRequest>,
+        ) -> std::result::Result<tonic::Response<super::Card>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/cardego_data.CardegoDataService/UpdateCard",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("cardego_data.CardegoDataService", "UpdateCard"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -120,6 +205,16 @@ pub mod cardego_data_service_server {
             &self,
             request: tonic::Request<()>,
         ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
+        async fn get_card(
+            &self,
+            request: tonic::Request<super::GetCardRequest>,
+        ) -> std::result::Result<tonic::Response<super::Card>, tonic::Status>;
+        /// Fails with FAILED_PRECONDITION if `expected_version` is set and the
+        /// card has been changed since that version.
+        async fn update_card(
+            &self,
+            request: tonic::Request<super::UpdateCardRequest>,
+        ) -> std::result::Result<tonic::Response<super::Card>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct CardegoDataServiceServer<T: CardegoDataService> {
@@ -241,6 +336,92 @@ pub mod cardego_data_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/cardego_data.CardegoDataService/GetCard" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetCardSvc<T: CardegoDataService>(pub Arc<T>);
+                    impl<
+                        T: CardegoDataService,
+                    > tonic::server::UnaryService<super::GetCardRequest> for GetCardSvc<T> {
+                        type Response = super::Card;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetCardRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).get_card(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetCardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/cardego_data.CardegoDataService/UpdateCard" => {
+                    #[allow(non_camel_case_types)]
+                    struct UpdateCardSvc<T: CardegoDataService>(pub Arc<T>);
+                    impl<
+                        T: CardegoDataService,
+                    > tonic::server::UnaryService<super::UpdateCardRequest> for UpdateCardSvc<T> {
+                        type Response = super::Card;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UpdateCardRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).update_card(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = UpdateCardSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(